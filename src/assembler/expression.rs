@@ -0,0 +1,263 @@
+// Expressions as BeebAsm writes them: numbers in decimal, &hex, $hex or
+// %binary, names, P% for the current address, + - * / DIV MOD AND OR EOR,
+// unary minus, brackets, and LO() and HI() for the bytes of a word.
+//
+// A value is None while it depends on a name that isn't defined yet, which
+// is only an error on the final pass.
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Operator(char),
+    Word(&'static str),
+    Open,
+    Close,
+}
+
+const WORDS: [&str; 7] = ["AND", "OR", "EOR", "DIV", "MOD", "LO", "HI"];
+
+fn tokenise(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut n = 0;
+
+    let digits = |n: &mut usize, radix: u32| -> Result<i64, String> {
+        let start = *n;
+        while *n < chars.len() && chars[*n].is_digit(radix) {
+            *n += 1;
+        }
+        let digits: String = chars[start..*n].iter().collect();
+        i64::from_str_radix(&digits, radix).map_err(|_| format!("invalid number in '{}'", text))
+    };
+
+    while n < chars.len() {
+        let c = chars[n];
+        match c {
+            _ if c.is_whitespace() => n += 1,
+            '&' | '$' => {
+                n += 1;
+                tokens.push(Token::Number(digits(&mut n, 16)?));
+            }
+            '%' => {
+                n += 1;
+                tokens.push(Token::Number(digits(&mut n, 2)?));
+            }
+            '0'..='9' => tokens.push(Token::Number(digits(&mut n, 10)?)),
+            '+' | '-' | '*' | '/' => {
+                tokens.push(Token::Operator(c));
+                n += 1;
+            }
+            '(' => {
+                tokens.push(Token::Open);
+                n += 1;
+            }
+            ')' => {
+                tokens.push(Token::Close);
+                n += 1;
+            }
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                let start = n;
+                while n < chars.len() && (chars[n].is_ascii_alphanumeric() || chars[n] == '_') {
+                    n += 1;
+                }
+                if n < chars.len() && chars[n] == '%' {
+                    n += 1;
+                }
+                let name: String = chars[start..n].iter().collect();
+                match WORDS.iter().find(|w| w.eq_ignore_ascii_case(&name)) {
+                    Some(word) => tokens.push(Token::Word(word)),
+                    None => tokens.push(Token::Name(name)),
+                }
+            }
+            _ => return Err(format!("unexpected '{}' in '{}'", c, text)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a, F: Fn(&str) -> Option<i64>> {
+    tokens: Vec<Token>,
+    position: usize,
+    lookup: &'a F,
+    text: &'a str,
+}
+
+type Value = Result<Option<i64>, String>;
+
+impl<F: Fn(&str) -> Option<i64>> Parser<'_, F> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn error(&self) -> String {
+        format!("invalid expression '{}'", self.text)
+    }
+
+    fn binary(
+        &mut self,
+        operand: fn(&mut Self) -> Value,
+        operators: &[Token],
+        apply: fn(&Token, i64, i64) -> Option<i64>,
+    ) -> Value {
+        let mut value = operand(self)?;
+        while let Some(operator) = self.peek().filter(|t| operators.contains(t)).cloned() {
+            self.position += 1;
+            let right = operand(self)?;
+            value = match (value, right) {
+                (Some(left), Some(right)) => Some(
+                    apply(&operator, left, right)
+                        .ok_or_else(|| format!("division by zero in '{}'", self.text))?,
+                ),
+                _ => None,
+            };
+        }
+        Ok(value)
+    }
+
+    fn logical(&mut self) -> Value {
+        self.binary(
+            Self::and,
+            &[Token::Word("OR"), Token::Word("EOR")],
+            |operator, left, right| match operator {
+                Token::Word("OR") => Some(left | right),
+                _ => Some(left ^ right),
+            },
+        )
+    }
+
+    fn and(&mut self) -> Value {
+        self.binary(Self::sum, &[Token::Word("AND")], |_, left, right| {
+            Some(left & right)
+        })
+    }
+
+    fn sum(&mut self) -> Value {
+        self.binary(
+            Self::product,
+            &[Token::Operator('+'), Token::Operator('-')],
+            |operator, left, right| match operator {
+                Token::Operator('+') => Some(left + right),
+                _ => Some(left - right),
+            },
+        )
+    }
+
+    fn product(&mut self) -> Value {
+        self.binary(
+            Self::unary,
+            &[
+                Token::Operator('*'),
+                Token::Operator('/'),
+                Token::Word("DIV"),
+                Token::Word("MOD"),
+            ],
+            |operator, left, right| match operator {
+                Token::Operator('*') => Some(left * right),
+                Token::Word("MOD") => left.checked_rem(right),
+                _ => left.checked_div(right),
+            },
+        )
+    }
+
+    fn unary(&mut self) -> Value {
+        match self.peek() {
+            Some(Token::Operator('-')) => {
+                self.position += 1;
+                Ok(self.unary()?.map(|value| -value))
+            }
+            Some(Token::Operator('+')) => {
+                self.position += 1;
+                self.unary()
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn bracketed(&mut self) -> Value {
+        if self.next() != Some(Token::Open) {
+            return Err(self.error());
+        }
+        let value = self.logical()?;
+        if self.next() != Some(Token::Close) {
+            return Err(self.error());
+        }
+        Ok(value)
+    }
+
+    fn primary(&mut self) -> Value {
+        match self.peek().cloned() {
+            Some(Token::Number(value)) => {
+                self.position += 1;
+                Ok(Some(value))
+            }
+            Some(Token::Name(name)) => {
+                self.position += 1;
+                Ok((self.lookup)(&name))
+            }
+            Some(Token::Open) => self.bracketed(),
+            Some(Token::Word("LO")) => {
+                self.position += 1;
+                Ok(self.bracketed()?.map(|value| value & 0xff))
+            }
+            Some(Token::Word("HI")) => {
+                self.position += 1;
+                Ok(self.bracketed()?.map(|value| (value >> 8) & 0xff))
+            }
+            _ => Err(self.error()),
+        }
+    }
+}
+
+// Names are looked up as they're found, including P%
+pub(crate) fn evaluate<F: Fn(&str) -> Option<i64>>(text: &str, lookup: &F) -> Value {
+    let mut parser = Parser {
+        tokens: tokenise(text)?,
+        position: 0,
+        lookup,
+        text,
+    };
+    let value = parser.logical()?;
+    if parser.position != parser.tokens.len() {
+        return Err(parser.error());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(text: &str) -> Value {
+        evaluate(text, &|name| match name {
+            "table" => Some(0x1a40),
+            "P%" => Some(0x1900),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn operators_and_precedence() {
+        assert_eq!(value("&10 + 2 * 3"), Ok(Some(22)));
+        assert_eq!(value("(&10 + 2) * 3"), Ok(Some(54)));
+        assert_eq!(value("%1010 AND 12 OR 1"), Ok(Some(9)));
+        assert_eq!(value("-7 DIV 2 + 17 MOD 5"), Ok(Some(-1)));
+        assert_eq!(value("LO(table) + HI(table+&100)"), Ok(Some(0x40 + 0x1b)));
+        assert_eq!(value("P% - $100"), Ok(Some(0x1800)));
+    }
+
+    #[test]
+    fn unknown_names_and_mistakes() {
+        assert_eq!(value("later + 1"), Ok(None));
+        assert!(value("1 +").is_err());
+        assert!(value("(1").is_err());
+        assert!(value("1 / 0").is_err());
+        assert!(value("1 ? 2").is_err());
+    }
+}
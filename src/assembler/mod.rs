@@ -0,0 +1,678 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::{
+    Address, AddressingMode, Byte, Error, ErrorType, InstructionDecoder, Opcode, Result,
+};
+use crate::disassembler::symbols::Symbols;
+
+use self::expression::evaluate;
+
+mod expression;
+
+// A two-pass assembler for the syntax the disassembler writes, which is
+// BeebAsm's take on the BBC BASIC assembler:
+//
+//   \ comments start with \ or ;
+//   oswrch = &FFEE           constants
+//   ORG &1900                where the following code goes
+//   .start LDA #65           labels, which can share a line
+//          JSR oswrch : RTS  several statements on a line
+//   EQUB 1, 2, "text"        bytes, and strings as bytes
+//   EQUW start, &FFFF        little-endian words
+//   EQUS "text", 13          strings, and bytes as characters
+//   INCLUDE "other.s"        another file, assembled in place
+//
+// The first pass works out where the labels are; the second emits the
+// code. Operands that are unknown on the first pass, such as forward
+// references, are taken to be absolute rather than zero page, and stay that
+// way so nothing moves between passes.
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+const MEMORY_SIZE: usize = 0x10000;
+
+// The bytes from the lowest address written to the highest, with any gaps
+// between ORGs left as zero
+pub struct Assembly {
+    pub origin: Address,
+    pub bytes: Vec<Byte>,
+    // Labels and constants that fit in an address
+    pub symbols: Symbols,
+}
+
+pub struct Assembler {
+    opcodes: HashMap<String, Opcode>,
+    encodings: HashMap<(Opcode, AddressingMode), Byte>,
+    sources: HashMap<String, String>,
+    include_dir: PathBuf,
+}
+
+// Operands as written, before a mode is picked
+enum Operand<'a> {
+    None,
+    Accumulator,
+    Immediate(&'a str),
+    Direct(&'a str),
+    IndexedX(&'a str),
+    IndexedY(&'a str),
+    Indirect(&'a str),
+    IndirectX(&'a str),
+    IndirectY(&'a str),
+}
+
+fn assembly_error(message: String) -> Error {
+    Error::without_pc(ErrorType::Assembly(message))
+}
+
+// Whether the text is a single bracketed expression, rather than something
+// like (a+1)*(b+1)
+fn bracketed(text: &str) -> Option<&str> {
+    let inner = text.strip_prefix('(')?.strip_suffix(')')?;
+    let mut depth = 0;
+    for c in inner.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return None,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    Some(inner)
+}
+
+fn parse_operand(text: &str) -> Operand<'_> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Operand::None;
+    }
+    if text.eq_ignore_ascii_case("A") {
+        return Operand::Accumulator;
+    }
+    if let Some(value) = text.strip_prefix('#') {
+        return Operand::Immediate(value);
+    }
+
+    if let Some(comma) = text.rfind(',') {
+        let (before, index) = (text[..comma].trim(), text[comma + 1..].trim());
+        if index.eq_ignore_ascii_case("Y") {
+            return match bracketed(before) {
+                Some(inner) => Operand::IndirectY(inner),
+                None => Operand::IndexedY(before),
+            };
+        }
+        if index.eq_ignore_ascii_case("X") {
+            return Operand::IndexedX(before);
+        }
+        let index: String = index.chars().filter(|c| !c.is_whitespace()).collect();
+        if index.eq_ignore_ascii_case("X)") {
+            if let Some(inner) = before.strip_prefix('(') {
+                return Operand::IndirectX(inner);
+            }
+        }
+    }
+
+    match bracketed(text) {
+        Some(inner) => Operand::Indirect(inner),
+        None => Operand::Direct(text),
+    }
+}
+
+// Splits a line into statements, leaving out any comment. Colons and comment
+// characters inside strings don't count.
+fn statements(line: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (n, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' | '\\' if !quoted => {
+                statements.push(&line[start..n]);
+                return statements;
+            }
+            ':' if !quoted => {
+                statements.push(&line[start..n]);
+                start = n + 1;
+            }
+            _ => {}
+        }
+    }
+    statements.push(&line[start..]);
+    statements
+}
+
+// Splits a list of values at commas outside strings and brackets
+fn values(text: &str) -> Vec<&str> {
+    let mut values = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut depth = 0;
+    for (n, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                values.push(text[start..n].trim());
+                start = n + 1;
+            }
+            _ => {}
+        }
+    }
+    values.push(text[start..].trim());
+    values
+}
+
+fn string(text: &str) -> Option<&str> {
+    text.strip_prefix('"')?.strip_suffix('"')
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+struct Pass {
+    last: bool,
+    pc: Option<Address>,
+    labels: HashMap<String, i64>,
+    memory: Vec<Option<Byte>>,
+    // Which instructions were assembled with a 16-bit operand on the first
+    // pass, by the order they came in
+    absolute: Vec<bool>,
+    instruction: usize,
+}
+
+impl Pass {
+    fn lookup(&self, name: &str) -> Option<i64> {
+        match name {
+            "P%" => self.pc.map(i64::from),
+            _ => self.labels.get(name).copied(),
+        }
+    }
+
+    // The value, which may only be unknown on the first pass
+    fn value(&self, text: &str) -> std::result::Result<Option<i64>, String> {
+        let value = evaluate(text, &|name| self.lookup(name))?;
+        if value.is_none() && self.last {
+            return Err(format!("unknown name in '{}'", text));
+        }
+        Ok(value)
+    }
+
+    fn ranged(&self, text: &str, min: i64, max: i64) -> std::result::Result<i64, String> {
+        match self.value(text)? {
+            Some(value) if value < min || value > max => {
+                Err(format!("'{}' is out of range (&{:X})", text, value))
+            }
+            value => Ok(value.unwrap_or(0)),
+        }
+    }
+
+    fn emit(&mut self, bytes: &[Byte]) -> std::result::Result<(), String> {
+        let pc = self.pc.ok_or("ORG is needed before any code")?;
+        if pc as usize + bytes.len() > MEMORY_SIZE {
+            return Err("code runs past &FFFF".to_string());
+        }
+        if self.last {
+            for (n, &byte) in bytes.iter().enumerate() {
+                let address = pc as usize + n;
+                if self.memory[address].is_some() {
+                    return Err(format!("&{:04X} is assembled twice", address));
+                }
+                self.memory[address] = Some(byte);
+            }
+        }
+        self.pc = Some(pc.wrapping_add(bytes.len() as Address));
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, value: Option<i64>) -> std::result::Result<(), String> {
+        if !is_name(name) {
+            return Err(format!("invalid name '{}'", name));
+        }
+        let Some(value) = value else {
+            return Ok(());
+        };
+        match self.labels.insert(name.to_string(), value) {
+            Some(old) if old != value && self.last => {
+                Err(format!("'{}' moved between passes", name))
+            }
+            Some(_) if !self.last => Err(format!("'{}' is defined twice", name)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Assembler {
+    // The instructions are whatever the decoder can decode, so a 65C02
+    // decoder allows the CMOS instructions too
+    pub fn new<I: InstructionDecoder>(decoder: &I) -> Self {
+        let mut opcodes = HashMap::new();
+        let mut encodings = HashMap::new();
+
+        // The 65C12's undefined opcodes decode as NOPs, but EA is the one
+        // anyone would expect
+        for byte in std::iter::once(0xea).chain(0..=0xff) {
+            let Ok(instruction) = decoder.decode(byte) else {
+                continue;
+            };
+            if matches!(instruction.opcode, Opcode::Invalid(_))
+                || (instruction.addressing_mode == AddressingMode::Implicit
+                    && instruction.byte_length > 1)
+            {
+                continue;
+            }
+            opcodes.insert(format!("{:?}", instruction.opcode), instruction.opcode);
+            encodings
+                .entry((instruction.opcode, instruction.addressing_mode))
+                .or_insert(byte);
+        }
+
+        Assembler {
+            opcodes,
+            encodings,
+            sources: HashMap::new(),
+            include_dir: PathBuf::from("."),
+        }
+    }
+
+    // Where INCLUDE looks for files it hasn't been given
+    pub fn with_include_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.include_dir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    // A file for INCLUDE to use instead of reading it from disk
+    pub fn with_source(mut self, name: &str, text: &str) -> Self {
+        self.sources.insert(name.to_string(), text.to_string());
+        self
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<Assembly> {
+        let mut pass = Pass {
+            last: false,
+            pc: None,
+            labels: HashMap::new(),
+            memory: Vec::new(),
+            absolute: Vec::new(),
+            instruction: 0,
+        };
+        self.source(&mut pass, source, None, 0)?;
+
+        pass.last = true;
+        pass.pc = None;
+        pass.memory = vec![None; MEMORY_SIZE];
+        pass.instruction = 0;
+        self.source(&mut pass, source, None, 0)?;
+
+        let written: Vec<usize> = (0..MEMORY_SIZE)
+            .filter(|&address| pass.memory[address].is_some())
+            .collect();
+        let (origin, bytes) = match (written.first(), written.last()) {
+            (Some(&first), Some(&last)) => (
+                first as Address,
+                pass.memory[first..=last]
+                    .iter()
+                    .map(|byte| byte.unwrap_or(0))
+                    .collect(),
+            ),
+            _ => (0, Vec::new()),
+        };
+
+        let mut symbols = Symbols::new();
+        for (name, &value) in &pass.labels {
+            if let Ok(address) = Address::try_from(value) {
+                symbols.insert(address, name);
+            }
+        }
+
+        Ok(Assembly {
+            origin,
+            bytes,
+            symbols,
+        })
+    }
+
+    pub fn assemble_file<P: AsRef<Path>>(&self, path: P) -> Result<Assembly> {
+        self.assemble(&fs::read_to_string(path)?)
+    }
+
+    fn source(&self, pass: &mut Pass, text: &str, file: Option<&str>, depth: usize) -> Result<()> {
+        for (n, line) in text.lines().enumerate() {
+            for statement in statements(line) {
+                self.statement(pass, statement, depth)
+                    .map_err(|message| match file {
+                        Some(file) => {
+                            assembly_error(format!("{} line {}: {}", file, n + 1, message))
+                        }
+                        None => assembly_error(format!("line {}: {}", n + 1, message)),
+                    })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn statement(
+        &self,
+        pass: &mut Pass,
+        statement: &str,
+        depth: usize,
+    ) -> std::result::Result<(), String> {
+        let mut statement = statement.trim();
+
+        // A label, possibly followed by something else
+        if let Some(rest) = statement.strip_prefix('.') {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            pass.define(&rest[..end], pass.pc.map(i64::from))?;
+            statement = rest[end..].trim();
+        }
+        if statement.is_empty() {
+            return Ok(());
+        }
+
+        let (word, rest) = match statement.find(char::is_whitespace) {
+            Some(n) => (&statement[..n], statement[n..].trim()),
+            None => (statement, ""),
+        };
+
+        if let Some((name, value)) = statement.split_once('=') {
+            if is_name(name.trim()) {
+                let value = pass.value(value)?;
+                return pass.define(name.trim(), value);
+            }
+        }
+
+        match word.to_ascii_uppercase().as_str() {
+            "ORG" => {
+                let origin = pass.ranged(rest, 0, 0xffff)?;
+                pass.pc = Some(origin as Address);
+                Ok(())
+            }
+            "EQUB" | "EQUS" => {
+                for value in values(rest) {
+                    match string(value) {
+                        Some(text) => pass.emit(text.as_bytes())?,
+                        None => {
+                            let byte = pass.ranged(value, -0x80, 0xff)?;
+                            pass.emit(&[byte as Byte])?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            "EQUW" => {
+                for value in values(rest) {
+                    let word = pass.ranged(value, -0x8000, 0xffff)? as Address;
+                    pass.emit(&word.to_le_bytes())?;
+                }
+                Ok(())
+            }
+            "INCLUDE" => {
+                let name = string(rest).ok_or("INCLUDE needs a file name in quotes")?;
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(format!("'{}' is included too deeply", name));
+                }
+                let text = match self.sources.get(name) {
+                    Some(text) => text.clone(),
+                    None => fs::read_to_string(self.include_dir.join(name))
+                        .map_err(|e| format!("{}: {}", name, e))?,
+                };
+                self.source(pass, &text, Some(name), depth + 1)
+                    .map_err(|e| match e.error_type {
+                        ErrorType::Assembly(message) => message,
+                        _ => e.to_string(),
+                    })
+            }
+            mnemonic => {
+                let opcode = *self
+                    .opcodes
+                    .get(mnemonic)
+                    .ok_or_else(|| format!("unknown instruction '{}'", word))?;
+                let bytes = self.instruction(pass, opcode, rest)?;
+                pass.emit(&bytes)
+            }
+        }
+    }
+
+    fn encoding(&self, opcode: Opcode, mode: AddressingMode) -> Option<Byte> {
+        self.encodings.get(&(opcode, mode)).copied()
+    }
+
+    fn instruction(
+        &self,
+        pass: &mut Pass,
+        opcode: Opcode,
+        operand: &str,
+    ) -> std::result::Result<Vec<Byte>, String> {
+        let invalid = || format!("{:?} can't take '{}'", opcode, operand.trim());
+        let with_byte = |mode, text: &str| -> std::result::Result<Vec<Byte>, String> {
+            let byte = self.encoding(opcode, mode).ok_or_else(invalid)?;
+            Ok(vec![byte, pass.ranged(text, -0x80, 0xff)? as Byte])
+        };
+
+        // Modes that come in zero page and absolute forms
+        let (zero_page, absolute, text) = match parse_operand(operand) {
+            Operand::None => {
+                let byte = self
+                    .encoding(opcode, AddressingMode::Implicit)
+                    .or_else(|| self.encoding(opcode, AddressingMode::Accumulator))
+                    .ok_or_else(invalid)?;
+                return Ok(vec![byte]);
+            }
+            Operand::Accumulator => {
+                let byte = self
+                    .encoding(opcode, AddressingMode::Accumulator)
+                    .ok_or_else(invalid)?;
+                return Ok(vec![byte]);
+            }
+            Operand::Immediate(text) => return with_byte(AddressingMode::Immediate, text),
+            Operand::IndirectY(text) => return with_byte(AddressingMode::IndirectY, text),
+            Operand::Direct(text) => {
+                if let Some(byte) = self.encoding(opcode, AddressingMode::Relative) {
+                    let pc = pass.pc.unwrap_or(0) as i64;
+                    let offset = match pass.value(text)? {
+                        Some(target) => target - (pc + 2),
+                        None => 0,
+                    };
+                    if pass.last && !(-0x80..=0x7f).contains(&offset) {
+                        return Err(format!("branch to '{}' is out of range", text));
+                    }
+                    return Ok(vec![byte, offset as Byte]);
+                }
+                (AddressingMode::ZeroPage, AddressingMode::Absolute, text)
+            }
+            Operand::IndexedX(text) => (AddressingMode::ZeroPageX, AddressingMode::AbsoluteX, text),
+            Operand::IndexedY(text) => (AddressingMode::ZeroPageY, AddressingMode::AbsoluteY, text),
+            Operand::Indirect(text) => (
+                AddressingMode::ZeroPageIndirect,
+                AddressingMode::Indirect,
+                text,
+            ),
+            Operand::IndirectX(text) => (
+                AddressingMode::IndirectX,
+                AddressingMode::AbsoluteIndexedIndirect,
+                text,
+            ),
+        };
+
+        let value = pass.value(text)?;
+        let instruction = pass.instruction;
+        pass.instruction += 1;
+        let was_absolute = match pass.absolute.get(instruction) {
+            Some(&was_absolute) => was_absolute,
+            None => {
+                let was_absolute = !value.is_some_and(|v| (0..0x100).contains(&v));
+                pass.absolute.push(was_absolute);
+                was_absolute
+            }
+        };
+
+        let zero_page = self.encoding(opcode, zero_page);
+        let absolute = self.encoding(opcode, absolute);
+        match (zero_page, absolute) {
+            (Some(byte), _) if !was_absolute || absolute.is_none() => {
+                Ok(vec![byte, pass.ranged(text, 0, 0xff)? as Byte])
+            }
+            (_, Some(byte)) => {
+                let [low, high] = (pass.ranged(text, 0, 0xffff)? as Address).to_le_bytes();
+                Ok(vec![byte, low, high])
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instruction_decode::InstructionDecoder;
+    use crate::disassembler::analysis::Analyser;
+
+    fn assemble(source: &str) -> Result<Assembly> {
+        Assembler::new(&InstructionDecoder::new()).assemble(source)
+    }
+
+    fn error(source: &str) -> String {
+        match assemble(source) {
+            Err(Error {
+                error_type: ErrorType::Assembly(message),
+                ..
+            }) => message,
+            _ => panic!("expected an assembly error"),
+        }
+    }
+
+    #[test]
+    fn every_addressing_mode() -> Result<()> {
+        let assembly = assemble(
+            "ORG &2000
+             NOP : ROR A : ASL
+             ADC #&01
+             LDA &70 : LDA &70,X : LDX &70,Y
+             .back BPL back
+             JSR &FFE3
+             ADC &0400,X : ADC &0400,Y : LDA &0070,Y
+             JMP (&020E)
+             LDA (&80,X) : STA (&80),Y",
+        )?;
+        assert_eq!(assembly.origin, 0x2000);
+        assert_eq!(
+            assembly.bytes,
+            [
+                0xea, 0x6a, 0x0a, 0x69, 0x01, 0xa5, 0x70, 0xb5, 0x70, 0xb6, 0x70, 0x10, 0xfe, 0x20,
+                0xe3, 0xff, 0x7d, 0x00, 0x04, 0x79, 0x00, 0x04, 0xb9, 0x70, 0x00, 0x6c, 0x0e, 0x02,
+                0xa1, 0x80, 0x91, 0x80,
+            ]
+        );
+
+        let cmos = Assembler::new(&InstructionDecoder::new_65c02())
+            .assemble("ORG &2000 : LDA (&70) : JMP (&2000,X) : BRA P% : NOP")?;
+        assert_eq!(cmos.bytes, [0xb2, 0x70, 0x7c, 0x00, 0x20, 0x80, 0xfe, 0xea]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn labels_expressions_and_data() -> Result<()> {
+        let assembly = assemble(
+            "\\ forward references stay absolute
+             oswrch = &FFEE
+             ORG &1900
+             .start LDX #0
+             .loop  LDA message,X : BEQ done
+                    JSR oswrch : INX : BNE loop
+             .done  LDA counter : RTS   ; counter is in zero page
+             .message EQUS \"Hi;\", 13 : EQUB 0
+             EQUW start, HI(message) * 2
+             counter = &70",
+        )?;
+        assert_eq!(
+            assembly.bytes,
+            [
+                0xa2, 0x00, 0xbd, 0x11, 0x19, 0xf0, 0x06, 0x20, 0xee, 0xff, 0xe8, 0xd0, 0xf5, 0xad,
+                0x70, 0x00, 0x60, 0x48, 0x69, 0x3b, 0x0d, 0x00, 0x00, 0x19, 0x32, 0x00,
+            ]
+        );
+        assert_eq!(assembly.symbols.name(0x1911), Some("message"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn includes_and_gaps() -> Result<()> {
+        let assembly = Assembler::new(&InstructionDecoder::new())
+            .with_source("vectors.s", "ORG &FFFC : EQUW reset, reset")
+            .assemble("ORG &FFF0 : .reset JMP reset\nINCLUDE \"vectors.s\"")?;
+        assert_eq!(assembly.origin, 0xfff0);
+        assert_eq!(assembly.bytes.len(), 0x10);
+        assert_eq!(&assembly.bytes[..4], [0x4c, 0xf0, 0xff, 0x00]);
+        assert_eq!(&assembly.bytes[0xc..], [0xf0, 0xff, 0xf0, 0xff]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn mistakes() {
+        assert_eq!(error("NOP"), "line 1: ORG is needed before any code");
+        assert_eq!(
+            error("ORG 0\nLDA #256"),
+            "line 2: '256' is out of range (&100)"
+        );
+        assert_eq!(error("ORG 0\nFOO"), "line 2: unknown instruction 'FOO'");
+        assert_eq!(
+            error("ORG 0\nJMP nowhere"),
+            "line 2: unknown name in 'nowhere'"
+        );
+        assert_eq!(error("ORG 0\nSTX &12,X"), "line 2: STX can't take '&12,X'");
+        assert_eq!(
+            error("ORG 0\n.a NOP\n.a NOP"),
+            "line 3: 'a' is defined twice"
+        );
+        assert_eq!(
+            error("ORG 0\nBNE far\nORG &100\n.far"),
+            "line 2: branch to 'far' is out of range"
+        );
+
+        let looped = Assembler::new(&InstructionDecoder::new())
+            .with_source("self.s", "INCLUDE \"self.s\"")
+            .assemble("INCLUDE \"self.s\"");
+        assert!(matches!(
+            looped,
+            Err(Error { error_type: ErrorType::Assembly(message), .. })
+                if message.ends_with("'self.s' is included too deeply")
+        ));
+    }
+
+    // What the ROM analyser writes assembles back to the same bytes
+    #[test]
+    fn disassembly_round_trips() -> Result<()> {
+        let decoder = InstructionDecoder::new();
+        let rom = assemble(
+            "ORG &8000
+             EQUB 0, 0, 0 : JMP service
+             EQUB &82, copyright - &8000, 1 : EQUS \"Test\"
+             .copyright EQUB 0 : EQUS \"(C)\", 0
+             .service CMP #4 : BNE done
+             LDA &0070 : STA &0400,Y : JSR &FFEE
+             .done RTS
+             EQUS \"data\"",
+        )?;
+
+        let source = Analyser::new(&decoder)
+            .with_symbols(Symbols::mos())
+            .analyse(&rom.bytes, 0x8000)?
+            .source();
+        let again = assemble(&source)?;
+        assert_eq!(again.origin, rom.origin);
+        assert_eq!(again.bytes, rom.bytes);
+
+        Ok(())
+    }
+}
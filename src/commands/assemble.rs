@@ -0,0 +1,48 @@
+use std::fs;
+use std::path::Path;
+
+use beeb_rs::assembler::Assembler;
+use beeb_rs::cpu::instruction_decode::InstructionDecoder;
+
+use super::Result;
+
+const USAGE: &str = "usage: beeb-rs assemble SOURCE OUTPUT [--65c02]
+
+Assembles BeebAsm-style source and writes the bytes from the lowest address
+assembled to the highest. INCLUDE paths are relative to the source file.";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+
+    let cmos = match args.iter().position(|a| a == "--65c02") {
+        Some(n) => {
+            args.remove(n);
+            true
+        }
+        None => false,
+    };
+    let (source, output) = match args.as_slice() {
+        [source, output] => (source, output),
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let decoder = if cmos {
+        InstructionDecoder::new_65c02()
+    } else {
+        InstructionDecoder::new()
+    };
+    let dir = Path::new(source).parent().unwrap_or(Path::new("."));
+    let assembly = Assembler::new(&decoder)
+        .with_include_dir(dir)
+        .assemble_file(source)
+        .map_err(|e| format!("{}: {}", source, e))?;
+
+    fs::write(output, &assembly.bytes).map_err(|e| format!("{}: {}", output, e))?;
+    println!(
+        "&{:04X}-&{:04X} ({} bytes)",
+        assembly.origin,
+        assembly.origin as usize + assembly.bytes.len(),
+        assembly.bytes.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use beeb_rs::sound::DEFAULT_SAMPLE_RATE;
+use beeb_rs::video::capture::{VideoCapture, VideoFormat};
+
+use super::screenshot::run_steps;
+use super::{monitor, take_option, Result};
+
+const USAGE: &str = "usage: beeb-rs capture MOS [--model MODEL] [--rom SLOT:FILE]...
+                       [--wav FILE [--sample-rate HZ]] [--video FILE] [STEP]...
+
+steps, taken in order with everything asked for being captured throughout:
+  N          run for N frames
+  type:TEXT  type TEXT, with |M for RETURN
+
+--wav saves the sound, at 44100Hz unless --sample-rate says otherwise.
+--video saves the display at 50 frames a second and 640x256, in step with
+the sound: as YUV4MPEG2 if FILE ends .y4m, or raw 24-bit RGB frames
+otherwise.
+
+e.g. beeb-rs capture os12.rom --rom f:basic2.rom --wav run.wav 200 'type:RUN|M' 500";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let wav = take_option(&mut args, "--wav")?;
+    let video = take_option(&mut args, "--video")?;
+    let sample_rate = match take_option(&mut args, "--sample-rate")? {
+        Some(rate) => rate
+            .parse()
+            .ok()
+            .filter(|&rate| rate > 0)
+            .ok_or_else(|| format!("invalid sample rate '{}'", rate))?,
+        None => DEFAULT_SAMPLE_RATE,
+    };
+    let mut machine = monitor::machine(&mut args)?;
+    if args.is_empty() || (wav.is_none() && video.is_none()) {
+        return Err(USAGE.to_string());
+    }
+    if video.is_some() && machine.screen().is_none() {
+        return Err(format!(
+            "the {:?}'s display can't be captured yet",
+            machine.model()
+        ));
+    }
+    if wav.is_some() {
+        machine.start_sound_capture(sample_rate);
+    }
+    if let Some(video) = &video {
+        let format = if video.ends_with(".y4m") {
+            VideoFormat::Y4m
+        } else {
+            VideoFormat::Rgb
+        };
+        let capture =
+            VideoCapture::to_file(video, format).map_err(|e| format!("{}: {}", video, e))?;
+        machine
+            .start_video_capture(capture)
+            .map_err(|e| format!("{}: {}", video, e))?;
+    }
+
+    run_steps(&mut machine, &args[1..], USAGE)?;
+
+    if let (Some(wav), Some(sound)) = (wav, machine.stop_sound_capture()) {
+        sound
+            .save_wav(&wav)
+            .map_err(|e| format!("{}: {}", wav, e))?;
+        println!("{}: {} samples", wav, sound.samples().len());
+    }
+    if let Some(video) = video {
+        let capture = machine
+            .stop_video_capture()
+            .map_err(|e| format!("{}: {}", video, e))?
+            .unwrap();
+        println!(
+            "{}: {} frames of {}x{}",
+            video,
+            capture.frames(),
+            capture.width(),
+            capture.height()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn captures_sound_and_video() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("beeb-rs-capture-{}", std::process::id()));
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        fs::write(path("mos.rom"), beeb_rs::roms::hello_mos()).map_err(|e| e.to_string())?;
+
+        let args: Vec<String> = [
+            &path("mos.rom"),
+            "--wav",
+            &path("sound.wav"),
+            "--sample-rate",
+            "8000",
+            "--video",
+            &path("video.y4m"),
+            "10",
+            "type:RUN|M",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let result = run(&args);
+
+        let read = |name: &str| fs::read(path(name)).unwrap_or_default();
+        let (wav, video) = (read("sound.wav"), read("video.y4m"));
+        fs::remove_dir_all(&dir).map_err(|e| e.to_string())?;
+        result?;
+
+        assert!(wav.starts_with(b"RIFF"));
+        // The fmt chunk's sample rate
+        assert_eq!(wav[24..28], 8000u32.to_le_bytes());
+        assert!(video.starts_with(b"YUV4MPEG2 W640 H256 F50:1"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use std::fs;
+
+use beeb_rs::cpu::instruction_decode::InstructionDecoder;
+use beeb_rs::disassembler::analysis::Analyser;
+use beeb_rs::disassembler::symbols::Symbols;
+
+use super::{parse_address, take_option, Result};
+
+const USAGE: &str = "usage: beeb-rs disassemble FILE [--base ADDR] [--entry ADDR]...
+                            [--symbols FILE]... [--65c02]
+
+Follows the code from the vectors, a sideways ROM's entries and any given
+entry points, and writes BeebAsm source for the whole image. The base
+defaults to &8000; use &C000 for a MOS.";
+
+const DEFAULT_BASE: u32 = 0x8000;
+
+fn address(text: &str) -> Result<u16> {
+    u16::try_from(parse_address(text)?).map_err(|_| format!("invalid address '{}'", text))
+}
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+
+    let cmos = match args.iter().position(|a| a == "--65c02") {
+        Some(n) => {
+            args.remove(n);
+            true
+        }
+        None => false,
+    };
+    let base = match take_option(&mut args, "--base")? {
+        Some(text) => address(&text)?,
+        None => DEFAULT_BASE as u16,
+    };
+
+    let decoder = if cmos {
+        InstructionDecoder::new_65c02()
+    } else {
+        InstructionDecoder::new()
+    };
+    let mut analyser = Analyser::new(&decoder).with_symbols(Symbols::mos());
+    while let Some(entry) = take_option(&mut args, "--entry")? {
+        analyser = analyser.with_entry(address(&entry)?);
+    }
+    while let Some(path) = take_option(&mut args, "--symbols")? {
+        let symbols = Symbols::load(&path).map_err(|e| format!("{}: {}", path, e))?;
+        analyser = analyser.with_symbols(symbols);
+    }
+
+    let path = match args.as_slice() {
+        [path] => path,
+        _ => return Err(USAGE.to_string()),
+    };
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    if base as usize + bytes.len() > 0x10000 {
+        return Err(format!("{}: too long to load at &{:04X}", path, base));
+    }
+
+    let analysis = analyser.analyse(&bytes, base).map_err(|e| e.to_string())?;
+    print!("{}", analysis.source());
+    Ok(())
+}
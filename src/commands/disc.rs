@@ -0,0 +1,155 @@
+use std::fs;
+use std::path::Path;
+
+use beeb_rs::disc::dfs::{BootOption, Dfs, Entry};
+use beeb_rs::disc::image::{DiscImage, Format};
+
+use super::{parse_address, take_option, Result};
+
+const USAGE: &str = "usage: beeb-rs disc <command> IMAGE [args] [--side N]
+
+commands:
+  new IMAGE [TITLE]                  create a blank 80 track .ssd or .dsd
+  cat IMAGE                          list the catalogue
+  extract IMAGE NAME [OUTPUT]        copy a file out of the image
+  add IMAGE FILE [NAME [LOAD [EXEC]]]
+                                     copy a file into the image
+  delete IMAGE NAME                  remove a file
+  lock IMAGE NAME                    lock a file
+  unlock IMAGE NAME                  unlock a file
+  title IMAGE TITLE                  set the disc title
+  boot IMAGE OPTION                  set the boot option (off/load/run/exec)
+  compact IMAGE                      close the gaps between files";
+
+// Shows addresses the way *INFO does, with I/O processor addresses as &FFxxxx
+fn host_address(address: u32) -> u32 {
+    if address & 0x30000 == 0x30000 {
+        0xff0000 | (address & 0xffff)
+    } else {
+        address
+    }
+}
+
+fn info(entry: &Entry) -> String {
+    format!(
+        "{:<9} {} {:06X} {:06X} {:06X} {:03X}",
+        entry.full_name(),
+        if entry.locked { "L" } else { " " },
+        host_address(entry.load_address),
+        host_address(entry.exec_address),
+        entry.length,
+        entry.start_sector
+    )
+}
+
+fn load(path: &str) -> Result<DiscImage> {
+    DiscImage::load(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn save(image: &DiscImage, path: &str) -> Result<()> {
+    image.save(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn argument(args: &[String], n: usize) -> Result<&str> {
+    args.get(n)
+        .map(String::as_str)
+        .ok_or_else(|| USAGE.to_string())
+}
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let side = match take_option(&mut args, "--side")? {
+        Some(side) => side
+            .parse::<usize>()
+            .map_err(|_| format!("invalid side '{}'", side))?,
+        None => 0,
+    };
+
+    let command = argument(&args, 0)?.to_string();
+    let path = argument(&args, 1)?.to_string();
+
+    if command == "new" {
+        let format = Format::from_extension(Path::new(&path))
+            .filter(|f| !f.double_density())
+            .ok_or_else(|| format!("{}: expected a .ssd or .dsd image", path))?;
+        let title = args.get(2).map_or("", String::as_str);
+
+        let mut image = DiscImage::new(format, 80);
+        for side in 0..format.sides() {
+            Dfs::format(&mut image, side, title).map_err(|e| e.to_string())?;
+        }
+        return save(&image, &path);
+    }
+
+    let mut image = load(&path)?;
+    let mut dfs = Dfs::open(&mut image, side).map_err(|e| format!("{}: {}", path, e))?;
+
+    match command.as_str() {
+        "cat" => {
+            let catalogue = dfs.catalogue();
+            println!("{} ({:02X})", catalogue.title, catalogue.cycle);
+            println!(
+                "Side {}  Option {} ({})  {} of {} sectors free",
+                side,
+                catalogue.boot_option.bits(),
+                catalogue.boot_option.name(),
+                catalogue.free_sectors(),
+                catalogue.sectors
+            );
+            for entry in catalogue.entries.iter().rev() {
+                println!("{}", info(entry));
+            }
+            return Ok(());
+        }
+        "extract" => {
+            let name = argument(&args, 2)?;
+            let data = dfs.read_file(name).map_err(|e| e.to_string())?;
+            let output = match args.get(3) {
+                Some(output) => output.clone(),
+                None => dfs
+                    .catalogue()
+                    .find(name)
+                    .map_err(|e| e.to_string())?
+                    .name
+                    .clone(),
+            };
+            return fs::write(&output, data).map_err(|e| format!("{}: {}", output, e));
+        }
+        "add" => {
+            let file = argument(&args, 2)?;
+            let data = fs::read(file).map_err(|e| format!("{}: {}", file, e))?;
+            let name = match args.get(3) {
+                Some(name) => name.clone(),
+                None => Path::new(file)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_ascii_uppercase())
+                    .unwrap_or_default(),
+            };
+            let load_address = args.get(4).map_or(Ok(0), |a| parse_address(a))?;
+            let exec_address = args.get(5).map_or(Ok(load_address), |a| parse_address(a))?;
+            dfs.write_file(&name, load_address, exec_address, &data)
+        }
+        "delete" => dfs.delete_file(argument(&args, 2)?),
+        "lock" => dfs.set_locked(argument(&args, 2)?, true),
+        "unlock" => dfs.set_locked(argument(&args, 2)?, false),
+        "title" => {
+            dfs.set_title(argument(&args, 2)?);
+            Ok(())
+        }
+        "boot" => {
+            let option = argument(&args, 2)?;
+            let option = BootOption::parse(option)
+                .ok_or_else(|| format!("invalid boot option '{}'", option))?;
+            dfs.set_boot_option(option);
+            Ok(())
+        }
+        "compact" => {
+            dfs.compact();
+            Ok(())
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    .map_err(|e| e.to_string())?;
+
+    save(&image, &path)
+}
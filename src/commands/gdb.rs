@@ -0,0 +1,21 @@
+use beeb_rs::gdb;
+
+use super::{monitor, take_option, Result};
+
+const DEFAULT_PORT: u16 = 6502;
+
+// Takes the same options as the monitor to build the machine, plus the local
+// port to listen on
+pub fn run(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let port = match take_option(&mut args, "--port")? {
+        Some(port) => port
+            .parse()
+            .map_err(|_| format!("invalid port '{}'", port))?,
+        None => DEFAULT_PORT,
+    };
+    let mut machine = monitor::machine(&mut args)?;
+
+    eprintln!("waiting for a debugger on localhost:{}", port);
+    gdb::serve(&mut machine, ("127.0.0.1", port)).map_err(|e| e.to_string())
+}
@@ -0,0 +1,31 @@
+pub mod assemble;
+pub mod capture;
+pub mod disassemble;
+pub mod disc;
+pub mod gdb;
+pub mod monitor;
+pub mod screenshot;
+
+pub type Result<T> = std::result::Result<T, String>;
+
+// Addresses are hex, optionally written the BBC way (&1900) or as 0x1900
+pub fn parse_address(text: &str) -> Result<u32> {
+    let digits = text
+        .strip_prefix('&')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    u32::from_str_radix(digits, 16).map_err(|_| format!("invalid address '{}'", text))
+}
+
+// Removes "--name value" from the arguments, returning the value
+pub fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>> {
+    match args.iter().position(|a| a == name) {
+        Some(n) if n + 1 < args.len() => {
+            let value = args.remove(n + 1);
+            args.remove(n);
+            Ok(Some(value))
+        }
+        Some(_) => Err(format!("{} needs a value", name)),
+        None => Ok(None),
+    }
+}
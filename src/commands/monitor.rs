@@ -0,0 +1,63 @@
+use std::fs;
+use std::io;
+
+use beeb_rs::machine::{Machine, Model, Roms};
+use beeb_rs::monitor::Monitor;
+
+use super::{take_option, Result};
+
+const USAGE: &str = "usage: beeb-rs monitor MOS [--model MODEL] [--rom SLOT:FILE]...
+
+models: b (default), b+, b+128, master, electron";
+
+fn parse_model(name: &str) -> Result<Model> {
+    match name.to_ascii_lowercase().as_str() {
+        "b" => Ok(Model::B),
+        "b+" | "bplus" => Ok(Model::BPlus),
+        "b+128" | "bplus128" => Ok(Model::BPlus128),
+        "master" | "master128" => Ok(Model::Master128),
+        "electron" => Ok(Model::Electron),
+        _ => Err(format!("unknown model '{}'\n{}", name, USAGE)),
+    }
+}
+
+fn read(path: &str) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+// Builds a machine from the command line, shared with anything else that
+// needs one
+pub fn machine(args: &mut Vec<String>) -> Result<Machine> {
+    let model = match take_option(args, "--model")? {
+        Some(name) => parse_model(&name)?,
+        None => Model::B,
+    };
+
+    let mut sideways = Vec::new();
+    while let Some(rom) = take_option(args, "--rom")? {
+        let (slot, path) = rom
+            .split_once(':')
+            .ok_or_else(|| format!("expected SLOT:FILE, not '{}'", rom))?;
+        let slot = u8::from_str_radix(slot, 16)
+            .ok()
+            .filter(|&slot| slot < 16)
+            .ok_or_else(|| format!("invalid ROM slot '{}'", slot))?;
+        sideways.push((slot as usize, read(path)?));
+    }
+
+    let mos = args.first().ok_or_else(|| USAGE.to_string())?;
+    let mut roms = Roms::new(read(mos)?);
+    for (slot, image) in sideways {
+        roms.insert(slot, image);
+    }
+
+    Machine::new(model, roms).map_err(|e| e.to_string())
+}
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let mut monitor = Monitor::new(machine(&mut args)?);
+    monitor
+        .run(io::stdin().lock(), io::stdout())
+        .map_err(|e| e.to_string())
+}
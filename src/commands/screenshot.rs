@@ -0,0 +1,124 @@
+use beeb_rs::devices::keyboard::{key_for, SHIFT};
+use beeb_rs::machine::Machine;
+use beeb_rs::video::capture::FRAME_CYCLES;
+
+use super::{monitor, Result};
+
+const USAGE: &str =
+    "usage: beeb-rs screenshot MOS OUTPUT [--model MODEL] [--rom SLOT:FILE]... [STEP]...
+
+steps, taken in order before the screen is saved as a PNG:
+  N          run for N frames
+  type:TEXT  type TEXT, with |M for RETURN
+
+e.g. beeb-rs screenshot os12.rom screen.png --rom f:basic2.rom 200 'type:RUN|M' 50";
+
+// Long enough for the MOS's keyboard scan to see each key go down and up,
+// and short of auto-repeat
+const KEY_FRAMES: u64 = 3;
+
+fn run_frames(machine: &mut Machine, frames: u64) -> Result<()> {
+    machine
+        .run(frames * FRAME_CYCLES)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn type_text(machine: &mut Machine, text: &str) -> Result<()> {
+    let text = text.replace("|M", "\r");
+    for c in text.chars() {
+        let (row, column, shift) =
+            key_for(c).ok_or_else(|| format!("no key types '{}'", c.escape_default()))?;
+        if shift {
+            machine.press_key(SHIFT.0, SHIFT.1);
+        }
+        machine.press_key(row, column);
+        run_frames(machine, KEY_FRAMES)?;
+        machine.release_key(row, column);
+        if shift {
+            machine.release_key(SHIFT.0, SHIFT.1);
+        }
+        run_frames(machine, KEY_FRAMES)?;
+    }
+    Ok(())
+}
+
+// Takes each step in turn, with the usage to show if one makes no sense
+pub fn run_steps(machine: &mut Machine, steps: &[String], usage: &str) -> Result<()> {
+    for step in steps {
+        match step.strip_prefix("type:") {
+            Some(text) => type_text(machine, text)?,
+            None => {
+                let frames = step
+                    .parse()
+                    .map_err(|_| format!("invalid step '{}'\n{}", step, usage))?;
+                run_frames(machine, frames)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let mut machine = monitor::machine(&mut args)?;
+    if args.len() < 2 {
+        return Err(USAGE.to_string());
+    }
+    if machine.screen().is_none() {
+        return Err(format!(
+            "the {:?}'s display can't be captured yet",
+            machine.model()
+        ));
+    }
+
+    run_steps(&mut machine, &args[2..], USAGE)?;
+
+    let output = &args[1];
+    let frame = machine.frame().unwrap();
+    if frame.width() == 0 || frame.height() == 0 {
+        return Err("the display is off".to_string());
+    }
+    frame
+        .save_png(output)
+        .map_err(|e| format!("{}: {}", output, e))?;
+    println!("{}: {}x{}", output, frame.width(), frame.height());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn captures_a_running_mos() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("beeb-rs-screenshot-{}", std::process::id()));
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        fs::write(path("mos.rom"), beeb_rs::roms::hello_mos()).map_err(|e| e.to_string())?;
+
+        let args: Vec<String> = [
+            &path("mos.rom"),
+            &path("screen.png"),
+            "10",
+            "type:RUN|M",
+            "5",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let result = run(&args);
+
+        let read = |name: &str| fs::read(path(name)).unwrap_or_default();
+        let png = read("screen.png");
+        fs::remove_dir_all(&dir).map_err(|e| e.to_string())?;
+        result?;
+
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        // The IHDR chunk's width and height: MODE 7's 25 rows of 10 lines
+        assert_eq!(png[16..24], [0, 0, 2, 128, 0, 0, 0, 250]);
+
+        Ok(())
+    }
+}
@@ -62,12 +62,19 @@ where
 
     fn absolute_x(&self, memory: &M, registers: &Registers) -> Result<Option<Address>> {
         let address = memory.read_word(registers.pc + 1)?;
-        Ok(Some(address + registers.x as u16))
+        Ok(Some(address.wrapping_add(registers.x as u16)))
     }
 
     fn absolute_y(&self, memory: &M, registers: &Registers) -> Result<Option<Address>> {
         let address = memory.read_word(registers.pc + 1)?;
-        Ok(Some(address + registers.y as u16))
+        Ok(Some(address.wrapping_add(registers.y as u16)))
+    }
+
+    // A pointer in zero page, with its high byte wrapping round to &00
+    fn zero_page_pointer(&self, memory: &M, zero_page: u8) -> Result<Address> {
+        let lsb = memory.read_byte(zero_page as Address)? as Address;
+        let msb = memory.read_byte(zero_page.wrapping_add(1) as Address)? as Address;
+        Ok(lsb + (msb << 8))
     }
 
     fn indirect(&self, memory: &M, registers: &Registers) -> Result<Option<Address>> {
@@ -77,17 +84,41 @@ where
     }
 
     fn indirect_x(&self, memory: &M, registers: &Registers) -> Result<Option<Address>> {
-        let mut indir_address = memory.read_word(registers.pc + 1)?;
-        indir_address += registers.x as u16;
-        indir_address &= 0xff;
-        let address = memory.read_word(indir_address as Address)?;
+        let zero_page = memory.read_byte(registers.pc + 1)?;
+        let address = self.zero_page_pointer(memory, zero_page.wrapping_add(registers.x))?;
         Ok(Some(address))
     }
 
     fn indirect_y(&self, memory: &M, registers: &Registers) -> Result<Option<Address>> {
+        let zero_page = memory.read_byte(registers.pc + 1)?;
+        let address = self.zero_page_pointer(memory, zero_page)?;
+        Ok(Some(address.wrapping_add(registers.y as u16)))
+    }
+
+    // 65C02 only: (zp)
+    fn zero_page_indirect(&self, memory: &M, registers: &Registers) -> Result<Option<Address>> {
+        let zero_page = memory.read_byte(registers.pc + 1)?;
+        Ok(Some(self.zero_page_pointer(memory, zero_page)?))
+    }
+
+    // 65C02 only: JMP (abs,X)
+    fn absolute_indexed_indirect(
+        &self,
+        memory: &M,
+        registers: &Registers,
+    ) -> Result<Option<Address>> {
         let indir_address = memory.read_word(registers.pc + 1)?;
-        let address = memory.read_word(indir_address as Address)?;
-        Ok(Some(address + registers.y as u16))
+        let address = memory.read_word(indir_address.wrapping_add(registers.x as u16))?;
+        Ok(Some(address))
+    }
+}
+
+impl<M> Default for AddressAndDataDispatch<M>
+where
+    M: Memory,
+{
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -115,6 +146,10 @@ where
             AddressingMode::Indirect => self.indirect(memory, registers),
             AddressingMode::IndirectX => self.indirect_x(memory, registers),
             AddressingMode::IndirectY => self.indirect_y(memory, registers),
+            AddressingMode::ZeroPageIndirect => self.zero_page_indirect(memory, registers),
+            AddressingMode::AbsoluteIndexedIndirect => {
+                self.absolute_indexed_indirect(memory, registers)
+            }
             AddressingMode::None => Err(Error::with_pc(
                 registers.pc,
                 ErrorType::InvalidAddressingMode,
@@ -186,6 +221,16 @@ where
                 let b = memory.read_byte(address.unwrap())?;
                 Ok(Some(b))
             }
+            AddressingMode::ZeroPageIndirect => {
+                let address = self.zero_page_indirect(memory, registers)?;
+                let b = memory.read_byte(address.unwrap())?;
+                Ok(Some(b))
+            }
+            AddressingMode::AbsoluteIndexedIndirect => {
+                let address = self.absolute_indexed_indirect(memory, registers)?;
+                let b = memory.read_byte(address.unwrap())?;
+                Ok(Some(b))
+            }
             AddressingMode::None => Err(Error::with_pc(
                 registers.pc,
                 ErrorType::InvalidAddressingMode,
@@ -195,6 +240,7 @@ where
 }
 
 #[cfg(test)]
+#[allow(clippy::unnecessary_cast)]
 mod address_tests {
     use super::*;
     use crate::cpu::{ram::Ram, AddressDataDispatcher};
@@ -392,8 +438,8 @@ mod address_tests {
 
         r.pc = 0x00;
         r.y = 0x10;
-        m.write_word(0x01, 0x1234)?;
-        m.write_word(0x1234, 0x4567)?;
+        m.write_byte(0x01, 0x34)?;
+        m.write_word(0x34, 0x4567)?;
 
         let address = address_dispatcher.get_address(&AddressingMode::IndirectY, &m, &r)?;
 
@@ -607,7 +653,9 @@ mod data_tests {
         r.pc = 0x0010;
         r.y = 0x10;
         m.write_byte(0x0011, 0xff)?;
-        m.write_word(0x00ff, 0x1234)?;
+        // The pointer's high byte wraps round to &00
+        m.write_byte(0x00ff, 0x34)?;
+        m.write_byte(0x0000, 0x12)?;
         m.write_byte(0x1244, 0xaa)?;
 
         let data = data_dispatcher.get_data(&AddressingMode::IndirectY, &m, &r)?;
@@ -615,4 +663,40 @@ mod data_tests {
 
         Ok(())
     }
+
+    #[test]
+    fn zero_page_indirect() -> Result<()> {
+        let data_dispatcher = AddressAndDataDispatch::new();
+        let mut m = Ram::new(65536);
+        let mut r = Registers::new();
+
+        r.pc = 0x0010;
+        m.write_byte(0x0011, 0xff)?;
+        m.write_byte(0x00ff, 0x34)?;
+        m.write_byte(0x0000, 0x12)?;
+        m.write_byte(0x1234, 0xaa)?;
+
+        let data = data_dispatcher.get_data(&AddressingMode::ZeroPageIndirect, &m, &r)?;
+        assert_eq!(data, Some(0xaa));
+
+        Ok(())
+    }
+
+    #[test]
+    fn absolute_indexed_indirect() -> Result<()> {
+        let address_dispatcher = AddressAndDataDispatch::new();
+        let mut m = Ram::new(65536);
+        let mut r = Registers::new();
+
+        r.pc = 0x0010;
+        r.x = 0x04;
+        m.write_word(0x0011, 0x2000)?;
+        m.write_word(0x2004, 0x1234)?;
+
+        let address =
+            address_dispatcher.get_address(&AddressingMode::AbsoluteIndexedIndirect, &m, &r)?;
+        assert_eq!(address, Some(0x1234));
+
+        Ok(())
+    }
 }
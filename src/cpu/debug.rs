@@ -0,0 +1,282 @@
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+
+use crate::cpu::history::Journal;
+use crate::cpu::registers::{Registers, StatusBits};
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    SP,
+    PS,
+    PC,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Register(Register, Comparison, Word),
+    Flag(StatusBits, bool),
+    All(Vec<Condition>),
+}
+
+impl Condition {
+    pub fn holds(&self, registers: &Registers) -> bool {
+        match self {
+            Condition::Register(register, comparison, value) => {
+                let actual = match register {
+                    Register::A => registers.a as Word,
+                    Register::X => registers.x as Word,
+                    Register::Y => registers.y as Word,
+                    Register::SP => registers.sp as Word,
+                    Register::PS => registers.status() as Word,
+                    Register::PC => registers.pc,
+                };
+                match comparison {
+                    Comparison::Equal => actual == *value,
+                    Comparison::NotEqual => actual != *value,
+                    Comparison::Less => actual < *value,
+                    Comparison::Greater => actual > *value,
+                }
+            }
+            Condition::Flag(bit, set) => registers.get_flag(*bit) == *set,
+            Condition::All(conditions) => conditions.iter().all(|c| c.holds(registers)),
+        }
+    }
+}
+
+// Stops before the instruction at the address runs. Without an address the
+// condition is checked before every instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: Option<Address>,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    pub fn at(address: Address) -> Self {
+        Breakpoint {
+            address: Some(address),
+            condition: None,
+        }
+    }
+
+    pub fn when(condition: Condition) -> Self {
+        Breakpoint {
+            address: None,
+            condition: Some(condition),
+        }
+    }
+
+    pub fn with_condition(mut self, condition: Condition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+
+    fn hit(&self, registers: &Registers) -> bool {
+        self.address.is_none_or(|address| address == registers.pc)
+            && self.condition.as_ref().is_none_or(|c| c.holds(registers))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: Address,
+    pub data: Byte,
+    pub access: Access,
+}
+
+// Stops after an instruction (or interrupt) that touched the range. The
+// condition is checked against the registers once it has finished.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<Address>,
+    pub read: bool,
+    pub write: bool,
+    pub condition: Option<Condition>,
+}
+
+impl Watchpoint {
+    pub fn read(range: RangeInclusive<Address>) -> Self {
+        Watchpoint {
+            range,
+            read: true,
+            write: false,
+            condition: None,
+        }
+    }
+
+    pub fn write(range: RangeInclusive<Address>) -> Self {
+        Watchpoint {
+            range,
+            read: false,
+            write: true,
+            condition: None,
+        }
+    }
+
+    pub fn access(range: RangeInclusive<Address>) -> Self {
+        Watchpoint {
+            range,
+            read: true,
+            write: true,
+            condition: None,
+        }
+    }
+
+    pub fn with_condition(mut self, condition: Condition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+
+    fn matches(&self, access: &MemoryAccess) -> bool {
+        self.range.contains(&access.address)
+            && match access.access {
+                Access::Read => self.read,
+                Access::Write => self.write,
+            }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Break {
+    Breakpoint(Address),
+    Watchpoint(MemoryAccess),
+}
+
+// Everything the CPU sees goes through here, so watchpoints catch the stack,
+// vectors and I/O registers as well as ordinary loads and stores. Accesses
+// are only recorded while there's a watchpoint to match them. Old values are
+// only read back while a history is being kept.
+pub struct Watched<M: Memory> {
+    memory: M,
+    watchpoints: Vec<Watchpoint>,
+    accesses: RefCell<Vec<MemoryAccess>>,
+    journal: Option<Journal>,
+}
+
+impl<M: Memory> Watched<M> {
+    pub fn new(memory: M) -> Self {
+        Watched {
+            memory,
+            watchpoints: Vec::new(),
+            accesses: RefCell::new(Vec::new()),
+            journal: None,
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        (index < self.watchpoints.len()).then(|| self.watchpoints.remove(index))
+    }
+
+    pub(crate) fn set_journal(&mut self, journal: Option<Journal>) {
+        self.journal = journal;
+    }
+
+    pub(crate) fn journal_mut(&mut self) -> Option<&mut Journal> {
+        self.journal.as_mut()
+    }
+
+    pub(crate) fn take_accesses(&mut self) -> Vec<MemoryAccess> {
+        std::mem::take(self.accesses.get_mut())
+    }
+
+    // The first access that trips a watchpoint whose condition holds
+    pub(crate) fn triggered(
+        &self,
+        accesses: &[MemoryAccess],
+        registers: &Registers,
+    ) -> Option<MemoryAccess> {
+        accesses.iter().copied().find(|access| {
+            self.watchpoints.iter().any(|w| {
+                w.matches(access) && w.condition.as_ref().is_none_or(|c| c.holds(registers))
+            })
+        })
+    }
+
+    fn record(&self, address: Address, data: Byte, access: Access) {
+        let access = MemoryAccess {
+            address,
+            data,
+            access,
+        };
+        if self.watchpoints.iter().any(|w| w.matches(&access)) {
+            self.accesses.borrow_mut().push(access);
+        }
+    }
+}
+
+impl<M: Memory> Memory for Watched<M> {
+    fn length(&self) -> usize {
+        self.memory.length()
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        let data = self.memory.read_byte(address)?;
+        self.record(address, data, Access::Read);
+        Ok(data)
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let data = self.memory.read_word(address)?;
+        self.record(address, data as Byte, Access::Read);
+        self.record(address.wrapping_add(1), (data >> 8) as Byte, Access::Read);
+        Ok(data)
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        if let Some(journal) = &mut self.journal {
+            journal.record(&self.memory, address)?;
+        }
+        self.memory.write_byte(address, data)?;
+        self.record(address, data, Access::Write);
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        if let Some(journal) = &mut self.journal {
+            journal.record(&self.memory, address)?;
+            journal.record(&self.memory, address.wrapping_add(1))?;
+        }
+        self.memory.write_word(address, data)?;
+        self.record(address, data as Byte, Access::Write);
+        self.record(address.wrapping_add(1), (data >> 8) as Byte, Access::Write);
+        Ok(())
+    }
+}
+
+pub(crate) fn breakpoint_hit(breakpoints: &[Breakpoint], registers: &Registers) -> bool {
+    breakpoints.iter().any(|b| b.hit(registers))
+}
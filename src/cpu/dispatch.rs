@@ -1,33 +1,73 @@
+use crate::cpu::debug::{breakpoint_hit, Access, Break, Breakpoint, MemoryAccess, Watched};
+use crate::cpu::history::{History, Step};
 use crate::cpu::registers::Registers;
-use crate::cpu::{AddressDataDispatcher, InstructionDecoder, Memory, Opcode, Result};
+use crate::cpu::trace::Trace;
+use crate::cpu::{
+    Address, AddressDataDispatcher, AddressingMode, Byte, InstructionDecoder, Memory, Opcode,
+    Result, IRQ_VECTOR, NMI_VECTOR, RESET_VECTOR,
+};
 
 use crate::cpu::ExecutionUnit;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
 
 use super::{ExecutionResult, WritebackUnit};
 
+use crate::cpu::registers::StatusBits;
+
+// Taking an interrupt costs the same as BRK
+const INTERRUPT_CYCLES: usize = 7;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DispatchResult {
+    Cycles(usize),
+    // A breakpoint stops before the instruction runs, so takes no cycles. A
+    // watchpoint stops once the instruction that tripped it has finished.
+    Break(Break, usize),
+}
+
+impl DispatchResult {
+    pub fn cycles(&self) -> usize {
+        match *self {
+            DispatchResult::Cycles(cycles) | DispatchResult::Break(_, cycles) => cycles,
+        }
+    }
+}
+
+// The units work on the memory through a watch, so watchpoints see every
+// access they make
 pub struct Dispatcher<I, A, M, E, W>
 where
     I: InstructionDecoder,
     M: Memory,
-    A: AddressDataDispatcher<M>,
-    E: ExecutionUnit<M>,
-    W: WritebackUnit<M>,
+    A: AddressDataDispatcher<Watched<M>>,
+    E: ExecutionUnit<Watched<M>>,
+    W: WritebackUnit<Watched<M>>,
 {
     instruction_decoder: I,
     address_dispatcher: A,
-    memory: M,
+    memory: Watched<M>,
     registers: Registers,
     execution_unit: E,
     writeback_unit: W,
+
+    irq: bool,
+    nmi: bool,
+    nmi_pending: bool,
+
+    breakpoints: Vec<Breakpoint>,
+    stopped_at: Option<Address>,
+
+    trace: Option<Trace>,
+    history: Option<History>,
 }
 
 impl<I, A, M, E, W> Dispatcher<I, A, M, E, W>
 where
     I: InstructionDecoder,
     M: Memory,
-    A: AddressDataDispatcher<M>,
-    E: ExecutionUnit<M>,
-    W: WritebackUnit<M>,
+    A: AddressDataDispatcher<Watched<M>>,
+    E: ExecutionUnit<Watched<M>>,
+    W: WritebackUnit<Watched<M>>,
 {
     pub fn new(
         registers: Registers,
@@ -40,20 +80,213 @@ where
         Dispatcher {
             instruction_decoder,
             address_dispatcher,
-            memory,
+            memory: Watched::new(memory),
             registers,
             execution_unit,
             writeback_unit,
+            irq: false,
+            nmi: false,
+            nmi_pending: false,
+            breakpoints: Vec::new(),
+            stopped_at: None,
+            trace: None,
+            history: None,
+        }
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    pub fn instruction_decoder(&self) -> &I {
+        &self.instruction_decoder
+    }
+
+    pub fn memory(&self) -> &M {
+        self.memory.inner()
+    }
+
+    pub fn memory_mut(&mut self) -> &mut M {
+        self.memory.inner_mut()
+    }
+
+    pub fn watched(&self) -> &Watched<M> {
+        &self.memory
+    }
+
+    pub fn watched_mut(&mut self) -> &mut Watched<M> {
+        &mut self.memory
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    pub fn remove_breakpoint(&mut self, index: usize) -> Option<Breakpoint> {
+        (index < self.breakpoints.len()).then(|| self.breakpoints.remove(index))
+    }
+
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    // Starts or stops tracing, handing back the trace that was running
+    pub fn set_trace(&mut self, trace: Option<Trace>) -> Option<Trace> {
+        std::mem::replace(&mut self.trace, trace)
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    pub fn history_mut(&mut self) -> Option<&mut History> {
+        self.history.as_mut()
+    }
+
+    // Starts or stops keeping a history, handing back the one that was kept
+    pub fn set_history(&mut self, history: Option<History>) -> Option<History> {
+        self.memory
+            .set_journal(history.as_ref().map(History::journal));
+        std::mem::replace(&mut self.history, history)
+    }
+
+    // Undoes the last step in the history, returning it. Going forward again
+    // runs the instruction rather than stopping at a breakpoint on it.
+    pub fn step_back(&mut self) -> Result<Option<Step>> {
+        let Some(step) = self.history.as_mut().and_then(History::pop) else {
+            return Ok(None);
+        };
+        self.registers = step.undo(self.memory.inner_mut())?;
+        self.stopped_at = Some(self.registers.pc);
+        Ok(Some(step))
+    }
+
+    // IRQ is level-sensitive and masked by the I flag
+    pub fn set_irq(&mut self, level: bool) {
+        self.irq = level;
+    }
+
+    // NMI is edge-triggered: only a rising edge causes an interrupt
+    pub fn set_nmi(&mut self, level: bool) {
+        if level && !self.nmi {
+            self.nmi_pending = true;
+        }
+        self.nmi = level;
+    }
+
+    // The RESET line: the 6502 goes through the motions of an interrupt
+    // without writing to the stack, then starts at the reset vector
+    pub fn reset(&mut self) -> Result<()> {
+        self.irq = false;
+        self.nmi = false;
+        self.nmi_pending = false;
+        self.stopped_at = None;
+
+        self.registers.sp = self.registers.sp.wrapping_sub(3);
+        self.registers.set_flag(StatusBits::Int);
+        self.registers.pc = self.memory.inner().read_word(RESET_VECTOR)?;
+        Ok(())
+    }
+
+    fn service_interrupts(&mut self) -> Result<bool> {
+        let vector = if self.nmi_pending {
+            self.nmi_pending = false;
+            NMI_VECTOR
+        } else if self.irq && !self.registers.int() {
+            IRQ_VECTOR
+        } else {
+            return Ok(false);
+        };
+
+        self.execution_unit
+            .interrupt(vector, &mut self.memory, &mut self.registers)?;
+        Ok(true)
+    }
+
+    // Runs one instruction, or takes an interrupt, returning the cycles used.
+    // Having stopped at a breakpoint, the next call runs the instruction
+    // there rather than stopping again.
+    pub fn dispatch(&mut self) -> Result<DispatchResult> {
+        let resuming = self.stopped_at.take() == Some(self.registers.pc);
+        let before = self.history.as_ref().map(|_| self.registers.clone());
+        if let Some(journal) = self.memory.journal_mut() {
+            journal.clear();
+        }
+
+        let interrupted = self.service_interrupts();
+        let accesses = self.memory.take_accesses();
+        if interrupted? {
+            self.advance_trace(INTERRUPT_CYCLES);
+            self.remember(before, INTERRUPT_CYCLES);
+            return Ok(self.watch(&accesses, INTERRUPT_CYCLES));
+        }
+
+        let pc = self.registers.pc;
+        if !resuming && breakpoint_hit(&self.breakpoints, &self.registers) {
+            self.stopped_at = Some(pc);
+            return Ok(DispatchResult::Break(Break::Breakpoint(pc), 0));
+        }
+
+        if let Some(trace) = &mut self.trace {
+            trace.record(
+                self.memory.inner(),
+                &self.instruction_decoder,
+                &self.registers,
+            )?;
+        }
+
+        // The opcode fetch doesn't count as a read
+        let opcode = self.memory.inner().read_byte(pc)?;
+        let executed = self.execute(opcode);
+        let mut accesses = self.memory.take_accesses();
+        // Failed instructions are kept too, as the end of the trail
+        self.remember(before, *executed.as_ref().unwrap_or(&0));
+        let cycles = executed?;
+        self.advance_trace(cycles);
+
+        // Nor do the operand bytes
+        let instruction = self.instruction_decoder.decode(opcode)?;
+        let operands = pc..pc.wrapping_add(instruction.byte_length as Address);
+        accesses.retain(|a| a.access == Access::Write || !operands.contains(&a.address));
+
+        Ok(self.watch(&accesses, cycles))
+    }
+
+    fn remember(&mut self, before: Option<Registers>, cycles: usize) {
+        if let (Some(history), Some(registers), Some(journal)) =
+            (&mut self.history, before, self.memory.journal_mut())
+        {
+            history.push(registers, cycles, journal);
+        }
+    }
+
+    fn advance_trace(&mut self, cycles: usize) {
+        if let Some(trace) = &mut self.trace {
+            trace.advance(cycles);
+        }
+    }
+
+    fn watch(&self, accesses: &[MemoryAccess], cycles: usize) -> DispatchResult {
+        match self.memory.triggered(accesses, &self.registers) {
+            Some(access) => DispatchResult::Break(Break::Watchpoint(access), cycles),
+            None => DispatchResult::Cycles(cycles),
         }
     }
 
-    pub fn dispatch(&mut self) -> Result<()> {
-        let opcode = self.memory.read_byte(self.registers.pc)?;
+    fn execute(&mut self, opcode: Byte) -> Result<usize> {
         let instruction = self.instruction_decoder.decode(opcode)?;
 
         match instruction.opcode {
             Opcode::Invalid(_) => {
-                self.registers.pc_next = self.registers.pc + 1;
+                self.registers.pc_next = self.registers.pc.wrapping_add(1);
 
                 let result = self.execution_unit.execute(
                     &instruction.opcode,
@@ -66,10 +299,13 @@ where
                 self.registers.pc = self.registers.pc_next;
 
                 result?;
-                Ok(())
+                Ok(instruction.ticks)
             }
             _ => {
-                self.registers.pc_next = self.registers.pc + instruction.byte_length as u16;
+                self.registers.pc_next = self
+                    .registers
+                    .pc
+                    .wrapping_add(instruction.byte_length as u16);
 
                 let address = self.address_dispatcher.get_address(
                     &instruction.addressing_mode,
@@ -77,12 +313,18 @@ where
                     &self.registers,
                 )?;
 
-                let data = self.address_dispatcher.get_data(
-                    &instruction.addressing_mode,
-                    &self.memory,
-                    &self.registers,
-                )?;
+                // A store never reads its target, so reading it here would set
+                // off the side effects of reading a device register
+                let data = match instruction.opcode {
+                    Opcode::STA | Opcode::STX | Opcode::STY | Opcode::STZ => None,
+                    _ => self.address_dispatcher.get_data(
+                        &instruction.addressing_mode,
+                        &self.memory,
+                        &self.registers,
+                    )?,
+                };
 
+                let flags = (self.registers.negative(), self.registers.overflow());
                 let result = self.execution_unit.execute(
                     &instruction.opcode,
                     data,
@@ -91,6 +333,15 @@ where
                     &mut self.registers,
                 )?;
 
+                // The 65C02's BIT # has no memory operand to copy N and V
+                // from, so it only sets Z
+                if instruction.opcode == Opcode::BIT
+                    && instruction.addressing_mode == AddressingMode::Immediate
+                {
+                    self.registers.write_flag(StatusBits::Neg, flags.0);
+                    self.registers.write_flag(StatusBits::Ovf, flags.1);
+                }
+
                 if result != ExecutionResult::None {
                     self.writeback_unit.writeback(
                         &instruction.writeback,
@@ -102,8 +353,370 @@ where
                 }
 
                 self.registers.pc = self.registers.pc_next;
-                Ok(())
+                Ok(instruction.ticks)
             }
         }
     }
 }
+
+// The registers and interrupt lines, followed by everything on the bus
+impl<I, A, M, E, W> Snapshot for Dispatcher<I, A, M, E, W>
+where
+    I: InstructionDecoder,
+    M: Memory + Snapshot,
+    A: AddressDataDispatcher<Watched<M>>,
+    E: ExecutionUnit<Watched<M>>,
+    W: WritebackUnit<Watched<M>>,
+{
+    fn save(&self, state: &mut StateWriter) {
+        self.registers.save(state);
+        state.bool(self.irq);
+        state.bool(self.nmi);
+        state.bool(self.nmi_pending);
+        self.memory.inner().save(state);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.registers.restore(state)?;
+        self.irq = state.bool()?;
+        self.nmi = state.bool()?;
+        self.nmi_pending = state.bool()?;
+        self.memory.inner_mut().restore(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::address::AddressAndDataDispatch;
+    use crate::cpu::debug::{Comparison, Condition, Register, Watchpoint};
+    use crate::cpu::execution::ExecutionUnit;
+    use crate::cpu::instruction_decode::InstructionDecoder;
+    use crate::cpu::ram::Ram;
+    use crate::cpu::registers::{Registers, StatusBits};
+    use crate::cpu::writeback::WritebackUnit;
+
+    type TestDispatcher = Dispatcher<
+        InstructionDecoder,
+        AddressAndDataDispatch<Watched<Ram>>,
+        Ram,
+        ExecutionUnit<Watched<Ram>>,
+        WritebackUnit<Watched<Ram>>,
+    >;
+
+    // A page of CLCs at &1000, with the NMI and IRQ handlers at &2000 and &3000
+    fn dispatcher() -> Result<TestDispatcher> {
+        let mut memory = Ram::new(0x10000);
+        for address in 0x1000..0x1100 {
+            memory.write_byte(address, 0x18)?;
+        }
+        memory.write_word(NMI_VECTOR, 0x2000)?;
+        memory.write_word(IRQ_VECTOR, 0x3000)?;
+
+        let mut registers = Registers::new();
+        registers.pc = 0x1000;
+
+        Ok(Dispatcher::new(
+            registers,
+            memory,
+            InstructionDecoder::new(),
+            AddressAndDataDispatch::new(),
+            ExecutionUnit::new(),
+            WritebackUnit::new(),
+        ))
+    }
+
+    #[test]
+    fn dispatch_without_interrupts() -> Result<()> {
+        let mut cpu = dispatcher()?;
+
+        cpu.dispatch()?;
+        assert_eq!(cpu.registers().pc, 0x1001);
+
+        Ok(())
+    }
+
+    #[test]
+    fn trace_records_instructions_not_interrupts() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        assert!(cpu.set_trace(Some(Trace::ring(8))).is_none());
+
+        cpu.dispatch()?;
+        cpu.set_nmi(true);
+        cpu.dispatch()?;
+        cpu.registers_mut().pc = 0x1001;
+        cpu.dispatch()?;
+
+        let trace = cpu.set_trace(None).unwrap();
+        let kept: Vec<(Address, u64)> = trace
+            .entries()
+            .map(|entry| (entry.line.address, entry.cycles))
+            .collect();
+        assert_eq!(kept, [(0x1000, 0), (0x1001, 9)]);
+        assert_eq!(trace.cycles(), 11);
+
+        Ok(())
+    }
+
+    #[test]
+    fn step_back_undoes_registers_and_memory() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        cpu.memory_mut().write_byte(0x1001, 0x06)?; // ASL &70
+        cpu.memory_mut().write_byte(0x1002, 0x70)?;
+        cpu.memory_mut().write_byte(0x70, 0xc1)?;
+        for address in 0x01fd..=0x01ff {
+            cpu.memory_mut().write_byte(address, 0xaa)?;
+        }
+        cpu.set_history(Some(History::new(4).without(0x01fe..=0x01fe)));
+
+        cpu.dispatch()?;
+        cpu.dispatch()?;
+        cpu.set_nmi(true);
+        cpu.dispatch()?;
+        assert_eq!(cpu.history().unwrap().trail(), [0x1000, 0x1001, 0x1003]);
+        assert_eq!(cpu.memory().read_byte(0x70)?, 0x82);
+
+        let interrupt = cpu.step_back()?.unwrap();
+        assert!(!interrupt.undoable());
+        assert_eq!(cpu.registers().pc, 0x1003);
+        assert_eq!(cpu.registers().sp, 0xff);
+        assert_eq!(cpu.memory().read_byte(0x01ff)?, 0xaa);
+        assert_ne!(cpu.memory().read_byte(0x01fe)?, 0xaa);
+        assert_eq!(cpu.memory().read_byte(0x01fd)?, 0xaa);
+
+        assert!(cpu.step_back()?.unwrap().undoable());
+        assert_eq!(cpu.memory().read_byte(0x70)?, 0xc1);
+        assert!(!cpu.registers().carry());
+        cpu.step_back()?;
+        assert_eq!(cpu.registers().pc, 0x1000);
+        assert!(cpu.step_back()?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn dispatch_returns_cycles() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        assert_eq!(cpu.dispatch()?, DispatchResult::Cycles(2));
+
+        cpu.set_nmi(true);
+        assert_eq!(cpu.dispatch()?, DispatchResult::Cycles(7));
+
+        Ok(())
+    }
+
+    #[test]
+    fn branch_is_written_back_to_pc() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        cpu.memory_mut().write_byte(0x1000, 0x90)?; // BCC +&10
+        cpu.memory_mut().write_byte(0x1001, 0x10)?;
+
+        cpu.dispatch()?;
+        assert_eq!(cpu.registers().pc, 0x1012);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reset_starts_at_reset_vector() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        cpu.memory_mut().write_word(RESET_VECTOR, 0x1080)?;
+        cpu.set_nmi(true);
+
+        cpu.reset()?;
+        assert_eq!(cpu.registers().pc, 0x1080);
+        assert_eq!(cpu.registers().sp, 0xfc);
+        assert!(cpu.registers().int());
+
+        // Nothing pending survives the reset
+        cpu.dispatch()?;
+        assert_eq!(cpu.registers().pc, 0x1081);
+
+        Ok(())
+    }
+
+    #[test]
+    fn nmi_on_rising_edge() -> Result<()> {
+        let mut cpu = dispatcher()?;
+
+        cpu.set_nmi(true);
+        cpu.dispatch()?;
+        assert_eq!(cpu.registers().pc, 0x2000);
+        assert_eq!(cpu.memory().read_word(0x1fe)?, 0x1000);
+
+        // Holding the line doesn't retrigger
+        cpu.registers_mut().pc = 0x1000;
+        cpu.set_nmi(true);
+        cpu.dispatch()?;
+        assert_eq!(cpu.registers().pc, 0x1001);
+
+        cpu.set_nmi(false);
+        cpu.set_nmi(true);
+        cpu.dispatch()?;
+        assert_eq!(cpu.registers().pc, 0x2000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn nmi_ignores_interrupt_disable() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        cpu.registers_mut().set_flag(StatusBits::Int);
+
+        cpu.set_nmi(true);
+        cpu.dispatch()?;
+        assert_eq!(cpu.registers().pc, 0x2000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn irq_is_masked() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        cpu.registers_mut().set_flag(StatusBits::Int);
+
+        cpu.set_irq(true);
+        cpu.dispatch()?;
+        assert_eq!(cpu.registers().pc, 0x1001);
+
+        cpu.registers_mut().clear_flag(StatusBits::Int);
+        cpu.dispatch()?;
+        assert_eq!(cpu.registers().pc, 0x3000);
+        assert!(cpu.registers().int());
+
+        Ok(())
+    }
+
+    #[test]
+    fn nmi_takes_priority_over_irq() -> Result<()> {
+        let mut cpu = dispatcher()?;
+
+        cpu.set_irq(true);
+        cpu.set_nmi(true);
+        cpu.dispatch()?;
+        assert_eq!(cpu.registers().pc, 0x2000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn breakpoint_stops_before_instruction() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        cpu.add_breakpoint(Breakpoint::at(0x1001));
+
+        cpu.dispatch()?;
+        assert_eq!(
+            cpu.dispatch()?,
+            DispatchResult::Break(Break::Breakpoint(0x1001), 0)
+        );
+        assert_eq!(cpu.registers().pc, 0x1001);
+
+        // Carrying on runs the instruction at the breakpoint
+        assert_eq!(cpu.dispatch()?, DispatchResult::Cycles(2));
+        assert_eq!(cpu.registers().pc, 0x1002);
+
+        Ok(())
+    }
+
+    #[test]
+    fn conditional_breakpoint() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        cpu.registers_mut().set_flag(StatusBits::Carry);
+        cpu.add_breakpoint(Breakpoint::when(Condition::All(vec![
+            Condition::Flag(StatusBits::Carry, false),
+            Condition::Register(Register::PC, Comparison::Greater, 0x1002),
+        ])));
+
+        for _ in 0..3 {
+            assert_eq!(cpu.dispatch()?, DispatchResult::Cycles(2));
+        }
+        assert_eq!(
+            cpu.dispatch()?,
+            DispatchResult::Break(Break::Breakpoint(0x1003), 0)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ps_conditions_use_the_pushed_layout() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        cpu.registers_mut().set_flag(StatusBits::Neg);
+        cpu.add_breakpoint(Breakpoint::when(Condition::Register(
+            Register::PS,
+            Comparison::Greater,
+            0x7f,
+        )));
+
+        assert_eq!(
+            cpu.dispatch()?,
+            DispatchResult::Break(Break::Breakpoint(0x1000), 0)
+        );
+        assert!(Condition::Register(Register::PS, Comparison::Equal, 0xa0).holds(cpu.registers()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn watchpoints_see_data_not_instructions() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        cpu.memory_mut().write_byte(0x1000, 0x29)?; // AND #&70
+        cpu.memory_mut().write_byte(0x1001, 0x70)?;
+        cpu.memory_mut().write_byte(0x1002, 0x06)?; // ASL &70
+        cpu.memory_mut().write_byte(0x1003, 0x70)?;
+        cpu.memory_mut().write_byte(0x70, 0x21)?;
+        cpu.watched_mut()
+            .add_watchpoint(Watchpoint::read(0x1000..=0x1003));
+        cpu.watched_mut()
+            .add_watchpoint(Watchpoint::write(0x70..=0x70));
+
+        assert_eq!(cpu.dispatch()?, DispatchResult::Cycles(2));
+        assert_eq!(
+            cpu.dispatch()?,
+            DispatchResult::Break(
+                Break::Watchpoint(MemoryAccess {
+                    address: 0x70,
+                    data: 0x42,
+                    access: Access::Write,
+                }),
+                5
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn stores_dont_read_their_target() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        cpu.memory_mut().write_byte(0x1000, 0x85)?; // STA &70
+        cpu.memory_mut().write_byte(0x1001, 0x70)?;
+        cpu.watched_mut()
+            .add_watchpoint(Watchpoint::read(0x70..=0x70));
+
+        assert_eq!(cpu.dispatch()?, DispatchResult::Cycles(3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn watchpoints_see_the_stack() -> Result<()> {
+        let mut cpu = dispatcher()?;
+        cpu.watched_mut().add_watchpoint(
+            Watchpoint::write(0x100..=0x1ff).with_condition(Condition::Register(
+                Register::PC,
+                Comparison::Equal,
+                0x2000,
+            )),
+        );
+
+        cpu.set_nmi(true);
+        let DispatchResult::Break(Break::Watchpoint(access), 7) = cpu.dispatch()? else {
+            panic!("expected a watchpoint");
+        };
+        assert_eq!(access.access, Access::Write);
+        assert_eq!(cpu.registers().pc, 0x2000);
+
+        Ok(())
+    }
+}
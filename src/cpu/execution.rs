@@ -2,7 +2,7 @@ use std::marker::PhantomData;
 
 use crate::cpu::{
     registers::Registers, registers::StatusBits, Byte, Word, Address, Data, Error, ErrorType, ExecutionResult,
-    Memory, Opcode, Result,
+    Memory, Opcode, Result, IRQ_VECTOR,
 };
 
 pub struct ExecutionUnit<M> {
@@ -11,6 +11,9 @@ pub struct ExecutionUnit<M> {
 
 const STACK_BASE: Address = 0x100;
 
+// B in the status byte BRK and PHP push
+const BREAK: Byte = 0x10;
+
 impl<M> ExecutionUnit<M>
 where
     M: Memory,
@@ -52,13 +55,91 @@ where
         Ok(lsb + (msb << 8))
     }
 
-    fn compare(&self, lhs: Byte, rhs: Byte, registers: &mut Registers) {
-        let result = rhs.wrapping_sub(lhs);
+    fn compare(&self, register: Byte, data: Byte, registers: &mut Registers) {
+        let result = register.wrapping_sub(data);
         registers.write_flag(StatusBits::Neg, (result & 0x80) == 0x80);
-        registers.write_flag(StatusBits::Zero, result == 0x00);
+        registers.write_flag(StatusBits::Zero, register == data);
+        registers.write_flag(StatusBits::Carry, register >= data);
+    }
+
+    fn load(&self, value: Byte, registers: &mut Registers) -> ExecutionResult {
+        registers.write_flag(StatusBits::Neg, value & 0x80 == 0x80);
+        registers.write_flag(StatusBits::Zero, value == 0);
+        ExecutionResult::Data(value)
+    }
+
+    fn operand(&self, data: Option<Data>, registers: &Registers) -> Result<Data> {
+        data.ok_or_else(|| Error::with_pc(registers.pc, ErrorType::MissingData))
+    }
+
+    fn target(&self, address: Option<Address>, registers: &Registers) -> Result<Address> {
+        address.ok_or_else(|| Error::with_pc(registers.pc, ErrorType::MissingAddress))
+    }
+
+    // In decimal mode Z still comes from the binary sum, and N and V from
+    // the sum before the high digit is adjusted, as on the NMOS 6502
+    fn add(&self, d: Byte, registers: &mut Registers) -> Byte {
+        let a = registers.a;
+        let carry = u16::from(registers.carry());
+        let result: u16 = a as u16 + d as u16 + carry;
+        let sign_a = a & 0x80 == 0x80;
+        let sign_d = d & 0x80 == 0x80;
+        let sign_r = result & 0x80 == 0x80;
+
+        registers.write_flag(StatusBits::Neg, sign_r);
+        registers.write_flag(StatusBits::Zero, result & 0xff == 0);
+        registers.write_flag(StatusBits::Carry, result > 255);
+        registers.write_flag(StatusBits::Ovf, (sign_a == sign_d) && (sign_a != sign_r));
+        if !registers.dec() {
+            return (result & 0xff) as Byte;
+        }
+
+        let mut low = (a & 0x0f) as u16 + (d & 0x0f) as u16 + carry;
+        if low >= 0x0a {
+            low = ((low + 0x06) & 0x0f) + 0x10;
+        }
+        let mut sum = (a & 0xf0) as u16 + (d & 0xf0) as u16 + low;
+        let sign_s = sum & 0x80 == 0x80;
+        registers.write_flag(StatusBits::Neg, sign_s);
+        registers.write_flag(StatusBits::Ovf, (sign_a == sign_d) && (sign_a != sign_s));
+        if sum >= 0xa0 {
+            sum += 0x60;
+        }
+        registers.write_flag(StatusBits::Carry, sum > 0xff);
+        (sum & 0xff) as Byte
+    }
+
+    // The flags all come from the binary difference, even in decimal mode
+    fn subtract(&self, d: Byte, registers: &mut Registers) -> Byte {
+        let a = registers.a;
+        let borrow = i16::from(!registers.carry());
+        let decimal = registers.dec();
+        registers.clear_flag(StatusBits::Dec);
+        let result = self.add(!d, registers);
+        registers.write_flag(StatusBits::Dec, decimal);
+        if !decimal {
+            return result;
+        }
 
+        let mut low = (a & 0x0f) as i16 - (d & 0x0f) as i16 - borrow;
+        if low < 0 {
+            low = ((low - 0x06) & 0x0f) - 0x10;
+        }
+        let mut difference = (a & 0xf0) as i16 - (d & 0xf0) as i16 + low;
+        if difference < 0 {
+            difference -= 0x60;
+        }
+        (difference & 0xff) as Byte
     }
+}
 
+impl<M> Default for ExecutionUnit<M>
+where
+    M: Memory,
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<M> crate::cpu::ExecutionUnit<M> for ExecutionUnit<M>
@@ -75,20 +156,8 @@ where
     ) -> Result<ExecutionResult> {
         match opcode {
             Opcode::ADC => {
-                if let Some(d) = data {
-                    let result: u16 = registers.a as u16 + d as u16 + u16::from(registers.carry());
-                    let sign_a = registers.a & 0x80 == 0x80;
-                    let sign_d = d & 0x80 == 0x80;
-                    let sign_r = result & 0x80 == 0x80;
-
-                    registers.write_flag(StatusBits::Neg, sign_r);
-                    registers.write_flag(StatusBits::Zero, result & 0xff == 0);
-                    registers.write_flag(StatusBits::Carry, result > 255);
-                    registers.write_flag(StatusBits::Ovf, (sign_a == sign_d) && (sign_a != sign_r));
-                    Ok(ExecutionResult::Data((result & 0xff) as u8))
-                } else {
-                    Err(Error::with_pc(registers.pc, ErrorType::MissingData))
-                }
+                let d = self.operand(data, registers)?;
+                Ok(ExecutionResult::Data(self.add(d, registers)))
             }
             Opcode::AND => {
                 if let Some(d) = data {
@@ -190,11 +259,19 @@ where
                     Ok(ExecutionResult::None)
                 }
             }
+            Opcode::BRA => {
+                if let Some(a) = address {
+                    Ok(ExecutionResult::Address(a))
+                } else {
+                    Err(Error::with_pc(registers.pc, ErrorType::MissingAddress))
+                }
+            }
             Opcode::BRK => {
                 registers.write_flag(StatusBits::Brk, true);
-                self.push_word(registers.pc + 2, memory, registers)?;
-                self.push_byte(registers.ps, memory, registers)?;
-                let a = memory.read_word(0xfffe)?;
+                self.push_word(registers.pc.wrapping_add(2), memory, registers)?;
+                self.push_byte(registers.status() | BREAK, memory, registers)?;
+                registers.set_flag(StatusBits::Int);
+                let a = memory.read_word(IRQ_VECTOR)?;
                 Ok(ExecutionResult::Address(a))
             }
             Opcode::BVC => 
@@ -236,51 +313,169 @@ where
                 registers.clear_flag(StatusBits::Ovf);
                 Ok(ExecutionResult::None)
             }
-            Opcode::CMP => todo!(),
-            Opcode::CPX => todo!(),
-            Opcode::CPY => todo!(),
-            Opcode::DEC => todo!(),
-            Opcode::DEX => todo!(),
-            Opcode::DEY => todo!(),
-            Opcode::EOR => todo!(),
-            Opcode::INC => todo!(),
-            Opcode::INX => todo!(),
-            Opcode::INY => todo!(),
-            Opcode::JMP => todo!(),
-            Opcode::JSR => todo!(),
-            Opcode::LDA => todo!(),
-            Opcode::LDX => todo!(),
-            Opcode::LDY => todo!(),
-            Opcode::LSR => todo!(),
-            Opcode::NOP => todo!(),
-            Opcode::ORA => todo!(),
-            Opcode::PHA => todo!(),
-            Opcode::PHP => todo!(),
-            Opcode::PLA => todo!(),
-            Opcode::PLP => todo!(),
-            Opcode::ROL => todo!(),
-            Opcode::ROR => todo!(),
-            Opcode::RTI => todo!(),
-            Opcode::RTS => todo!(),
-            Opcode::SBC => todo!(), // take two's complement of data and ADC
-            Opcode::SEC => todo!(),
-            Opcode::SED => todo!(),
-            Opcode::SEI => todo!(),
-            Opcode::STA => todo!(),
-            Opcode::STX => todo!(),
-            Opcode::STY => todo!(),
-            Opcode::TAX => todo!(),
-            Opcode::TAY => todo!(),
-            Opcode::TSX => todo!(),
-            Opcode::TXA => todo!(),
-            Opcode::TXS => todo!(),
-            Opcode::TYA => todo!(),
-            Opcode::Invalid(_) => todo!(),
+            Opcode::CMP => {
+                let d = self.operand(data, registers)?;
+                self.compare(registers.a, d, registers);
+                Ok(ExecutionResult::None)
+            }
+            Opcode::CPX => {
+                let d = self.operand(data, registers)?;
+                self.compare(registers.x, d, registers);
+                Ok(ExecutionResult::None)
+            }
+            Opcode::CPY => {
+                let d = self.operand(data, registers)?;
+                self.compare(registers.y, d, registers);
+                Ok(ExecutionResult::None)
+            }
+            Opcode::DEC => {
+                let d = self.operand(data, registers)?;
+                Ok(self.load(d.wrapping_sub(1), registers))
+            }
+            Opcode::DEX => Ok(self.load(registers.x.wrapping_sub(1), registers)),
+            Opcode::DEY => Ok(self.load(registers.y.wrapping_sub(1), registers)),
+            Opcode::EOR => {
+                let d = self.operand(data, registers)?;
+                Ok(self.load(registers.a ^ d, registers))
+            }
+            Opcode::INC => {
+                let d = self.operand(data, registers)?;
+                Ok(self.load(d.wrapping_add(1), registers))
+            }
+            Opcode::INX => Ok(self.load(registers.x.wrapping_add(1), registers)),
+            Opcode::INY => Ok(self.load(registers.y.wrapping_add(1), registers)),
+            Opcode::JMP => Ok(ExecutionResult::Address(self.target(address, registers)?)),
+            Opcode::JSR => {
+                let a = self.target(address, registers)?;
+                // The address pushed is the last byte of the JSR
+                self.push_word(registers.pc_next.wrapping_sub(1), memory, registers)?;
+                Ok(ExecutionResult::Address(a))
+            }
+            Opcode::LDA | Opcode::LDX | Opcode::LDY => {
+                let d = self.operand(data, registers)?;
+                Ok(self.load(d, registers))
+            }
+            Opcode::LSR => {
+                let d = self.operand(data, registers)?;
+                registers.write_flag(StatusBits::Carry, d & 0x01 == 0x01);
+                Ok(self.load(d >> 1, registers))
+            }
+            Opcode::NOP => Ok(ExecutionResult::None),
+            Opcode::ORA => {
+                let d = self.operand(data, registers)?;
+                Ok(self.load(registers.a | d, registers))
+            }
+            Opcode::PHA => {
+                self.push_byte(registers.a, memory, registers)?;
+                Ok(ExecutionResult::Data(registers.sp))
+            }
+            Opcode::PHP => {
+                self.push_byte(registers.status() | BREAK, memory, registers)?;
+                Ok(ExecutionResult::Data(registers.sp))
+            }
+            Opcode::PHX => {
+                self.push_byte(registers.x, memory, registers)?;
+                Ok(ExecutionResult::Data(registers.sp))
+            }
+            Opcode::PHY => {
+                self.push_byte(registers.y, memory, registers)?;
+                Ok(ExecutionResult::Data(registers.sp))
+            }
+            Opcode::PLA => {
+                let result = self.pop_byte(memory, registers)?;
+                Ok(self.load(result, registers))
+            }
+            Opcode::PLP => {
+                let p = self.pop_byte(memory, registers)?;
+                registers.set_status(p);
+                Ok(ExecutionResult::Data(registers.ps))
+            }
+            Opcode::PLX | Opcode::PLY => {
+                let result = self.pop_byte(memory, registers)?;
+                registers.write_flag(StatusBits::Neg, result & 0x80 == 0x80);
+                registers.write_flag(StatusBits::Zero, result == 0);
+                Ok(ExecutionResult::Data(result))
+            }
+            Opcode::ROL => {
+                let d = self.operand(data, registers)?;
+                let result = (d << 1) | u8::from(registers.carry());
+                registers.write_flag(StatusBits::Carry, d & 0x80 == 0x80);
+                Ok(self.load(result, registers))
+            }
+            Opcode::ROR => {
+                let d = self.operand(data, registers)?;
+                let result = (d >> 1) | (u8::from(registers.carry()) << 7);
+                registers.write_flag(StatusBits::Carry, d & 0x01 == 0x01);
+                Ok(self.load(result, registers))
+            }
+            Opcode::RTI => {
+                let p = self.pop_byte(memory, registers)?;
+                registers.set_status(p);
+                Ok(ExecutionResult::Address(self.pop_word(memory, registers)?))
+            }
+            Opcode::RTS => {
+                let a = self.pop_word(memory, registers)?;
+                Ok(ExecutionResult::Address(a.wrapping_add(1)))
+            }
+            Opcode::SBC => {
+                let d = self.operand(data, registers)?;
+                Ok(ExecutionResult::Data(self.subtract(d, registers)))
+            }
+            Opcode::SEC => {
+                registers.set_flag(StatusBits::Carry);
+                Ok(ExecutionResult::None)
+            }
+            Opcode::SED => {
+                registers.set_flag(StatusBits::Dec);
+                Ok(ExecutionResult::None)
+            }
+            Opcode::SEI => {
+                registers.set_flag(StatusBits::Int);
+                Ok(ExecutionResult::None)
+            }
+            Opcode::STA => Ok(ExecutionResult::Data(registers.a)),
+            Opcode::STX => Ok(ExecutionResult::Data(registers.x)),
+            Opcode::STY => Ok(ExecutionResult::Data(registers.y)),
+            Opcode::STZ => Ok(ExecutionResult::Data(0x00)),
+            Opcode::TAX | Opcode::TAY => Ok(self.load(registers.a, registers)),
+            Opcode::TRB => {
+                if let Some(d) = data {
+                    registers.write_flag(StatusBits::Zero, registers.a & d == 0);
+                    Ok(ExecutionResult::Data(d & !registers.a))
+                } else {
+                    Err(Error::with_pc(registers.pc, ErrorType::MissingData))
+                }
+            }
+            Opcode::TSB => {
+                if let Some(d) = data {
+                    registers.write_flag(StatusBits::Zero, registers.a & d == 0);
+                    Ok(ExecutionResult::Data(d | registers.a))
+                } else {
+                    Err(Error::with_pc(registers.pc, ErrorType::MissingData))
+                }
+            }
+            Opcode::TSX => Ok(self.load(registers.sp, registers)),
+            Opcode::TXA => Ok(self.load(registers.x, registers)),
+            Opcode::TXS => Ok(ExecutionResult::Data(registers.x)),
+            Opcode::TYA => Ok(self.load(registers.y, registers)),
+            Opcode::Invalid(o) => Err(Error::with_pc(
+                registers.pc,
+                ErrorType::InvalidInstruction(*o),
+            )),
         }
     }
+
+    fn interrupt(&self, vector: Address, memory: &mut M, registers: &mut Registers) -> Result<()> {
+        self.push_word(registers.pc, memory, registers)?;
+        self.push_byte(registers.status(), memory, registers)?;
+        registers.set_flag(StatusBits::Int);
+        registers.pc = memory.read_word(vector)?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::let_unit_value)]
 mod tests {
     use super::*;
     use crate::cpu::{ram::Ram, ExecutionUnit, Memory};
@@ -798,13 +993,14 @@ mod tests {
         registers.write_flag(StatusBits::Neg, true);
         memory.write_word(0xfffe, 0x1234)?;
 
-        let expected_ps = registers.ps | StatusBits::Brk as u8;
+        let expected_ps = registers.status() | BREAK;
         let expected_pc = registers.pc + 2;
         let expected_sp = registers.sp.wrapping_sub(3);
 
         let r = execution_unit.execute(&Opcode::BRK, None, None, &mut memory, &mut registers)?;
 
         assert!(registers.brk());
+        assert!(registers.int());
         assert_eq!(registers.sp, expected_sp);
         assert_eq!(r, ExecutionResult::Address(0x1234));
 
@@ -938,4 +1134,337 @@ mod tests {
         assert!(!registers.overflow(), "Overflow should be clear");
         Ok(())
     }
+
+    #[test]
+    fn interrupt() -> Result<()> {
+        let execution_unit = super::ExecutionUnit::new();
+        let mut memory = Ram::new(0x10000);
+        let mut registers = Registers::new();
+
+        registers.sp = 0xff;
+        registers.pc = 0x1234;
+        registers.write_flag(StatusBits::Brk, true);
+        registers.write_flag(StatusBits::Carry, true);
+        memory.write_word(0xfffa, 0x0d00)?;
+
+        let expected_ps = registers.status();
+
+        execution_unit.interrupt(0xfffa, &mut memory, &mut registers)?;
+
+        assert_eq!(registers.pc, 0x0d00);
+        assert!(registers.int());
+        assert_eq!(registers.sp, 0xfc);
+
+        let ps = execution_unit.pop_byte(&mut memory, &mut registers)?;
+        assert_eq!(ps, expected_ps);
+        let pc = execution_unit.pop_word(&mut memory, &mut registers)?;
+        assert_eq!(pc, 0x1234);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tsb_and_trb() -> Result<()> {
+        let execution_unit = super::ExecutionUnit::new();
+        let mut memory = Ram::new(1);
+        let mut registers = Registers::new();
+
+        registers.a = 0x0f;
+        let r = execution_unit.execute(&Opcode::TSB, Some(0x30), None, &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Data(0x3f));
+        assert!(registers.zero());
+
+        let r = execution_unit.execute(&Opcode::TRB, Some(0x3c), None, &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Data(0x30));
+        assert!(!registers.zero());
+
+        Ok(())
+    }
+
+    #[test]
+    fn phx_and_ply() -> Result<()> {
+        let execution_unit = super::ExecutionUnit::new();
+        let mut memory = Ram::new(0x200);
+        let mut registers = Registers::new();
+
+        registers.sp = 0xff;
+        registers.x = 0x80;
+        let r = execution_unit.execute(&Opcode::PHX, None, None, &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Data(0xfe));
+        assert_eq!(memory.read_byte(0x1ff)?, 0x80);
+
+        let r = execution_unit.execute(&Opcode::PLY, None, None, &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Data(0x80));
+        assert_eq!(registers.sp, 0xff);
+        assert!(registers.negative());
+
+        Ok(())
+    }
+
+    #[test]
+    fn adc_decimal() -> Result<()> {
+        let execution_unit = super::ExecutionUnit::new();
+        let mut memory = Ram::new(1);
+        let mut registers = Registers::new();
+        registers.set_flag(StatusBits::Dec);
+
+        let test_cases = vec![
+            // Acc, Data, Carry, Result, C
+            (0x09, 0x01, false, 0x10, false),
+            (0x58, 0x46, true, 0x05, true),
+            (0x99, 0x00, true, 0x00, true),
+            (0x12, 0x34, false, 0x46, false),
+        ];
+
+        for (acc, data, carry_in, expected_result, carry) in test_cases {
+            let case = format!("{:02x} + {:02x} + {}", acc, data, carry_in as u8);
+            registers.write_flag(StatusBits::Carry, carry_in);
+            registers.a = acc;
+
+            let result =
+                execution_unit.execute(&Opcode::ADC, Some(data), None, &mut memory, &mut registers)?;
+
+            assert_eq!(result, ExecutionResult::Data(expected_result), "{}", case);
+            assert_eq!(registers.carry(), carry, "C: {}", case);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn sbc() -> Result<()> {
+        let execution_unit = super::ExecutionUnit::new();
+        let mut memory = Ram::new(1);
+        let mut registers = Registers::new();
+
+        let test_cases = vec![
+            // Dec, Acc, Data, Carry, Result, N, Z, C, V
+            (false, 0x05, 0x03, true, 0x02, false, false, true, false),
+            (false, 0x05, 0x03, false, 0x01, false, false, true, false),
+            (false, 0x03, 0x05, true, 0xfe, true, false, false, false),
+            (false, 0x80, 0x01, true, 0x7f, false, false, true, true),
+            (false, 0x40, 0x40, true, 0x00, false, true, true, false),
+            (true, 0x46, 0x12, true, 0x34, false, false, true, false),
+            (true, 0x40, 0x13, true, 0x27, false, false, true, false),
+            (true, 0x21, 0x34, true, 0x87, true, false, false, false),
+            (true, 0x00, 0x00, false, 0x99, true, false, false, false),
+        ];
+
+        for (dec, acc, data, carry_in, expected_result, neg, zero, carry, overflow) in test_cases {
+            let case = format!("{:02x} - {:02x} - {} (D {})", acc, data, !carry_in as u8, dec);
+            registers.write_flag(StatusBits::Dec, dec);
+            registers.write_flag(StatusBits::Carry, carry_in);
+            registers.a = acc;
+
+            let result =
+                execution_unit.execute(&Opcode::SBC, Some(data), None, &mut memory, &mut registers)?;
+
+            assert_eq!(result, ExecutionResult::Data(expected_result), "{}", case);
+            assert_eq!(registers.carry(), carry, "C: {}", case);
+            assert_eq!(registers.zero(), zero, "Z: {}", case);
+            assert_eq!(registers.negative(), neg, "N: {}", case);
+            assert_eq!(registers.overflow(), overflow, "V: {}", case);
+            assert_eq!(registers.dec(), dec, "D: {}", case);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn compare() -> Result<()> {
+        let execution_unit = super::ExecutionUnit::new();
+        let mut memory = Ram::new(1);
+        let mut registers = Registers::new();
+
+        let test_cases = vec![
+            // Opcode, Register, Data, N, Z, C
+            (Opcode::CMP, 0x40, 0x40, false, true, true),
+            (Opcode::CMP, 0x40, 0x41, true, false, false),
+            (Opcode::CPX, 0x80, 0x01, false, false, true),
+            (Opcode::CPY, 0x00, 0xff, false, false, false),
+        ];
+
+        for (opcode, register, data, neg, zero, carry) in test_cases {
+            let case = format!("{:?} {:02x} with {:02x}", opcode, register, data);
+            registers.a = register;
+            registers.x = register;
+            registers.y = register;
+
+            let result =
+                execution_unit.execute(&opcode, Some(data), None, &mut memory, &mut registers)?;
+
+            assert_eq!(result, ExecutionResult::None, "{}", case);
+            assert_eq!(registers.negative(), neg, "N: {}", case);
+            assert_eq!(registers.zero(), zero, "Z: {}", case);
+            assert_eq!(registers.carry(), carry, "C: {}", case);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn loads_and_logic() -> Result<()> {
+        let execution_unit = super::ExecutionUnit::new();
+        let mut memory = Ram::new(1);
+        let mut registers = Registers::new();
+        registers.a = 0x0f;
+
+        let test_cases = vec![
+            // Opcode, Data, Result, N, Z
+            (Opcode::LDA, 0x00, 0x00, false, true),
+            (Opcode::LDX, 0x80, 0x80, true, false),
+            (Opcode::LDY, 0x7f, 0x7f, false, false),
+            (Opcode::EOR, 0xff, 0xf0, true, false),
+            (Opcode::ORA, 0x30, 0x3f, false, false),
+            (Opcode::INC, 0xff, 0x00, false, true),
+            (Opcode::DEC, 0x00, 0xff, true, false),
+        ];
+
+        for (opcode, data, expected_result, neg, zero) in test_cases {
+            let case = format!("{:?} {:02x}", opcode, data);
+
+            let result =
+                execution_unit.execute(&opcode, Some(data), None, &mut memory, &mut registers)?;
+
+            assert_eq!(result, ExecutionResult::Data(expected_result), "{}", case);
+            assert_eq!(registers.negative(), neg, "N: {}", case);
+            assert_eq!(registers.zero(), zero, "Z: {}", case);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn shifts_and_rotates() -> Result<()> {
+        let execution_unit = super::ExecutionUnit::new();
+        let mut memory = Ram::new(1);
+        let mut registers = Registers::new();
+
+        let test_cases = vec![
+            // Opcode, Data, Carry in, Result, C
+            (Opcode::LSR, 0x81, false, 0x40, true),
+            (Opcode::ROL, 0x80, true, 0x01, true),
+            (Opcode::ROL, 0x40, false, 0x80, false),
+            (Opcode::ROR, 0x01, true, 0x80, true),
+            (Opcode::ROR, 0x02, false, 0x01, false),
+        ];
+
+        for (opcode, data, carry_in, expected_result, carry) in test_cases {
+            let case = format!("{:?} {:02x} C {}", opcode, data, carry_in);
+            registers.write_flag(StatusBits::Carry, carry_in);
+
+            let result =
+                execution_unit.execute(&opcode, Some(data), None, &mut memory, &mut registers)?;
+
+            assert_eq!(result, ExecutionResult::Data(expected_result), "{}", case);
+            assert_eq!(registers.carry(), carry, "C: {}", case);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn registers_and_transfers() -> Result<()> {
+        let execution_unit = super::ExecutionUnit::new();
+        let mut memory = Ram::new(1);
+        let mut registers = Registers::new();
+        registers.a = 0x80;
+        registers.x = 0x00;
+        registers.y = 0x01;
+        registers.sp = 0xf0;
+
+        let r = execution_unit.execute(&Opcode::TAX, None, None, &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Data(0x80));
+        assert!(registers.negative());
+
+        let r = execution_unit.execute(&Opcode::DEY, None, None, &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Data(0x00));
+        assert!(registers.zero());
+
+        // TXS leaves the flags alone
+        let r = execution_unit.execute(&Opcode::TXS, None, None, &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Data(0x00));
+        assert!(registers.zero());
+
+        let r = execution_unit.execute(&Opcode::TSX, None, None, &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Data(0xf0));
+        assert!(registers.negative());
+
+        let r = execution_unit.execute(&Opcode::STY, None, Some(0), &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Data(0x01));
+
+        Ok(())
+    }
+
+    #[test]
+    fn jsr_and_rts() -> Result<()> {
+        let execution_unit = super::ExecutionUnit::new();
+        let mut memory = Ram::new(0x200);
+        let mut registers = Registers::new();
+
+        registers.sp = 0xff;
+        registers.pc = 0x1234;
+        registers.pc_next = 0x1237;
+        let r = execution_unit.execute(&Opcode::JSR, None, Some(0x2000), &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Address(0x2000));
+        assert_eq!(memory.read_byte(0x1ff)?, 0x12);
+        assert_eq!(memory.read_byte(0x1fe)?, 0x36);
+
+        let r = execution_unit.execute(&Opcode::RTS, None, None, &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Address(0x1237));
+        assert_eq!(registers.sp, 0xff);
+
+        Ok(())
+    }
+
+    #[test]
+    fn status_on_the_stack() -> Result<()> {
+        let execution_unit = super::ExecutionUnit::new();
+        let mut memory = Ram::new(0x200);
+        let mut registers = Registers::new();
+
+        // PHP pushes NV1BDIZC with B set, and PLP takes it back
+        registers.sp = 0xff;
+        registers.set_flag(StatusBits::Neg);
+        registers.set_flag(StatusBits::Carry);
+        execution_unit.execute(&Opcode::PHP, None, None, &mut memory, &mut registers)?;
+        assert_eq!(memory.read_byte(0x1ff)?, 0xb1);
+
+        memory.write_byte(0x1ff, 0x4e)?;
+        let r = execution_unit.execute(&Opcode::PLP, None, None, &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Data(registers.ps));
+        assert!(registers.overflow() && registers.dec() && registers.int() && registers.zero());
+        assert!(!registers.negative() && !registers.carry());
+
+        // RTI pulls the status and then the return address
+        registers.sp = 0xfc;
+        memory.write_byte(0x1fd, 0x01)?;
+        memory.write_word(0x1fe, 0x1234)?;
+        let r = execution_unit.execute(&Opcode::RTI, None, None, &mut memory, &mut registers)?;
+        assert_eq!(r, ExecutionResult::Address(0x1234));
+        assert!(registers.carry() && !registers.int());
+        assert_eq!(registers.sp, 0xff);
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_instruction() {
+        let execution_unit = super::ExecutionUnit::new();
+        let mut memory = Ram::new(1);
+        let mut registers = Registers::new();
+        registers.pc = 0x1234;
+
+        let result = execution_unit.execute(
+            &Opcode::Invalid(0x02),
+            None,
+            None,
+            &mut memory,
+            &mut registers,
+        );
+        assert_eq!(
+            result,
+            Err(Error::with_pc(0x1234, ErrorType::InvalidInstruction(0x02)))
+        );
+    }
 }
@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+
+use crate::cpu::registers::Registers;
+use crate::cpu::{Address, Byte, Memory, Result};
+
+// The last few instructions the CPU ran, each with what it takes to undo it:
+// the registers from before it ran and the old value of every byte it wrote.
+// Stepping back only puts the CPU and memory back. Devices keep their state,
+// and writes to their registers can't be undone, since reading the old value
+// could change it.
+
+// One call to dispatch: an instruction, or taking an interrupt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub registers: Registers,
+    pub cycles: usize,
+    // The old values in the order they were overwritten, with None for
+    // writes that can't be undone
+    writes: Vec<(Address, Option<Byte>)>,
+}
+
+impl Step {
+    pub fn pc(&self) -> Address {
+        self.registers.pc
+    }
+
+    pub fn writes(&self) -> &[(Address, Option<Byte>)] {
+        &self.writes
+    }
+
+    // Whether stepping back puts everything back as it was
+    pub fn undoable(&self) -> bool {
+        self.writes.iter().all(|(_, old)| old.is_some())
+    }
+
+    pub(crate) fn undo<M: Memory>(&self, memory: &mut M) -> Result<Registers> {
+        for &(address, old) in self.writes.iter().rev() {
+            if let Some(old) = old {
+                memory.write_byte(address, old)?;
+            }
+        }
+        Ok(self.registers.clone())
+    }
+}
+
+pub struct History {
+    steps: VecDeque<Step>,
+    capacity: usize,
+    unrecorded: Vec<RangeInclusive<Address>>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            steps: VecDeque::with_capacity(capacity),
+            capacity,
+            unrecorded: Vec::new(),
+        }
+    }
+
+    // Memory-mapped I/O, where writes aren't read back first
+    pub fn without(mut self, range: RangeInclusive<Address>) -> Self {
+        self.unrecorded.push(range);
+        self
+    }
+
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    // Oldest first
+    pub fn steps(&self) -> impl Iterator<Item = &Step> {
+        self.steps.iter()
+    }
+
+    // Where the recent steps started, oldest first, for showing how the CPU
+    // got where it is
+    pub fn trail(&self) -> Vec<Address> {
+        self.steps.iter().map(Step::pc).collect()
+    }
+
+    pub(crate) fn journal(&self) -> Journal {
+        Journal {
+            unrecorded: self.unrecorded.clone(),
+            writes: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, registers: Registers, cycles: usize, journal: &mut Journal) {
+        let writes = std::mem::take(&mut journal.writes);
+        if self.capacity == 0 {
+            return;
+        }
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(Step {
+            registers,
+            cycles,
+            writes,
+        });
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Step> {
+        self.steps.pop_back()
+    }
+}
+
+// Collects the old values of the bytes written while a step runs
+pub(crate) struct Journal {
+    unrecorded: Vec<RangeInclusive<Address>>,
+    writes: Vec<(Address, Option<Byte>)>,
+}
+
+impl Journal {
+    pub(crate) fn record<M: Memory>(&mut self, memory: &M, address: Address) -> Result<()> {
+        let old = if self.unrecorded.iter().any(|range| range.contains(&address)) {
+            None
+        } else {
+            Some(memory.read_byte(address)?)
+        };
+        self.writes.push((address, old));
+        Ok(())
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.writes.clear();
+    }
+}
@@ -1852,8 +1852,267 @@ impl InstructionDecoder {
     }
 }
 
+impl InstructionDecoder {
+    // The 65C12 in the Master: the 6502 plus the CMOS additions, with every
+    // undefined opcode a NOP. It has no Rockwell bit instructions.
+    pub fn new_65c02() -> Self {
+        let mut decoder = Self::new();
+
+        for opcode in 0..=0xff_u8 {
+            if let Opcode::Invalid(_) = decoder.decode_table[opcode as usize].opcode {
+                let (byte_length, ticks) = match opcode {
+                    0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xc2 | 0xe2 => (2, 2),
+                    0x44 => (2, 3),
+                    0x54 | 0xd4 | 0xf4 => (2, 4),
+                    0x5c => (3, 8),
+                    0xdc | 0xfc => (3, 4),
+                    _ => (1, 1),
+                };
+                decoder.set(
+                    opcode,
+                    Opcode::NOP,
+                    AddressingMode::Implicit,
+                    Writeback::NoWriteback,
+                    byte_length,
+                    ticks,
+                );
+            }
+        }
+
+        let zero_page_indirect = [
+            (0x12, Opcode::ORA, Writeback::Accumulator),
+            (0x32, Opcode::AND, Writeback::Accumulator),
+            (0x52, Opcode::EOR, Writeback::Accumulator),
+            (0x72, Opcode::ADC, Writeback::Accumulator),
+            (0x92, Opcode::STA, Writeback::Memory),
+            (0xb2, Opcode::LDA, Writeback::Accumulator),
+            (0xd2, Opcode::CMP, Writeback::NoWriteback),
+            (0xf2, Opcode::SBC, Writeback::Accumulator),
+        ];
+        for (opcode, op, writeback) in zero_page_indirect {
+            decoder.set(
+                opcode,
+                op,
+                AddressingMode::ZeroPageIndirect,
+                writeback,
+                2,
+                5,
+            );
+        }
+
+        let additions = [
+            (
+                0x04,
+                Opcode::TSB,
+                AddressingMode::ZeroPage,
+                Writeback::Memory,
+                2,
+                5,
+            ),
+            (
+                0x0c,
+                Opcode::TSB,
+                AddressingMode::Absolute,
+                Writeback::Memory,
+                3,
+                6,
+            ),
+            (
+                0x14,
+                Opcode::TRB,
+                AddressingMode::ZeroPage,
+                Writeback::Memory,
+                2,
+                5,
+            ),
+            (
+                0x1c,
+                Opcode::TRB,
+                AddressingMode::Absolute,
+                Writeback::Memory,
+                3,
+                6,
+            ),
+            (
+                0x1a,
+                Opcode::INC,
+                AddressingMode::Accumulator,
+                Writeback::Accumulator,
+                1,
+                2,
+            ),
+            (
+                0x3a,
+                Opcode::DEC,
+                AddressingMode::Accumulator,
+                Writeback::Accumulator,
+                1,
+                2,
+            ),
+            (
+                0x34,
+                Opcode::BIT,
+                AddressingMode::ZeroPageX,
+                Writeback::NoWriteback,
+                2,
+                4,
+            ),
+            (
+                0x3c,
+                Opcode::BIT,
+                AddressingMode::AbsoluteX,
+                Writeback::NoWriteback,
+                3,
+                4,
+            ),
+            (
+                0x89,
+                Opcode::BIT,
+                AddressingMode::Immediate,
+                Writeback::NoWriteback,
+                2,
+                2,
+            ),
+            (
+                0x5a,
+                Opcode::PHY,
+                AddressingMode::Implicit,
+                Writeback::SP,
+                1,
+                3,
+            ),
+            (
+                0x7a,
+                Opcode::PLY,
+                AddressingMode::Implicit,
+                Writeback::Y,
+                1,
+                4,
+            ),
+            (
+                0xda,
+                Opcode::PHX,
+                AddressingMode::Implicit,
+                Writeback::SP,
+                1,
+                3,
+            ),
+            (
+                0xfa,
+                Opcode::PLX,
+                AddressingMode::Implicit,
+                Writeback::X,
+                1,
+                4,
+            ),
+            (
+                0x64,
+                Opcode::STZ,
+                AddressingMode::ZeroPage,
+                Writeback::Memory,
+                2,
+                3,
+            ),
+            (
+                0x74,
+                Opcode::STZ,
+                AddressingMode::ZeroPageX,
+                Writeback::Memory,
+                2,
+                4,
+            ),
+            (
+                0x9c,
+                Opcode::STZ,
+                AddressingMode::Absolute,
+                Writeback::Memory,
+                3,
+                4,
+            ),
+            (
+                0x9e,
+                Opcode::STZ,
+                AddressingMode::AbsoluteX,
+                Writeback::Memory,
+                3,
+                5,
+            ),
+            (
+                0x7c,
+                Opcode::JMP,
+                AddressingMode::AbsoluteIndexedIndirect,
+                Writeback::PC,
+                3,
+                6,
+            ),
+            (
+                0x80,
+                Opcode::BRA,
+                AddressingMode::Relative,
+                Writeback::PC,
+                2,
+                3,
+            ),
+        ];
+        for (opcode, op, mode, writeback, byte_length, ticks) in additions {
+            decoder.set(opcode, op, mode, writeback, byte_length, ticks);
+        }
+
+        decoder
+    }
+
+    fn set(
+        &mut self,
+        opcode: Byte,
+        op: Opcode,
+        addressing_mode: AddressingMode,
+        writeback: Writeback,
+        byte_length: usize,
+        ticks: usize,
+    ) {
+        self.decode_table[opcode as usize] =
+            Instruction::new(op, addressing_mode, writeback, byte_length, ticks);
+    }
+}
+
+impl Default for InstructionDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl super::InstructionDecoder for InstructionDecoder {
     fn decode(&self, opcode: Byte) -> Result<&Instruction> {
         Ok(&self.decode_table[opcode as usize])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::InstructionDecoder as _;
+
+    #[test]
+    fn nmos_leaves_cmos_opcodes_invalid() -> Result<()> {
+        let decoder = InstructionDecoder::new();
+        assert_eq!(decoder.decode(0x80)?.opcode, Opcode::Invalid(0x80));
+        assert_eq!(decoder.decode(0xda)?.opcode, Opcode::Invalid(0xda));
+
+        Ok(())
+    }
+
+    #[test]
+    fn cmos_additions() -> Result<()> {
+        let decoder = InstructionDecoder::new_65c02();
+        assert_eq!(decoder.decode(0x80)?.opcode, Opcode::BRA);
+        assert_eq!(decoder.decode(0xda)?.opcode, Opcode::PHX);
+        assert_eq!(decoder.decode(0xb2)?.byte_length, 2);
+        assert_eq!(decoder.decode(0xa9)?.opcode, Opcode::LDA);
+
+        // Undefined opcodes are NOPs of various lengths
+        assert_eq!(decoder.decode(0x03)?.opcode, Opcode::NOP);
+        assert_eq!(decoder.decode(0x5c)?.byte_length, 3);
+
+        Ok(())
+    }
+}
@@ -3,13 +3,16 @@ use std::fmt;
 use self::registers::Registers;
 
 pub mod address;
+pub mod debug;
 pub mod dispatch;
 pub mod execution;
+pub mod history;
 pub mod instruction_decode;
 pub mod memory;
 pub mod ram;
 pub mod registers;
 pub mod rom;
+pub mod trace;
 pub mod writeback;
 
 pub type Byte = u8;
@@ -17,13 +20,35 @@ pub type Word = u16;
 pub type Address = Word;
 pub type Data = Byte;
 
+pub const NMI_VECTOR: Address = 0xfffa;
+pub const RESET_VECTOR: Address = 0xfffc;
+pub const IRQ_VECTOR: Address = 0xfffe;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ErrorType {
     AddressOutOfRange(Address),
     InvalidAddressingMode,
     InvalidInstruction(Byte),
     MissingData,
-    MissingAddress
+    MissingAddress,
+    Io(String),
+    InvalidUef,
+    InvalidTapeBlock,
+    CrcMismatch,
+    InvalidDiscImage,
+    InvalidFilename(String),
+    FileNotFound(String),
+    FileLocked(String),
+    CatalogueFull,
+    DiscFull,
+    DirectoryNotEmpty(String),
+    InvalidRom,
+    InvalidSnapshot,
+    UnsupportedChunk(Word),
+    InvalidSymbol(usize),
+    Assembly(String),
+    InvalidRecording,
+    NoSuchDrive(usize),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -33,14 +58,14 @@ pub struct Error {
 }
 
 impl Error {
-    fn with_pc(pc: Address, error_type: ErrorType) -> Self {
+    pub(crate) fn with_pc(pc: Address, error_type: ErrorType) -> Self {
         Error {
             pc: Some(pc),
             error_type,
         }
     }
 
-    fn without_pc(error_type: ErrorType) -> Self {
+    pub(crate) fn without_pc(error_type: ErrorType) -> Self {
         Error {
             pc: None,
             error_type,
@@ -66,6 +91,60 @@ impl fmt::Display for Error {
             ErrorType::MissingAddress => {
                 f.write_fmt(format_args!("Missing address"))?;
             }
+            ErrorType::Io(ref message) => {
+                f.write_fmt(format_args!("I/O error ({})", message))?;
+            }
+            ErrorType::InvalidUef => {
+                f.write_fmt(format_args!("Invalid UEF file"))?;
+            }
+            ErrorType::InvalidTapeBlock => {
+                f.write_fmt(format_args!("Invalid tape block"))?;
+            }
+            ErrorType::CrcMismatch => {
+                f.write_fmt(format_args!("CRC mismatch"))?;
+            }
+            ErrorType::InvalidDiscImage => {
+                f.write_fmt(format_args!("Invalid disc image"))?;
+            }
+            ErrorType::InvalidFilename(ref name) => {
+                f.write_fmt(format_args!("Invalid filename ({})", name))?;
+            }
+            ErrorType::FileNotFound(ref name) => {
+                f.write_fmt(format_args!("File not found ({})", name))?;
+            }
+            ErrorType::FileLocked(ref name) => {
+                f.write_fmt(format_args!("File locked ({})", name))?;
+            }
+            ErrorType::CatalogueFull => {
+                f.write_fmt(format_args!("Catalogue full"))?;
+            }
+            ErrorType::DiscFull => {
+                f.write_fmt(format_args!("Disc full"))?;
+            }
+            ErrorType::DirectoryNotEmpty(ref name) => {
+                f.write_fmt(format_args!("Directory not empty ({})", name))?;
+            }
+            ErrorType::InvalidRom => {
+                f.write_fmt(format_args!("Invalid ROM image"))?;
+            }
+            ErrorType::InvalidSnapshot => {
+                f.write_fmt(format_args!("Invalid snapshot"))?;
+            }
+            ErrorType::UnsupportedChunk(id) => {
+                f.write_fmt(format_args!("Unsupported chunk (0x{:04x})", id))?;
+            }
+            ErrorType::InvalidSymbol(line) => {
+                f.write_fmt(format_args!("Invalid symbol (line {})", line))?;
+            }
+            ErrorType::Assembly(ref message) => {
+                f.write_fmt(format_args!("Assembly error ({})", message))?;
+            }
+            ErrorType::InvalidRecording => {
+                f.write_fmt(format_args!("Invalid recording"))?;
+            }
+            ErrorType::NoSuchDrive(drive) => {
+                f.write_fmt(format_args!("No such drive ({})", drive))?;
+            }
         }
 
         if let Some(pc) = self.pc {
@@ -76,6 +155,12 @@ impl fmt::Display for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::without_pc(ErrorType::Io(error.to_string()))
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub trait Memory {
@@ -89,7 +174,7 @@ pub trait Memory {
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Opcode {
     ADC,
     AND,
@@ -101,6 +186,7 @@ pub enum Opcode {
     BMI,
     BNE,
     BPL,
+    BRA,
     BRK,
     BVC,
     BVS,
@@ -128,8 +214,12 @@ pub enum Opcode {
     ORA,
     PHA,
     PHP,
+    PHX,
+    PHY,
     PLA,
     PLP,
+    PLX,
+    PLY,
     ROL,
     ROR,
     RTI,
@@ -141,8 +231,11 @@ pub enum Opcode {
     STA,
     STX,
     STY,
+    STZ,
     TAX,
     TAY,
+    TRB,
+    TSB,
     TSX,
     TXA,
     TXS,
@@ -150,6 +243,7 @@ pub enum Opcode {
     Invalid(Byte),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum AddressingMode {
     Implicit,
     Accumulator,
@@ -164,6 +258,8 @@ pub enum AddressingMode {
     Indirect,
     IndirectX,
     IndirectY,
+    ZeroPageIndirect,
+    AbsoluteIndexedIndirect,
     None = -1,
 }
 
@@ -246,6 +342,8 @@ where
         memory: &mut M,
         registers: &mut Registers,
     ) -> Result<ExecutionResult>;
+
+    fn interrupt(&self, vector: Address, memory: &mut M, registers: &mut Registers) -> Result<()>;
 }
 
 pub trait WritebackUnit<M>
@@ -2,6 +2,7 @@ use crate::cpu::{Error, ErrorType, Memory};
 
 use crate::cpu::Result;
 use crate::cpu::{Address, Byte, Word};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
 
 pub struct Ram {
     memory: Vec<u8>,
@@ -13,6 +14,10 @@ impl Ram {
             memory: vec![0; size],
         }
     }
+
+    pub fn bytes(&self) -> &[Byte] {
+        &self.memory
+    }
 }
 
 impl Memory for Ram {
@@ -65,6 +70,22 @@ impl Memory for Ram {
     }
 }
 
+// The size is fixed by the machine, so a snapshot must match it
+impl Snapshot for Ram {
+    fn save(&self, state: &mut StateWriter) {
+        state.bytes(&self.memory);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        let memory = state.bytes()?;
+        if memory.len() != self.memory.len() {
+            return Err(Error::without_pc(ErrorType::InvalidSnapshot));
+        }
+        self.memory = memory;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
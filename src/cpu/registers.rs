@@ -1,7 +1,9 @@
 use std::fmt;
 
-use crate::cpu::{Address, Data};
+use crate::cpu::{Address, Data, Result};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Registers {
     pub pc: Address,
     pub sp: Data,
@@ -14,7 +16,7 @@ pub struct Registers {
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum StatusBits {
     Carry = 1 << 0,
     Zero = 1 << 1,
@@ -25,6 +27,16 @@ pub enum StatusBits {
     Neg = 1 << 6,
 }
 
+// Where each flag sits in the status byte pushed to the stack
+const STATUS_LAYOUT: [(Data, StatusBits); 6] = [
+    (0x01, StatusBits::Carry),
+    (0x02, StatusBits::Zero),
+    (0x04, StatusBits::Int),
+    (0x08, StatusBits::Dec),
+    (0x40, StatusBits::Ovf),
+    (0x80, StatusBits::Neg),
+];
+
 impl Registers {
     pub fn new() -> Self {
         Registers {
@@ -85,6 +97,54 @@ impl Registers {
     pub fn negative(&self) -> bool {
         self.get_flag(StatusBits::Neg)
     }
+
+    // The status register as the 6502 lays it out: NV1BDIZC, with B clear
+    // since it only exists on the stack
+    pub fn status(&self) -> Data {
+        let mut p = 0x20;
+        for (bit, flag) in STATUS_LAYOUT {
+            if self.get_flag(flag) {
+                p |= bit;
+            }
+        }
+        p
+    }
+
+    // Sets the flags from a status byte laid out as the 6502 does
+    pub fn set_status(&mut self, p: Data) {
+        for (bit, flag) in STATUS_LAYOUT {
+            self.write_flag(flag, p & bit == bit);
+        }
+    }
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshot for Registers {
+    fn save(&self, state: &mut StateWriter) {
+        state.word(self.pc);
+        state.byte(self.sp);
+        state.byte(self.a);
+        state.byte(self.x);
+        state.byte(self.y);
+        state.word(self.pc_next);
+        state.byte(self.ps);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.pc = state.word()?;
+        self.sp = state.byte()?;
+        self.a = state.byte()?;
+        self.x = state.byte()?;
+        self.y = state.byte()?;
+        self.pc_next = state.word()?;
+        self.ps = state.byte()?;
+        Ok(())
+    }
 }
 
 impl fmt::Display for Registers {
@@ -123,6 +183,7 @@ impl fmt::Display for Registers {
 }
 
 #[cfg(test)]
+#[allow(clippy::type_complexity)]
 mod tests {
     use super::*;
 
@@ -154,4 +215,17 @@ mod tests {
             assert_eq!(get_test_flag(&r), r.get_flag(flag));
         }
     }
+
+    #[test]
+    fn status_layout() {
+        let mut r = Registers::new();
+        r.set_status(0xc3);
+        assert!(r.negative() && r.overflow() && r.zero() && r.carry());
+        assert!(!r.int() && !r.dec());
+        assert_eq!(r.status(), 0xe3);
+
+        r.set_status(0x1c);
+        assert_eq!(r.status(), 0x2c);
+        assert!(r.int() && r.dec() && !r.brk());
+    }
 }
@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::cpu::registers::Registers;
+use crate::cpu::{Byte, InstructionDecoder, Memory, Result};
+use crate::disassembler::{line_at, Line};
+
+// A log of every instruction run, one line each, laid out like nestest.log so
+// it can be diffed against other emulators' traces:
+//
+//   C000  A9 07     LDA #&07                        A:00 X:00 Y:00 P:24 SP:FD CYC:7
+//
+// The registers and cycle count are as they were before the instruction ran.
+// P is in the 6502's own bit order, with bit 5 set and B clear, as other
+// emulators show it.
+
+// Where the disassembly ends and the registers start
+const REGISTER_COLUMN: usize = 48;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub cycles: u64,
+    pub line: Line,
+    pub a: Byte,
+    pub x: Byte,
+    pub y: Byte,
+    pub p: Byte,
+    pub sp: Byte,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<width$}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            self.line.to_string(),
+            self.a,
+            self.x,
+            self.y,
+            self.p,
+            self.sp,
+            self.cycles,
+            width = REGISTER_COLUMN
+        )
+    }
+}
+
+enum Output {
+    Writer(Box<dyn Write>),
+    Buffer(VecDeque<TraceEntry>, usize),
+}
+
+pub struct Trace {
+    output: Output,
+    cycles: u64,
+}
+
+impl Trace {
+    // Writes each line as the instruction runs
+    pub fn to_writer<W: Write + 'static>(writer: W) -> Self {
+        Trace {
+            output: Output::Writer(Box::new(writer)),
+            cycles: 0,
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::to_writer(BufWriter::new(File::create(path)?)))
+    }
+
+    // Keeps only the most recent lines, for a look at what led up to a crash
+    pub fn ring(capacity: usize) -> Self {
+        Trace {
+            output: Output::Buffer(VecDeque::with_capacity(capacity), capacity),
+            cycles: 0,
+        }
+    }
+
+    // Counts cycles from the given number rather than from zero
+    pub fn with_cycles(mut self, cycles: u64) -> Self {
+        self.cycles = cycles;
+        self
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // What a ring has kept, oldest first. A trace to a writer keeps nothing.
+    pub fn entries(&self) -> impl Iterator<Item = &TraceEntry> {
+        let buffer = match &self.output {
+            Output::Buffer(entries, _) => Some(entries),
+            Output::Writer(_) => None,
+        };
+        buffer.into_iter().flatten()
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if let Output::Writer(writer) = &mut self.output {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn record<M: Memory, I: InstructionDecoder>(
+        &mut self,
+        memory: &M,
+        decoder: &I,
+        registers: &Registers,
+    ) -> Result<()> {
+        let entry = TraceEntry {
+            cycles: self.cycles,
+            line: line_at(memory, decoder, registers.pc)?,
+            a: registers.a,
+            x: registers.x,
+            y: registers.y,
+            p: registers.status(),
+            sp: registers.sp,
+        };
+
+        match &mut self.output {
+            Output::Writer(writer) => writeln!(writer, "{}", entry)?,
+            Output::Buffer(entries, capacity) => {
+                if entries.len() == *capacity {
+                    entries.pop_front();
+                }
+                if *capacity > 0 {
+                    entries.push_back(entry);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn advance(&mut self, cycles: usize) {
+        self.cycles += cycles as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instruction_decode::InstructionDecoder;
+    use crate::cpu::ram::Ram;
+    use crate::cpu::registers::StatusBits;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn memory() -> Result<Ram> {
+        let mut memory = Ram::new(0x10000);
+        for (n, &byte) in [0xa9, 0x07, 0x18].iter().enumerate() {
+            memory.write_byte(0xc000 + n as u16, byte)?;
+        }
+        Ok(memory)
+    }
+
+    #[test]
+    fn nestest_layout() -> Result<()> {
+        let shared = Shared(Rc::new(RefCell::new(Vec::new())));
+        let mut trace = Trace::to_writer(shared.clone()).with_cycles(7);
+        let mut registers = Registers::new();
+        registers.pc = 0xc000;
+        registers.sp = 0xfd;
+        registers.set_flag(StatusBits::Int);
+        registers.set_flag(StatusBits::Ovf);
+        registers.set_flag(StatusBits::Brk);
+
+        trace.record(&memory()?, &InstructionDecoder::new(), &registers)?;
+        trace.advance(2);
+        registers.pc = 0xc002;
+        registers.a = 0x07;
+        trace.record(&memory()?, &InstructionDecoder::new(), &registers)?;
+
+        assert_eq!(trace.entries().count(), 0);
+        assert_eq!(
+            String::from_utf8(shared.0.borrow().clone()).unwrap(),
+            "C000  A9 07     LDA #&07                        A:00 X:00 Y:00 P:64 SP:FD CYC:7\n\
+             C002  18        CLC                             A:07 X:00 Y:00 P:64 SP:FD CYC:9\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn ring_keeps_the_latest() -> Result<()> {
+        let memory = memory()?;
+        let decoder = InstructionDecoder::new();
+        let mut registers = Registers::new();
+        let mut trace = Trace::ring(2);
+        for pc in [0xc000, 0xc002, 0xc003] {
+            registers.pc = pc;
+            trace.record(&memory, &decoder, &registers)?;
+            trace.advance(2);
+        }
+
+        let kept: Vec<(u16, u64)> = trace
+            .entries()
+            .map(|entry| (entry.line.address, entry.cycles))
+            .collect();
+        assert_eq!(kept, [(0xc002, 2), (0xc003, 4)]);
+        assert_eq!(trace.cycles(), 6);
+
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use std::marker::PhantomData;
 
-use crate::cpu::{registers::Registers, Address, Memory, Result, Writeback};
+use crate::cpu::{registers::Registers, Address, Error, ErrorType, Memory, Result, Writeback};
 
 use super::ExecutionResult;
 
@@ -19,18 +19,118 @@ where
     }
 }
 
+impl<M> Default for WritebackUnit<M>
+where
+    M: Memory,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<M> crate::cpu::WritebackUnit<M> for WritebackUnit<M>
 where
     M: Memory,
 {
     fn writeback(
         &self,
-        _writeback: &Writeback,
-        _data: ExecutionResult,
-        _address: Option<Address>,
-        _memory: &mut M,
-        _registers: &mut Registers,
+        writeback: &Writeback,
+        data: ExecutionResult,
+        address: Option<Address>,
+        memory: &mut M,
+        registers: &mut Registers,
     ) -> Result<()> {
-        todo!()
+        match (writeback, data) {
+            (Writeback::Accumulator, ExecutionResult::Data(d)) => registers.a = d,
+            (Writeback::X, ExecutionResult::Data(d)) => registers.x = d,
+            (Writeback::Y, ExecutionResult::Data(d)) => registers.y = d,
+            (Writeback::SP, ExecutionResult::Data(d)) => registers.sp = d,
+            (Writeback::PS, ExecutionResult::Data(d)) => registers.ps = d,
+            (Writeback::Memory, ExecutionResult::Data(d)) => match address {
+                Some(a) => memory.write_byte(a, d)?,
+                None => return Err(Error::with_pc(registers.pc, ErrorType::MissingAddress)),
+            },
+            // The dispatcher moves PC on to pc_next once writeback is done
+            (Writeback::PC, ExecutionResult::Address(a)) => registers.pc_next = a,
+            (Writeback::NoWriteback, _) | (_, ExecutionResult::None) => {}
+            (Writeback::PC, _) => {
+                return Err(Error::with_pc(registers.pc, ErrorType::MissingAddress))
+            }
+            _ => return Err(Error::with_pc(registers.pc, ErrorType::MissingData)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::ram::Ram;
+    use crate::cpu::WritebackUnit as _;
+
+    type RegisterGetter = fn(&Registers) -> u8;
+
+    #[test]
+    fn registers() -> Result<()> {
+        let writeback_unit = WritebackUnit::new();
+        let mut memory = Ram::new(0x100);
+        let mut registers = Registers::new();
+
+        let targets: [(Writeback, RegisterGetter); 5] = [
+            (Writeback::Accumulator, |r| r.a),
+            (Writeback::X, |r| r.x),
+            (Writeback::Y, |r| r.y),
+            (Writeback::SP, |r| r.sp),
+            (Writeback::PS, |r| r.ps),
+        ];
+
+        for (target, check) in targets {
+            writeback_unit.writeback(
+                &target,
+                ExecutionResult::Data(0x5a),
+                None,
+                &mut memory,
+                &mut registers,
+            )?;
+            assert_eq!(check(&registers), 0x5a);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn memory_and_pc() -> Result<()> {
+        let writeback_unit = WritebackUnit::new();
+        let mut memory = Ram::new(0x100);
+        let mut registers = Registers::new();
+
+        writeback_unit.writeback(
+            &Writeback::Memory,
+            ExecutionResult::Data(0xde),
+            Some(0x80),
+            &mut memory,
+            &mut registers,
+        )?;
+        assert_eq!(memory.read_byte(0x80)?, 0xde);
+
+        writeback_unit.writeback(
+            &Writeback::PC,
+            ExecutionResult::Address(0x1234),
+            None,
+            &mut memory,
+            &mut registers,
+        )?;
+        assert_eq!(registers.pc_next, 0x1234);
+
+        let result = writeback_unit.writeback(
+            &Writeback::Memory,
+            ExecutionResult::Data(0xde),
+            None,
+            &mut memory,
+            &mut registers,
+        );
+        assert_eq!(result, Err(Error::with_pc(0, ErrorType::MissingAddress)));
+
+        Ok(())
     }
 }
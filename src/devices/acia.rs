@@ -0,0 +1,472 @@
+use std::cell::Cell;
+
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::{Device, CLOCK_HZ};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// Motorola MC6850 Asynchronous Communications Interface Adapter
+
+#[repr(u8)]
+#[derive(Copy, Clone)]
+pub enum StatusBits {
+    ReceiveFull = 1 << 0,
+    TransmitEmpty = 1 << 1,
+    CarrierLost = 1 << 2,
+    ClearToSend = 1 << 3,
+    FramingError = 1 << 4,
+    Overrun = 1 << 5,
+    ParityError = 1 << 6,
+    Irq = 1 << 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WordFormat {
+    pub data_bits: usize,
+    pub parity: Parity,
+    pub stop_bits: usize,
+}
+
+impl WordFormat {
+    pub fn from_control(control: Byte) -> Self {
+        let (data_bits, parity, stop_bits) = match (control >> 2) & 0x07 {
+            0 => (7, Parity::Even, 2),
+            1 => (7, Parity::Odd, 2),
+            2 => (7, Parity::Even, 1),
+            3 => (7, Parity::Odd, 1),
+            4 => (8, Parity::None, 2),
+            5 => (8, Parity::None, 1),
+            6 => (8, Parity::Even, 1),
+            _ => (8, Parity::Odd, 1),
+        };
+
+        WordFormat {
+            data_bits,
+            parity,
+            stop_bits,
+        }
+    }
+
+    pub fn frame_bits(&self) -> usize {
+        let parity_bits = if self.parity == Parity::None { 0 } else { 1 };
+        1 + self.data_bits + parity_bits + self.stop_bits
+    }
+}
+
+const MASTER_RESET: Byte = 0x03;
+
+pub struct Acia {
+    control: Byte,
+    clock_hz: usize,
+
+    transmit_data: Option<Byte>,
+    shift_register: Option<Byte>,
+    shift_cycles: usize,
+    transmitted: Vec<Byte>,
+
+    receive_data: Byte,
+    receive_full: Cell<bool>,
+    overrun: Cell<bool>,
+}
+
+impl Acia {
+    pub fn new(clock_hz: usize) -> Self {
+        Acia {
+            control: MASTER_RESET,
+            clock_hz,
+            transmit_data: None,
+            shift_register: None,
+            shift_cycles: 0,
+            transmitted: Vec::new(),
+            receive_data: 0,
+            receive_full: Cell::new(false),
+            overrun: Cell::new(false),
+        }
+    }
+
+    pub fn set_clock(&mut self, clock_hz: usize) {
+        self.clock_hz = clock_hz;
+    }
+
+    pub fn control(&self) -> Byte {
+        self.control
+    }
+
+    pub fn word_format(&self) -> WordFormat {
+        WordFormat::from_control(self.control)
+    }
+
+    fn divide(&self) -> usize {
+        match self.control & 0x03 {
+            0 => 1,
+            1 => 16,
+            _ => 64,
+        }
+    }
+
+    pub fn baud(&self) -> usize {
+        self.clock_hz / self.divide()
+    }
+
+    pub fn bit_cycles(&self) -> usize {
+        (CLOCK_HZ / self.baud().max(1)).max(1)
+    }
+
+    fn in_reset(&self) -> bool {
+        self.control & 0x03 == MASTER_RESET
+    }
+
+    fn transmit_interrupt_enabled(&self) -> bool {
+        (self.control >> 5) & 0x03 == 0x01
+    }
+
+    fn receive_interrupt_enabled(&self) -> bool {
+        self.control & 0x80 == 0x80
+    }
+
+    pub fn status(&self) -> Byte {
+        let mut status = 0;
+        if self.receive_full.get() {
+            status |= StatusBits::ReceiveFull as u8;
+        }
+        if self.transmit_data.is_none() {
+            status |= StatusBits::TransmitEmpty as u8;
+        }
+        if self.overrun.get() {
+            status |= StatusBits::Overrun as u8;
+        }
+        if self.irq() {
+            status |= StatusBits::Irq as u8;
+        }
+        status
+    }
+
+    pub fn transmitting(&self) -> bool {
+        self.shift_register.is_some()
+    }
+
+    // The transmit line: low for the start bit and any zeros, high for ones,
+    // stop bits and while idle
+    pub fn transmit_line(&self) -> bool {
+        let Some(data) = self.shift_register else {
+            return true;
+        };
+        let format = self.word_format();
+        let frame = format.frame_bits() * self.bit_cycles();
+        let data = data & ((1 << format.data_bits) - 1) as Byte;
+        match frame.saturating_sub(self.shift_cycles) / self.bit_cycles() {
+            0 => false,
+            bit if bit <= format.data_bits => data & (1 << (bit - 1)) != 0,
+            bit if bit == format.data_bits + 1 => {
+                let odd = data.count_ones() % 2 == 1;
+                match format.parity {
+                    Parity::Even => odd,
+                    Parity::Odd => !odd,
+                    Parity::None => true,
+                }
+            }
+            _ => true,
+        }
+    }
+
+    pub fn take_transmitted(&mut self) -> Vec<Byte> {
+        std::mem::take(&mut self.transmitted)
+    }
+
+    pub fn receive(&mut self, data: Byte) {
+        if self.receive_full.get() {
+            self.overrun.set(true);
+        }
+        self.receive_data = data;
+        self.receive_full.set(true);
+    }
+
+    fn write_control(&mut self, data: Byte) {
+        self.control = data;
+        if self.in_reset() {
+            self.transmit_data = None;
+            self.shift_register = None;
+            self.shift_cycles = 0;
+            self.receive_full.set(false);
+            self.overrun.set(false);
+        }
+    }
+
+    fn write_transmit_data(&mut self, data: Byte) {
+        if self.in_reset() {
+            return;
+        }
+        self.transmit_data = Some(data);
+        if self.shift_register.is_none() {
+            self.load_shift_register();
+        }
+    }
+
+    fn load_shift_register(&mut self) {
+        if let Some(data) = self.transmit_data.take() {
+            self.shift_register = Some(data);
+            self.shift_cycles = self.word_format().frame_bits() * self.bit_cycles();
+        }
+    }
+
+    fn read_receive_data(&self) -> Byte {
+        self.receive_full.set(false);
+        self.overrun.set(false);
+        self.receive_data
+    }
+}
+
+// Transmitted bytes are passed on every tick, so there are never any to save
+impl Snapshot for Acia {
+    fn save(&self, state: &mut StateWriter) {
+        state.byte(self.control);
+        state.usize(self.clock_hz);
+        state.option_byte(self.transmit_data);
+        state.option_byte(self.shift_register);
+        state.usize(self.shift_cycles);
+        state.byte(self.receive_data);
+        state.bool(self.receive_full.get());
+        state.bool(self.overrun.get());
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.control = state.byte()?;
+        self.clock_hz = state.usize()?;
+        self.transmit_data = state.option_byte()?;
+        self.shift_register = state.option_byte()?;
+        self.shift_cycles = state.usize()?;
+        self.receive_data = state.byte()?;
+        self.receive_full.set(state.bool()?);
+        self.overrun.set(state.bool()?);
+        Ok(())
+    }
+}
+
+impl Memory for Acia {
+    fn length(&self) -> usize {
+        2
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        if address & 0x01 == 0 {
+            Ok(self.status())
+        } else {
+            Ok(self.read_receive_data())
+        }
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let lsb = self.read_byte(address)? as Word;
+        let msb = self.read_byte(address.wrapping_add(1))? as Word;
+        Ok(lsb + (msb << 8))
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        if address & 0x01 == 0 {
+            self.write_control(data);
+        } else {
+            self.write_transmit_data(data);
+        }
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for Acia {
+    fn tick(&mut self, cycles: usize) {
+        let mut cycles = cycles;
+        while let Some(data) = self.shift_register {
+            if cycles < self.shift_cycles {
+                self.shift_cycles -= cycles;
+                break;
+            }
+
+            cycles -= self.shift_cycles;
+            self.transmitted.push(data);
+            self.shift_register = None;
+            self.shift_cycles = 0;
+            self.load_shift_register();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        (self.receive_interrupt_enabled() && self.receive_full.get())
+            || (self.transmit_interrupt_enabled()
+                && !self.in_reset()
+                && self.transmit_data.is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8N1, divide by 16
+    const CONTROL_8N1: Byte = 0x15;
+
+    #[test]
+    fn construct() {
+        let _ = Acia::new(19200);
+    }
+
+    #[test]
+    fn word_formats_decode() {
+        let cases = vec![
+            (0x00, 7, Parity::Even, 2, 11),
+            (0x04, 7, Parity::Odd, 2, 11),
+            (0x10, 8, Parity::None, 2, 11),
+            (0x14, 8, Parity::None, 1, 10),
+            (0x1c, 8, Parity::Odd, 1, 11),
+        ];
+
+        for (control, data_bits, parity, stop_bits, frame_bits) in cases {
+            let format = WordFormat::from_control(control);
+            assert_eq!(format.data_bits, data_bits, "{:02x}", control);
+            assert_eq!(format.parity, parity, "{:02x}", control);
+            assert_eq!(format.stop_bits, stop_bits, "{:02x}", control);
+            assert_eq!(format.frame_bits(), frame_bits, "{:02x}", control);
+        }
+    }
+
+    #[test]
+    fn transmit_line_follows_the_frame() -> Result<()> {
+        let mut acia = Acia::new(19200);
+        acia.write_byte(0, CONTROL_8N1)?;
+        assert!(acia.transmit_line());
+
+        acia.write_byte(1, 0x01)?;
+        let bit = acia.bit_cycles();
+        let mut line = Vec::new();
+        for _ in 0..10 {
+            line.push(acia.transmit_line());
+            acia.tick(bit);
+        }
+        let mut expected = [false; 10];
+        expected[1] = true;
+        expected[9] = true;
+        assert_eq!(line, expected);
+        assert!(acia.transmit_line());
+
+        Ok(())
+    }
+
+    #[test]
+    fn baud_follows_divide() -> Result<()> {
+        let mut acia = Acia::new(19200);
+
+        acia.write_byte(0, 0x15)?;
+        assert_eq!(acia.baud(), 1200);
+
+        acia.write_byte(0, 0x16)?;
+        assert_eq!(acia.baud(), 300);
+
+        Ok(())
+    }
+
+    #[test]
+    fn master_reset_holds_transmitter() -> Result<()> {
+        let mut acia = Acia::new(19200);
+
+        acia.write_byte(1, 0xde)?;
+        assert!(!acia.transmitting());
+
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_takes_one_frame() -> Result<()> {
+        let mut acia = Acia::new(19200);
+        acia.write_byte(0, CONTROL_8N1)?;
+
+        acia.write_byte(1, 0xde)?;
+        assert!(acia.transmitting());
+        assert_eq!(
+            acia.status() & StatusBits::TransmitEmpty as u8,
+            StatusBits::TransmitEmpty as u8
+        );
+
+        let frame = 10 * acia.bit_cycles();
+        acia.tick(frame - 1);
+        assert!(acia.take_transmitted().is_empty());
+
+        acia.tick(1);
+        assert_eq!(acia.take_transmitted(), vec![0xde]);
+        assert!(!acia.transmitting());
+
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_is_double_buffered() -> Result<()> {
+        let mut acia = Acia::new(19200);
+        acia.write_byte(0, CONTROL_8N1)?;
+
+        acia.write_byte(1, 0xde)?;
+        acia.write_byte(1, 0xad)?;
+        assert_eq!(acia.status() & StatusBits::TransmitEmpty as u8, 0);
+
+        acia.tick(20 * acia.bit_cycles());
+        assert_eq!(acia.take_transmitted(), vec![0xde, 0xad]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn transmit_interrupt() -> Result<()> {
+        let mut acia = Acia::new(19200);
+        acia.write_byte(0, CONTROL_8N1 | 0x20)?;
+        assert!(acia.irq());
+
+        acia.write_byte(1, 0xde)?;
+        acia.write_byte(1, 0xad)?;
+        assert!(!acia.irq());
+
+        acia.tick(10 * acia.bit_cycles());
+        assert!(acia.irq());
+        assert_eq!(acia.status() & StatusBits::Irq as u8, StatusBits::Irq as u8);
+
+        Ok(())
+    }
+
+    #[test]
+    fn receive_sets_and_read_clears() -> Result<()> {
+        let mut acia = Acia::new(19200);
+        acia.write_byte(0, CONTROL_8N1 | 0x80)?;
+
+        acia.receive(0x41);
+        assert!(acia.irq());
+        assert_eq!(acia.status() & StatusBits::ReceiveFull as u8, 1);
+
+        assert_eq!(acia.read_byte(1)?, 0x41);
+        assert!(!acia.irq());
+        assert_eq!(acia.status() & StatusBits::ReceiveFull as u8, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn receive_overrun() -> Result<()> {
+        let mut acia = Acia::new(19200);
+        acia.write_byte(0, CONTROL_8N1)?;
+
+        acia.receive(0x41);
+        acia.receive(0x42);
+        assert_eq!(
+            acia.status() & StatusBits::Overrun as u8,
+            StatusBits::Overrun as u8
+        );
+        assert_eq!(acia.read_byte(1)?, 0x42);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::Device;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// Motorola 6845 CRT controller at &FE00 (address register) and &FE01 (data).
+// Timing is tracked a scanline at a time, which is enough to drive vertical
+// sync into the System VIA and to know where the screen starts.
+
+pub const REGISTERS: usize = 18;
+
+pub const R0_HORIZONTAL_TOTAL: usize = 0;
+pub const R1_HORIZONTAL_DISPLAYED: usize = 1;
+pub const R3_SYNC_WIDTH: usize = 3;
+pub const R4_VERTICAL_TOTAL: usize = 4;
+pub const R5_VERTICAL_ADJUST: usize = 5;
+pub const R6_VERTICAL_DISPLAYED: usize = 6;
+pub const R7_VERTICAL_SYNC: usize = 7;
+pub const R8_INTERLACE: usize = 8;
+pub const R9_SCANLINES: usize = 9;
+pub const R12_START_HIGH: usize = 12;
+pub const R13_START_LOW: usize = 13;
+pub const R14_CURSOR_HIGH: usize = 14;
+pub const R15_CURSOR_LOW: usize = 15;
+
+// Registers that can't be read back read as zero
+const READABLE: [bool; REGISTERS] = [
+    false, false, false, false, false, false, false, false, false, false, false, false, false,
+    false, true, true, true, true,
+];
+
+// Only the bits each register implements
+const MASKS: [Byte; REGISTERS] = [
+    0xff, 0xff, 0xff, 0xff, 0x7f, 0x1f, 0x7f, 0x7f, 0xf3, 0x1f, 0x7f, 0x1f, 0x3f, 0xff, 0x3f, 0xff,
+    0x3f, 0xff,
+];
+
+pub struct Crtc {
+    address: Byte,
+    registers: [Byte; REGISTERS],
+
+    // Characters are clocked at 2MHz in the high resolution modes
+    pub fast_clock: bool,
+
+    cycles: usize,
+    scanline: usize,
+    row: usize,
+    adjust: bool,
+    vsync_lines: usize,
+    frames: u64,
+}
+
+impl Crtc {
+    pub fn new() -> Self {
+        Crtc {
+            address: 0,
+            registers: [0; REGISTERS],
+            fast_clock: false,
+            cycles: 0,
+            scanline: 0,
+            row: 0,
+            adjust: false,
+            vsync_lines: 0,
+            frames: 0,
+        }
+    }
+
+    pub fn register(&self, register: usize) -> Byte {
+        self.registers[register]
+    }
+
+    pub fn set_register(&mut self, register: usize, data: Byte) {
+        if register < REGISTERS {
+            self.registers[register] = data & MASKS[register];
+        }
+    }
+
+    pub fn start_address(&self) -> Word {
+        ((self.registers[R12_START_HIGH] as Word) << 8) | self.registers[R13_START_LOW] as Word
+    }
+
+    pub fn cursor_address(&self) -> Word {
+        ((self.registers[R14_CURSOR_HIGH] as Word) << 8) | self.registers[R15_CURSOR_LOW] as Word
+    }
+
+    pub fn vsync(&self) -> bool {
+        self.vsync_lines > 0
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Interlace sync and video (MODE 7) draws alternate scanlines in each field
+    pub fn scanlines_per_row(&self) -> usize {
+        let scanlines = self.registers[R9_SCANLINES] as usize;
+        if self.registers[R8_INTERLACE] & 0x03 == 0x03 {
+            scanlines / 2 + 1
+        } else {
+            scanlines + 1
+        }
+    }
+
+    fn line_cycles(&self) -> usize {
+        let characters = self.registers[R0_HORIZONTAL_TOTAL] as usize + 1;
+        if self.fast_clock {
+            characters
+        } else {
+            characters * 2
+        }
+    }
+
+    fn vsync_width(&self) -> usize {
+        match self.registers[R3_SYNC_WIDTH] >> 4 {
+            0 => 16,
+            width => width as usize,
+        }
+    }
+
+    fn next_scanline(&mut self) {
+        if self.vsync_lines > 0 {
+            self.vsync_lines -= 1;
+        }
+
+        self.scanline += 1;
+        if self.adjust {
+            if self.scanline >= self.registers[R5_VERTICAL_ADJUST] as usize {
+                self.new_frame();
+            }
+            return;
+        }
+
+        if self.scanline < self.scanlines_per_row() {
+            return;
+        }
+
+        self.scanline = 0;
+        self.row += 1;
+        if self.row > self.registers[R4_VERTICAL_TOTAL] as usize {
+            if self.registers[R5_VERTICAL_ADJUST] == 0 {
+                self.new_frame();
+            } else {
+                self.adjust = true;
+            }
+            return;
+        }
+
+        if self.row == self.registers[R7_VERTICAL_SYNC] as usize {
+            self.vsync_lines = self.vsync_width();
+        }
+    }
+
+    fn new_frame(&mut self) {
+        self.scanline = 0;
+        self.row = 0;
+        self.adjust = false;
+        self.frames += 1;
+        if self.registers[R7_VERTICAL_SYNC] == 0 {
+            self.vsync_lines = self.vsync_width();
+        }
+    }
+}
+
+impl Snapshot for Crtc {
+    fn save(&self, state: &mut StateWriter) {
+        state.byte(self.address);
+        state.array(&self.registers);
+        state.bool(self.fast_clock);
+        state.usize(self.cycles);
+        state.usize(self.scanline);
+        state.usize(self.row);
+        state.bool(self.adjust);
+        state.usize(self.vsync_lines);
+        state.u64(self.frames);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.address = state.byte()?;
+        state.array(&mut self.registers)?;
+        self.fast_clock = state.bool()?;
+        self.cycles = state.usize()?;
+        self.scanline = state.usize()?;
+        self.row = state.usize()?;
+        self.adjust = state.bool()?;
+        self.vsync_lines = state.usize()?;
+        self.frames = state.u64()?;
+        Ok(())
+    }
+}
+
+impl Default for Crtc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for Crtc {
+    fn length(&self) -> usize {
+        0x08
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        let register = self.address as usize;
+        if address & 0x01 == 0x01 && register < REGISTERS && READABLE[register] {
+            Ok(self.registers[register])
+        } else {
+            Ok(0x00)
+        }
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let lsb = self.read_byte(address)? as Word;
+        let msb = self.read_byte(address.wrapping_add(1))? as Word;
+        Ok(lsb + (msb << 8))
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        if address & 0x01 == 0x00 {
+            self.address = data & 0x1f;
+        } else if (self.address as usize) < 16 {
+            self.set_register(self.address as usize, data);
+        }
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for Crtc {
+    fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles;
+        loop {
+            let line = self.line_cycles();
+            if self.cycles < line {
+                break;
+            }
+            self.cycles -= line;
+            self.next_scanline();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MODE 7's registers, as the MOS programs them
+    const MODE7: [Byte; 14] = [
+        0x3f, 0x28, 0x33, 0x24, 0x1e, 0x02, 0x19, 0x1b, 0x93, 0x12, 0x72, 0x13, 0x28, 0x00,
+    ];
+
+    fn mode7() -> Result<Crtc> {
+        let mut crtc = Crtc::new();
+        for (n, &value) in MODE7.iter().enumerate() {
+            crtc.write_byte(0, n as Byte)?;
+            crtc.write_byte(1, value)?;
+        }
+        Ok(crtc)
+    }
+
+    #[test]
+    fn registers() -> Result<()> {
+        let crtc = mode7()?;
+        assert_eq!(crtc.start_address(), 0x2800);
+        assert_eq!(crtc.register(R1_HORIZONTAL_DISPLAYED), 40);
+
+        // R12 isn't readable
+        assert_eq!(crtc.read_byte(1)?, 0x00);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_is_readable() -> Result<()> {
+        let mut crtc = Crtc::new();
+        crtc.write_byte(0, R14_CURSOR_HIGH as Byte)?;
+        crtc.write_byte(1, 0x12)?;
+        assert_eq!(crtc.read_byte(1)?, 0x12);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fifty_fields_a_second() -> Result<()> {
+        let mut crtc = mode7()?;
+        let mut vsyncs = 0;
+        let mut vsync = false;
+
+        for _ in 0..crate::devices::CLOCK_HZ / 100 {
+            crtc.tick(100);
+            if crtc.vsync() && !vsync {
+                vsyncs += 1;
+            }
+            vsync = crtc.vsync();
+        }
+
+        // 64us lines, 312 of them per field
+        assert_eq!(vsyncs, 2_000_000 / (128 * 312));
+        assert_eq!(crtc.frames(), vsyncs as u64);
+
+        Ok(())
+    }
+
+    #[test]
+    fn vsync_lasts_sync_width_lines() -> Result<()> {
+        let mut crtc = mode7()?;
+        while !crtc.vsync() {
+            crtc.tick(128);
+        }
+
+        crtc.tick(128);
+        assert!(crtc.vsync());
+        crtc.tick(128);
+        assert!(!crtc.vsync());
+
+        Ok(())
+    }
+}
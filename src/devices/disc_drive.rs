@@ -0,0 +1,109 @@
+use crate::cpu::Result;
+use crate::disc::image::DiscImage;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+pub const MAX_TRACK: usize = 83;
+
+pub struct DiscDrive {
+    pub image: Option<DiscImage>,
+    head: usize,
+}
+
+impl DiscDrive {
+    pub fn new() -> Self {
+        DiscDrive {
+            image: None,
+            head: 0,
+        }
+    }
+
+    pub fn insert(&mut self, image: DiscImage) -> Option<DiscImage> {
+        self.image.replace(image)
+    }
+
+    pub fn eject(&mut self) -> Option<DiscImage> {
+        self.image.take()
+    }
+
+    pub fn ready(&self) -> bool {
+        self.image.is_some()
+    }
+
+    pub fn write_protected(&self) -> bool {
+        self.image.as_ref().is_some_and(|i| i.write_protected)
+    }
+
+    pub fn head(&self) -> usize {
+        self.head
+    }
+
+    pub fn at_track0(&self) -> bool {
+        self.head == 0
+    }
+
+    pub fn seek(&mut self, track: usize) {
+        self.head = track.min(MAX_TRACK);
+    }
+
+    pub fn step_in(&mut self) {
+        self.seek(self.head + 1);
+    }
+
+    pub fn step_out(&mut self) {
+        self.head = self.head.saturating_sub(1);
+    }
+}
+
+// Only the head position: discs are files on the host and are inserted
+// separately
+impl Snapshot for DiscDrive {
+    fn save(&self, state: &mut StateWriter) {
+        state.usize(self.head);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.head = state.usize()?.min(MAX_TRACK);
+        Ok(())
+    }
+}
+
+impl Default for DiscDrive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disc::image::Format;
+
+    #[test]
+    fn construct() {
+        let drive = DiscDrive::new();
+        assert!(!drive.ready());
+        assert!(drive.at_track0());
+    }
+
+    #[test]
+    fn insert_and_eject() {
+        let mut drive = DiscDrive::new();
+        drive.insert(DiscImage::new(Format::Ssd, 40));
+        assert!(drive.ready());
+
+        assert!(drive.eject().is_some());
+        assert!(!drive.ready());
+    }
+
+    #[test]
+    fn head_stops_at_ends() {
+        let mut drive = DiscDrive::new();
+        drive.step_out();
+        assert_eq!(drive.head(), 0);
+
+        drive.seek(200);
+        assert_eq!(drive.head(), MAX_TRACK);
+        drive.step_in();
+        assert_eq!(drive.head(), MAX_TRACK);
+    }
+}
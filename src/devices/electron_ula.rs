@@ -0,0 +1,553 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::acia::{Parity, WordFormat};
+use crate::devices::{Device, CLOCK_HZ};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use crate::tape::recorder::TapeRecorder;
+
+// The Electron's ULA at &FE00-&FE0F (mirrored through SHEILA). It does the
+// work of the BBC's CRTC, video ULA, VIAs, ACIA and sound chip: screen
+// modes, interrupts, ROM paging, the cassette interface and 1-bit sound.
+
+#[repr(u8)]
+#[derive(Copy, Clone)]
+pub enum InterruptBits {
+    Master = 1 << 0,
+    PowerOn = 1 << 1,
+    DisplayEnd = 1 << 2,
+    RealTimeClock = 1 << 3,
+    ReceiveFull = 1 << 4,
+    TransmitEmpty = 1 << 5,
+    HighTone = 1 << 6,
+}
+
+pub const REGISTER_INTERRUPT: Address = 0x00;
+pub const REGISTER_SCREEN_LOW: Address = 0x02;
+pub const REGISTER_SCREEN_HIGH: Address = 0x03;
+pub const REGISTER_CASSETTE_DATA: Address = 0x04;
+pub const REGISTER_PAGING: Address = 0x05;
+pub const REGISTER_COUNTER: Address = 0x06;
+pub const REGISTER_CONTROL: Address = 0x07;
+pub const REGISTER_PALETTE: Address = 0x08;
+
+// Only these can be enabled
+const MASKABLE: Byte = 0x7c;
+
+const CONTROL_CASSETTE_MODE: Byte = 0x06;
+const CONTROL_SCREEN_MODE: Byte = 0x38;
+const CONTROL_MOTOR: Byte = 0x40;
+const CONTROL_CAPS_LOCK: Byte = 0x80;
+
+// A PAL field: 312 lines of 64us
+pub const LINE_CYCLES: usize = 128;
+pub const FIELD_LINES: usize = 312;
+const RTC_LINE: usize = 100;
+
+// The cassette runs at 1200 baud with a start and stop bit around each byte
+const CASSETTE_BAUD: usize = 1200;
+const CASSETTE_FORMAT: WordFormat = WordFormat {
+    data_bits: 8,
+    parity: Parity::None,
+    stop_bits: 1,
+};
+const BYTE_CYCLES: usize = 10 * CLOCK_HZ / CASSETTE_BAUD;
+const BIT_CYCLES: usize = CLOCK_HZ / CASSETTE_BAUD;
+const LEAD_TONE_CYCLES: usize = CLOCK_HZ;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    Input,
+    Sound,
+    Output,
+}
+
+pub struct ElectronUla {
+    status: Cell<Byte>,
+    enable: Byte,
+    screen_start: Word,
+    paging: Byte,
+    counter: Byte,
+    control: Byte,
+    palette: [Byte; 8],
+
+    line_cycles: usize,
+    line: usize,
+
+    // Shift register in each direction
+    receive: Byte,
+    transmit: Option<Byte>,
+    cassette_cycles: usize,
+    tape: VecDeque<Byte>,
+    pub recorder: TapeRecorder,
+
+    // Free running, for the phase of the tone on the speaker or cassette
+    sound_cycles: usize,
+}
+
+impl ElectronUla {
+    pub fn new() -> Self {
+        ElectronUla {
+            status: Cell::new(InterruptBits::PowerOn as Byte),
+            enable: 0,
+            screen_start: 0,
+            paging: 0,
+            counter: 0,
+            control: 0,
+            palette: [0; 8],
+            line_cycles: 0,
+            line: 0,
+            receive: 0,
+            transmit: None,
+            cassette_cycles: 0,
+            tape: VecDeque::new(),
+            recorder: TapeRecorder::new(),
+            sound_cycles: 0,
+        }
+    }
+
+    pub fn power_on(&mut self) {
+        let recorder = std::mem::take(&mut self.recorder);
+        let tape = std::mem::take(&mut self.tape);
+        *self = ElectronUla {
+            recorder,
+            tape,
+            ..ElectronUla::new()
+        };
+    }
+
+    pub fn status(&self) -> Byte {
+        let status = self.status.get() & !(InterruptBits::Master as Byte);
+        if self.irq() {
+            status | InterruptBits::Master as Byte
+        } else {
+            status
+        }
+    }
+
+    // The selected ROM page. Pages 8 and 9 are the keyboard, 10 and 11 BASIC.
+    pub fn rom_page(&self) -> Byte {
+        self.paging
+    }
+
+    pub fn screen_start(&self) -> Word {
+        self.screen_start
+    }
+
+    pub fn screen_mode(&self) -> Byte {
+        ((self.control & CONTROL_SCREEN_MODE) >> 3).min(6)
+    }
+
+    pub fn palette(&self, register: usize) -> Byte {
+        self.palette[register & 0x07]
+    }
+
+    // The physical colour (bit 0 red, 1 green, 2 blue) of one of the 16
+    // logical colours. Each pair of palette registers holds colours 0, 2, 8
+    // and 10 from some base, a bit low for each gun that's on. The two and
+    // four colour modes only use colours 0 and 8, and 0, 2, 8 and 10.
+    pub fn colour(&self, logical: usize) -> usize {
+        let pair = match logical & 0x05 {
+            0x00 => 0,
+            0x04 => 1,
+            0x05 => 2,
+            _ => 3,
+        };
+        let even = self.palette[pair * 2];
+        let odd = self.palette[pair * 2 + 1];
+        let (red, green, blue) = match logical & 0x0a {
+            0x00 => (odd & 0x01, odd & 0x10, even & 0x10),
+            0x02 => (odd & 0x02, odd & 0x20, even & 0x20),
+            0x08 => (odd & 0x04, even & 0x04, even & 0x40),
+            _ => (odd & 0x08, even & 0x08, even & 0x80),
+        };
+        (red == 0) as usize | ((green == 0) as usize) << 1 | ((blue == 0) as usize) << 2
+    }
+
+    pub fn caps_lock(&self) -> bool {
+        self.control & CONTROL_CAPS_LOCK == CONTROL_CAPS_LOCK
+    }
+
+    pub fn motor_on(&self) -> bool {
+        self.control & CONTROL_MOTOR == CONTROL_MOTOR
+    }
+
+    pub fn cassette_mode(&self) -> CassetteMode {
+        match (self.control & CONTROL_CASSETTE_MODE) >> 1 {
+            1 => CassetteMode::Sound,
+            2 => CassetteMode::Output,
+            _ => CassetteMode::Input,
+        }
+    }
+
+    // The square wave on the speaker, if it's on: 1MHz / 16(S+1)
+    pub fn sound_frequency(&self) -> Option<usize> {
+        if self.cassette_mode() == CassetteMode::Sound {
+            Some(CLOCK_HZ / 2 / (16 * (self.counter as usize + 1)))
+        } else {
+            None
+        }
+    }
+
+    // The speaker's square wave, or the tones going out to the cassette: a
+    // cycle of 1200Hz for a 0 bit, two of 2400Hz for a 1 and 2400Hz carrier
+    // between bytes
+    pub fn sound_level(&self) -> f32 {
+        let half_cycle = match self.cassette_mode() {
+            CassetteMode::Sound => 16 * (self.counter as usize + 1),
+            CassetteMode::Output if self.motor_on() => {
+                let bit = self.cassette_cycles / BIT_CYCLES;
+                let one = match self.transmit {
+                    Some(data) if (1..=8).contains(&bit) => data & (1 << (bit - 1)) != 0,
+                    Some(_) => bit != 0,
+                    None => true,
+                };
+                if one {
+                    CLOCK_HZ / 4800
+                } else {
+                    CLOCK_HZ / 2400
+                }
+            }
+            _ => return 0.0,
+        };
+        if (self.sound_cycles / half_cycle).is_multiple_of(2) {
+            0.5
+        } else {
+            -0.5
+        }
+    }
+
+    // Queues bytes to be read from the cassette once the motor is started
+    pub fn insert_tape(&mut self, data: Vec<Byte>) {
+        self.tape = data.into();
+        self.cassette_cycles = 0;
+    }
+
+    fn raise(&self, bit: InterruptBits) {
+        self.status.set(self.status.get() | bit as Byte);
+    }
+
+    fn clear(&self, bits: Byte) {
+        self.status.set(self.status.get() & !bits);
+    }
+
+    fn write_paging(&mut self, data: Byte) {
+        // Bits 4-6 clear the display end, RTC and high tone interrupts
+        for (bit, interrupt) in [
+            (0x10, InterruptBits::DisplayEnd),
+            (0x20, InterruptBits::RealTimeClock),
+            (0x40, InterruptBits::HighTone),
+        ] {
+            if data & bit == bit {
+                self.clear(interrupt as Byte);
+            }
+        }
+
+        // With the keyboard or BASIC paged in, only pages 8-15 can be
+        // selected directly
+        let page = data & 0x0f;
+        if self.paging & 0x0c != 0x08 || page & 0x08 == 0x08 {
+            self.paging = page;
+        }
+    }
+
+    fn write_control(&mut self, data: Byte) {
+        let was_output = self.cassette_mode() == CassetteMode::Output;
+        self.control = data;
+        self.recorder.motor(self.motor_on());
+
+        if self.cassette_mode() == CassetteMode::Output && !was_output {
+            self.transmit = None;
+            self.raise(InterruptBits::TransmitEmpty);
+        }
+        if self.motor_on() && self.cassette_mode() == CassetteMode::Input && !self.tape.is_empty() {
+            self.raise(InterruptBits::HighTone);
+        }
+    }
+
+    fn next_line(&mut self) {
+        self.line = (self.line + 1) % FIELD_LINES;
+
+        // The text modes have 25 rows of 10 lines, the rest 32 of 8
+        let display_end = match self.screen_mode() {
+            3 | 6 => 250,
+            _ => 256,
+        };
+        if self.line == display_end {
+            self.raise(InterruptBits::DisplayEnd);
+        } else if self.line == RTC_LINE {
+            self.raise(InterruptBits::RealTimeClock);
+        }
+    }
+
+    fn tick_cassette(&mut self, cycles: usize) {
+        match self.cassette_mode() {
+            CassetteMode::Output => {
+                let Some(data) = self.transmit else {
+                    self.recorder.idle(cycles);
+                    return;
+                };
+                self.cassette_cycles += cycles;
+                if self.cassette_cycles >= BYTE_CYCLES {
+                    self.cassette_cycles = 0;
+                    self.transmit = None;
+                    self.recorder.byte(data, CASSETTE_FORMAT, CASSETTE_BAUD);
+                    self.raise(InterruptBits::TransmitEmpty);
+                }
+            }
+            CassetteMode::Input if self.motor_on() && !self.tape.is_empty() => {
+                self.cassette_cycles += cycles;
+                if self.cassette_cycles >= LEAD_TONE_CYCLES + BYTE_CYCLES {
+                    self.cassette_cycles -= BYTE_CYCLES;
+                    self.receive = self.tape.pop_front().unwrap_or(0);
+                    self.raise(InterruptBits::ReceiveFull);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// As with the BBC, the recorder's tape is left out but a tape being read is
+// part of the state
+impl Snapshot for ElectronUla {
+    fn save(&self, state: &mut StateWriter) {
+        state.byte(self.status.get());
+        state.byte(self.enable);
+        state.word(self.screen_start);
+        state.byte(self.paging);
+        state.byte(self.counter);
+        state.byte(self.control);
+        state.array(&self.palette);
+        state.usize(self.line_cycles);
+        state.usize(self.line);
+        state.byte(self.receive);
+        state.option_byte(self.transmit);
+        state.usize(self.cassette_cycles);
+        state.bytes(&self.tape.iter().copied().collect::<Vec<_>>());
+        state.usize(self.sound_cycles);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.status.set(state.byte()?);
+        self.enable = state.byte()?;
+        self.screen_start = state.word()?;
+        self.paging = state.byte()?;
+        self.counter = state.byte()?;
+        self.control = state.byte()?;
+        state.array(&mut self.palette)?;
+        self.line_cycles = state.usize()?;
+        self.line = state.usize()?;
+        self.receive = state.byte()?;
+        self.transmit = state.option_byte()?;
+        self.cassette_cycles = state.usize()?;
+        self.tape = state.bytes()?.into();
+        self.sound_cycles = state.usize()?;
+        Ok(())
+    }
+}
+
+impl Default for ElectronUla {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for ElectronUla {
+    fn length(&self) -> usize {
+        0x10
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        match address & 0x0f {
+            REGISTER_INTERRUPT => {
+                let status = self.status();
+                self.clear(InterruptBits::PowerOn as Byte);
+                Ok(status)
+            }
+            REGISTER_CASSETTE_DATA => {
+                self.clear(InterruptBits::ReceiveFull as Byte);
+                Ok(self.receive)
+            }
+            _ => Ok(0x00),
+        }
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let lsb = self.read_byte(address)? as Word;
+        let msb = self.read_byte(address.wrapping_add(1))? as Word;
+        Ok(lsb + (msb << 8))
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        match address & 0x0f {
+            REGISTER_INTERRUPT => self.enable = data & MASKABLE,
+            REGISTER_SCREEN_LOW => {
+                self.screen_start = (self.screen_start & 0x7e00) | ((data as Word & 0xe0) << 1);
+            }
+            REGISTER_SCREEN_HIGH => {
+                self.screen_start = (self.screen_start & 0x01c0) | ((data as Word & 0x3f) << 9);
+            }
+            REGISTER_CASSETTE_DATA => {
+                self.clear(InterruptBits::TransmitEmpty as Byte);
+                if self.cassette_mode() == CassetteMode::Output {
+                    self.transmit = Some(data);
+                    self.cassette_cycles = 0;
+                }
+            }
+            REGISTER_PAGING => self.write_paging(data),
+            REGISTER_COUNTER => self.counter = data,
+            REGISTER_CONTROL => self.write_control(data),
+            0x01 => {}
+            register => self.palette[(register - REGISTER_PALETTE) as usize] = data,
+        }
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for ElectronUla {
+    fn tick(&mut self, cycles: usize) {
+        self.line_cycles += cycles;
+        while self.line_cycles >= LINE_CYCLES {
+            self.line_cycles -= LINE_CYCLES;
+            self.next_line();
+        }
+
+        self.tick_cassette(cycles);
+        self.sound_cycles = self.sound_cycles.wrapping_add(cycles);
+    }
+
+    fn irq(&self) -> bool {
+        self.status.get() & self.enable & MASKABLE != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_on_bit_clears_on_read() -> Result<()> {
+        let ula = ElectronUla::new();
+        assert_eq!(ula.read_byte(REGISTER_INTERRUPT)?, 0x02);
+        assert_eq!(ula.read_byte(REGISTER_INTERRUPT)?, 0x00);
+
+        Ok(())
+    }
+
+    #[test]
+    fn field_interrupts() -> Result<()> {
+        let mut ula = ElectronUla::new();
+        ula.write_byte(REGISTER_INTERRUPT, InterruptBits::RealTimeClock as Byte)?;
+
+        ula.tick(LINE_CYCLES * 99);
+        assert!(!ula.irq());
+        ula.tick(LINE_CYCLES);
+        assert!(ula.irq());
+        assert_eq!(ula.status() & 0x09, 0x09);
+
+        // Cleared through the paging register
+        ula.write_byte(REGISTER_PAGING, 0x20)?;
+        assert!(!ula.irq());
+
+        ula.tick(LINE_CYCLES * 156);
+        assert_ne!(ula.status() & InterruptBits::DisplayEnd as Byte, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn paging_keyboard_and_basic() -> Result<()> {
+        let mut ula = ElectronUla::new();
+        ula.write_byte(REGISTER_PAGING, 0x0a)?;
+        assert_eq!(ula.rom_page(), 0x0a);
+
+        // Pages 0-7 need 12-15 selecting first
+        ula.write_byte(REGISTER_PAGING, 0x01)?;
+        assert_eq!(ula.rom_page(), 0x0a);
+        ula.write_byte(REGISTER_PAGING, 0x0c)?;
+        ula.write_byte(REGISTER_PAGING, 0x01)?;
+        assert_eq!(ula.rom_page(), 0x01);
+
+        Ok(())
+    }
+
+    #[test]
+    fn screen_start_address() -> Result<()> {
+        let mut ula = ElectronUla::new();
+        ula.write_byte(REGISTER_SCREEN_LOW, 0x00)?;
+        ula.write_byte(REGISTER_SCREEN_HIGH, 0x18)?;
+        assert_eq!(ula.screen_start(), 0x3000);
+
+        ula.write_byte(REGISTER_CONTROL, 0x30)?;
+        assert_eq!(ula.screen_mode(), 6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn palette_bits() -> Result<()> {
+        let mut ula = ElectronUla::new();
+        // Black, red, yellow and white, as in MODE 1
+        ula.write_byte(REGISTER_PALETTE, 0x73)?;
+        ula.write_byte(REGISTER_PALETTE + 1, 0x31)?;
+        let colours: Vec<usize> = [0, 2, 8, 10].iter().map(|&c| ula.colour(c)).collect();
+        assert_eq!(colours, [0, 1, 3, 7]);
+
+        // Colour 5 is in the third pair
+        ula.write_byte(REGISTER_PALETTE + 4, 0xef)?;
+        ula.write_byte(REGISTER_PALETTE + 5, 0xff)?;
+        assert_eq!(ula.colour(5), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sound_frequency() -> Result<()> {
+        let mut ula = ElectronUla::new();
+        assert_eq!(ula.sound_frequency(), None);
+
+        ula.write_byte(REGISTER_CONTROL, 0x02)?;
+        ula.write_byte(REGISTER_COUNTER, 0x3e)?;
+        assert_eq!(ula.sound_frequency(), Some(1_000_000 / (16 * 63)));
+
+        // Half a cycle high, half low
+        assert_eq!(ula.sound_level(), 0.5);
+        ula.tick(16 * 63);
+        assert_eq!(ula.sound_level(), -0.5);
+        ula.tick(16 * 63);
+        assert_eq!(ula.sound_level(), 0.5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cassette_round_trip() -> Result<()> {
+        let mut ula = ElectronUla::new();
+        ula.write_byte(REGISTER_CONTROL, 0x44)?;
+        assert_ne!(ula.status() & InterruptBits::TransmitEmpty as Byte, 0);
+
+        ula.write_byte(REGISTER_CASSETTE_DATA, 0x2a)?;
+        ula.tick(BYTE_CYCLES);
+        assert_ne!(ula.status() & InterruptBits::TransmitEmpty as Byte, 0);
+        ula.write_byte(REGISTER_CONTROL, 0x00)?;
+        let tape = ula.recorder.take().tape_data();
+        assert_eq!(tape, vec![0x2a]);
+
+        ula.insert_tape(tape);
+        ula.write_byte(REGISTER_CONTROL, 0x40)?;
+        assert_ne!(ula.status() & InterruptBits::HighTone as Byte, 0);
+        ula.tick(LEAD_TONE_CYCLES + BYTE_CYCLES);
+        assert_ne!(ula.status() & InterruptBits::ReceiveFull as Byte, 0);
+        assert_eq!(ula.read_byte(REGISTER_CASSETTE_DATA)?, 0x2a);
+        assert_eq!(ula.status() & InterruptBits::ReceiveFull as Byte, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1101 @@
+use std::cell::Cell;
+
+use crate::cpu::{Address, Byte, Error, ErrorType, Memory, Result, Word};
+use crate::devices::disc_drive::DiscDrive;
+use crate::devices::Device;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// Intel 8271 floppy disc controller at &FE80-&FE9F, in non-DMA mode with its
+// interrupt output wired to NMI. Every data byte is handed over by an NMI, so
+// a byte that isn't collected in time ends the command with a late DMA error.
+
+#[repr(u8)]
+#[derive(Copy, Clone)]
+pub enum StatusBits {
+    DataRequest = 1 << 2,
+    Interrupt = 1 << 3,
+    ResultFull = 1 << 4,
+    ParameterFull = 1 << 5,
+    CommandFull = 1 << 6,
+    Busy = 1 << 7,
+}
+
+// Its eight registers repeat through the range
+pub const BASE: Address = 0xfe80;
+pub const END: Address = 0xfe9f;
+
+pub const SCAN_DATA: Byte = 0x00;
+pub const SCAN_DATA_AND_DELETED: Byte = 0x04;
+pub const WRITE_DATA_128: Byte = 0x0a;
+pub const WRITE_DATA: Byte = 0x0b;
+pub const WRITE_DELETED_DATA_128: Byte = 0x0e;
+pub const WRITE_DELETED_DATA: Byte = 0x0f;
+pub const READ_DATA_128: Byte = 0x12;
+pub const READ_DATA: Byte = 0x13;
+pub const READ_DATA_AND_DELETED_128: Byte = 0x16;
+pub const READ_DATA_AND_DELETED: Byte = 0x17;
+pub const READ_ID: Byte = 0x1b;
+pub const VERIFY_DATA_AND_DELETED_128: Byte = 0x1e;
+pub const VERIFY_DATA_AND_DELETED: Byte = 0x1f;
+pub const FORMAT: Byte = 0x23;
+pub const SEEK: Byte = 0x29;
+pub const READ_DRIVE_STATUS: Byte = 0x2c;
+pub const SPECIFY: Byte = 0x35;
+pub const WRITE_SPECIAL_REGISTER: Byte = 0x3a;
+pub const READ_SPECIAL_REGISTER: Byte = 0x3d;
+
+pub const RESULT_OK: Byte = 0x00;
+pub const RESULT_LATE_DMA: Byte = 0x0a;
+pub const RESULT_NOT_READY: Byte = 0x10;
+pub const RESULT_WRITE_PROTECT: Byte = 0x12;
+pub const RESULT_SECTOR_NOT_FOUND: Byte = 0x18;
+
+pub const SPECIAL_SCAN_SECTOR: Byte = 0x06;
+pub const SPECIAL_SURFACE0_BAD_TRACK1: Byte = 0x10;
+pub const SPECIAL_SURFACE0_BAD_TRACK2: Byte = 0x11;
+pub const SPECIAL_SURFACE0_TRACK: Byte = 0x12;
+pub const SPECIAL_MODE: Byte = 0x17;
+pub const SPECIAL_SURFACE1_BAD_TRACK1: Byte = 0x18;
+pub const SPECIAL_SURFACE1_BAD_TRACK2: Byte = 0x19;
+pub const SPECIAL_SURFACE1_TRACK: Byte = 0x1a;
+pub const SPECIAL_INPUT_PORT: Byte = 0x22;
+pub const SPECIAL_OUTPUT_PORT: Byte = 0x23;
+
+// The BBC uses the write fault reset output to select the disc side
+const OUTPUT_SIDE_SELECT: Byte = 0x20;
+
+// FM data arrives every 64us
+const BYTE_CYCLES: usize = 128;
+const HEAD_LOAD_CYCLES: usize = 200;
+const CYCLES_PER_2MS: usize = 4000;
+
+const REGISTER_STATUS: Address = 0x00;
+const REGISTER_RESULT: Address = 0x01;
+const REGISTER_RESET: Address = 0x02;
+const REGISTER_DATA: Address = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    Read,
+    Write,
+    Verify,
+    ReadId,
+    Format,
+    Seek,
+}
+
+struct Operation {
+    transfer: Transfer,
+    drive: usize,
+    side: usize,
+    track: usize,
+    sector: usize,
+    size: usize,
+    remaining: usize,
+    offset: usize,
+    requested: bool,
+    finishing: bool,
+}
+
+pub struct Fdc8271 {
+    pub drives: [DiscDrive; 2],
+
+    command: Byte,
+    parameters: Vec<Byte>,
+    busy: bool,
+    result: Byte,
+    result_full: Cell<bool>,
+    interrupt: Cell<bool>,
+    data: Byte,
+    data_request: Cell<bool>,
+    special: [Byte; 0x40],
+
+    operation: Option<Operation>,
+    countdown: usize,
+    step_cycles: usize,
+    settle_cycles: usize,
+}
+
+fn parameter_count(command: Byte) -> usize {
+    match command & 0x3f {
+        SCAN_DATA | SCAN_DATA_AND_DELETED | FORMAT => 5,
+        SPECIFY => 4,
+        WRITE_DATA
+        | WRITE_DELETED_DATA
+        | READ_DATA
+        | READ_DATA_AND_DELETED
+        | READ_ID
+        | VERIFY_DATA_AND_DELETED => 3,
+        WRITE_DATA_128
+        | WRITE_DELETED_DATA_128
+        | READ_DATA_128
+        | READ_DATA_AND_DELETED_128
+        | VERIFY_DATA_AND_DELETED_128
+        | WRITE_SPECIAL_REGISTER => 2,
+        SEEK | READ_SPECIAL_REGISTER => 1,
+        _ => 0,
+    }
+}
+
+impl Fdc8271 {
+    pub fn new() -> Self {
+        Fdc8271 {
+            drives: [DiscDrive::new(), DiscDrive::new()],
+            command: 0,
+            parameters: Vec::new(),
+            busy: false,
+            result: RESULT_OK,
+            result_full: Cell::new(false),
+            interrupt: Cell::new(false),
+            data: 0,
+            data_request: Cell::new(false),
+            special: [0; 0x40],
+            operation: None,
+            countdown: 0,
+            step_cycles: 6 * CYCLES_PER_2MS / 2,
+            settle_cycles: 10 * CYCLES_PER_2MS,
+        }
+    }
+
+    pub fn status(&self) -> Byte {
+        let mut status = 0;
+        if self.busy {
+            status |= StatusBits::Busy as u8;
+        }
+        if self.result_full.get() {
+            status |= StatusBits::ResultFull as u8;
+        }
+        if self.interrupt.get() {
+            status |= StatusBits::Interrupt as u8;
+        }
+        if self.data_request.get() {
+            status |= StatusBits::DataRequest as u8;
+        }
+        status
+    }
+
+    pub fn reset(&mut self) {
+        self.parameters.clear();
+        self.busy = false;
+        self.result_full.set(false);
+        self.interrupt.set(false);
+        self.data_request.set(false);
+        self.operation = None;
+        self.countdown = 0;
+    }
+
+    fn selected_drive(&self) -> Option<usize> {
+        match self.command & 0xc0 {
+            0x40 => Some(0),
+            0x80 => Some(1),
+            _ => None,
+        }
+    }
+
+    fn side(&self) -> usize {
+        if self.special[SPECIAL_OUTPUT_PORT as usize] & OUTPUT_SIDE_SELECT != 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    fn track_register(drive: usize) -> usize {
+        if drive == 0 {
+            SPECIAL_SURFACE0_TRACK as usize
+        } else {
+            SPECIAL_SURFACE1_TRACK as usize
+        }
+    }
+
+    fn write_command(&mut self, data: Byte) {
+        if self.busy {
+            return;
+        }
+
+        self.command = data;
+        self.parameters.clear();
+        self.busy = true;
+        self.result_full.set(false);
+
+        if parameter_count(data) == 0 {
+            self.execute();
+        }
+    }
+
+    fn write_parameter(&mut self, data: Byte) {
+        if !self.busy || self.operation.is_some() {
+            return;
+        }
+
+        self.parameters.push(data);
+        if self.parameters.len() == parameter_count(self.command) {
+            self.execute();
+        }
+    }
+
+    fn read_result(&self) -> Byte {
+        self.result_full.set(false);
+        self.interrupt.set(false);
+        self.result
+    }
+
+    fn read_data(&self) -> Byte {
+        self.data_request.set(false);
+        self.interrupt.set(false);
+        self.data
+    }
+
+    fn write_data(&mut self, data: Byte) {
+        self.data = data;
+        self.data_request.set(false);
+        self.interrupt.set(false);
+    }
+
+    // Commands that don't touch the disc finish straight away without
+    // interrupting
+    fn finish_immediately(&mut self, result: Option<Byte>) {
+        self.busy = false;
+        if let Some(result) = result {
+            self.result = result;
+            self.result_full.set(true);
+        }
+    }
+
+    fn complete(&mut self, result: Byte) {
+        self.operation = None;
+        self.countdown = 0;
+        self.busy = false;
+        self.result = result;
+        self.result_full.set(true);
+        self.data_request.set(false);
+        self.interrupt.set(true);
+    }
+
+    fn drive_status(&self) -> Byte {
+        let mut status = 0;
+        if self.drives[0].ready() {
+            status |= 0x04;
+        }
+        if self.drives[1].ready() {
+            status |= 0x40;
+        }
+        if let Some(drive) = self.selected_drive() {
+            if self.drives[drive].write_protected() {
+                status |= 0x08;
+            }
+            if self.drives[drive].at_track0() {
+                status |= 0x02;
+            }
+        }
+        status
+    }
+
+    fn specify(&mut self) {
+        let p = &self.parameters;
+        match p[0] {
+            0x0d => {
+                self.step_cycles = (p[1] as usize * CYCLES_PER_2MS).max(1);
+                self.settle_cycles = p[2] as usize * CYCLES_PER_2MS;
+            }
+            0x10 => {
+                self.special[SPECIAL_SURFACE0_BAD_TRACK1 as usize] = p[1];
+                self.special[SPECIAL_SURFACE0_BAD_TRACK2 as usize] = p[2];
+                self.special[SPECIAL_SURFACE0_TRACK as usize] = p[3];
+            }
+            0x18 => {
+                self.special[SPECIAL_SURFACE1_BAD_TRACK1 as usize] = p[1];
+                self.special[SPECIAL_SURFACE1_BAD_TRACK2 as usize] = p[2];
+                self.special[SPECIAL_SURFACE1_TRACK as usize] = p[3];
+            }
+            _ => {}
+        }
+    }
+
+    fn read_special_register(&self, register: Byte) -> Byte {
+        match register {
+            SPECIAL_INPUT_PORT => self.drive_status(),
+            _ => self.special[(register & 0x3f) as usize],
+        }
+    }
+
+    fn execute(&mut self) {
+        let command = self.command & 0x3f;
+
+        match command {
+            READ_DRIVE_STATUS => {
+                let status = self.drive_status();
+                self.finish_immediately(Some(status));
+                return;
+            }
+            SPECIFY => {
+                self.specify();
+                self.finish_immediately(None);
+                return;
+            }
+            WRITE_SPECIAL_REGISTER => {
+                let register = (self.parameters[0] & 0x3f) as usize;
+                self.special[register] = self.parameters[1];
+                self.finish_immediately(None);
+                return;
+            }
+            READ_SPECIAL_REGISTER => {
+                let value = self.read_special_register(self.parameters[0]);
+                self.finish_immediately(Some(value));
+                return;
+            }
+            _ => {}
+        }
+
+        let drive = match self.selected_drive() {
+            Some(drive) if self.drives[drive].ready() => drive,
+            _ => {
+                self.complete(RESULT_NOT_READY);
+                return;
+            }
+        };
+
+        let (transfer, size, count) = match command {
+            READ_DATA_128 | READ_DATA_AND_DELETED_128 => (Transfer::Read, 128, 1),
+            WRITE_DATA_128 | WRITE_DELETED_DATA_128 => (Transfer::Write, 128, 1),
+            VERIFY_DATA_AND_DELETED_128 => (Transfer::Verify, 128, 1),
+            READ_DATA | READ_DATA_AND_DELETED => {
+                let p = self.parameters[2];
+                (Transfer::Read, 128 << (p >> 5), p & 0x1f)
+            }
+            WRITE_DATA | WRITE_DELETED_DATA => {
+                let p = self.parameters[2];
+                (Transfer::Write, 128 << (p >> 5), p & 0x1f)
+            }
+            VERIFY_DATA_AND_DELETED => {
+                let p = self.parameters[2];
+                (Transfer::Verify, 128 << (p >> 5), p & 0x1f)
+            }
+            READ_ID => (Transfer::ReadId, 4, self.parameters[2]),
+            FORMAT => {
+                let p = self.parameters[2];
+                (Transfer::Format, 128 << (p >> 5), p & 0x1f)
+            }
+            SEEK => (Transfer::Seek, 0, 0),
+            _ => {
+                self.complete(RESULT_SECTOR_NOT_FOUND);
+                return;
+            }
+        };
+
+        if matches!(transfer, Transfer::Write | Transfer::Format)
+            && self.drives[drive].write_protected()
+        {
+            self.complete(RESULT_WRITE_PROTECT);
+            return;
+        }
+
+        let track = self.parameters[0] as usize;
+        let sector = if transfer == Transfer::Seek || transfer == Transfer::Format {
+            0
+        } else {
+            self.parameters[1] as usize
+        };
+
+        // Every disc command seeks to its track first
+        let register = Fdc8271::track_register(drive);
+        let current = self.special[register] as usize;
+        let steps = (track as isize - current as isize).unsigned_abs();
+        self.special[register] = track as Byte;
+        self.drives[drive].seek(track);
+
+        let countdown = if steps > 0 {
+            steps * self.step_cycles + self.settle_cycles
+        } else {
+            HEAD_LOAD_CYCLES
+        };
+
+        self.operation = Some(Operation {
+            transfer,
+            drive,
+            side: self.side(),
+            track,
+            sector,
+            size,
+            remaining: count as usize,
+            offset: 0,
+            requested: false,
+            finishing: count == 0,
+        });
+        self.countdown = countdown;
+    }
+
+    fn sector_exists(&self, op: &Operation) -> bool {
+        let image = match &self.drives[op.drive].image {
+            Some(image) => image,
+            None => return false,
+        };
+        // The 8271 only reads FM, so ADFS discs look unformatted
+        !image.format.double_density()
+            && op.size <= image.geometry().sector_size
+            && image.sector(op.side, op.track, op.sector).is_some()
+    }
+
+    // Whether a restored operation can carry on with the disc that's in the
+    // drive now: everything it will index has to be there
+    fn operation_fits(&self, op: &Operation) -> bool {
+        if op.transfer == Transfer::Seek {
+            return true;
+        }
+        if self.drives[op.drive].image.is_none() || !(op.finishing || op.remaining > 0) {
+            return false;
+        }
+        match op.transfer {
+            Transfer::Read | Transfer::Write => {
+                op.offset < op.size && (op.offset == 0 && !op.requested || self.sector_exists(op))
+            }
+            Transfer::ReadId | Transfer::Format => op.offset < 4,
+            _ => true,
+        }
+    }
+
+    fn next_sector(op: &mut Operation) {
+        op.offset = 0;
+        op.sector += 1;
+        op.remaining -= 1;
+        if op.remaining == 0 {
+            op.finishing = true;
+        }
+    }
+
+    fn step(&mut self) {
+        let mut op = match self.operation.take() {
+            Some(op) => op,
+            None => return,
+        };
+
+        let outcome = match op.transfer {
+            Transfer::Seek => Some(RESULT_OK),
+            Transfer::Read => self.step_read(&mut op),
+            Transfer::Write => self.step_write(&mut op),
+            Transfer::Verify => self.step_verify(&mut op),
+            Transfer::ReadId => self.step_read_id(&mut op),
+            Transfer::Format => self.step_format(&mut op),
+        };
+
+        match outcome {
+            Some(result) => self.complete(result),
+            None => self.operation = Some(op),
+        }
+    }
+
+    fn deliver(&mut self, data: Byte) {
+        self.data = data;
+        self.data_request.set(true);
+        self.interrupt.set(true);
+        self.countdown = BYTE_CYCLES;
+    }
+
+    fn request(&mut self) {
+        self.data_request.set(true);
+        self.interrupt.set(true);
+        self.countdown = BYTE_CYCLES;
+    }
+
+    fn step_read(&mut self, op: &mut Operation) -> Option<Byte> {
+        if self.data_request.get() {
+            return Some(RESULT_LATE_DMA);
+        }
+        if op.finishing {
+            return Some(RESULT_OK);
+        }
+        if op.offset == 0 && !self.sector_exists(op) {
+            return Some(RESULT_SECTOR_NOT_FOUND);
+        }
+
+        let image = self.drives[op.drive].image.as_ref().unwrap();
+        let data = image.sector(op.side, op.track, op.sector).unwrap()[op.offset];
+        self.deliver(data);
+
+        op.offset += 1;
+        if op.offset == op.size {
+            Fdc8271::next_sector(op);
+        }
+        None
+    }
+
+    fn step_write(&mut self, op: &mut Operation) -> Option<Byte> {
+        if op.requested {
+            if self.data_request.get() {
+                return Some(RESULT_LATE_DMA);
+            }
+
+            let image = self.drives[op.drive].image.as_mut().unwrap();
+            image.sector_mut(op.side, op.track, op.sector).unwrap()[op.offset] = self.data;
+            op.requested = false;
+
+            op.offset += 1;
+            if op.offset == op.size {
+                Fdc8271::next_sector(op);
+            }
+        }
+
+        if op.finishing {
+            return Some(RESULT_OK);
+        }
+        if op.offset == 0 && !self.sector_exists(op) {
+            return Some(RESULT_SECTOR_NOT_FOUND);
+        }
+
+        self.request();
+        op.requested = true;
+        None
+    }
+
+    fn step_verify(&mut self, op: &mut Operation) -> Option<Byte> {
+        if op.finishing {
+            return Some(RESULT_OK);
+        }
+        if !self.sector_exists(op) {
+            return Some(RESULT_SECTOR_NOT_FOUND);
+        }
+
+        Fdc8271::next_sector(op);
+        self.countdown = op.size * BYTE_CYCLES;
+        None
+    }
+
+    fn step_read_id(&mut self, op: &mut Operation) -> Option<Byte> {
+        if self.data_request.get() {
+            return Some(RESULT_LATE_DMA);
+        }
+        if op.finishing {
+            return Some(RESULT_OK);
+        }
+
+        let image = self.drives[op.drive].image.as_ref().unwrap();
+        let geometry = image.geometry();
+        if op.track >= geometry.tracks || op.side >= geometry.sides {
+            return Some(RESULT_SECTOR_NOT_FOUND);
+        }
+
+        let size_code = match geometry.sector_size {
+            128 => 0,
+            256 => 1,
+            512 => 2,
+            _ => 3,
+        };
+        let id = [
+            op.track as Byte,
+            op.side as Byte,
+            (op.sector % geometry.sectors_per_track) as Byte,
+            size_code,
+        ];
+        self.deliver(id[op.offset]);
+
+        op.offset += 1;
+        if op.offset == 4 {
+            Fdc8271::next_sector(op);
+        }
+        None
+    }
+
+    // Formatting takes the four ID bytes for each sector from the CPU, then
+    // clears the track
+    fn step_format(&mut self, op: &mut Operation) -> Option<Byte> {
+        if op.requested {
+            if self.data_request.get() {
+                return Some(RESULT_LATE_DMA);
+            }
+            op.requested = false;
+            op.offset += 1;
+            if op.offset == 4 {
+                Fdc8271::next_sector(op);
+            }
+        }
+
+        if op.finishing {
+            let image = self.drives[op.drive].image.as_mut().unwrap();
+            let sectors = image.geometry().sectors_per_track;
+            for sector in 0..sectors {
+                if let Some(data) = image.sector_mut(op.side, op.track, sector) {
+                    data.fill(0xe5);
+                }
+            }
+            return Some(RESULT_OK);
+        }
+
+        self.request();
+        op.requested = true;
+        None
+    }
+}
+
+impl Transfer {
+    fn from_byte(byte: Byte) -> Result<Self> {
+        match byte {
+            0 => Ok(Transfer::Read),
+            1 => Ok(Transfer::Write),
+            2 => Ok(Transfer::Verify),
+            3 => Ok(Transfer::ReadId),
+            4 => Ok(Transfer::Format),
+            5 => Ok(Transfer::Seek),
+            _ => Err(Error::without_pc(ErrorType::InvalidSnapshot)),
+        }
+    }
+}
+
+impl Operation {
+    fn save(&self, state: &mut StateWriter) {
+        state.byte(self.transfer as Byte);
+        state.usize(self.drive);
+        state.usize(self.side);
+        state.usize(self.track);
+        state.usize(self.sector);
+        state.usize(self.size);
+        state.usize(self.remaining);
+        state.usize(self.offset);
+        state.bool(self.requested);
+        state.bool(self.finishing);
+    }
+
+    fn restore(state: &mut StateReader) -> Result<Self> {
+        Ok(Operation {
+            transfer: Transfer::from_byte(state.byte()?)?,
+            drive: state.usize()? & 0x01,
+            side: state.usize()?,
+            track: state.usize()?,
+            sector: state.usize()?,
+            size: state.usize()?,
+            remaining: state.usize()?,
+            offset: state.usize()?,
+            requested: state.bool()?,
+            finishing: state.bool()?,
+        })
+    }
+}
+
+impl Snapshot for Fdc8271 {
+    fn save(&self, state: &mut StateWriter) {
+        for drive in &self.drives {
+            drive.save(state);
+        }
+        state.byte(self.command);
+        state.bytes(&self.parameters);
+        state.bool(self.busy);
+        state.byte(self.result);
+        state.bool(self.result_full.get());
+        state.bool(self.interrupt.get());
+        state.byte(self.data);
+        state.bool(self.data_request.get());
+        state.array(&self.special);
+        state.bool(self.operation.is_some());
+        if let Some(operation) = &self.operation {
+            operation.save(state);
+        }
+        state.usize(self.countdown);
+        state.usize(self.step_cycles);
+        state.usize(self.settle_cycles);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        for drive in &mut self.drives {
+            drive.restore(state)?;
+        }
+        self.command = state.byte()?;
+        self.parameters = state.bytes()?;
+        self.busy = state.bool()?;
+        self.result = state.byte()?;
+        self.result_full.set(state.bool()?);
+        self.interrupt.set(state.bool()?);
+        self.data = state.byte()?;
+        self.data_request.set(state.bool()?);
+        state.array(&mut self.special)?;
+        self.operation = if state.bool()? {
+            let operation = Operation::restore(state)?;
+            if !self.operation_fits(&operation) {
+                return Err(Error::without_pc(ErrorType::InvalidSnapshot));
+            }
+            Some(operation)
+        } else {
+            None
+        };
+        self.countdown = state.usize()?;
+        self.step_cycles = state.usize()?;
+        self.settle_cycles = state.usize()?;
+        Ok(())
+    }
+}
+
+impl Default for Fdc8271 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for Fdc8271 {
+    fn length(&self) -> usize {
+        0x20
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        match address & 0x07 {
+            REGISTER_STATUS => Ok(self.status()),
+            REGISTER_RESULT => Ok(self.read_result()),
+            REGISTER_DATA..=0x07 => Ok(self.read_data()),
+            _ => Ok(0x00),
+        }
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let lsb = self.read_byte(address)? as Word;
+        let msb = self.read_byte(address.wrapping_add(1))? as Word;
+        Ok(lsb + (msb << 8))
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        match address & 0x07 {
+            REGISTER_STATUS => self.write_command(data),
+            REGISTER_RESULT => self.write_parameter(data),
+            REGISTER_RESET if data & 0x01 == 0x01 => self.reset(),
+            REGISTER_DATA..=0x07 => self.write_data(data),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for Fdc8271 {
+    fn tick(&mut self, cycles: usize) {
+        let mut cycles = cycles;
+        while self.operation.is_some() {
+            if cycles < self.countdown {
+                self.countdown -= cycles;
+                return;
+            }
+            cycles -= self.countdown;
+            self.countdown = 0;
+            self.step();
+        }
+    }
+
+    fn nmi(&self) -> bool {
+        self.interrupt.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disc::image::{DiscImage, Format};
+
+    const DRIVE0: Byte = 0x40;
+
+    fn fdc_with_disc() -> Fdc8271 {
+        let mut image = DiscImage::new(Format::Ssd, 80);
+        for track in 0..80 {
+            for sector in 0..10 {
+                let data = image.sector_mut(0, track, sector).unwrap();
+                for (n, b) in data.iter_mut().enumerate() {
+                    *b = (track * 10 + sector + n) as Byte;
+                }
+            }
+        }
+
+        let mut fdc = Fdc8271::new();
+        fdc.drives[0].insert(image);
+        fdc
+    }
+
+    fn command(fdc: &mut Fdc8271, command: Byte, parameters: &[Byte]) -> Result<()> {
+        fdc.write_byte(REGISTER_STATUS, command)?;
+        for &p in parameters {
+            fdc.write_byte(REGISTER_RESULT, p)?;
+        }
+        Ok(())
+    }
+
+    // Runs the controller the way the DFS NMI handler does, servicing each
+    // data request until the command completes
+    fn run(fdc: &mut Fdc8271, write_data: &[Byte]) -> Result<(Byte, Vec<Byte>)> {
+        let mut read = Vec::new();
+        let mut written = write_data.iter();
+
+        for _ in 0..10_000_000 {
+            fdc.tick(16);
+            if !fdc.nmi() {
+                continue;
+            }
+
+            let status = fdc.read_byte(REGISTER_STATUS)?;
+            if status & StatusBits::DataRequest as u8 != 0 {
+                if let Some(&b) = written.next() {
+                    fdc.write_byte(REGISTER_DATA, b)?;
+                } else {
+                    read.push(fdc.read_byte(REGISTER_DATA)?);
+                }
+            } else if status & StatusBits::ResultFull as u8 != 0 {
+                return Ok((fdc.read_byte(REGISTER_RESULT)?, read));
+            }
+        }
+        panic!("command didn't complete");
+    }
+
+    #[test]
+    fn construct() {
+        let fdc = Fdc8271::new();
+        assert_eq!(fdc.status(), 0);
+        assert!(!fdc.nmi());
+    }
+
+    #[test]
+    fn registers_are_mirrored() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        command(&mut fdc, READ_DRIVE_STATUS | DRIVE0, &[])?;
+        assert_eq!(fdc.read_byte(0x19)? & 0x04, 0x04);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_drive_status() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        command(&mut fdc, READ_DRIVE_STATUS | DRIVE0, &[])?;
+
+        assert_eq!(fdc.status() & StatusBits::Busy as u8, 0);
+        assert!(!fdc.nmi());
+        let status = fdc.read_byte(REGISTER_RESULT)?;
+        assert_eq!(status, 0x04 | 0x02);
+
+        Ok(())
+    }
+
+    #[test]
+    fn special_registers() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        command(
+            &mut fdc,
+            WRITE_SPECIAL_REGISTER,
+            &[SPECIAL_OUTPUT_PORT, 0x20],
+        )?;
+        command(&mut fdc, READ_SPECIAL_REGISTER, &[SPECIAL_OUTPUT_PORT])?;
+
+        assert_eq!(fdc.read_byte(REGISTER_RESULT)?, 0x20);
+
+        Ok(())
+    }
+
+    #[test]
+    fn busy_while_waiting_for_parameters() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        command(&mut fdc, SEEK | DRIVE0, &[])?;
+        assert_eq!(
+            fdc.status() & StatusBits::Busy as u8,
+            StatusBits::Busy as u8
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn seek() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        command(&mut fdc, SEEK | DRIVE0, &[20])?;
+
+        let (result, _) = run(&mut fdc, &[])?;
+        assert_eq!(result, RESULT_OK);
+        assert_eq!(fdc.drives[0].head(), 20);
+        assert_eq!(fdc.special[SPECIAL_SURFACE0_TRACK as usize], 20);
+
+        Ok(())
+    }
+
+    #[test]
+    fn not_ready_without_disc() -> Result<()> {
+        let mut fdc = Fdc8271::new();
+        command(&mut fdc, SEEK | DRIVE0, &[0])?;
+
+        assert!(fdc.nmi());
+        assert_eq!(fdc.read_byte(REGISTER_RESULT)?, RESULT_NOT_READY);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_sectors() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        command(&mut fdc, READ_DATA | DRIVE0, &[2, 3, 0x22])?;
+
+        let (result, data) = run(&mut fdc, &[])?;
+        assert_eq!(result, RESULT_OK);
+        assert_eq!(data.len(), 512);
+        assert_eq!(data[0], 23);
+        assert_eq!(data[256], 24);
+        assert_eq!(data[511], (24 + 255) as Byte);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_missing_sector() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        command(&mut fdc, READ_DATA | DRIVE0, &[0, 9, 0x22])?;
+
+        let (result, data) = run(&mut fdc, &[])?;
+        assert_eq!(result, RESULT_SECTOR_NOT_FOUND);
+        assert_eq!(data.len(), 256);
+
+        Ok(())
+    }
+
+    #[test]
+    fn late_dma_when_nmi_not_serviced() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        command(&mut fdc, READ_DATA | DRIVE0, &[0, 0, 0x21])?;
+
+        fdc.tick(HEAD_LOAD_CYCLES + 2 * BYTE_CYCLES);
+        assert_eq!(
+            fdc.status() & StatusBits::ResultFull as u8,
+            StatusBits::ResultFull as u8
+        );
+        assert_eq!(fdc.read_byte(REGISTER_RESULT)?, RESULT_LATE_DMA);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_sectors() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        let data: Vec<Byte> = (0..512).map(|n| (n * 3) as Byte).collect();
+        command(&mut fdc, WRITE_DATA | DRIVE0, &[5, 8, 0x22])?;
+
+        let (result, _) = run(&mut fdc, &data)?;
+        assert_eq!(result, RESULT_OK);
+
+        let image = fdc.drives[0].image.as_ref().unwrap();
+        assert_eq!(image.sector(0, 5, 8).unwrap(), &data[..256]);
+        assert_eq!(image.sector(0, 5, 9).unwrap(), &data[256..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_protected() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        fdc.drives[0].image.as_mut().unwrap().write_protected = true;
+        command(&mut fdc, WRITE_DATA | DRIVE0, &[0, 0, 0x21])?;
+
+        assert_eq!(fdc.read_byte(REGISTER_RESULT)?, RESULT_WRITE_PROTECT);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        command(&mut fdc, VERIFY_DATA_AND_DELETED | DRIVE0, &[1, 0, 0x2a])?;
+        let (result, data) = run(&mut fdc, &[])?;
+        assert_eq!(result, RESULT_OK);
+        assert!(data.is_empty());
+
+        command(&mut fdc, VERIFY_DATA_AND_DELETED | DRIVE0, &[1, 5, 0x2a])?;
+        let (result, _) = run(&mut fdc, &[])?;
+        assert_eq!(result, RESULT_SECTOR_NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_id() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        command(&mut fdc, READ_ID | DRIVE0, &[7, 0, 2])?;
+
+        let (result, data) = run(&mut fdc, &[])?;
+        assert_eq!(result, RESULT_OK);
+        assert_eq!(data, vec![7, 0, 0, 1, 7, 0, 1, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn format_track() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        let ids: Vec<Byte> = (0..10).flat_map(|s| vec![3, 0, s, 1]).collect();
+        command(&mut fdc, FORMAT | DRIVE0, &[3, 0x15, 0x2a, 0x00, 0x10])?;
+
+        let (result, _) = run(&mut fdc, &ids)?;
+        assert_eq!(result, RESULT_OK);
+
+        let image = fdc.drives[0].image.as_ref().unwrap();
+        assert!(image.sector(0, 3, 0).unwrap().iter().all(|&b| b == 0xe5));
+        assert!(image.sector(0, 3, 9).unwrap().iter().all(|&b| b == 0xe5));
+        assert_eq!(image.sector(0, 4, 0).unwrap()[0], 40);
+
+        Ok(())
+    }
+
+    #[test]
+    fn side_select_reads_second_side() -> Result<()> {
+        let mut image = DiscImage::new(Format::Dsd, 80);
+        image.sector_mut(1, 0, 0).unwrap()[0] = 0xaa;
+
+        let mut fdc = Fdc8271::new();
+        fdc.drives[0].insert(image);
+        command(
+            &mut fdc,
+            WRITE_SPECIAL_REGISTER,
+            &[SPECIAL_OUTPUT_PORT, OUTPUT_SIDE_SELECT],
+        )?;
+        command(&mut fdc, READ_DATA | DRIVE0, &[0, 0, 0x21])?;
+
+        let (result, data) = run(&mut fdc, &[])?;
+        assert_eq!(result, RESULT_OK);
+        assert_eq!(data[0], 0xaa);
+
+        Ok(())
+    }
+
+    #[test]
+    fn restored_transfer_must_fit_the_disc() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        command(&mut fdc, READ_DATA | DRIVE0, &[2, 3, 0x21])?;
+        while !fdc.nmi() {
+            fdc.tick(16);
+        }
+        assert_eq!(fdc.read_byte(REGISTER_DATA)?, 23);
+        let mut state = StateWriter::new();
+        fdc.save(&mut state);
+        let bytes = state.into_bytes();
+
+        let mut same_disc = fdc_with_disc();
+        same_disc.restore(&mut StateReader::new(&bytes))?;
+        let (result, data) = run(&mut same_disc, &[])?;
+        assert_eq!(result, RESULT_OK);
+        assert_eq!((data.len(), data[0]), (255, 24));
+
+        let mut short_disc = Fdc8271::new();
+        short_disc.drives[0].insert(DiscImage::new(Format::Ssd, 1));
+        for mut fdc in [Fdc8271::new(), short_disc] {
+            assert_eq!(
+                fdc.restore(&mut StateReader::new(&bytes)),
+                Err(Error::without_pc(ErrorType::InvalidSnapshot))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn reset_abandons_command() -> Result<()> {
+        let mut fdc = fdc_with_disc();
+        command(&mut fdc, READ_DATA | DRIVE0, &[0, 0, 0x21])?;
+        fdc.write_byte(REGISTER_RESET, 0x01)?;
+        fdc.write_byte(REGISTER_RESET, 0x00)?;
+
+        assert_eq!(fdc.status(), 0);
+        fdc.tick(10_000);
+        assert!(!fdc.nmi());
+
+        Ok(())
+    }
+}
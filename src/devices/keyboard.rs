@@ -0,0 +1,163 @@
+use crate::cpu::{Byte, Result};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// The BBC keyboard matrix: 10 columns by 8 rows, addressed by the System VIA.
+// Row 0 of columns 2-9 holds the startup option links rather than keys.
+
+pub const COLUMNS: usize = 10;
+pub const ROWS: usize = 8;
+
+// As (row, column)
+pub const SHIFT: (usize, usize) = (0, 0);
+
+// The character on each key, by row and column, with NUL for the keys that
+// don't type one: the function keys, cursor keys, CAPS LOCK, SHIFT LOCK and
+// COPY
+const ROW_KEYS: [&str; ROWS] = [
+    "",
+    "Q345\08\0-^\0",
+    "\0WET7I90_\0",
+    "12DR6UOP[\0",
+    "\0AXFYJK@:\r",
+    "\0SCGHNL;]\x7f",
+    "\tZ VBM,./\0",
+    "\x1b\0\0\0\0\0\0\0\\\0",
+];
+
+// What SHIFT makes of the symbol and number keys, in the same order as the
+// keys themselves
+const SHIFTED: &str = "!\"#$%&'()=~|{}+*<>?";
+const SHIFTED_KEYS: &str = "123456789-^\\[];:,./";
+
+// The key that types a character, and whether it needs SHIFT too. Letters
+// are capitals with CAPS LOCK on, as it is after the MOS starts, so lower
+// case needs SHIFT. RETURN is '\r', DELETE '\x7f' and ESCAPE '\x1b'.
+pub fn key_for(c: char) -> Option<(usize, usize, bool)> {
+    let (c, shift) = match SHIFTED.chars().position(|shifted| shifted == c) {
+        Some(n) => (SHIFTED_KEYS.chars().nth(n)?, true),
+        None if c.is_ascii_lowercase() => (c.to_ascii_uppercase(), true),
+        None => (c, false),
+    };
+    if c == '\0' {
+        return None;
+    }
+    ROW_KEYS.iter().enumerate().find_map(|(row, keys)| {
+        keys.chars()
+            .position(|key| key == c)
+            .map(|column| (row, column, shift))
+    })
+}
+
+pub struct Keyboard {
+    // One bit per row for each column
+    matrix: [Byte; COLUMNS],
+
+    // Link n is read at row 0 of column 9 - n; a set bit reads as pressed
+    pub links: Byte,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Keyboard {
+            matrix: [0; COLUMNS],
+            links: 0x00,
+        }
+    }
+
+    pub fn press(&mut self, row: usize, column: usize) {
+        if row < ROWS && column < COLUMNS {
+            self.matrix[column] |= 1 << row;
+        }
+    }
+
+    pub fn release(&mut self, row: usize, column: usize) {
+        if row < ROWS && column < COLUMNS {
+            self.matrix[column] &= !(1 << row);
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        self.matrix = [0; COLUMNS];
+    }
+
+    pub fn is_pressed(&self, row: usize, column: usize) -> bool {
+        if row == 0 && (2..COLUMNS).contains(&column) {
+            return self.links & (1 << (9 - column)) != 0;
+        }
+        column < COLUMNS && row < ROWS && self.matrix[column] & (1 << row) != 0
+    }
+
+    // Keys in rows 1-7 of a column raise CA2; the columns beyond the matrix
+    // are never pressed
+    pub fn column_active(&self, column: usize) -> bool {
+        column < COLUMNS && self.matrix[column] & 0xfe != 0
+    }
+
+    pub fn any_active(&self) -> bool {
+        (0..COLUMNS).any(|c| self.column_active(c))
+    }
+}
+
+impl Snapshot for Keyboard {
+    fn save(&self, state: &mut StateWriter) {
+        state.array(&self.matrix);
+        state.byte(self.links);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        state.array(&mut self.matrix)?;
+        self.links = state.byte()?;
+        Ok(())
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn press_and_release() {
+        let mut keyboard = Keyboard::new();
+        keyboard.press(4, 1);
+        assert!(keyboard.is_pressed(4, 1));
+        assert!(keyboard.column_active(1));
+        assert!(!keyboard.column_active(2));
+
+        keyboard.release(4, 1);
+        assert!(!keyboard.any_active());
+    }
+
+    #[test]
+    fn shift_and_ctrl_dont_interrupt() {
+        let mut keyboard = Keyboard::new();
+        keyboard.press(0, 0);
+        keyboard.press(0, 1);
+        assert!(keyboard.is_pressed(0, 0));
+        assert!(!keyboard.any_active());
+    }
+
+    #[test]
+    fn typing() {
+        assert_eq!(key_for('R'), Some((3, 3, false)));
+        assert_eq!(key_for('r'), Some((3, 3, true)));
+        assert_eq!(key_for('\r'), Some((4, 9, false)));
+        assert_eq!(key_for('"'), Some((3, 1, true)));
+        assert_eq!(key_for('?'), Some((6, 8, true)));
+        assert_eq!(key_for('\u{a3}'), None);
+    }
+
+    #[test]
+    fn links() {
+        let mut keyboard = Keyboard::new();
+        keyboard.links = 0x05;
+        assert!(keyboard.is_pressed(0, 9));
+        assert!(!keyboard.is_pressed(0, 8));
+        assert!(keyboard.is_pressed(0, 7));
+    }
+}
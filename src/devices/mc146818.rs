@@ -0,0 +1,230 @@
+use crate::cpu::{Byte, Result};
+use crate::devices::CLOCK_HZ;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// Motorola MC146818 real time clock with 50 bytes of battery-backed RAM, as
+// fitted to the Master. It isn't memory mapped: the System VIA drives its
+// address and data lines, so it is accessed by register number.
+//
+// The clock starts from whatever time is written to it and advances with
+// the emulated clock rather than the host's, so runs are repeatable.
+
+pub const REGISTERS: usize = 64;
+pub const RAM_START: usize = 0x0e;
+
+const SECONDS: usize = 0x00;
+const MINUTES: usize = 0x02;
+const HOURS: usize = 0x04;
+const DAY_OF_WEEK: usize = 0x06;
+const DAY_OF_MONTH: usize = 0x07;
+const MONTH: usize = 0x08;
+const YEAR: usize = 0x09;
+const REGISTER_A: usize = 0x0a;
+const REGISTER_B: usize = 0x0b;
+const REGISTER_C: usize = 0x0c;
+const REGISTER_D: usize = 0x0d;
+
+const B_SET: Byte = 0x80;
+const B_BINARY: Byte = 0x04;
+const B_24_HOUR: Byte = 0x02;
+const D_VALID_RAM_AND_TIME: Byte = 0x80;
+
+pub struct Mc146818 {
+    registers: [Byte; REGISTERS],
+    cycles: usize,
+}
+
+impl Mc146818 {
+    pub fn new() -> Self {
+        let mut registers = [0; REGISTERS];
+        registers[REGISTER_B] = B_24_HOUR;
+        registers[REGISTER_D] = D_VALID_RAM_AND_TIME;
+        registers[DAY_OF_WEEK] = 1;
+        registers[DAY_OF_MONTH] = 1;
+        registers[MONTH] = 1;
+
+        Mc146818 {
+            registers,
+            cycles: 0,
+        }
+    }
+
+    pub fn read(&mut self, register: Byte) -> Byte {
+        let register = register as usize % REGISTERS;
+        let value = self.registers[register];
+        if register == REGISTER_C {
+            self.registers[REGISTER_C] = 0x00;
+        }
+        value
+    }
+
+    pub fn write(&mut self, register: Byte, data: Byte) {
+        match register as usize % REGISTERS {
+            REGISTER_C | REGISTER_D => {}
+            REGISTER_A => self.registers[REGISTER_A] = data & 0x7f,
+            register => self.registers[register] = data,
+        }
+    }
+
+    // The battery-backed RAM, which the Master MOS uses for its configuration
+    pub fn ram(&self) -> &[Byte] {
+        &self.registers[RAM_START..]
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [Byte] {
+        &mut self.registers[RAM_START..]
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles;
+        while self.cycles >= CLOCK_HZ {
+            self.cycles -= CLOCK_HZ;
+            // SET stops the clock while it is being written
+            if self.registers[REGISTER_B] & B_SET == 0 {
+                self.advance_second();
+            }
+        }
+    }
+
+    fn binary(&self) -> bool {
+        self.registers[REGISTER_B] & B_BINARY == B_BINARY
+    }
+
+    fn get(&self, register: usize) -> Byte {
+        let value = self.registers[register];
+        if self.binary() {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0x0f)
+        }
+    }
+
+    fn put(&mut self, register: usize, value: Byte) {
+        self.registers[register] = if self.binary() {
+            value
+        } else {
+            ((value / 10) << 4) | (value % 10)
+        };
+    }
+
+    fn days_in_month(&self) -> Byte {
+        let year = self.get(YEAR);
+        match self.get(MONTH) {
+            2 if year.is_multiple_of(4) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    // Each unit rolls over into the next, as far as it needs to go. A unit
+    // already out of range, as software can set it, rolls over too.
+    fn advance_second(&mut self) {
+        for (register, limit) in [(SECONDS, 60), (MINUTES, 60), (HOURS, 24)] {
+            let value = self.get(register);
+            if value < limit - 1 {
+                self.put(register, value + 1);
+                return;
+            }
+            self.put(register, 0);
+        }
+
+        let day_of_week = self.get(DAY_OF_WEEK) % 7 + 1;
+        self.put(DAY_OF_WEEK, day_of_week);
+
+        let day = self.get(DAY_OF_MONTH);
+        if day < self.days_in_month() {
+            self.put(DAY_OF_MONTH, day + 1);
+            return;
+        }
+        self.put(DAY_OF_MONTH, 1);
+
+        let month = self.get(MONTH);
+        if month < 12 {
+            self.put(MONTH, month + 1);
+            return;
+        }
+        self.put(MONTH, 1);
+
+        let year = (self.get(YEAR) % 100 + 1) % 100;
+        self.put(YEAR, year);
+    }
+}
+
+impl Snapshot for Mc146818 {
+    fn save(&self, state: &mut StateWriter) {
+        state.array(&self.registers);
+        state.usize(self.cycles);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        state.array(&mut self.registers)?;
+        self.cycles = state.usize()?;
+        Ok(())
+    }
+}
+
+impl Default for Mc146818 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_survives() {
+        let mut rtc = Mc146818::new();
+        rtc.write(0x20, 0x5a);
+        assert_eq!(rtc.read(0x20), 0x5a);
+        assert_eq!(rtc.ram()[0x20 - RAM_START], 0x5a);
+    }
+
+    #[test]
+    fn clock_rolls_over_in_bcd() {
+        let mut rtc = Mc146818::new();
+        rtc.write(YEAR as Byte, 0x99);
+        rtc.write(MONTH as Byte, 0x12);
+        rtc.write(DAY_OF_MONTH as Byte, 0x31);
+        rtc.write(HOURS as Byte, 0x23);
+        rtc.write(MINUTES as Byte, 0x59);
+        rtc.write(SECONDS as Byte, 0x59);
+
+        rtc.tick(CLOCK_HZ);
+        assert_eq!(rtc.read(SECONDS as Byte), 0x00);
+        assert_eq!(rtc.read(HOURS as Byte), 0x00);
+        assert_eq!(rtc.read(DAY_OF_MONTH as Byte), 0x01);
+        assert_eq!(rtc.read(MONTH as Byte), 0x01);
+        assert_eq!(rtc.read(YEAR as Byte), 0x00);
+    }
+
+    #[test]
+    fn out_of_range_values_roll_over() {
+        let mut rtc = Mc146818::new();
+        rtc.write(REGISTER_B as Byte, B_24_HOUR | B_BINARY);
+        for register in [SECONDS, MINUTES, HOURS, DAY_OF_MONTH, MONTH, YEAR] {
+            rtc.write(register as Byte, 0xff);
+        }
+
+        rtc.tick(CLOCK_HZ);
+        assert_eq!(rtc.read(SECONDS as Byte), 0);
+        assert_eq!(rtc.read(HOURS as Byte), 0);
+        assert_eq!(rtc.read(DAY_OF_MONTH as Byte), 1);
+        assert_eq!(rtc.read(MONTH as Byte), 1);
+        assert_eq!(rtc.read(YEAR as Byte), 56);
+    }
+
+    #[test]
+    fn set_stops_the_clock() {
+        let mut rtc = Mc146818::new();
+        rtc.write(REGISTER_B as Byte, B_SET | B_24_HOUR | B_BINARY);
+        rtc.tick(CLOCK_HZ * 2);
+        assert_eq!(rtc.read(SECONDS as Byte), 0);
+
+        rtc.write(REGISTER_B as Byte, B_24_HOUR | B_BINARY);
+        rtc.tick(CLOCK_HZ * 2);
+        assert_eq!(rtc.read(SECONDS as Byte), 2);
+    }
+}
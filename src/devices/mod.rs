@@ -0,0 +1,32 @@
+use crate::cpu::Memory;
+
+pub mod acia;
+pub mod crtc;
+pub mod disc_drive;
+pub mod electron_ula;
+pub mod fdc8271;
+pub mod keyboard;
+pub mod mc146818;
+pub mod serial;
+pub mod serial_ula;
+pub mod sn76489;
+pub mod system_via;
+pub mod upd7002;
+pub mod via;
+pub mod video_ula;
+pub mod wd1770;
+
+// All device timings are expressed in cycles of the 2MHz system clock
+pub const CLOCK_HZ: usize = 2_000_000;
+
+pub trait Device: Memory {
+    fn tick(&mut self, _cycles: usize) {}
+
+    fn irq(&self) -> bool {
+        false
+    }
+
+    fn nmi(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::VecDeque;
+
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::acia::Acia;
+use crate::devices::serial_ula::{SerialUla, CASSETTE_CLOCK_HZ};
+use crate::devices::{Device, CLOCK_HZ};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use crate::tape::recorder::TapeRecorder;
+
+// The ACIA at &FE08-&FE0F and serial ULA at &FE10-&FE17, along with the
+// cassette recorder on the end of them
+pub struct Serial {
+    pub acia: Acia,
+    pub ula: SerialUla,
+    pub recorder: TapeRecorder,
+
+    tape: VecDeque<Byte>,
+    tape_cycles: usize,
+
+    // Free running, for the phase of the tones going out to the cassette
+    tone_cycles: usize,
+}
+
+const ULA_OFFSET: Address = 0x08;
+
+// Tapes are read at 1200 baud, with a start and stop bit around each byte,
+// after a second of lead tone
+const TAPE_BYTE_CYCLES: usize = 10 * CLOCK_HZ / 1200;
+const LEAD_TONE_CYCLES: usize = CLOCK_HZ;
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            acia: Acia::new(CASSETTE_CLOCK_HZ),
+            ula: SerialUla::new(),
+            recorder: TapeRecorder::new(),
+            tape: VecDeque::new(),
+            tape_cycles: 0,
+            tone_cycles: 0,
+        }
+    }
+
+    pub fn insert_tape(&mut self, data: Vec<Byte>) {
+        self.tape = data.into();
+        self.tape_cycles = 0;
+    }
+
+    fn recording(&self) -> bool {
+        self.ula.motor_on() && !self.ula.rs423_selected()
+    }
+
+    // The tones going out to the cassette while the motor runs: a cycle of
+    // 1200Hz for a 0 bit, two of 2400Hz for a 1 and 2400Hz carrier between
+    // bytes
+    pub fn sound_level(&self) -> f32 {
+        if !self.recording() {
+            return 0.0;
+        }
+        let half_cycle = if self.acia.transmit_line() {
+            CLOCK_HZ / 4800
+        } else {
+            CLOCK_HZ / 2400
+        };
+        if (self.tone_cycles / half_cycle).is_multiple_of(2) {
+            0.5
+        } else {
+            -0.5
+        }
+    }
+
+    fn tick_tape(&mut self, cycles: usize) {
+        if !self.recording() || self.tape.is_empty() {
+            return;
+        }
+        self.tape_cycles += cycles;
+        if self.tape_cycles >= LEAD_TONE_CYCLES + TAPE_BYTE_CYCLES {
+            self.tape_cycles -= TAPE_BYTE_CYCLES;
+            if let Some(data) = self.tape.pop_front() {
+                self.acia.receive(data);
+            }
+        }
+    }
+}
+
+// The recorder holds what has been saved to tape rather than machine state,
+// but a tape being read is part of it
+impl Snapshot for Serial {
+    fn save(&self, state: &mut StateWriter) {
+        self.acia.save(state);
+        self.ula.save(state);
+        state.bytes(&self.tape.iter().copied().collect::<Vec<_>>());
+        state.usize(self.tape_cycles);
+        state.usize(self.tone_cycles);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.acia.restore(state)?;
+        self.ula.restore(state)?;
+        self.tape = state.bytes()?.into();
+        self.tape_cycles = state.usize()?;
+        self.tone_cycles = state.usize()?;
+        Ok(())
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for Serial {
+    fn length(&self) -> usize {
+        0x10
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        if address & 0x0f < ULA_OFFSET {
+            self.acia.read_byte(address & 0x01)
+        } else {
+            self.ula.read_byte(0)
+        }
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let lsb = self.read_byte(address)? as Word;
+        let msb = self.read_byte(address.wrapping_add(1))? as Word;
+        Ok(lsb + (msb << 8))
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        if address & 0x0f < ULA_OFFSET {
+            self.acia.write_byte(address & 0x01, data)
+        } else {
+            self.ula.write_byte(0, data)?;
+            self.acia.set_clock(self.ula.transmit_clock_hz());
+            self.recorder.motor(self.ula.motor_on());
+            Ok(())
+        }
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for Serial {
+    fn tick(&mut self, cycles: usize) {
+        self.acia.tick(cycles);
+
+        let transmitted = self.acia.take_transmitted();
+        if self.recording() {
+            let format = self.acia.word_format();
+            let baud = self.acia.baud();
+            for data in transmitted {
+                self.recorder.byte(data, format, baud);
+            }
+        }
+
+        if !self.acia.transmitting() && !self.ula.rs423_selected() {
+            self.recorder.idle(cycles);
+        }
+
+        self.tick_tape(cycles);
+        self.tone_cycles = self.tone_cycles.wrapping_add(cycles);
+    }
+
+    fn irq(&self) -> bool {
+        self.acia.irq()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tape::block::{file_blocks, parse_blocks};
+    use crate::tape::uef::{Uef, CARRIER_TONE, IMPLICIT_DATA};
+
+    const ACIA_CONTROL: Address = 0x00;
+    const ACIA_DATA: Address = 0x01;
+    const ULA_CONTROL: Address = 0x08;
+
+    // Writes bytes the way the MOS does, waiting for TDRE between each one
+    fn send(serial: &mut Serial, bytes: &[Byte]) -> Result<()> {
+        for &b in bytes {
+            while serial.read_byte(ACIA_CONTROL)? & 0x02 == 0 {
+                serial.tick(50);
+            }
+            serial.write_byte(ACIA_DATA, b)?;
+        }
+        while serial.acia.transmitting() {
+            serial.tick(50);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn construct() {
+        let _ = Serial::new();
+    }
+
+    #[test]
+    fn registers_are_mirrored() -> Result<()> {
+        let mut serial = Serial::new();
+
+        serial.write_byte(0x06, 0x15)?;
+        assert_eq!(serial.acia.control(), 0x15);
+
+        serial.write_byte(0x0f, 0x85)?;
+        assert_eq!(serial.ula.control(), 0x85);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rs423_output_is_not_recorded() -> Result<()> {
+        let mut serial = Serial::new();
+        serial.write_byte(ULA_CONTROL, 0xc0)?;
+        serial.write_byte(ACIA_CONTROL, 0x15)?;
+
+        send(&mut serial, &[0x41, 0x42])?;
+        assert!(serial.recorder.take().tape_data().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cassette_tones() -> Result<()> {
+        let mut serial = Serial::new();
+        serial.write_byte(ACIA_CONTROL, 0x15)?;
+        assert_eq!(serial.sound_level(), 0.0);
+
+        // Carrier: 2400Hz
+        serial.write_byte(ULA_CONTROL, 0x80)?;
+        assert_eq!(serial.sound_level(), 0.5);
+        serial.tick(CLOCK_HZ / 4800);
+        assert_eq!(serial.sound_level(), -0.5);
+        serial.tick(CLOCK_HZ / 4800);
+        assert_eq!(serial.sound_level(), 0.5);
+
+        // The start bit: 1200Hz
+        let mut serial = Serial::new();
+        serial.write_byte(ACIA_CONTROL, 0x15)?;
+        serial.write_byte(ULA_CONTROL, 0x80)?;
+        serial.write_byte(ACIA_DATA, 0x00)?;
+        serial.tick(CLOCK_HZ / 4800);
+        assert_eq!(serial.sound_level(), 0.5);
+        serial.tick(CLOCK_HZ / 2400);
+        assert_eq!(serial.sound_level(), -0.5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tape_is_read_with_the_motor_on() -> Result<()> {
+        let mut serial = Serial::new();
+        serial.write_byte(ACIA_CONTROL, 0x15)?;
+        serial.insert_tape(vec![0x2a, 0x41]);
+
+        serial.tick(LEAD_TONE_CYCLES + TAPE_BYTE_CYCLES);
+        assert_eq!(serial.read_byte(ACIA_CONTROL)? & 0x01, 0x00);
+
+        serial.write_byte(ULA_CONTROL, 0x80)?;
+        serial.tick(LEAD_TONE_CYCLES + TAPE_BYTE_CYCLES);
+        assert_eq!(serial.read_byte(ACIA_CONTROL)? & 0x01, 0x01);
+        assert_eq!(serial.read_byte(ACIA_DATA)?, 0x2a);
+        serial.tick(TAPE_BYTE_CYCLES);
+        assert_eq!(serial.read_byte(ACIA_DATA)?, 0x41);
+
+        Ok(())
+    }
+
+    #[test]
+    fn saved_file_round_trips_through_uef() -> Result<()> {
+        let mut serial = Serial::new();
+        serial.write_byte(ACIA_CONTROL, 0x03)?;
+        serial.write_byte(ACIA_CONTROL, 0x15)?;
+        serial.write_byte(ULA_CONTROL, 0x80)?;
+
+        let program: Vec<Byte> = (0..300).map(|n| (n * 7) as Byte).collect();
+        let blocks = file_blocks("PROG", 0x1900, 0x8023, &program);
+
+        // Leader, then each block separated by a short inter-block tone
+        serial.tick(CLOCK_HZ);
+        for block in &blocks {
+            send(&mut serial, &block.to_bytes())?;
+            serial.tick(CLOCK_HZ / 4);
+        }
+        serial.write_byte(ULA_CONTROL, 0x00)?;
+
+        let uef = Uef::from_bytes(&serial.recorder.take().to_bytes())?;
+        assert_eq!(uef.chunks[0].id, CARRIER_TONE);
+        assert!(uef.chunks[0].word().unwrap() >= 2400);
+        assert_eq!(uef.chunks[1].id, IMPLICIT_DATA);
+        assert_eq!(uef.chunks.last().unwrap().id, CARRIER_TONE);
+
+        let parsed = parse_blocks(&uef.tape_data())?;
+        assert_eq!(parsed, blocks);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::Device;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// Ferranti serial processor ULA: selects cassette or RS423, sets the ACIA
+// clocks and drives the cassette motor relay
+
+const BAUD_RATES: [usize; 8] = [19200, 1200, 4800, 150, 9600, 300, 2400, 75];
+
+// The cassette interface always clocks the ACIA at 16x 1200 baud
+pub const CASSETTE_CLOCK_HZ: usize = 19200;
+
+pub struct SerialUla {
+    control: Byte,
+}
+
+impl SerialUla {
+    pub fn new() -> Self {
+        SerialUla { control: 0x00 }
+    }
+
+    pub fn control(&self) -> Byte {
+        self.control
+    }
+
+    pub fn motor_on(&self) -> bool {
+        self.control & 0x80 == 0x80
+    }
+
+    pub fn rs423_selected(&self) -> bool {
+        self.control & 0x40 == 0x40
+    }
+
+    pub fn transmit_baud(&self) -> usize {
+        BAUD_RATES[(self.control & 0x07) as usize]
+    }
+
+    pub fn receive_baud(&self) -> usize {
+        BAUD_RATES[((self.control >> 3) & 0x07) as usize]
+    }
+
+    pub fn transmit_clock_hz(&self) -> usize {
+        if self.rs423_selected() {
+            self.transmit_baud() * 16
+        } else {
+            CASSETTE_CLOCK_HZ
+        }
+    }
+}
+
+impl Snapshot for SerialUla {
+    fn save(&self, state: &mut StateWriter) {
+        state.byte(self.control);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.control = state.byte()?;
+        Ok(())
+    }
+}
+
+impl Default for SerialUla {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for SerialUla {
+    fn length(&self) -> usize {
+        1
+    }
+
+    fn read_byte(&self, _address: Address) -> Result<Byte> {
+        Ok(0x00)
+    }
+
+    fn read_word(&self, _address: Address) -> Result<Word> {
+        Ok(0x0000)
+    }
+
+    fn write_byte(&mut self, _address: Address, data: Byte) -> Result<()> {
+        self.control = data;
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data >> 8) as Byte)
+    }
+}
+
+impl Device for SerialUla {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn construct() {
+        let _ = SerialUla::new();
+    }
+
+    #[test]
+    fn motor_and_mode() -> Result<()> {
+        let mut ula = SerialUla::new();
+        assert!(!ula.motor_on());
+        assert!(!ula.rs423_selected());
+
+        ula.write_byte(0, 0x80)?;
+        assert!(ula.motor_on());
+        assert!(!ula.rs423_selected());
+
+        ula.write_byte(0, 0x40)?;
+        assert!(!ula.motor_on());
+        assert!(ula.rs423_selected());
+
+        Ok(())
+    }
+
+    #[test]
+    fn baud_rates() -> Result<()> {
+        let mut ula = SerialUla::new();
+
+        ula.write_byte(0, 0x40 | (0x06 << 3) | 0x04)?;
+        assert_eq!(ula.transmit_baud(), 9600);
+        assert_eq!(ula.receive_baud(), 2400);
+        assert_eq!(ula.transmit_clock_hz(), 9600 * 16);
+
+        ula.write_byte(0, 0x04)?;
+        assert_eq!(ula.transmit_clock_hz(), CASSETTE_CLOCK_HZ);
+
+        Ok(())
+    }
+}
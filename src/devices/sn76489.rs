@@ -0,0 +1,241 @@
+use crate::cpu::{Byte, Result, Word};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// Texas Instruments SN76489 sound generator, written through the System VIA's
+// slow data bus: three square wave tone channels and a noise channel, each
+// with 15 steps of 2dB attenuation. It runs from the 4MHz clock, so its
+// counters count down at 250kHz.
+//
+// A byte with bit 7 set latches a register (bits 4-6) and sets its low four
+// bits; a byte with it clear sets the high six bits of the latched tone
+// period, or the whole of anything else.
+
+pub const CHANNELS: usize = 4;
+pub const NOISE: usize = 3;
+
+// 2MHz cycles per count
+const COUNT_CYCLES: usize = 8;
+
+const SILENT: Byte = 0x0f;
+
+// Noise control bits
+const NOISE_WHITE: Word = 0x04;
+const NOISE_RATE: Word = 0x03;
+
+// The noise shift register is 15 bits and starts with only the top one set
+const SHIFT_RESET: Word = 0x4000;
+
+pub struct Sn76489 {
+    // Tone periods for channels 0-2, and the noise control in 3
+    registers: [Word; CHANNELS],
+    attenuation: [Byte; CHANNELS],
+    latched: usize,
+
+    counters: [Word; CHANNELS],
+    outputs: [bool; CHANNELS],
+    shift: Word,
+    cycles: usize,
+}
+
+impl Sn76489 {
+    pub fn new() -> Self {
+        Sn76489 {
+            registers: [0; CHANNELS],
+            attenuation: [SILENT; CHANNELS],
+            latched: 0,
+            counters: [0; CHANNELS],
+            outputs: [false; CHANNELS],
+            shift: SHIFT_RESET,
+            cycles: 0,
+        }
+    }
+
+    pub fn period(&self, channel: usize) -> Word {
+        self.registers[channel]
+    }
+
+    pub fn attenuation(&self, channel: usize) -> Byte {
+        self.attenuation[channel]
+    }
+
+    pub fn write(&mut self, data: Byte) {
+        if data & 0x80 == 0x80 {
+            self.latched = ((data >> 4) & 0x07) as usize;
+        }
+        let channel = self.latched >> 1;
+
+        if self.latched & 0x01 == 0x01 {
+            self.attenuation[channel] = data & 0x0f;
+        } else if channel == NOISE {
+            self.registers[NOISE] = (data & 0x07) as Word;
+            self.shift = SHIFT_RESET;
+        } else if data & 0x80 == 0x80 {
+            self.registers[channel] = (self.registers[channel] & 0x3f0) | (data & 0x0f) as Word;
+        } else {
+            self.registers[channel] =
+                (self.registers[channel] & 0x00f) | ((data & 0x3f) as Word) << 4;
+        }
+    }
+
+    // A period of zero counts as 1024
+    fn reload(&self, channel: usize) -> Word {
+        let period = if channel == NOISE {
+            match self.registers[NOISE] & NOISE_RATE {
+                3 => self.registers[2],
+                rate => 0x10 << rate,
+            }
+        } else {
+            self.registers[channel]
+        };
+        if period == 0 {
+            0x400
+        } else {
+            period
+        }
+    }
+
+    fn count(&mut self) {
+        for channel in 0..CHANNELS {
+            // A period of 1 holds the output high, which is how samples are
+            // played through the volume
+            if channel != NOISE && self.registers[channel] == 1 {
+                self.outputs[channel] = true;
+                continue;
+            }
+
+            self.counters[channel] = self.counters[channel].saturating_sub(1);
+            if self.counters[channel] > 0 {
+                continue;
+            }
+            self.counters[channel] = self.reload(channel);
+            self.outputs[channel] = !self.outputs[channel];
+            if channel == NOISE && self.outputs[NOISE] {
+                let feedback = if self.registers[NOISE] & NOISE_WHITE == NOISE_WHITE {
+                    (self.shift ^ (self.shift >> 1)) & 0x01
+                } else {
+                    self.shift & 0x01
+                };
+                self.shift = (self.shift >> 1) | (feedback << 14);
+            }
+        }
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles;
+        while self.cycles >= COUNT_CYCLES {
+            self.cycles -= COUNT_CYCLES;
+            self.count();
+        }
+    }
+
+    // The four channels mixed, from -1.0 to 1.0
+    pub fn level(&self) -> f32 {
+        let mut level = 0.0;
+        for channel in 0..CHANNELS {
+            if self.attenuation[channel] == SILENT {
+                continue;
+            }
+            let volume = 10_f32.powf(-0.1 * self.attenuation[channel] as f32);
+            let high = if channel == NOISE {
+                self.shift & 0x01 == 0x01
+            } else {
+                self.outputs[channel]
+            };
+            level += if high { volume } else { -volume };
+        }
+        level / CHANNELS as f32
+    }
+}
+
+impl Default for Sn76489 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshot for Sn76489 {
+    fn save(&self, state: &mut StateWriter) {
+        for channel in 0..CHANNELS {
+            state.word(self.registers[channel]);
+            state.byte(self.attenuation[channel]);
+            state.word(self.counters[channel]);
+            state.bool(self.outputs[channel]);
+        }
+        state.usize(self.latched);
+        state.word(self.shift);
+        state.usize(self.cycles);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        for channel in 0..CHANNELS {
+            self.registers[channel] = state.word()?;
+            self.attenuation[channel] = state.byte()?;
+            self.counters[channel] = state.word()?;
+            self.outputs[channel] = state.bool()?;
+        }
+        self.latched = state.usize()? & 0x07;
+        self.shift = state.word()?;
+        self.cycles = state.usize()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latch_and_data_bytes() {
+        let mut sound = Sn76489::new();
+        // Channel 1 tone: low bits 5, then high bits &12
+        sound.write(0xa5);
+        sound.write(0x12);
+        assert_eq!(sound.period(1), 0x125);
+        // Channel 1 volume, loudest
+        sound.write(0xb0);
+        assert_eq!(sound.attenuation(1), 0);
+        // A data byte goes to the volume now it's latched
+        sound.write(0x07);
+        assert_eq!(sound.attenuation(1), 7);
+        assert_eq!(sound.period(1), 0x125);
+    }
+
+    #[test]
+    fn tone_frequency() {
+        let mut sound = Sn76489::new();
+        // 250kHz / (2 * 125) = 1kHz on channel 0
+        sound.write(0x8d);
+        sound.write(0x07);
+        sound.write(0x90);
+
+        let mut edges = 0;
+        let mut high = sound.level() > 0.0;
+        for _ in 0..2000 {
+            sound.tick(1000);
+            if (sound.level() > 0.0) != high {
+                high = !high;
+                edges += 1;
+            }
+        }
+        // A second at 2MHz, sampled every half millisecond
+        assert_eq!(edges, 2000);
+        assert!((sound.level().abs() - 0.25).abs() < 0.001);
+    }
+
+    #[test]
+    fn silence_and_noise() {
+        let mut sound = Sn76489::new();
+        sound.tick(10_000);
+        assert_eq!(sound.level(), 0.0);
+
+        // White noise at the fastest rate, 6dB down
+        sound.write(0xe4);
+        sound.write(0xf3);
+        let mut levels = Vec::new();
+        for _ in 0..64 {
+            sound.tick(256);
+            levels.push(sound.level() > 0.0);
+        }
+        assert!(levels.contains(&true) && levels.contains(&false));
+    }
+}
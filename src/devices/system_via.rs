@@ -0,0 +1,313 @@
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::keyboard::Keyboard;
+use crate::devices::mc146818::Mc146818;
+use crate::devices::sn76489::Sn76489;
+use crate::devices::via::{
+    Via, REGISTER_DDRA, REGISTER_DDRB, REGISTER_ORA, REGISTER_ORA_NO_HANDSHAKE, REGISTER_ORB,
+};
+use crate::devices::Device;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// The System VIA at &FE40, with the addressable latch (IC32) on port B and
+// the keyboard and sound chip on port A. CA1 is vertical sync, CA2 the
+// keyboard interrupt.
+// On the Master, port A is also the RTC's data bus: PB6 enables it, PB7 is
+// its address strobe and latch bits 1 and 2 (speech on the B) are its
+// read/write and data strobe lines.
+
+// Latch bits; sound and keyboard enables are active low
+pub const LATCH_SOUND_WRITE: Byte = 0x01;
+pub const LATCH_SPEECH_READ: Byte = 0x02;
+pub const LATCH_SPEECH_WRITE: Byte = 0x04;
+pub const LATCH_KEYBOARD_ENABLE: Byte = 0x08;
+pub const LATCH_SCREEN_SIZE: Byte = 0x30;
+pub const LATCH_CAPS_LOCK: Byte = 0x40;
+pub const LATCH_SHIFT_LOCK: Byte = 0x80;
+
+pub const LATCH_RTC_READ: Byte = 0x02;
+pub const LATCH_RTC_DATA_STROBE: Byte = 0x04;
+
+const PORT_B_RTC_ENABLE: Byte = 0x40;
+const PORT_B_RTC_ADDRESS_STROBE: Byte = 0x80;
+
+pub struct SystemVia {
+    pub via: Via,
+    pub keyboard: Keyboard,
+    pub sound: Sn76489,
+    pub rtc: Option<Mc146818>,
+    latch: Byte,
+
+    rtc_address: Byte,
+    address_strobe: bool,
+    data_strobe: bool,
+}
+
+impl SystemVia {
+    pub fn new() -> Self {
+        let mut system_via = SystemVia {
+            via: Via::new(),
+            keyboard: Keyboard::new(),
+            sound: Sn76489::new(),
+            rtc: None,
+            latch: 0x00,
+            rtc_address: 0x00,
+            address_strobe: false,
+            data_strobe: false,
+        };
+        system_via.update();
+        system_via
+    }
+
+    pub fn reset(&mut self) {
+        self.via.reset();
+        self.sound = Sn76489::new();
+        self.latch = 0x00;
+        self.update();
+    }
+
+    pub fn latch(&self) -> Byte {
+        self.latch
+    }
+
+    // Writing port B sets latch bit PB0-2 to PB3. The sound chip takes the
+    // byte on port A as its write enable goes low.
+    fn update_latch(&mut self) {
+        let port_b = self.via.port_b();
+        let bit = 1 << (port_b & 0x07);
+        let sound_write = self.latch & LATCH_SOUND_WRITE == LATCH_SOUND_WRITE;
+        if port_b & 0x08 == 0x08 {
+            self.latch |= bit;
+        } else {
+            self.latch &= !bit;
+        }
+        if sound_write && self.latch & LATCH_SOUND_WRITE == 0 {
+            self.sound.write(self.via.port_a());
+        }
+    }
+
+    // With the keyboard enabled the hardware scans the columns itself and
+    // interrupts on any key; otherwise the MOS picks a key with PA0-6 and
+    // reads it back on PA7
+    pub fn update(&mut self) {
+        let port_a = self.via.port_a();
+        let column = (port_a & 0x0f) as usize;
+        let row = ((port_a >> 4) & 0x07) as usize;
+
+        let scanning = self.latch & LATCH_KEYBOARD_ENABLE == LATCH_KEYBOARD_ENABLE;
+        let pressed = !scanning && self.keyboard.is_pressed(row, column);
+        self.via.port_a_input = if pressed { 0xff } else { 0x7f };
+
+        let active = if scanning {
+            self.keyboard.any_active()
+        } else {
+            self.keyboard.column_active(column)
+        };
+        self.via.set_ca2(active);
+    }
+
+    // The address is latched as AS falls; data is driven onto port A while
+    // DS is high for a read, and written as DS falls
+    fn update_rtc(&mut self) {
+        let Some(rtc) = self.rtc.as_mut() else {
+            return;
+        };
+
+        let port_b = self.via.port_b();
+        let address_strobe = port_b & PORT_B_RTC_ADDRESS_STROBE == PORT_B_RTC_ADDRESS_STROBE;
+        let data_strobe = self.latch & LATCH_RTC_DATA_STROBE == LATCH_RTC_DATA_STROBE;
+        let read = self.latch & LATCH_RTC_READ == LATCH_RTC_READ;
+
+        if port_b & PORT_B_RTC_ENABLE == PORT_B_RTC_ENABLE {
+            if self.address_strobe && !address_strobe {
+                self.rtc_address = self.via.port_a();
+            }
+            if data_strobe && read {
+                self.via.port_a_input = rtc.read(self.rtc_address);
+            } else if self.data_strobe && !data_strobe && !read {
+                rtc.write(self.rtc_address, self.via.port_a());
+            }
+        }
+
+        self.address_strobe = address_strobe;
+        self.data_strobe = data_strobe;
+    }
+
+    pub fn press_key(&mut self, row: usize, column: usize) {
+        self.keyboard.press(row, column);
+        self.update();
+    }
+
+    pub fn release_key(&mut self, row: usize, column: usize) {
+        self.keyboard.release(row, column);
+        self.update();
+    }
+}
+
+// The RTC is only there on the Master, so is only in its snapshots
+impl Snapshot for SystemVia {
+    fn save(&self, state: &mut StateWriter) {
+        self.via.save(state);
+        self.keyboard.save(state);
+        self.sound.save(state);
+        if let Some(rtc) = &self.rtc {
+            rtc.save(state);
+        }
+        state.byte(self.latch);
+        state.byte(self.rtc_address);
+        state.bool(self.address_strobe);
+        state.bool(self.data_strobe);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.via.restore(state)?;
+        self.keyboard.restore(state)?;
+        self.sound.restore(state)?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.restore(state)?;
+        }
+        self.latch = state.byte()?;
+        self.rtc_address = state.byte()?;
+        self.address_strobe = state.bool()?;
+        self.data_strobe = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Default for SystemVia {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for SystemVia {
+    fn length(&self) -> usize {
+        0x20
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        self.via.read_byte(address)
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        self.via.read_word(address)
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        self.via.write_byte(address, data)?;
+
+        match address & 0x0f {
+            REGISTER_ORB | REGISTER_DDRB => self.update_latch(),
+            REGISTER_ORA | REGISTER_DDRA | REGISTER_ORA_NO_HANDSHAKE => {}
+            _ => return Ok(()),
+        }
+        self.update();
+        self.update_rtc();
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for SystemVia {
+    fn tick(&mut self, cycles: usize) {
+        self.via.tick(cycles);
+        self.sound.tick(cycles);
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.tick(cycles);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.via.irq()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::via::{InterruptBits, REGISTER_IER};
+
+    // Sets latch bit n to value, as the MOS does with STA &FE40
+    fn set_latch(via: &mut SystemVia, bit: Byte, value: bool) -> Result<()> {
+        via.write_byte(REGISTER_ORB, bit | if value { 0x08 } else { 0x00 })
+    }
+
+    fn system_via() -> Result<SystemVia> {
+        let mut via = SystemVia::new();
+        via.write_byte(REGISTER_DDRB, 0x0f)?;
+        via.write_byte(REGISTER_DDRA, 0x7f)?;
+        Ok(via)
+    }
+
+    #[test]
+    fn addressable_latch() -> Result<()> {
+        let mut via = system_via()?;
+        set_latch(&mut via, 3, true)?;
+        set_latch(&mut via, 6, true)?;
+        assert_eq!(via.latch(), LATCH_KEYBOARD_ENABLE | LATCH_CAPS_LOCK);
+
+        set_latch(&mut via, 3, false)?;
+        assert_eq!(via.latch(), LATCH_CAPS_LOCK);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reading_a_key() -> Result<()> {
+        let mut via = system_via()?;
+        set_latch(&mut via, 3, false)?;
+        via.press_key(4, 1);
+
+        // Row 4, column 1
+        via.write_byte(REGISTER_ORA, 0x41)?;
+        assert_eq!(via.read_byte(REGISTER_ORA)? & 0x80, 0x80);
+
+        via.write_byte(REGISTER_ORA, 0x42)?;
+        assert_eq!(via.read_byte(REGISTER_ORA)? & 0x80, 0x00);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keypress_interrupts_while_scanning() -> Result<()> {
+        let mut via = system_via()?;
+        via.write_byte(REGISTER_IER, 0x80 | InterruptBits::Ca2 as u8)?;
+        via.write_byte(0x0c, 0x04)?;
+        set_latch(&mut via, 3, true)?;
+
+        via.press_key(3, 5);
+        assert!(via.irq());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rtc_on_port_a() -> Result<()> {
+        let mut via = system_via()?;
+        via.rtc = Some(Mc146818::new());
+        via.write_byte(REGISTER_DDRB, 0xcf)?;
+
+        // Latch address &20 with AS, then write &5A with DS, keeping CE (PB6)
+        // high throughout
+        via.write_byte(REGISTER_DDRA, 0xff)?;
+        via.write_byte(REGISTER_ORB, 0xc0)?;
+        via.write_byte(REGISTER_ORA, 0x20)?;
+        via.write_byte(REGISTER_ORB, 0x40)?;
+        via.write_byte(REGISTER_ORA, 0x5a)?;
+        via.write_byte(REGISTER_ORB, 0x41)?;
+        via.write_byte(REGISTER_ORB, 0x4a)?;
+        via.write_byte(REGISTER_ORB, 0x42)?;
+        assert_eq!(via.rtc.as_ref().unwrap().ram()[0x20 - 0x0e], 0x5a);
+
+        // And read it back
+        via.write_byte(REGISTER_DDRA, 0x00)?;
+        via.write_byte(REGISTER_ORB, 0x49)?;
+        via.write_byte(REGISTER_ORB, 0x4a)?;
+        assert_eq!(via.read_byte(REGISTER_ORA)?, 0x5a);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::{Device, CLOCK_HZ};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// NEC uPD7002 analogue to digital converter, at &FEC0 on the B and B+ and
+// &FE18 on the Master. Its four channels are the joystick port. End of
+// conversion goes to the System VIA's CB1, active low.
+
+pub const CHANNELS: usize = 4;
+
+// Halfway, as a joystick at rest reads
+pub const CENTRE: Word = 0x8000;
+
+const REGISTER_STATUS: Address = 0x00;
+const REGISTER_HIGH: Address = 0x01;
+const REGISTER_LOW: Address = 0x02;
+
+const STATUS_CHANNEL: Byte = 0x03;
+const STATUS_12_BIT: Byte = 0x08;
+const STATUS_NOT_BUSY: Byte = 0x40;
+const STATUS_NOT_COMPLETE: Byte = 0x80;
+
+const CONVERSION_8_BIT_CYCLES: usize = CLOCK_HZ / 250;
+const CONVERSION_12_BIT_CYCLES: usize = CLOCK_HZ / 100;
+
+pub struct Upd7002 {
+    inputs: [Word; CHANNELS],
+    status: Byte,
+    result: Word,
+    cycles: usize,
+}
+
+impl Upd7002 {
+    pub fn new() -> Self {
+        Upd7002 {
+            inputs: [CENTRE; CHANNELS],
+            status: STATUS_NOT_BUSY,
+            result: 0,
+            cycles: 0,
+        }
+    }
+
+    pub fn input(&self, channel: usize) -> Word {
+        self.inputs[channel % CHANNELS]
+    }
+
+    // Takes effect at the next conversion
+    pub fn set_input(&mut self, channel: usize, value: Word) {
+        self.inputs[channel % CHANNELS] = value;
+    }
+
+    pub fn end_of_conversion(&self) -> bool {
+        self.status & STATUS_NOT_COMPLETE == 0
+    }
+
+    // Writing the status register starts a conversion on the channel in bits
+    // 0-1, to 12 bits if bit 3 is set or 8 otherwise
+    fn start(&mut self, data: Byte) {
+        self.status = (data & 0x0f) | STATUS_NOT_COMPLETE;
+        self.cycles = if data & STATUS_12_BIT == STATUS_12_BIT {
+            CONVERSION_12_BIT_CYCLES
+        } else {
+            CONVERSION_8_BIT_CYCLES
+        };
+    }
+
+    // The top two bits of the result are repeated in bits 4 and 5 of the status
+    fn complete(&mut self) {
+        let mask = if self.status & STATUS_12_BIT == STATUS_12_BIT {
+            0xfff0
+        } else {
+            0xff00
+        };
+        self.result = self.input((self.status & STATUS_CHANNEL) as usize) & mask;
+        self.status = (self.status & 0x0f) | STATUS_NOT_BUSY | ((self.result >> 10) as Byte & 0x30);
+    }
+}
+
+impl Snapshot for Upd7002 {
+    fn save(&self, state: &mut StateWriter) {
+        for input in self.inputs {
+            state.word(input);
+        }
+        state.byte(self.status);
+        state.word(self.result);
+        state.usize(self.cycles);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        for input in &mut self.inputs {
+            *input = state.word()?;
+        }
+        self.status = state.byte()?;
+        self.result = state.word()?;
+        self.cycles = state.usize()?;
+        Ok(())
+    }
+}
+
+impl Default for Upd7002 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for Upd7002 {
+    fn length(&self) -> usize {
+        4
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        Ok(match address & 0x03 {
+            REGISTER_STATUS => self.status,
+            REGISTER_HIGH => (self.result >> 8) as Byte,
+            REGISTER_LOW => (self.result & 0xff) as Byte,
+            _ => 0x00,
+        })
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let lsb = self.read_byte(address)? as Word;
+        let msb = self.read_byte(address.wrapping_add(1))? as Word;
+        Ok(lsb + (msb << 8))
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        if address & 0x03 == REGISTER_STATUS {
+            self.start(data);
+        }
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for Upd7002 {
+    fn tick(&mut self, cycles: usize) {
+        if self.end_of_conversion() {
+            return;
+        }
+        self.cycles = self.cycles.saturating_sub(cycles);
+        if self.cycles == 0 {
+            self.complete();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_to_8_bits() -> Result<()> {
+        let mut adc = Upd7002::new();
+        adc.set_input(2, 0xc3a5);
+        adc.write_byte(REGISTER_STATUS, 0x02)?;
+        assert!(!adc.end_of_conversion());
+        assert_eq!(adc.read_byte(REGISTER_STATUS)?, 0x82);
+
+        adc.tick(CONVERSION_8_BIT_CYCLES - 1);
+        assert!(!adc.end_of_conversion());
+        adc.tick(1);
+        assert!(adc.end_of_conversion());
+        assert_eq!(adc.read_byte(REGISTER_STATUS)?, 0x72);
+        assert_eq!(adc.read_byte(REGISTER_HIGH)?, 0xc3);
+        assert_eq!(adc.read_byte(REGISTER_LOW)?, 0x00);
+
+        Ok(())
+    }
+
+    #[test]
+    fn converts_to_12_bits() -> Result<()> {
+        let mut adc = Upd7002::new();
+        adc.set_input(1, 0x1234);
+        adc.write_byte(REGISTER_STATUS, 0x09)?;
+        adc.tick(CONVERSION_8_BIT_CYCLES);
+        assert!(!adc.end_of_conversion());
+        adc.tick(CONVERSION_12_BIT_CYCLES);
+        assert_eq!(adc.read_byte(REGISTER_STATUS)?, 0x49);
+        assert_eq!(adc.read_word(REGISTER_HIGH)?, 0x3012);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,594 @@
+use std::cell::Cell;
+
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::Device;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// MOS 6522 Versatile Interface Adapter. The BBC clocks its VIAs at 1MHz.
+
+#[repr(u8)]
+#[derive(Copy, Clone)]
+pub enum InterruptBits {
+    Ca2 = 1 << 0,
+    Ca1 = 1 << 1,
+    ShiftRegister = 1 << 2,
+    Cb2 = 1 << 3,
+    Cb1 = 1 << 4,
+    Timer2 = 1 << 5,
+    Timer1 = 1 << 6,
+    Any = 1 << 7,
+}
+
+pub const REGISTER_ORB: Address = 0x00;
+pub const REGISTER_ORA: Address = 0x01;
+pub const REGISTER_DDRB: Address = 0x02;
+pub const REGISTER_DDRA: Address = 0x03;
+pub const REGISTER_T1CL: Address = 0x04;
+pub const REGISTER_T1CH: Address = 0x05;
+pub const REGISTER_T1LL: Address = 0x06;
+pub const REGISTER_T1LH: Address = 0x07;
+pub const REGISTER_T2CL: Address = 0x08;
+pub const REGISTER_T2CH: Address = 0x09;
+pub const REGISTER_SR: Address = 0x0a;
+pub const REGISTER_ACR: Address = 0x0b;
+pub const REGISTER_PCR: Address = 0x0c;
+pub const REGISTER_IFR: Address = 0x0d;
+pub const REGISTER_IER: Address = 0x0e;
+pub const REGISTER_ORA_NO_HANDSHAKE: Address = 0x0f;
+
+const ACR_T1_FREE_RUN: Byte = 0x40;
+const ACR_T2_COUNT_PB6: Byte = 0x20;
+
+pub struct Via {
+    orb: Byte,
+    ora: Byte,
+    ddrb: Byte,
+    ddra: Byte,
+
+    // Levels driven onto the port pins from outside
+    pub port_a_input: Byte,
+    pub port_b_input: Byte,
+
+    t1_counter: i32,
+    t1_latch: Word,
+    t1_armed: bool,
+    t2_counter: i32,
+    t2_latch_low: Byte,
+    t2_armed: bool,
+
+    sr: Byte,
+    acr: Byte,
+    pcr: Byte,
+    ifr: Cell<Byte>,
+    ier: Byte,
+
+    ca1: bool,
+    ca2: bool,
+    cb1: bool,
+    cb2: bool,
+
+    // Left over 2MHz cycle, as the timers count at 1MHz
+    odd_cycle: bool,
+}
+
+impl Via {
+    pub fn new() -> Self {
+        Via {
+            orb: 0,
+            ora: 0,
+            ddrb: 0,
+            ddra: 0,
+            port_a_input: 0xff,
+            port_b_input: 0xff,
+            t1_counter: 0xffff,
+            t1_latch: 0xffff,
+            t1_armed: false,
+            t2_counter: 0xffff,
+            t2_latch_low: 0xff,
+            t2_armed: false,
+            sr: 0,
+            acr: 0,
+            pcr: 0,
+            ifr: Cell::new(0),
+            ier: 0,
+            ca1: false,
+            ca2: false,
+            cb1: false,
+            cb2: false,
+            odd_cycle: false,
+        }
+    }
+
+    // The RESET line clears the registers but not the timers or shift register
+    pub fn reset(&mut self) {
+        self.orb = 0;
+        self.ora = 0;
+        self.ddrb = 0;
+        self.ddra = 0;
+        self.acr = 0;
+        self.pcr = 0;
+        self.ifr.set(0);
+        self.ier = 0;
+        self.t1_armed = false;
+        self.t2_armed = false;
+    }
+
+    pub fn port_a(&self) -> Byte {
+        (self.ora & self.ddra) | (self.port_a_input & !self.ddra)
+    }
+
+    pub fn port_b(&self) -> Byte {
+        (self.orb & self.ddrb) | (self.port_b_input & !self.ddrb)
+    }
+
+    pub fn ifr(&self) -> Byte {
+        let flags = self.ifr.get() & 0x7f;
+        if flags & self.ier != 0 {
+            flags | InterruptBits::Any as u8
+        } else {
+            flags
+        }
+    }
+
+    pub fn ier(&self) -> Byte {
+        self.ier
+    }
+
+    pub fn pcr(&self) -> Byte {
+        self.pcr
+    }
+
+    // The timers and interrupt flags can't be put into an arbitrary state
+    // through the registers, so these are for loading other emulators' state
+    pub fn load_timer1(&mut self, counter: Word, latch: Word, armed: bool) {
+        self.t1_counter = counter as i32;
+        self.t1_latch = latch;
+        self.t1_armed = armed;
+    }
+
+    pub fn load_timer2(&mut self, counter: Word, latch_low: Byte, armed: bool) {
+        self.t2_counter = counter as i32;
+        self.t2_latch_low = latch_low;
+        self.t2_armed = armed;
+    }
+
+    pub fn load_interrupt_flags(&mut self, flags: Byte) {
+        self.ifr.set(flags & 0x7f);
+    }
+
+    fn set_flag(&self, bit: InterruptBits) {
+        self.ifr.set(self.ifr.get() | bit as u8);
+    }
+
+    fn clear_flags(&self, bits: Byte) {
+        self.ifr.set(self.ifr.get() & !bits);
+    }
+
+    // CA2 and CB2 in "independent interrupt" mode aren't cleared by port access
+    fn independent(control: Byte) -> bool {
+        control & 0x05 == 0x01
+    }
+
+    fn clear_port_a_flags(&self) {
+        let mut bits = InterruptBits::Ca1 as u8;
+        if !Via::independent((self.pcr >> 1) & 0x07) {
+            bits |= InterruptBits::Ca2 as u8;
+        }
+        self.clear_flags(bits);
+    }
+
+    fn clear_port_b_flags(&self) {
+        let mut bits = InterruptBits::Cb1 as u8;
+        if !Via::independent((self.pcr >> 5) & 0x07) {
+            bits |= InterruptBits::Cb2 as u8;
+        }
+        self.clear_flags(bits);
+    }
+
+    fn edge(old: bool, new: bool, positive: bool) -> bool {
+        old != new && new == positive
+    }
+
+    pub fn set_ca1(&mut self, level: bool) {
+        if Via::edge(self.ca1, level, self.pcr & 0x01 == 0x01) {
+            self.set_flag(InterruptBits::Ca1);
+        }
+        self.ca1 = level;
+    }
+
+    pub fn set_ca2(&mut self, level: bool) {
+        let control = (self.pcr >> 1) & 0x07;
+        if control & 0x04 == 0 && Via::edge(self.ca2, level, control & 0x02 == 0x02) {
+            self.set_flag(InterruptBits::Ca2);
+        }
+        self.ca2 = level;
+    }
+
+    pub fn set_cb1(&mut self, level: bool) {
+        if Via::edge(self.cb1, level, self.pcr & 0x10 == 0x10) {
+            self.set_flag(InterruptBits::Cb1);
+        }
+        self.cb1 = level;
+    }
+
+    pub fn set_cb2(&mut self, level: bool) {
+        let control = (self.pcr >> 5) & 0x07;
+        if control & 0x04 == 0 && Via::edge(self.cb2, level, control & 0x02 == 0x02) {
+            self.set_flag(InterruptBits::Cb2);
+        }
+        self.cb2 = level;
+    }
+
+    fn counter_value(counter: i32) -> Word {
+        (counter & 0xffff) as Word
+    }
+
+    // Counts down at 1MHz. Timer 1 fires N + 1.5 cycles after being loaded
+    // with N, which we round to N + 1, then every N + 2 in free-running mode.
+    fn count(&mut self, cycles: i32) {
+        self.t1_counter -= cycles;
+        while self.t1_counter < 0 {
+            if self.t1_armed {
+                self.set_flag(InterruptBits::Timer1);
+                self.t1_armed = self.acr & ACR_T1_FREE_RUN == ACR_T1_FREE_RUN;
+            }
+            self.t1_counter += if self.acr & ACR_T1_FREE_RUN == ACR_T1_FREE_RUN {
+                self.t1_latch as i32 + 2
+            } else {
+                0x10000
+            };
+        }
+
+        // Counting pulses on PB6 isn't wired to anything
+        if self.acr & ACR_T2_COUNT_PB6 == 0 {
+            self.t2_counter -= cycles;
+            while self.t2_counter < 0 {
+                if self.t2_armed {
+                    self.set_flag(InterruptBits::Timer2);
+                    self.t2_armed = false;
+                }
+                self.t2_counter += 0x10000;
+            }
+        }
+    }
+
+    fn read(&self, register: Address) -> Byte {
+        match register {
+            REGISTER_ORB => {
+                self.clear_port_b_flags();
+                self.port_b()
+            }
+            REGISTER_ORA => {
+                self.clear_port_a_flags();
+                self.port_a()
+            }
+            REGISTER_DDRB => self.ddrb,
+            REGISTER_DDRA => self.ddra,
+            REGISTER_T1CL => {
+                self.clear_flags(InterruptBits::Timer1 as u8);
+                Via::counter_value(self.t1_counter) as Byte
+            }
+            REGISTER_T1CH => (Via::counter_value(self.t1_counter) >> 8) as Byte,
+            REGISTER_T1LL => self.t1_latch as Byte,
+            REGISTER_T1LH => (self.t1_latch >> 8) as Byte,
+            REGISTER_T2CL => {
+                self.clear_flags(InterruptBits::Timer2 as u8);
+                Via::counter_value(self.t2_counter) as Byte
+            }
+            REGISTER_T2CH => (Via::counter_value(self.t2_counter) >> 8) as Byte,
+            REGISTER_SR => {
+                self.clear_flags(InterruptBits::ShiftRegister as u8);
+                self.sr
+            }
+            REGISTER_ACR => self.acr,
+            REGISTER_PCR => self.pcr,
+            REGISTER_IFR => self.ifr(),
+            REGISTER_IER => self.ier | 0x80,
+            _ => self.port_a(),
+        }
+    }
+
+    fn write(&mut self, register: Address, data: Byte) {
+        match register {
+            REGISTER_ORB => {
+                self.clear_port_b_flags();
+                self.orb = data;
+            }
+            REGISTER_ORA => {
+                self.clear_port_a_flags();
+                self.ora = data;
+            }
+            REGISTER_DDRB => self.ddrb = data,
+            REGISTER_DDRA => self.ddra = data,
+            REGISTER_T1CL | REGISTER_T1LL => {
+                self.t1_latch = (self.t1_latch & 0xff00) | data as Word;
+            }
+            REGISTER_T1CH => {
+                self.t1_latch = (self.t1_latch & 0x00ff) | ((data as Word) << 8);
+                self.t1_counter = self.t1_latch as i32;
+                self.t1_armed = true;
+                self.clear_flags(InterruptBits::Timer1 as u8);
+            }
+            REGISTER_T1LH => {
+                self.t1_latch = (self.t1_latch & 0x00ff) | ((data as Word) << 8);
+                self.clear_flags(InterruptBits::Timer1 as u8);
+            }
+            REGISTER_T2CL => self.t2_latch_low = data,
+            REGISTER_T2CH => {
+                self.t2_counter = (((data as Word) << 8) | self.t2_latch_low as Word) as i32;
+                self.t2_armed = true;
+                self.clear_flags(InterruptBits::Timer2 as u8);
+            }
+            REGISTER_SR => {
+                self.clear_flags(InterruptBits::ShiftRegister as u8);
+                self.sr = data;
+            }
+            REGISTER_ACR => self.acr = data,
+            REGISTER_PCR => self.pcr = data,
+            REGISTER_IFR => self.clear_flags(data & 0x7f),
+            REGISTER_IER => {
+                if data & 0x80 == 0x80 {
+                    self.ier |= data & 0x7f;
+                } else {
+                    self.ier &= !data;
+                }
+            }
+            _ => self.ora = data,
+        }
+    }
+}
+
+impl Snapshot for Via {
+    fn save(&self, state: &mut StateWriter) {
+        state.byte(self.orb);
+        state.byte(self.ora);
+        state.byte(self.ddrb);
+        state.byte(self.ddra);
+        state.byte(self.port_a_input);
+        state.byte(self.port_b_input);
+        state.i32(self.t1_counter);
+        state.word(self.t1_latch);
+        state.bool(self.t1_armed);
+        state.i32(self.t2_counter);
+        state.byte(self.t2_latch_low);
+        state.bool(self.t2_armed);
+        state.byte(self.sr);
+        state.byte(self.acr);
+        state.byte(self.pcr);
+        state.byte(self.ifr.get());
+        state.byte(self.ier);
+        state.bool(self.ca1);
+        state.bool(self.ca2);
+        state.bool(self.cb1);
+        state.bool(self.cb2);
+        state.bool(self.odd_cycle);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.orb = state.byte()?;
+        self.ora = state.byte()?;
+        self.ddrb = state.byte()?;
+        self.ddra = state.byte()?;
+        self.port_a_input = state.byte()?;
+        self.port_b_input = state.byte()?;
+        self.t1_counter = state.i32()?;
+        self.t1_latch = state.word()?;
+        self.t1_armed = state.bool()?;
+        self.t2_counter = state.i32()?;
+        self.t2_latch_low = state.byte()?;
+        self.t2_armed = state.bool()?;
+        self.sr = state.byte()?;
+        self.acr = state.byte()?;
+        self.pcr = state.byte()?;
+        self.ifr.set(state.byte()?);
+        self.ier = state.byte()?;
+        self.ca1 = state.bool()?;
+        self.ca2 = state.bool()?;
+        self.cb1 = state.bool()?;
+        self.cb2 = state.bool()?;
+        self.odd_cycle = state.bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Via {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for Via {
+    fn length(&self) -> usize {
+        0x10
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        Ok(self.read(address & 0x0f))
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let lsb = self.read_byte(address)? as Word;
+        let msb = self.read_byte(address.wrapping_add(1))? as Word;
+        Ok(lsb + (msb << 8))
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        self.write(address & 0x0f, data);
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for Via {
+    fn tick(&mut self, cycles: usize) {
+        let total = cycles + self.odd_cycle as usize;
+        self.odd_cycle = total % 2 == 1;
+        self.count((total / 2) as i32);
+    }
+
+    fn irq(&self) -> bool {
+        self.ifr.get() & self.ier & 0x7f != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn one_mhz(via: &mut Via, cycles: usize) {
+        via.tick(cycles * 2);
+    }
+
+    #[test]
+    fn construct() {
+        let via = Via::new();
+        assert_eq!(via.read_byte(REGISTER_IER), Ok(0x80));
+        assert!(!via.irq());
+    }
+
+    #[test]
+    fn ports_mix_outputs_and_inputs() -> Result<()> {
+        let mut via = Via::new();
+        via.port_a_input = 0x80;
+        via.write_byte(REGISTER_DDRA, 0x7f)?;
+        via.write_byte(REGISTER_ORA, 0x25)?;
+
+        assert_eq!(via.read_byte(REGISTER_ORA)?, 0xa5);
+        assert_eq!(via.port_a(), 0xa5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn interrupt_enable() -> Result<()> {
+        let mut via = Via::new();
+        via.write_byte(REGISTER_IER, 0x80 | 0x42)?;
+        assert_eq!(via.ier(), 0x42);
+
+        via.write_byte(REGISTER_IER, 0x02)?;
+        assert_eq!(via.ier(), 0x40);
+
+        Ok(())
+    }
+
+    #[test]
+    fn timer1_one_shot() -> Result<()> {
+        let mut via = Via::new();
+        via.write_byte(REGISTER_IER, 0xc0)?;
+        via.write_byte(REGISTER_T1CL, 100)?;
+        via.write_byte(REGISTER_T1CH, 0)?;
+
+        one_mhz(&mut via, 100);
+        assert!(!via.irq());
+        one_mhz(&mut via, 1);
+        assert!(via.irq());
+        assert_eq!(via.read_byte(REGISTER_IFR)?, 0xc0);
+
+        via.read_byte(REGISTER_T1CL)?;
+        assert!(!via.irq());
+
+        // Doesn't fire again until reloaded
+        one_mhz(&mut via, 0x20000);
+        assert!(!via.irq());
+
+        Ok(())
+    }
+
+    #[test]
+    fn timer1_free_running() -> Result<()> {
+        let mut via = Via::new();
+        via.write_byte(REGISTER_ACR, ACR_T1_FREE_RUN)?;
+        via.write_byte(REGISTER_IER, 0xc0)?;
+        via.write_word(REGISTER_T1CL, 9998)?;
+
+        one_mhz(&mut via, 9999);
+        assert!(via.irq());
+        via.write_byte(REGISTER_IFR, InterruptBits::Timer1 as u8)?;
+
+        one_mhz(&mut via, 9999);
+        assert!(!via.irq());
+        one_mhz(&mut via, 1);
+        assert!(via.irq());
+
+        Ok(())
+    }
+
+    #[test]
+    fn timer2_one_shot() -> Result<()> {
+        let mut via = Via::new();
+        via.write_byte(REGISTER_IER, 0xa0)?;
+        via.write_byte(REGISTER_T2CL, 0x10)?;
+        via.write_byte(REGISTER_T2CH, 0x00)?;
+
+        one_mhz(&mut via, 0x11);
+        assert!(via.irq());
+        assert_eq!(via.read_byte(REGISTER_T2CH)?, 0xff);
+
+        Ok(())
+    }
+
+    #[test]
+    fn counters_run_at_half_clock() -> Result<()> {
+        let mut via = Via::new();
+        via.write_byte(REGISTER_T1CL, 0x00)?;
+        via.write_byte(REGISTER_T1CH, 0x10)?;
+
+        via.tick(1);
+        via.tick(1);
+        via.tick(1);
+        assert_eq!(via.read_byte(REGISTER_T1CL)?, 0xff);
+
+        Ok(())
+    }
+
+    #[test]
+    fn control_line_edges() -> Result<()> {
+        let mut via = Via::new();
+        via.write_byte(REGISTER_IER, 0x80 | InterruptBits::Ca1 as u8)?;
+
+        // Negative edge by default
+        via.set_ca1(true);
+        assert!(!via.irq());
+        via.set_ca1(false);
+        assert!(via.irq());
+
+        // Reading port A clears it
+        via.read_byte(REGISTER_ORA)?;
+        assert!(!via.irq());
+
+        via.write_byte(REGISTER_PCR, 0x01)?;
+        via.set_ca1(true);
+        assert!(via.irq());
+
+        Ok(())
+    }
+
+    #[test]
+    fn independent_ca2_survives_port_access() -> Result<()> {
+        let mut via = Via::new();
+        via.write_byte(REGISTER_PCR, 0x06)?;
+        via.set_ca2(true);
+        via.read_byte(REGISTER_ORA)?;
+
+        assert_eq!(
+            via.ifr() & InterruptBits::Ca2 as u8,
+            InterruptBits::Ca2 as u8
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reset_clears_interrupt_enable() -> Result<()> {
+        let mut via = Via::new();
+        via.write_byte(REGISTER_IER, 0xff)?;
+        via.reset();
+        assert_eq!(via.ier(), 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::Device;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// Video ULA at &FE20 (control) and &FE21 (palette). Both are write only.
+
+pub const CONTROL_FLASH: Byte = 0x01;
+pub const CONTROL_TELETEXT: Byte = 0x02;
+pub const CONTROL_CHARACTERS_PER_LINE: Byte = 0x0c;
+pub const CONTROL_FAST_CLOCK: Byte = 0x10;
+pub const CONTROL_CURSOR: Byte = 0xe0;
+
+pub struct VideoUla {
+    control: Byte,
+
+    // Physical colour for each logical colour, as written (so with the
+    // flash and inverted RGB bits still in it)
+    palette: [Byte; 16],
+}
+
+impl VideoUla {
+    pub fn new() -> Self {
+        VideoUla {
+            control: 0x00,
+            palette: [0; 16],
+        }
+    }
+
+    pub fn control(&self) -> Byte {
+        self.control
+    }
+
+    pub fn palette(&self, index: usize) -> Byte {
+        self.palette[index & 0x0f]
+    }
+
+    pub fn fast_clock(&self) -> bool {
+        self.control & CONTROL_FAST_CLOCK == CONTROL_FAST_CLOCK
+    }
+
+    pub fn teletext(&self) -> bool {
+        self.control & CONTROL_TELETEXT == CONTROL_TELETEXT
+    }
+
+    pub fn flash(&self) -> bool {
+        self.control & CONTROL_FLASH == CONTROL_FLASH
+    }
+}
+
+impl Snapshot for VideoUla {
+    fn save(&self, state: &mut StateWriter) {
+        state.byte(self.control);
+        state.array(&self.palette);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.control = state.byte()?;
+        state.array(&mut self.palette)
+    }
+}
+
+impl Default for VideoUla {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for VideoUla {
+    fn length(&self) -> usize {
+        0x02
+    }
+
+    fn read_byte(&self, _address: Address) -> Result<Byte> {
+        Ok(0xff)
+    }
+
+    fn read_word(&self, _address: Address) -> Result<Word> {
+        Ok(0xffff)
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        if address & 0x01 == 0x00 {
+            self.control = data;
+        } else {
+            self.palette[(data >> 4) as usize] = data & 0x0f;
+        }
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for VideoUla {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_and_palette() -> Result<()> {
+        let mut ula = VideoUla::new();
+        ula.write_byte(0, 0x9c)?;
+        assert!(ula.fast_clock());
+        assert!(!ula.teletext());
+
+        ula.write_byte(1, 0x37)?;
+        assert_eq!(ula.palette(3), 0x07);
+
+        // Mirrored through the rest of &FE20-&FE2F
+        ula.write_byte(0x0e, 0x4b)?;
+        assert!(ula.teletext());
+
+        Ok(())
+    }
+}
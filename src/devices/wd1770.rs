@@ -0,0 +1,1432 @@
+use std::cell::Cell;
+
+use crate::cpu::{Address, Byte, Error, ErrorType, Memory, Result, Word};
+use crate::devices::disc_drive::DiscDrive;
+use crate::devices::Device;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+
+// Western Digital WD1770 floppy disc controller plus the board's drive
+// control latch. Both INTRQ and DRQ are wired to NMI.
+
+#[repr(u8)]
+#[derive(Copy, Clone)]
+pub enum StatusBits {
+    Busy = 1 << 0,
+    // Index pulse after a type I command, data request otherwise
+    IndexOrDataRequest = 1 << 1,
+    // Track 0 after a type I command, lost data otherwise
+    Track0OrLostData = 1 << 2,
+    CrcError = 1 << 3,
+    RecordNotFound = 1 << 4,
+    // Spin-up complete after a type I command, deleted data otherwise
+    SpinUpOrRecordType = 1 << 5,
+    WriteProtect = 1 << 6,
+    MotorOn = 1 << 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriveSelect {
+    // One latch bit per drive
+    Separate { drive0: Byte, drive1: Byte },
+    // A single bit choosing drive 0 or 1
+    Single(Byte),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlLatch {
+    pub drive_select: DriveSelect,
+    pub side: Byte,
+    pub density: Byte,
+    pub density_set_is_double: bool,
+    // Active low
+    pub reset: Option<Byte>,
+}
+
+// Where the latch and controller sit, relative to each other and in SHEILA
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interface {
+    pub base: Address,
+    pub fdc_offset: Address,
+    pub latch_offset: Address,
+    pub latch: ControlLatch,
+}
+
+// The latch and controller take up eight bytes between them
+const INTERFACE_SIZE: Address = 0x08;
+
+impl Interface {
+    pub fn contains(&self, address: Address) -> bool {
+        (self.base..self.base + INTERFACE_SIZE).contains(&address)
+    }
+
+    // Where the controller's own registers start
+    pub fn fdc_base(&self) -> Address {
+        self.base + self.fdc_offset
+    }
+}
+
+pub const MASTER: Interface = Interface {
+    base: 0xfe24,
+    fdc_offset: 0x04,
+    latch_offset: 0x00,
+    latch: ControlLatch {
+        drive_select: DriveSelect::Separate {
+            drive0: 0x01,
+            drive1: 0x02,
+        },
+        side: 0x10,
+        density: 0x20,
+        density_set_is_double: false,
+        reset: Some(0x04),
+    },
+};
+
+// The Acorn 1770 board, as fitted to the B+
+pub const ACORN: Interface = Interface {
+    base: 0xfe80,
+    fdc_offset: 0x04,
+    latch_offset: 0x00,
+    latch: ControlLatch {
+        drive_select: DriveSelect::Separate {
+            drive0: 0x01,
+            drive1: 0x02,
+        },
+        side: 0x04,
+        density: 0x08,
+        density_set_is_double: false,
+        reset: Some(0x20),
+    },
+};
+
+pub const OPUS: Interface = Interface {
+    base: 0xfe80,
+    fdc_offset: 0x00,
+    latch_offset: 0x04,
+    latch: ControlLatch {
+        drive_select: DriveSelect::Single(0x01),
+        side: 0x02,
+        density: 0x40,
+        density_set_is_double: true,
+        reset: None,
+    },
+};
+
+const REGISTER_STATUS: Address = 0x00;
+const REGISTER_TRACK: Address = 0x01;
+const REGISTER_SECTOR: Address = 0x02;
+const REGISTER_DATA: Address = 0x03;
+
+const CYCLES_PER_MS: usize = 2000;
+const REVOLUTION_CYCLES: usize = 200 * CYCLES_PER_MS;
+const SPIN_UP_CYCLES: usize = 6 * REVOLUTION_CYCLES;
+const MOTOR_OFF_CYCLES: usize = 9 * REVOLUTION_CYCLES;
+const SETTLE_CYCLES: usize = 30 * CYCLES_PER_MS;
+const SEARCH_CYCLES: usize = 5 * REVOLUTION_CYCLES;
+const STEP_RATES_MS: [usize; 4] = [6, 12, 20, 30];
+
+// Bytes arrive every 64us in FM and 32us in MFM
+const FM_BYTE_CYCLES: usize = 128;
+const MFM_BYTE_CYCLES: usize = 64;
+const FM_TRACK_LENGTH: usize = 3125;
+const MFM_TRACK_LENGTH: usize = 6250;
+
+const ID_ADDRESS_MARK: Byte = 0xfe;
+const DATA_ADDRESS_MARK: Byte = 0xfb;
+const DELETED_DATA_ADDRESS_MARK: Byte = 0xf8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Restore,
+    Seek,
+    Step { step_in: Option<bool>, update: bool },
+    ReadSector { multiple: bool },
+    WriteSector { multiple: bool },
+    ReadAddress,
+    ReadTrack,
+    WriteTrack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    SpinUp,
+    Start,
+    Stepping(usize),
+    Verify,
+    Transfer,
+    Finish,
+}
+
+struct Operation {
+    kind: Kind,
+    phase: Phase,
+    verify: bool,
+    settle: bool,
+    buffer: Vec<Byte>,
+    offset: usize,
+    requested: bool,
+}
+
+fn crc16(crc: Word, bytes: &[Byte]) -> Word {
+    let mut crc = crc;
+    for &byte in bytes {
+        crc ^= (byte as Word) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 == 0x8000 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+pub struct Wd1770 {
+    pub drives: [DiscDrive; 2],
+    pub interface: Interface,
+    control: Byte,
+
+    command: Byte,
+    track: Byte,
+    sector: Byte,
+    data: Byte,
+    type_one: bool,
+
+    busy: bool,
+    intrq: Cell<bool>,
+    drq: Cell<bool>,
+    record_not_found: bool,
+    lost_data: bool,
+    write_protect: bool,
+    deleted_data: bool,
+
+    motor_on: bool,
+    spun_up: bool,
+    idle_cycles: usize,
+    step_in: bool,
+
+    operation: Option<Operation>,
+    countdown: usize,
+}
+
+impl Wd1770 {
+    pub fn new(interface: Interface) -> Self {
+        Wd1770 {
+            drives: [DiscDrive::new(), DiscDrive::new()],
+            interface,
+            control: 0x00,
+            command: 0x00,
+            track: 0,
+            sector: 0,
+            data: 0,
+            type_one: true,
+            busy: false,
+            intrq: Cell::new(false),
+            drq: Cell::new(false),
+            record_not_found: false,
+            lost_data: false,
+            write_protect: false,
+            deleted_data: false,
+            motor_on: false,
+            spun_up: false,
+            idle_cycles: 0,
+            step_in: true,
+            operation: None,
+            countdown: 0,
+        }
+    }
+
+    pub fn control(&self) -> Byte {
+        self.control
+    }
+
+    pub fn selected_drive(&self) -> Option<usize> {
+        match self.interface.latch.drive_select {
+            DriveSelect::Separate { drive0, drive1 } => {
+                if self.control & drive0 != 0 {
+                    Some(0)
+                } else if self.control & drive1 != 0 {
+                    Some(1)
+                } else {
+                    None
+                }
+            }
+            DriveSelect::Single(mask) => Some(if self.control & mask != 0 { 1 } else { 0 }),
+        }
+    }
+
+    pub fn side(&self) -> usize {
+        if self.control & self.interface.latch.side != 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    pub fn double_density(&self) -> bool {
+        let set = self.control & self.interface.latch.density != 0;
+        set == self.interface.latch.density_set_is_double
+    }
+
+    fn byte_cycles(&self) -> usize {
+        if self.double_density() {
+            MFM_BYTE_CYCLES
+        } else {
+            FM_BYTE_CYCLES
+        }
+    }
+
+    fn track_length(&self) -> usize {
+        if self.double_density() {
+            MFM_TRACK_LENGTH
+        } else {
+            FM_TRACK_LENGTH
+        }
+    }
+
+    fn drive(&self) -> Option<&DiscDrive> {
+        self.selected_drive().map(|d| &self.drives[d])
+    }
+
+    fn drive_mut(&mut self) -> Option<&mut DiscDrive> {
+        self.selected_drive().map(move |d| &mut self.drives[d])
+    }
+
+    pub fn status(&self) -> Byte {
+        let mut status = 0;
+        if self.busy {
+            status |= StatusBits::Busy as u8;
+        }
+        if self.record_not_found {
+            status |= StatusBits::RecordNotFound as u8;
+        }
+        if self.motor_on {
+            status |= StatusBits::MotorOn as u8;
+        }
+
+        if self.type_one {
+            if self.drive().is_some_and(|d| d.write_protected()) {
+                status |= StatusBits::WriteProtect as u8;
+            }
+            if self.spun_up {
+                status |= StatusBits::SpinUpOrRecordType as u8;
+            }
+            if self.drive().is_some_and(|d| d.at_track0()) {
+                status |= StatusBits::Track0OrLostData as u8;
+            }
+        } else {
+            if self.write_protect {
+                status |= StatusBits::WriteProtect as u8;
+            }
+            if self.deleted_data {
+                status |= StatusBits::SpinUpOrRecordType as u8;
+            }
+            if self.lost_data {
+                status |= StatusBits::Track0OrLostData as u8;
+            }
+            if self.drq.get() {
+                status |= StatusBits::IndexOrDataRequest as u8;
+            }
+        }
+        status
+    }
+
+    pub fn reset(&mut self) {
+        self.operation = None;
+        self.countdown = 0;
+        self.busy = false;
+        self.intrq.set(false);
+        self.drq.set(false);
+        self.record_not_found = false;
+        self.lost_data = false;
+        self.write_protect = false;
+        self.deleted_data = false;
+        self.type_one = true;
+        self.track = 0;
+        self.sector = 1;
+    }
+
+    fn write_control(&mut self, data: Byte) {
+        self.control = data;
+        if let Some(reset) = self.interface.latch.reset {
+            if data & reset == 0 {
+                self.reset();
+            }
+        }
+    }
+
+    fn read_status(&self) -> Byte {
+        self.intrq.set(false);
+        self.status()
+    }
+
+    fn read_data(&self) -> Byte {
+        self.drq.set(false);
+        self.data
+    }
+
+    fn write_data(&mut self, data: Byte) {
+        self.data = data;
+        self.drq.set(false);
+    }
+
+    fn write_command(&mut self, command: Byte) {
+        // Force interrupt is the only command accepted while busy
+        if command & 0xf0 == 0xd0 {
+            self.force_interrupt(command);
+            return;
+        }
+        if self.busy {
+            return;
+        }
+
+        self.command = command;
+        self.intrq.set(false);
+        self.drq.set(false);
+        self.record_not_found = false;
+        self.lost_data = false;
+        self.write_protect = false;
+        self.deleted_data = false;
+
+        let kind = match command >> 4 {
+            0x0 => Kind::Restore,
+            0x1 => Kind::Seek,
+            0x2 | 0x3 => Kind::Step {
+                step_in: None,
+                update: command & 0x10 != 0,
+            },
+            0x4 | 0x5 => Kind::Step {
+                step_in: Some(true),
+                update: command & 0x10 != 0,
+            },
+            0x6 | 0x7 => Kind::Step {
+                step_in: Some(false),
+                update: command & 0x10 != 0,
+            },
+            0x8 | 0x9 => Kind::ReadSector {
+                multiple: command & 0x10 != 0,
+            },
+            0xa | 0xb => Kind::WriteSector {
+                multiple: command & 0x10 != 0,
+            },
+            0xc => Kind::ReadAddress,
+            0xe => Kind::ReadTrack,
+            _ => Kind::WriteTrack,
+        };
+
+        let type_one = command & 0x80 == 0;
+        self.type_one = type_one;
+        self.busy = true;
+        self.idle_cycles = 0;
+
+        let spin_up = command & 0x08 == 0 && !self.motor_on;
+        self.motor_on = true;
+
+        self.operation = Some(Operation {
+            kind,
+            phase: if spin_up { Phase::SpinUp } else { Phase::Start },
+            verify: type_one && command & 0x04 != 0,
+            settle: !type_one && command & 0x04 != 0,
+            buffer: Vec::new(),
+            offset: 0,
+            requested: false,
+        });
+        self.countdown = if spin_up { SPIN_UP_CYCLES } else { 0 };
+        if !spin_up {
+            self.step();
+        }
+    }
+
+    fn force_interrupt(&mut self, command: Byte) {
+        if self.operation.is_none() {
+            self.type_one = true;
+        }
+        self.operation = None;
+        self.countdown = 0;
+        self.busy = false;
+        self.drq.set(false);
+        if command & 0x08 != 0 {
+            self.intrq.set(true);
+        }
+    }
+
+    fn complete(&mut self) {
+        self.operation = None;
+        self.countdown = 0;
+        self.busy = false;
+        self.drq.set(false);
+        self.intrq.set(true);
+    }
+
+    fn step_cycles(&self) -> usize {
+        STEP_RATES_MS[(self.command & 0x03) as usize] * CYCLES_PER_MS
+    }
+
+    fn step(&mut self) {
+        let mut op = match self.operation.take() {
+            Some(op) => op,
+            None => return,
+        };
+
+        let done = match op.phase {
+            Phase::SpinUp => {
+                self.spun_up = true;
+                op.phase = Phase::Start;
+                self.countdown = 0;
+                false
+            }
+            Phase::Start if self.type_one => self.start_type_one(&mut op),
+            Phase::Start => {
+                self.start_transfer(&mut op);
+                false
+            }
+            Phase::Stepping(remaining) => self.step_head(&mut op, remaining),
+            Phase::Verify => {
+                self.record_not_found = !self.track_exists(self.track as usize);
+                true
+            }
+            Phase::Transfer => match op.kind {
+                Kind::ReadSector { multiple } => self.transfer_read(&mut op, multiple),
+                Kind::WriteSector { multiple } => self.transfer_write(&mut op, multiple),
+                Kind::ReadAddress | Kind::ReadTrack => self.transfer_read(&mut op, false),
+                _ => self.transfer_write_track(&mut op),
+            },
+            Phase::Finish => true,
+        };
+
+        if done {
+            self.complete();
+        } else {
+            self.operation = Some(op);
+            if self.countdown == 0 {
+                self.step();
+            }
+        }
+    }
+
+    fn start_type_one(&mut self, op: &mut Operation) -> bool {
+        let steps = match op.kind {
+            Kind::Restore => {
+                self.step_in = false;
+                self.track = 0;
+                self.drive().map_or(0, |d| d.head())
+            }
+            Kind::Seek => {
+                let target = self.data;
+                self.step_in = target > self.track;
+                let steps = (target as isize - self.track as isize).unsigned_abs();
+                self.track = target;
+                steps
+            }
+            Kind::Step { step_in, update } => {
+                if let Some(step_in) = step_in {
+                    self.step_in = step_in;
+                }
+                if update {
+                    self.track = if self.step_in {
+                        self.track.wrapping_add(1)
+                    } else {
+                        self.track.wrapping_sub(1)
+                    };
+                }
+                1
+            }
+            _ => 0,
+        };
+
+        op.phase = Phase::Stepping(steps);
+        self.countdown = 0;
+        false
+    }
+
+    fn step_head(&mut self, op: &mut Operation, remaining: usize) -> bool {
+        if remaining > 0 {
+            let step_in = self.step_in;
+            if let Some(drive) = self.drive_mut() {
+                if step_in {
+                    drive.step_in();
+                } else {
+                    drive.step_out();
+                }
+            }
+            op.phase = Phase::Stepping(remaining - 1);
+            self.countdown = self.step_cycles();
+            return false;
+        }
+
+        if op.verify {
+            op.phase = Phase::Verify;
+            self.countdown = SETTLE_CYCLES;
+            return false;
+        }
+        true
+    }
+
+    // The head is on the physical track; the track register has to agree
+    // with the IDs on it for a sector to be found
+    fn track_exists(&self, track: usize) -> bool {
+        let drive = match self.drive() {
+            Some(drive) => drive,
+            None => return false,
+        };
+        let image = match &drive.image {
+            Some(image) => image,
+            None => return false,
+        };
+
+        image.format.double_density() == self.double_density()
+            && drive.head() == track
+            && track < image.geometry().tracks
+            && self.side() < image.geometry().sides
+    }
+
+    fn current_sector(&self) -> Option<Vec<Byte>> {
+        if !self.track_exists(self.track as usize) {
+            return None;
+        }
+        let drive = self.drive()?;
+        let image = drive.image.as_ref()?;
+        image
+            .sector(self.side(), drive.head(), self.sector as usize)
+            .map(|s| s.to_vec())
+    }
+
+    fn id_field(&self, sector: usize, size_code: Byte) -> Vec<Byte> {
+        let mut id = vec![self.track, self.side() as Byte, sector as Byte, size_code];
+        let mut marked = if self.double_density() {
+            vec![0xa1, 0xa1, 0xa1, ID_ADDRESS_MARK]
+        } else {
+            vec![ID_ADDRESS_MARK]
+        };
+        marked.extend_from_slice(&id);
+        id.extend_from_slice(&crc16(0xffff, &marked).to_be_bytes());
+        id
+    }
+
+    // Synthesises the raw bytes of a track, gaps and address marks included
+    fn raw_track(&self) -> Vec<Byte> {
+        let (gap, sync, marks): (Byte, usize, &[Byte]) = if self.double_density() {
+            (0x4e, 12, &[0xa1, 0xa1, 0xa1])
+        } else {
+            (0xff, 6, &[])
+        };
+
+        let mut raw = Vec::new();
+        if self.track_exists(self.track as usize) {
+            let drive = self.drive().unwrap();
+            let image = drive.image.as_ref().unwrap();
+            let geometry = image.geometry();
+
+            for sector in 0..geometry.sectors_per_track {
+                raw.extend(std::iter::repeat_n(gap, 16));
+                raw.extend(std::iter::repeat_n(0x00, sync));
+                raw.extend_from_slice(marks);
+                raw.push(ID_ADDRESS_MARK);
+                raw.extend(self.id_field(sector, 1));
+
+                raw.extend(std::iter::repeat_n(gap, 22));
+                raw.extend(std::iter::repeat_n(0x00, sync));
+                raw.extend_from_slice(marks);
+                raw.push(DATA_ADDRESS_MARK);
+
+                let data = image.sector(self.side(), drive.head(), sector).unwrap();
+                let mut marked = marks.to_vec();
+                marked.push(DATA_ADDRESS_MARK);
+                marked.extend_from_slice(data);
+                raw.extend_from_slice(data);
+                raw.extend_from_slice(&crc16(0xffff, &marked).to_be_bytes());
+            }
+        }
+
+        raw.resize(self.track_length(), gap);
+        raw
+    }
+
+    fn start_transfer(&mut self, op: &mut Operation) {
+        op.phase = Phase::Transfer;
+        let settle = if op.settle { SETTLE_CYCLES } else { 0 };
+
+        match op.kind {
+            Kind::ReadSector { .. } => match self.current_sector() {
+                Some(data) => {
+                    op.buffer = data;
+                    self.countdown = settle + self.byte_cycles();
+                }
+                None => self.not_found(op, settle),
+            },
+            Kind::WriteSector { .. } => {
+                if self.drive().is_some_and(|d| d.write_protected()) {
+                    self.write_protect = true;
+                    op.phase = Phase::Finish;
+                    self.countdown = 0;
+                } else if self.current_sector().is_some() {
+                    self.countdown = settle + self.byte_cycles();
+                } else {
+                    self.not_found(op, settle);
+                }
+            }
+            Kind::ReadAddress => {
+                let geometry_ok = self.track_exists(self.track as usize);
+                if geometry_ok {
+                    op.buffer = self.id_field(0, 1);
+                    self.sector = self.track;
+                    self.countdown = settle + self.byte_cycles();
+                } else {
+                    self.not_found(op, settle);
+                }
+            }
+            Kind::ReadTrack => {
+                op.buffer = self.raw_track();
+                self.countdown = settle + self.byte_cycles();
+            }
+            _ => {
+                if self.drive().is_some_and(|d| d.write_protected()) {
+                    self.write_protect = true;
+                    op.phase = Phase::Finish;
+                    self.countdown = 0;
+                } else {
+                    self.countdown = settle + self.byte_cycles();
+                }
+            }
+        }
+    }
+
+    fn not_found(&mut self, op: &mut Operation, settle: usize) {
+        self.record_not_found = true;
+        op.phase = Phase::Finish;
+        self.countdown = settle + SEARCH_CYCLES;
+    }
+
+    fn transfer_read(&mut self, op: &mut Operation, multiple: bool) -> bool {
+        if op.offset == op.buffer.len() {
+            if !multiple {
+                return true;
+            }
+
+            self.sector = self.sector.wrapping_add(1);
+            match self.current_sector() {
+                Some(data) => {
+                    op.buffer = data;
+                    op.offset = 0;
+                }
+                None => {
+                    self.not_found(op, 0);
+                    return false;
+                }
+            }
+        }
+
+        if self.drq.get() {
+            self.lost_data = true;
+        }
+        self.data = op.buffer[op.offset];
+        self.drq.set(true);
+        op.offset += 1;
+        self.countdown = self.byte_cycles();
+        false
+    }
+
+    fn transfer_write(&mut self, op: &mut Operation, multiple: bool) -> bool {
+        if op.requested {
+            if self.drq.get() {
+                self.lost_data = true;
+                self.drq.set(false);
+                self.data = 0x00;
+            }
+            op.buffer.push(self.data);
+            op.requested = false;
+        }
+
+        let size = self.current_sector().map_or(0, |s| s.len());
+        if op.buffer.len() == size {
+            let side = self.side();
+            let sector = self.sector as usize;
+            let buffer = std::mem::take(&mut op.buffer);
+            if let Some(drive) = self.drive_mut() {
+                let head = drive.head();
+                if let Some(data) = drive
+                    .image
+                    .as_mut()
+                    .and_then(|i| i.sector_mut(side, head, sector))
+                {
+                    data.copy_from_slice(&buffer);
+                }
+            }
+
+            if !multiple {
+                return true;
+            }
+            self.sector = self.sector.wrapping_add(1);
+            if self.current_sector().is_none() {
+                self.not_found(op, 0);
+                return false;
+            }
+        }
+
+        self.drq.set(true);
+        op.requested = true;
+        self.countdown = self.byte_cycles();
+        false
+    }
+
+    fn transfer_write_track(&mut self, op: &mut Operation) -> bool {
+        if op.requested {
+            if self.drq.get() {
+                self.lost_data = true;
+                self.drq.set(false);
+                self.data = 0x00;
+            }
+            op.buffer.push(self.data);
+            op.requested = false;
+        }
+
+        if op.buffer.len() == self.track_length() {
+            let raw = std::mem::take(&mut op.buffer);
+            self.format_track(&raw);
+            return true;
+        }
+
+        self.drq.set(true);
+        op.requested = true;
+        self.countdown = self.byte_cycles();
+        false
+    }
+
+    // Picks the ID and data fields out of the raw track the CPU supplied and
+    // writes any sectors that fit the image
+    fn format_track(&mut self, raw: &[Byte]) {
+        let side = self.side();
+        let double_density = self.double_density();
+        let drive = match self.drive_mut() {
+            Some(drive) => drive,
+            None => return,
+        };
+        let head = drive.head();
+        let image = match drive.image.as_mut() {
+            Some(image) if image.format.double_density() == double_density => image,
+            _ => return,
+        };
+
+        let mut id: Option<(usize, usize)> = None;
+        let mut n = 0;
+        while n < raw.len() {
+            match raw[n] {
+                ID_ADDRESS_MARK if n + 4 < raw.len() => {
+                    id = Some((raw[n + 3] as usize, 128 << (raw[n + 4] & 0x03)));
+                    n += 5;
+                }
+                DATA_ADDRESS_MARK | DELETED_DATA_ADDRESS_MARK if id.is_some() => {
+                    let (sector, size) = id.take().unwrap();
+                    let end = (n + 1 + size).min(raw.len());
+                    if let Some(data) = image.sector_mut(side, head, sector) {
+                        if data.len() == size && end - n - 1 == size {
+                            data.copy_from_slice(&raw[n + 1..end]);
+                        }
+                    }
+                    n = end;
+                }
+                _ => n += 1,
+            }
+        }
+    }
+}
+
+impl Kind {
+    fn save(&self, state: &mut StateWriter) {
+        match *self {
+            Kind::Restore => state.byte(0),
+            Kind::Seek => state.byte(1),
+            Kind::Step { step_in, update } => {
+                state.byte(2);
+                state.option_byte(step_in.map(|step_in| step_in as Byte));
+                state.bool(update);
+            }
+            Kind::ReadSector { multiple } => {
+                state.byte(3);
+                state.bool(multiple);
+            }
+            Kind::WriteSector { multiple } => {
+                state.byte(4);
+                state.bool(multiple);
+            }
+            Kind::ReadAddress => state.byte(5),
+            Kind::ReadTrack => state.byte(6),
+            Kind::WriteTrack => state.byte(7),
+        }
+    }
+
+    fn restore(state: &mut StateReader) -> Result<Self> {
+        match state.byte()? {
+            0 => Ok(Kind::Restore),
+            1 => Ok(Kind::Seek),
+            2 => Ok(Kind::Step {
+                step_in: state.option_byte()?.map(|step_in| step_in != 0),
+                update: state.bool()?,
+            }),
+            3 => Ok(Kind::ReadSector {
+                multiple: state.bool()?,
+            }),
+            4 => Ok(Kind::WriteSector {
+                multiple: state.bool()?,
+            }),
+            5 => Ok(Kind::ReadAddress),
+            6 => Ok(Kind::ReadTrack),
+            7 => Ok(Kind::WriteTrack),
+            _ => Err(Error::without_pc(ErrorType::InvalidSnapshot)),
+        }
+    }
+}
+
+impl Phase {
+    fn save(&self, state: &mut StateWriter) {
+        let (phase, steps) = match *self {
+            Phase::SpinUp => (0, 0),
+            Phase::Start => (1, 0),
+            Phase::Stepping(steps) => (2, steps),
+            Phase::Verify => (3, 0),
+            Phase::Transfer => (4, 0),
+            Phase::Finish => (5, 0),
+        };
+        state.byte(phase);
+        state.usize(steps);
+    }
+
+    fn restore(state: &mut StateReader) -> Result<Self> {
+        let phase = state.byte()?;
+        let steps = state.usize()?;
+        match phase {
+            0 => Ok(Phase::SpinUp),
+            1 => Ok(Phase::Start),
+            2 => Ok(Phase::Stepping(steps)),
+            3 => Ok(Phase::Verify),
+            4 => Ok(Phase::Transfer),
+            5 => Ok(Phase::Finish),
+            _ => Err(Error::without_pc(ErrorType::InvalidSnapshot)),
+        }
+    }
+}
+
+impl Operation {
+    fn save(&self, state: &mut StateWriter) {
+        self.kind.save(state);
+        self.phase.save(state);
+        state.bool(self.verify);
+        state.bool(self.settle);
+        state.bytes(&self.buffer);
+        state.usize(self.offset);
+        state.bool(self.requested);
+    }
+
+    fn restore(state: &mut StateReader) -> Result<Self> {
+        Ok(Operation {
+            kind: Kind::restore(state)?,
+            phase: Phase::restore(state)?,
+            verify: state.bool()?,
+            settle: state.bool()?,
+            buffer: state.bytes()?,
+            offset: state.usize()?,
+            requested: state.bool()?,
+        })
+    }
+}
+
+// The interface is part of the machine rather than its state
+impl Snapshot for Wd1770 {
+    fn save(&self, state: &mut StateWriter) {
+        for drive in &self.drives {
+            drive.save(state);
+        }
+        state.byte(self.control);
+        state.byte(self.command);
+        state.byte(self.track);
+        state.byte(self.sector);
+        state.byte(self.data);
+        state.bool(self.type_one);
+        state.bool(self.busy);
+        state.bool(self.intrq.get());
+        state.bool(self.drq.get());
+        state.bool(self.record_not_found);
+        state.bool(self.lost_data);
+        state.bool(self.write_protect);
+        state.bool(self.deleted_data);
+        state.bool(self.motor_on);
+        state.bool(self.spun_up);
+        state.usize(self.idle_cycles);
+        state.bool(self.step_in);
+        state.bool(self.operation.is_some());
+        if let Some(operation) = &self.operation {
+            operation.save(state);
+        }
+        state.usize(self.countdown);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        for drive in &mut self.drives {
+            drive.restore(state)?;
+        }
+        self.control = state.byte()?;
+        self.command = state.byte()?;
+        self.track = state.byte()?;
+        self.sector = state.byte()?;
+        self.data = state.byte()?;
+        self.type_one = state.bool()?;
+        self.busy = state.bool()?;
+        self.intrq.set(state.bool()?);
+        self.drq.set(state.bool()?);
+        self.record_not_found = state.bool()?;
+        self.lost_data = state.bool()?;
+        self.write_protect = state.bool()?;
+        self.deleted_data = state.bool()?;
+        self.motor_on = state.bool()?;
+        self.spun_up = state.bool()?;
+        self.idle_cycles = state.usize()?;
+        self.step_in = state.bool()?;
+        self.operation = if state.bool()? {
+            Some(Operation::restore(state)?)
+        } else {
+            None
+        };
+        self.countdown = state.usize()?;
+        Ok(())
+    }
+}
+
+impl Memory for Wd1770 {
+    fn length(&self) -> usize {
+        INTERFACE_SIZE as usize
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        let address = address & 0x07;
+        if address & 0x04 != self.interface.fdc_offset {
+            return Ok(self.control);
+        }
+
+        match address & 0x03 {
+            REGISTER_STATUS => Ok(self.read_status()),
+            REGISTER_TRACK => Ok(self.track),
+            REGISTER_SECTOR => Ok(self.sector),
+            _ => Ok(self.read_data()),
+        }
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let lsb = self.read_byte(address)? as Word;
+        let msb = self.read_byte(address.wrapping_add(1))? as Word;
+        Ok(lsb + (msb << 8))
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        let address = address & 0x07;
+        if address & 0x04 != self.interface.fdc_offset {
+            self.write_control(data);
+            return Ok(());
+        }
+
+        match address & 0x03 {
+            REGISTER_STATUS => self.write_command(data),
+            REGISTER_TRACK if !self.busy => self.track = data,
+            REGISTER_SECTOR if !self.busy => self.sector = data,
+            REGISTER_DATA => self.write_data(data),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for Wd1770 {
+    fn tick(&mut self, cycles: usize) {
+        let mut cycles = cycles;
+        while self.operation.is_some() {
+            if cycles < self.countdown {
+                self.countdown -= cycles;
+                return;
+            }
+            cycles -= self.countdown;
+            self.countdown = 0;
+            self.step();
+        }
+
+        if self.motor_on {
+            self.idle_cycles += cycles;
+            if self.idle_cycles >= MOTOR_OFF_CYCLES {
+                self.motor_on = false;
+                self.spun_up = false;
+            }
+        }
+    }
+
+    fn nmi(&self) -> bool {
+        self.intrq.get() || self.drq.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disc::image::{DiscImage, Format};
+
+    const FDC: Address = 0x04;
+    const LATCH: Address = 0x00;
+
+    // Drive 0, side 0, double density, out of reset
+    const MASTER_ADFS: Byte = 0x01 | 0x04;
+    // Drive 0, side 0, single density, out of reset
+    const MASTER_DFS: Byte = 0x01 | 0x04 | 0x20;
+
+    // Commands with the motor spin-up wait disabled
+    const RESTORE: Byte = 0x08;
+    const SEEK: Byte = 0x18;
+    const STEP_IN: Byte = 0x48;
+    const READ_SECTOR: Byte = 0x88;
+    const READ_SECTORS: Byte = 0x98;
+    const WRITE_SECTOR: Byte = 0xa8;
+    const READ_ADDRESS: Byte = 0xc8;
+    const READ_TRACK: Byte = 0xe8;
+    const WRITE_TRACK: Byte = 0xf8;
+
+    fn fdc_with(format: Format, control: Byte) -> Result<Wd1770> {
+        let mut image = DiscImage::new(format, 80);
+        let geometry = image.geometry();
+        for track in 0..80 {
+            for sector in 0..geometry.sectors_per_track {
+                let data = image.sector_mut(0, track, sector).unwrap();
+                for (n, b) in data.iter_mut().enumerate() {
+                    *b = (track * 16 + sector + n) as Byte;
+                }
+            }
+        }
+
+        let mut fdc = Wd1770::new(MASTER);
+        fdc.drives[0].insert(image);
+        fdc.write_byte(LATCH, control)?;
+        Ok(fdc)
+    }
+
+    fn run(fdc: &mut Wd1770, write_data: &[Byte]) -> Result<(Byte, Vec<Byte>)> {
+        let mut read = Vec::new();
+        let mut written = write_data.iter();
+
+        for _ in 0..10_000_000 {
+            fdc.tick(16);
+            if !fdc.nmi() {
+                continue;
+            }
+
+            if fdc.drq.get() {
+                if let Some(&b) = written.next() {
+                    fdc.write_byte(FDC + REGISTER_DATA, b)?;
+                } else {
+                    read.push(fdc.read_byte(FDC + REGISTER_DATA)?);
+                }
+            } else {
+                return Ok((fdc.read_byte(FDC + REGISTER_STATUS)?, read));
+            }
+        }
+        panic!("command didn't complete");
+    }
+
+    fn command(fdc: &mut Wd1770, command: Byte, write_data: &[Byte]) -> Result<(Byte, Vec<Byte>)> {
+        fdc.write_byte(FDC + REGISTER_STATUS, command)?;
+        run(fdc, write_data)
+    }
+
+    #[test]
+    fn construct() {
+        let fdc = Wd1770::new(MASTER);
+        assert_eq!(fdc.status(), 0);
+        assert!(!fdc.nmi());
+    }
+
+    #[test]
+    fn latch_selects_drive_side_and_density() -> Result<()> {
+        let mut fdc = Wd1770::new(MASTER);
+        fdc.write_byte(LATCH, 0x02 | 0x10 | 0x04)?;
+        assert_eq!(fdc.selected_drive(), Some(1));
+        assert_eq!(fdc.side(), 1);
+        assert!(fdc.double_density());
+
+        fdc.write_byte(LATCH, 0x01 | 0x20 | 0x04)?;
+        assert_eq!(fdc.selected_drive(), Some(0));
+        assert_eq!(fdc.side(), 0);
+        assert!(!fdc.double_density());
+
+        Ok(())
+    }
+
+    #[test]
+    fn opus_layout() -> Result<()> {
+        let mut fdc = Wd1770::new(OPUS);
+        fdc.write_byte(0x04, 0x01 | 0x02 | 0x40)?;
+        assert_eq!(fdc.selected_drive(), Some(1));
+        assert_eq!(fdc.side(), 1);
+        assert!(fdc.double_density());
+
+        fdc.write_byte(REGISTER_TRACK, 0x20)?;
+        assert_eq!(fdc.read_byte(REGISTER_TRACK)?, 0x20);
+
+        Ok(())
+    }
+
+    #[test]
+    fn latch_reset() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+        fdc.write_byte(FDC + REGISTER_TRACK, 0x20)?;
+        fdc.write_byte(LATCH, 0x00)?;
+        assert_eq!(fdc.read_byte(FDC + REGISTER_TRACK)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn spin_up_before_stepping() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+        fdc.write_byte(FDC + REGISTER_STATUS, 0x00)?;
+        assert_eq!(
+            fdc.status() & StatusBits::MotorOn as u8,
+            StatusBits::MotorOn as u8
+        );
+
+        fdc.tick(SPIN_UP_CYCLES - 1);
+        assert_eq!(fdc.status() & StatusBits::SpinUpOrRecordType as u8, 0);
+
+        let (status, _) = run(&mut fdc, &[])?;
+        assert_eq!(
+            status & StatusBits::SpinUpOrRecordType as u8,
+            StatusBits::SpinUpOrRecordType as u8
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn motor_turns_off_when_idle() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+        command(&mut fdc, RESTORE, &[])?;
+        assert!(fdc.motor_on);
+
+        fdc.tick(MOTOR_OFF_CYCLES);
+        assert!(!fdc.motor_on);
+
+        Ok(())
+    }
+
+    #[test]
+    fn seek_and_restore() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+
+        fdc.write_byte(FDC + REGISTER_DATA, 30)?;
+        let (status, _) = command(&mut fdc, SEEK | 0x04, &[])?;
+        assert_eq!(status & StatusBits::RecordNotFound as u8, 0);
+        assert_eq!(fdc.drives[0].head(), 30);
+        assert_eq!(fdc.read_byte(FDC + REGISTER_TRACK)?, 30);
+
+        let (status, _) = command(&mut fdc, RESTORE, &[])?;
+        assert_eq!(fdc.drives[0].head(), 0);
+        assert_eq!(
+            status & StatusBits::Track0OrLostData as u8,
+            StatusBits::Track0OrLostData as u8
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn step_in_updates_track() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+        command(&mut fdc, STEP_IN | 0x10, &[])?;
+        command(&mut fdc, STEP_IN, &[])?;
+
+        assert_eq!(fdc.drives[0].head(), 2);
+        assert_eq!(fdc.read_byte(FDC + REGISTER_TRACK)?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_fails_when_track_register_disagrees() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+        let (status, _) = command(&mut fdc, STEP_IN | 0x04, &[])?;
+        assert_eq!(
+            status & StatusBits::RecordNotFound as u8,
+            StatusBits::RecordNotFound as u8
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_sector() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+        fdc.write_byte(FDC + REGISTER_SECTOR, 5)?;
+
+        let (status, data) = command(&mut fdc, READ_SECTOR, &[])?;
+        assert_eq!(status & 0x1f, 0);
+        assert_eq!(data.len(), 256);
+        assert_eq!(data[0], 5);
+        assert_eq!(data[255], (5 + 255) as Byte);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_multiple_sectors_ends_with_record_not_found() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+        fdc.write_byte(FDC + REGISTER_SECTOR, 14)?;
+
+        let (status, data) = command(&mut fdc, READ_SECTORS, &[])?;
+        assert_eq!(
+            status & StatusBits::RecordNotFound as u8,
+            StatusBits::RecordNotFound as u8
+        );
+        assert_eq!(data.len(), 512);
+        assert_eq!(fdc.read_byte(FDC + REGISTER_SECTOR)?, 16);
+
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_density_is_not_found() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_DFS)?;
+        let (status, data) = command(&mut fdc, READ_SECTOR, &[])?;
+        assert_eq!(
+            status & StatusBits::RecordNotFound as u8,
+            StatusBits::RecordNotFound as u8
+        );
+        assert!(data.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn dfs_disc_in_single_density() -> Result<()> {
+        let mut fdc = fdc_with(Format::Ssd, MASTER_DFS)?;
+        fdc.write_byte(FDC + REGISTER_SECTOR, 9)?;
+        let (status, data) = command(&mut fdc, READ_SECTOR, &[])?;
+        assert_eq!(status & 0x1f, 0);
+        assert_eq!(data[0], 9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lost_data() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+        fdc.write_byte(FDC + REGISTER_STATUS, READ_SECTOR)?;
+        fdc.tick(300 * MFM_BYTE_CYCLES);
+
+        assert_eq!(
+            fdc.read_byte(FDC + REGISTER_STATUS)? & StatusBits::Track0OrLostData as u8,
+            StatusBits::Track0OrLostData as u8
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_sector() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+        let data: Vec<Byte> = (0..256).map(|n| !(n as Byte)).collect();
+        fdc.write_byte(FDC + REGISTER_SECTOR, 3)?;
+
+        let (status, _) = command(&mut fdc, WRITE_SECTOR, &data)?;
+        assert_eq!(status & 0x5f, 0);
+
+        let image = fdc.drives[0].image.as_ref().unwrap();
+        assert_eq!(image.sector(0, 0, 3).unwrap(), &data[..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_protected() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+        fdc.drives[0].image.as_mut().unwrap().write_protected = true;
+
+        let (status, _) = command(&mut fdc, WRITE_SECTOR, &[])?;
+        assert_eq!(
+            status & StatusBits::WriteProtect as u8,
+            StatusBits::WriteProtect as u8
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_address() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+        fdc.write_byte(FDC + REGISTER_DATA, 4)?;
+        command(&mut fdc, SEEK, &[])?;
+
+        let (status, data) = command(&mut fdc, READ_ADDRESS, &[])?;
+        assert_eq!(status & 0x1f, 0);
+        assert_eq!(&data[..4], &[4, 0, 0, 1]);
+        assert_eq!(data.len(), 6);
+        assert_eq!(fdc.read_byte(FDC + REGISTER_SECTOR)?, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_track() -> Result<()> {
+        let mut fdc = fdc_with(Format::Ssd, MASTER_DFS)?;
+        let (_, data) = command(&mut fdc, READ_TRACK, &[])?;
+
+        assert_eq!(data.len(), FM_TRACK_LENGTH);
+        let id = data.iter().position(|&b| b == ID_ADDRESS_MARK).unwrap();
+        assert_eq!(&data[id + 1..id + 5], &[0, 0, 0, 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_track_formats_sectors() -> Result<()> {
+        let mut fdc = fdc_with(Format::Ssd, MASTER_DFS)?;
+
+        let mut raw = Vec::new();
+        for sector in 0..10 {
+            raw.extend(std::iter::repeat_n(0xff, 16));
+            raw.extend(std::iter::repeat_n(0x00, 6));
+            raw.extend_from_slice(&[ID_ADDRESS_MARK, 0, 0, sector, 1, 0xf7]);
+            raw.extend(std::iter::repeat_n(0xff, 11));
+            raw.extend(std::iter::repeat_n(0x00, 6));
+            raw.push(DATA_ADDRESS_MARK);
+            raw.extend(std::iter::repeat_n(0xe5, 256));
+            raw.push(0xf7);
+        }
+        raw.resize(FM_TRACK_LENGTH, 0xff);
+
+        let (status, _) = command(&mut fdc, WRITE_TRACK, &raw)?;
+        assert_eq!(status & 0x5f, 0);
+
+        let image = fdc.drives[0].image.as_ref().unwrap();
+        assert!(image.sector(0, 0, 0).unwrap().iter().all(|&b| b == 0xe5));
+        assert!(image.sector(0, 0, 9).unwrap().iter().all(|&b| b == 0xe5));
+        assert_eq!(image.sector(0, 1, 0).unwrap()[0], 16);
+
+        Ok(())
+    }
+
+    #[test]
+    fn force_interrupt() -> Result<()> {
+        let mut fdc = fdc_with(Format::Adf, MASTER_ADFS)?;
+        fdc.write_byte(FDC + REGISTER_STATUS, READ_SECTORS)?;
+        fdc.tick(1000);
+
+        fdc.write_byte(FDC + REGISTER_STATUS, 0xd0)?;
+        assert_eq!(fdc.status() & StatusBits::Busy as u8, 0);
+        assert!(!fdc.nmi());
+
+        fdc.write_byte(FDC + REGISTER_STATUS, 0xd8)?;
+        assert!(fdc.nmi());
+        fdc.read_byte(FDC + REGISTER_STATUS)?;
+        assert!(!fdc.nmi());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::BTreeSet;
+
+use crate::cpu::{
+    Address, AddressingMode, Byte, InstructionDecoder, Opcode, Result, Word, IRQ_VECTOR,
+    NMI_VECTOR, RESET_VECTOR,
+};
+use crate::disassembler::symbols::Symbols;
+use crate::disassembler::{Disassembler, Line, Statement};
+
+// Separates code from data by following every path through the code from its
+// entry points. Whatever no path reaches is data.
+
+const SIDEWAYS_BASE: Address = 0x8000;
+const LANGUAGE_ENTRY: Address = 0x8000;
+const SERVICE_ENTRY: Address = 0x8003;
+const ROM_TYPE: usize = 6;
+const COPYRIGHT_OFFSET: usize = 7;
+const HAS_LANGUAGE: Byte = 0x40;
+const HAS_SERVICE: Byte = 0x80;
+
+const DATA_PER_LINE: usize = 8;
+
+pub struct Analyser<'a, I: InstructionDecoder> {
+    decoder: &'a I,
+    entries: Vec<Address>,
+    symbols: Symbols,
+}
+
+pub struct Analysis {
+    pub base: Address,
+    pub lines: Vec<Line>,
+    // The names given, plus the entry points and labels for jump targets
+    pub symbols: Symbols,
+}
+
+// The vectors, if the image covers them, and the entries a sideways ROM's
+// header says it has
+fn entry_points(bytes: &[Byte], base: Address) -> Vec<(Address, &'static str)> {
+    let mut entries = Vec::new();
+    let byte = |address: Address| bytes.get(address.wrapping_sub(base) as usize).copied();
+    let word = |address: Address| {
+        Some(Word::from_le_bytes([
+            byte(address)?,
+            byte(address.wrapping_add(1))?,
+        ]))
+    };
+
+    if (base as usize) + bytes.len() > RESET_VECTOR as usize {
+        for (vector, name) in [
+            (NMI_VECTOR, "nmi"),
+            (RESET_VECTOR, "reset"),
+            (IRQ_VECTOR, "irq"),
+        ] {
+            if let Some(address) = word(vector) {
+                entries.push((address, name));
+            }
+        }
+    }
+
+    // The copyright string is how the MOS recognises a ROM
+    let copyright = bytes.get(COPYRIGHT_OFFSET).map(|&offset| offset as usize);
+    let recognised = copyright.and_then(|offset| bytes.get(offset..offset + 4)) == Some(b"\0(C)");
+    if base == SIDEWAYS_BASE && recognised {
+        let rom_type = bytes[ROM_TYPE];
+        if rom_type & HAS_LANGUAGE != 0 {
+            entries.push((LANGUAGE_ENTRY, "language"));
+        }
+        if rom_type & HAS_SERVICE != 0 {
+            entries.push((SERVICE_ENTRY, "service"));
+        }
+    }
+    entries
+}
+
+impl<'a, I: InstructionDecoder> Analyser<'a, I> {
+    pub fn new(decoder: &'a I) -> Self {
+        Analyser {
+            decoder,
+            entries: Vec::new(),
+            symbols: Symbols::new(),
+        }
+    }
+
+    // Code that's only reached indirectly, such as through a jump table
+    pub fn with_entry(mut self, address: Address) -> Self {
+        self.entries.push(address);
+        self
+    }
+
+    pub fn with_symbols(mut self, symbols: Symbols) -> Self {
+        self.symbols.extend(&symbols);
+        self
+    }
+
+    // Where execution can go after the instruction, other than into a
+    // subroutine that returns
+    fn successors(line: &Line) -> (Option<Address>, bool) {
+        let Statement::Instruction { opcode, mode, .. } = line.statement else {
+            return (None, false);
+        };
+        match (opcode, mode) {
+            (Opcode::BRK | Opcode::RTS | Opcode::RTI, _) => (None, false),
+            (Opcode::JMP, AddressingMode::Absolute) | (Opcode::BRA, _) => (line.target(), false),
+            (Opcode::JMP, _) => (None, false),
+            (Opcode::JSR, _) | (_, AddressingMode::Relative) => (line.target(), true),
+            _ => (None, true),
+        }
+    }
+
+    pub fn analyse(&self, bytes: &[Byte], base: Address) -> Result<Analysis> {
+        let disassembler = Disassembler::new(self.decoder);
+        let mut symbols = self.symbols.clone();
+        let offset = |address: Address| {
+            let offset = address.wrapping_sub(base) as usize;
+            (offset < bytes.len()).then_some(offset)
+        };
+
+        let mut pending: Vec<Address> = self.entries.clone();
+        for (address, name) in entry_points(bytes, base) {
+            if offset(address).is_some() && symbols.name(address).is_none() {
+                symbols.insert(address, name);
+            }
+            pending.push(address);
+        }
+
+        // Instructions by where they start, and every byte they cover
+        let mut code: Vec<Option<Line>> = vec![None; bytes.len()];
+        let mut covered = vec![false; bytes.len()];
+        while let Some(mut address) = pending.pop() {
+            // Paths end at anything that isn't an instruction, or overlaps
+            // one already found
+            while let Some(start) = offset(address) {
+                if covered[start] {
+                    break;
+                }
+                let line = disassembler.line(&bytes[start..], address)?;
+                if matches!(line.statement, Statement::Data(_))
+                    || covered[start..start + line.bytes.len()].contains(&true)
+                {
+                    break;
+                }
+                covered[start..start + line.bytes.len()].fill(true);
+
+                let (target, falls_through) = Self::successors(&line);
+                pending.extend(target);
+                address = line.next();
+                code[start] = Some(line);
+                if !falls_through {
+                    break;
+                }
+            }
+        }
+
+        let mut lines = Vec::new();
+        let mut start = 0;
+        while start < bytes.len() {
+            let line = code[start].take().unwrap_or_else(|| Line {
+                address: base.wrapping_add(start as Address),
+                bytes: vec![bytes[start]],
+                statement: Statement::Data(bytes[start]),
+            });
+            start += line.bytes.len();
+            lines.push(line);
+        }
+        symbols.add_labels(&lines);
+
+        Ok(Analysis {
+            base,
+            lines,
+            symbols,
+        })
+    }
+}
+
+impl Analysis {
+    pub fn is_code(&self, address: Address) -> bool {
+        self.lines.iter().any(|line| {
+            matches!(line.statement, Statement::Instruction { .. })
+                && (0..line.bytes.len()).any(|n| line.address.wrapping_add(n as Address) == address)
+        })
+    }
+
+    // BeebAsm source that assembles back to the same bytes. Names that aren't
+    // at the start of a line in the image are defined as constants first.
+    // Assemblers pick zero page addressing whenever they can, so absolute
+    // instructions with a zero page operand are written out as bytes.
+    pub fn source(&self) -> String {
+        let starts: BTreeSet<Address> = self.lines.iter().map(|line| line.address).collect();
+        let mut constants = BTreeSet::new();
+        for line in &self.lines {
+            if let Some(target) = line.target() {
+                if self.symbols.name(target).is_some() && !starts.contains(&target) {
+                    constants.insert(target);
+                }
+            }
+        }
+
+        let mut text = String::new();
+        for &address in &constants {
+            let name = self.symbols.name(address).unwrap_or_default();
+            text += &format!("{} = &{:04X}\n", name, address);
+        }
+        if !constants.is_empty() {
+            text += "\n";
+        }
+        text += &format!("ORG &{:04X}\n", self.base);
+
+        let mut data: Vec<Byte> = Vec::new();
+        let flush = |data: &mut Vec<Byte>, text: &mut String| {
+            for chunk in data.chunks(DATA_PER_LINE) {
+                let bytes: Vec<String> = chunk.iter().map(|b| format!("&{:02X}", b)).collect();
+                *text += &format!("    EQUB {}\n", bytes.join(","));
+            }
+            data.clear();
+        };
+
+        for line in &self.lines {
+            if let Some(name) = self.symbols.name(line.address) {
+                flush(&mut data, &mut text);
+                text += &format!(".{}\n", name);
+            }
+            match line.statement {
+                Statement::Data(byte) => data.push(byte),
+                Statement::Instruction { mode, operand, .. }
+                    if operand < 0x100
+                        && matches!(
+                            mode,
+                            AddressingMode::Absolute
+                                | AddressingMode::AbsoluteX
+                                | AddressingMode::AbsoluteY
+                        ) =>
+                {
+                    flush(&mut data, &mut text);
+                    let bytes: Vec<String> =
+                        line.bytes.iter().map(|b| format!("&{:02X}", b)).collect();
+                    text += &format!("    EQUB {} \\ {}\n", bytes.join(","), line.source());
+                }
+                Statement::Instruction { .. } => {
+                    flush(&mut data, &mut text);
+                    text += &format!("    {}\n", line.source_with(&self.symbols));
+                }
+            }
+        }
+        flush(&mut data, &mut text);
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instruction_decode::InstructionDecoder;
+
+    // A service ROM that prints something for service call 4
+    fn service_rom() -> Vec<Byte> {
+        let mut rom = vec![
+            0x00, 0x00, 0x00, // no language
+            0x4c, 0x0f, 0x80, // JMP &800F
+            0x82, 0x0a, 0x01, b'T', 0x00, b'(', b'C', b')', 0x00, // header
+            0xc9, 0x04, // CMP #&04
+            0xf0, 0x01, // BEQ &8014
+            0x60, // RTS
+            0x20, 0xee, 0xff, // JSR OSWRCH
+            0xad, 0x70, 0x00, // LDA &0070
+            0x60, // RTS
+            0x41, 0x42,
+        ];
+        rom.resize(0x20, 0x00);
+        rom
+    }
+
+    #[test]
+    fn sideways_rom_entries() -> Result<()> {
+        let decoder = InstructionDecoder::new();
+        let analysis = Analyser::new(&decoder)
+            .with_symbols(Symbols::mos())
+            .analyse(&service_rom(), 0x8000)?;
+
+        assert!(!analysis.is_code(0x8000));
+        assert!(analysis.is_code(0x8004));
+        assert!(!analysis.is_code(0x800a));
+        assert!(analysis.is_code(0x8019));
+        assert!(!analysis.is_code(0x801b));
+
+        assert_eq!(
+            analysis.source(),
+            "OSWRCH = &FFEE\n\
+             \n\
+             ORG &8000\n    \
+             EQUB &00,&00,&00\n\
+             .service\n    \
+             JMP L800F\n    \
+             EQUB &82,&0A,&01,&54,&00,&28,&43,&29\n    \
+             EQUB &00\n\
+             .L800F\n    \
+             CMP #&04\n    \
+             BEQ L8014\n    \
+             RTS\n\
+             .L8014\n    \
+             JSR OSWRCH\n    \
+             EQUB &AD,&70,&00 \\ LDA &0070\n    \
+             RTS\n    \
+             EQUB &41,&42,&00,&00,&00\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn vectors_and_user_entries() -> Result<()> {
+        let mut mos = vec![0xea; 0x10];
+        mos[0x0] = 0x40; // RTI
+        mos[0x1..0x4].copy_from_slice(&[0x4c, 0xf1, 0xff]); // JMP &FFF1
+        mos[0x4..0x6].copy_from_slice(&[0xd0, 0x02]); // BNE &FFF8
+        mos[0x6] = 0x02; // invalid
+        mos[0x8] = 0x60; // RTS
+        mos[0xa..0x10].copy_from_slice(&[0xf0, 0xff, 0xf1, 0xff, 0xf0, 0xff]);
+
+        let decoder = InstructionDecoder::new();
+        let analysis = Analyser::new(&decoder).analyse(&mos, 0xfff0)?;
+        assert!(analysis.is_code(0xfff0));
+        assert!(analysis.is_code(0xfff3));
+        assert!(!analysis.is_code(0xfff4));
+        assert_eq!(analysis.symbols.name(0xfff0), Some("nmi"));
+        assert_eq!(analysis.symbols.name(0xfff1), Some("reset"));
+
+        let analysis = Analyser::new(&decoder)
+            .with_entry(0xfff4)
+            .analyse(&mos, 0xfff0)?;
+        assert!(analysis.is_code(0xfff5));
+        assert!(!analysis.is_code(0xfff6));
+        assert!(analysis.is_code(0xfff8));
+        assert_eq!(analysis.symbols.name(0xfff8), Some("LFFF8"));
+
+        Ok(())
+    }
+}
@@ -1,8 +1,10 @@
 use std::marker::PhantomData;
 
 use crate::cpu::{registers::Registers, Address, Data, ExecutionResult, Memory, Opcode, Result};
+use crate::disassembler::symbols::Symbols;
 
 pub struct ExecutionUnit<M> {
+    symbols: Symbols,
     phantom: PhantomData<M>,
 }
 
@@ -12,9 +14,27 @@ where
 {
     pub fn new() -> Self {
         ExecutionUnit {
+            symbols: Symbols::new(),
             phantom: PhantomData,
         }
     }
+
+    // Addresses with a name are traced by it
+    pub fn with_symbols(symbols: Symbols) -> Self {
+        ExecutionUnit {
+            symbols,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<M> Default for ExecutionUnit<M>
+where
+    M: Memory,
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<M> crate::cpu::ExecutionUnit<M> for ExecutionUnit<M>
@@ -41,7 +61,10 @@ where
         }
 
         if let Some(a) = address {
-            diss += &format!(" &{:x}", a);
+            match self.symbols.name(a) {
+                Some(name) => diss += &format!(" {}", name),
+                None => diss += &format!(" &{:x}", a),
+            }
         }
         if let Some(d) = data {
             diss += &format!(" #{:x}", d)
@@ -49,4 +72,9 @@ where
         println!("{}", diss);
         Ok(ExecutionResult::None)
     }
+
+    fn interrupt(&self, vector: Address, _memory: &mut M, registers: &mut Registers) -> Result<()> {
+        println!("{:04x} : interrupt via &{:04x}", registers.pc, vector);
+        Ok(())
+    }
 }
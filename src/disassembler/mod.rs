@@ -1 +1,349 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::cpu::{
+    Address, AddressingMode, Byte, Error, ErrorType, InstructionDecoder, Memory, Opcode, Result,
+    Word,
+};
+
+pub mod analysis;
 pub mod execution;
+pub mod symbols;
+
+use self::symbols::Symbols;
+
+// Disassembly in BBC BASIC assembler syntax: hex as &1900, immediates as
+// #&12, and anything that isn't an instruction as EQUB.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Statement {
+    // The operand is the byte or word following the opcode, except for
+    // branches, where it's the target
+    Instruction {
+        opcode: Opcode,
+        mode: AddressingMode,
+        operand: Word,
+    },
+    Data(Byte),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub address: Address,
+    pub bytes: Vec<Byte>,
+    pub statement: Statement,
+}
+
+impl Line {
+    pub fn next(&self) -> Address {
+        self.address.wrapping_add(self.bytes.len() as Address)
+    }
+
+    // The address the operand refers to, if it refers to one
+    pub fn target(&self) -> Option<Address> {
+        match self.statement {
+            Statement::Instruction { mode, operand, .. } => match mode {
+                AddressingMode::Implicit
+                | AddressingMode::Accumulator
+                | AddressingMode::Immediate
+                | AddressingMode::None => None,
+                _ => Some(operand),
+            },
+            Statement::Data(_) => None,
+        }
+    }
+
+    // The statement as it would be written in the assembler
+    pub fn source(&self) -> String {
+        self.source_with(&Symbols::new())
+    }
+
+    // As source, with addresses that have a name replaced by it
+    pub fn source_with(&self, symbols: &Symbols) -> String {
+        match self.statement {
+            Statement::Instruction {
+                opcode,
+                mode,
+                operand,
+            } => {
+                let name = |digits: usize| match symbols.name(operand) {
+                    Some(name) => name.to_string(),
+                    None => format!("&{:0digits$X}", operand),
+                };
+                let operand = match mode {
+                    AddressingMode::Implicit | AddressingMode::None => String::new(),
+                    AddressingMode::Accumulator => " A".to_string(),
+                    AddressingMode::Immediate => format!(" #&{:02X}", operand),
+                    AddressingMode::ZeroPage => format!(" {}", name(2)),
+                    AddressingMode::ZeroPageX => format!(" {},X", name(2)),
+                    AddressingMode::ZeroPageY => format!(" {},Y", name(2)),
+                    AddressingMode::Relative | AddressingMode::Absolute => {
+                        format!(" {}", name(4))
+                    }
+                    AddressingMode::AbsoluteX => format!(" {},X", name(4)),
+                    AddressingMode::AbsoluteY => format!(" {},Y", name(4)),
+                    AddressingMode::Indirect => format!(" ({})", name(4)),
+                    AddressingMode::IndirectX => format!(" ({},X)", name(2)),
+                    AddressingMode::IndirectY => format!(" ({}),Y", name(2)),
+                    AddressingMode::ZeroPageIndirect => format!(" ({})", name(2)),
+                    AddressingMode::AbsoluteIndexedIndirect => format!(" ({},X)", name(4)),
+                };
+                format!("{:?}{}", opcode, operand)
+            }
+            Statement::Data(byte) => format!("EQUB &{:02X}", byte),
+        }
+    }
+}
+
+// Address, bytes and source, as in a monitor listing
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(
+            f,
+            "{:04X}  {:<9} {}",
+            self.address,
+            hex.join(" "),
+            self.source()
+        )
+    }
+}
+
+pub struct Disassembler<'a, I: InstructionDecoder> {
+    decoder: &'a I,
+    data: Vec<RangeInclusive<Address>>,
+}
+
+impl<'a, I: InstructionDecoder> Disassembler<'a, I> {
+    pub fn new(decoder: &'a I) -> Self {
+        Disassembler {
+            decoder,
+            data: Vec::new(),
+        }
+    }
+
+    // Bytes in the range are always data, such as tables within code
+    pub fn with_data(mut self, range: RangeInclusive<Address>) -> Self {
+        self.data.push(range);
+        self
+    }
+
+    fn is_data(&self, address: Address) -> bool {
+        self.data.iter().any(|range| range.contains(&address))
+    }
+
+    // Disassembles the statement at the start of the bytes, which are at the
+    // base address. Invalid opcodes, instructions that would run into data or
+    // off the end of the bytes, and the 65C02's multi-byte NOPs (which no
+    // assembler would produce) all become data. With no bytes at all there's
+    // nothing to disassemble.
+    pub fn line(&self, bytes: &[Byte], base: Address) -> Result<Line> {
+        let data = |byte: Byte| Line {
+            address: base,
+            bytes: vec![byte],
+            statement: Statement::Data(byte),
+        };
+
+        let Some(&opcode) = bytes.first() else {
+            return Err(Error::with_pc(base, ErrorType::MissingData));
+        };
+        if self.is_data(base) {
+            return Ok(data(opcode));
+        }
+        let instruction = self.decoder.decode(opcode)?;
+        let length = instruction.byte_length.max(1);
+        let operand_bytes = (1..length).map(|n| base.wrapping_add(n as Address));
+
+        if matches!(instruction.opcode, Opcode::Invalid(_))
+            || length > bytes.len()
+            || operand_bytes.clone().any(|address| self.is_data(address))
+            || (matches!(instruction.addressing_mode, AddressingMode::Implicit) && length > 1)
+        {
+            return Ok(data(opcode));
+        }
+
+        let bytes = bytes[..length].to_vec();
+        let mut operand = match length {
+            2 => bytes[1] as Word,
+            3 => Word::from_le_bytes([bytes[1], bytes[2]]),
+            _ => 0,
+        };
+        if matches!(instruction.addressing_mode, AddressingMode::Relative) {
+            operand = base
+                .wrapping_add(length as Address)
+                .wrapping_add(bytes[1] as i8 as Address);
+        }
+
+        Ok(Line {
+            address: base,
+            bytes,
+            statement: Statement::Instruction {
+                opcode: instruction.opcode,
+                mode: instruction.addressing_mode,
+                operand,
+            },
+        })
+    }
+
+    // Disassembles every byte, in order
+    pub fn disassemble(&self, bytes: &[Byte], base: Address) -> Result<Vec<Line>> {
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let line = self.line(&bytes[offset..], base.wrapping_add(offset as Address))?;
+            offset += line.bytes.len();
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+}
+
+// The lines as source, each under its label if it has one
+pub fn listing(lines: &[Line], symbols: &Symbols) -> String {
+    let mut text = String::new();
+    for line in lines {
+        if let Some(name) = symbols.name(line.address) {
+            text += &format!(".{}\n", name);
+        }
+        text += &format!("    {}\n", line.source_with(symbols));
+    }
+    text
+}
+
+// The instruction at address in memory
+pub fn line_at<M, I>(memory: &M, decoder: &I, address: Address) -> Result<Line>
+where
+    M: Memory,
+    I: InstructionDecoder,
+{
+    let length = decoder
+        .decode(memory.read_byte(address)?)?
+        .byte_length
+        .max(1);
+    let mut bytes = Vec::with_capacity(length);
+    for n in 0..length {
+        bytes.push(memory.read_byte(address.wrapping_add(n as Address))?);
+    }
+
+    Disassembler::new(decoder).line(&bytes, address)
+}
+
+// Disassembles the instruction at address in memory, returning it along with
+// the address of the next instruction
+pub fn disassemble<M, I>(memory: &M, decoder: &I, address: Address) -> Result<(String, Address)>
+where
+    M: Memory,
+    I: InstructionDecoder,
+{
+    let line = line_at(memory, decoder, address)?;
+    Ok((line.to_string(), line.next()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instruction_decode::InstructionDecoder;
+    use crate::cpu::ram::Ram;
+
+    fn line(bytes: &[u8]) -> Result<String> {
+        let mut memory = Ram::new(0x10000);
+        for (n, &b) in bytes.iter().enumerate() {
+            memory.write_byte(0x1900 + n as Address, b)?;
+        }
+        Ok(disassemble(&memory, &InstructionDecoder::new(), 0x1900)?.0)
+    }
+
+    #[test]
+    fn bbc_basic_syntax() -> Result<()> {
+        assert_eq!(line(&[0xb1, 0x70])?, "1900  B1 70     LDA (&70),Y");
+        assert_eq!(line(&[0x6c, 0x0e, 0x02])?, "1900  6C 0E 02  JMP (&020E)");
+        assert_eq!(line(&[0xd0, 0xfe])?, "1900  D0 FE     BNE &1900");
+        assert_eq!(line(&[0x0a])?, "1900  0A        ASL A");
+        assert_eq!(line(&[0x02])?, "1900  02        EQUB &02");
+
+        Ok(())
+    }
+
+    fn source(decoder: &InstructionDecoder, bytes: &[u8]) -> Result<Vec<String>> {
+        let lines = Disassembler::new(decoder).disassemble(bytes, 0x2000)?;
+        Ok(lines.iter().map(Line::source).collect())
+    }
+
+    #[test]
+    fn every_addressing_mode() -> Result<()> {
+        let nmos = InstructionDecoder::new();
+        let bytes = [
+            0xea, // NOP
+            0x6a, // ROR A
+            0x69, 0x01, // ADC #&01
+            0xa5, 0x70, // LDA &70
+            0xb5, 0x70, // LDA &70,X
+            0xb6, 0x70, // LDX &70,Y
+            0x10, 0x80, // BPL &1F8C
+            0x20, 0xe3, 0xff, // JSR &FFE3
+            0x7d, 0x00, 0x04, // ADC &0400,X
+            0x79, 0x00, 0x04, // ADC &0400,Y
+            0x6c, 0x0e, 0x02, // JMP (&020E)
+            0xa1, 0x80, // LDA (&80,X)
+            0x91, 0x80, // STA (&80),Y
+        ];
+        assert_eq!(
+            source(&nmos, &bytes)?,
+            [
+                "NOP",
+                "ROR A",
+                "ADC #&01",
+                "LDA &70",
+                "LDA &70,X",
+                "LDX &70,Y",
+                "BPL &1F8C",
+                "JSR &FFE3",
+                "ADC &0400,X",
+                "ADC &0400,Y",
+                "JMP (&020E)",
+                "LDA (&80,X)",
+                "STA (&80),Y",
+            ]
+        );
+
+        let cmos = InstructionDecoder::new_65c02();
+        assert_eq!(
+            source(&cmos, &[0xb2, 0x70, 0x7c, 0x00, 0x20, 0x80, 0x02])?,
+            ["LDA (&70)", "JMP (&2000,X)", "BRA &2009"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn data_and_invalid_bytes() -> Result<()> {
+        let nmos = InstructionDecoder::new();
+        // An invalid opcode, then an instruction cut off by the end
+        assert_eq!(
+            source(&nmos, &[0x02, 0xad, 0x00])?,
+            ["EQUB &02", "EQUB &AD", "BRK"]
+        );
+
+        // The 65C02's two byte NOPs
+        let cmos = InstructionDecoder::new_65c02();
+        assert_eq!(source(&cmos, &[0x02, 0xea])?, ["EQUB &02", "NOP"]);
+
+        // Instructions don't run into data
+        let lines = Disassembler::new(&nmos)
+            .with_data(0x2002..=0x2003)
+            .disassemble(&[0xa9, 0x41, 0x48, 0x49, 0x20, 0x00], 0x2000)?;
+        let sources: Vec<String> = lines.iter().map(Line::source).collect();
+        assert_eq!(
+            sources,
+            ["LDA #&41", "EQUB &48", "EQUB &49", "EQUB &20", "BRK"]
+        );
+        assert_eq!(lines[0].next(), 0x2002);
+
+        assert_eq!(
+            Disassembler::new(&nmos).line(&[], 0x2000).err(),
+            Some(Error::with_pc(0x2000, ErrorType::MissingData))
+        );
+
+        Ok(())
+    }
+}
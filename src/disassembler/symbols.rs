@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::cpu::{Address, AddressingMode, Error, ErrorType, Opcode, Result};
+use crate::disassembler::{Line, Statement};
+
+// The MOS entry points and vectors that any program might use
+const MOS: [(Address, &str); 40] = [
+    (0x0200, "USERV"),
+    (0x0202, "BRKV"),
+    (0x0204, "IRQ1V"),
+    (0x0206, "IRQ2V"),
+    (0x0208, "CLIV"),
+    (0x020a, "BYTEV"),
+    (0x020c, "WORDV"),
+    (0x020e, "WRCHV"),
+    (0x0210, "RDCHV"),
+    (0x0212, "FILEV"),
+    (0x0214, "ARGSV"),
+    (0x0216, "BGETV"),
+    (0x0218, "BPUTV"),
+    (0x021a, "GBPBV"),
+    (0x021c, "FINDV"),
+    (0x021e, "FSCV"),
+    (0x0220, "EVNTV"),
+    (0x0222, "UPTV"),
+    (0x0224, "NETV"),
+    (0x0226, "VDUV"),
+    (0x0228, "KEYV"),
+    (0x022a, "INSV"),
+    (0x022c, "REMV"),
+    (0x022e, "CNPV"),
+    (0xffb9, "OSRDRM"),
+    (0xffbc, "VDUCHR"),
+    (0xffbf, "OSEVEN"),
+    (0xffce, "OSFIND"),
+    (0xffd1, "OSGBPB"),
+    (0xffd4, "OSBPUT"),
+    (0xffd7, "OSBGET"),
+    (0xffda, "OSARGS"),
+    (0xffdd, "OSFILE"),
+    (0xffe0, "OSRDCH"),
+    (0xffe3, "OSASCI"),
+    (0xffe7, "OSNEWL"),
+    (0xffee, "OSWRCH"),
+    (0xfff1, "OSWORD"),
+    (0xfff4, "OSBYTE"),
+    (0xfff7, "OSCLI"),
+];
+
+// Names for addresses, used in place of the address in disassembly
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    names: BTreeMap<Address, String>,
+}
+
+fn parse_value(text: &str) -> Option<Address> {
+    let text = text.trim();
+    if let Some(hex) = text
+        .strip_prefix('&')
+        .or_else(|| text.strip_prefix('$'))
+        .or_else(|| text.strip_prefix("0x"))
+    {
+        Address::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn mos() -> Self {
+        let mut symbols = Self::new();
+        for (address, name) in MOS {
+            symbols.insert(address, name);
+        }
+        symbols
+    }
+
+    // Reads symbols one per line, in any mix of these forms:
+    //
+    //   oswrch = &FFEE       our own, and BeebAsm's; $, 0x or decimal too
+    //   al 00FFEE .oswrch    ld65's VICE label file (-Ln)
+    //
+    // Blank lines and comments starting with ; or # are skipped. A later
+    // name for an address replaces an earlier one.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut symbols = Self::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split([';', '#']).next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || Error::without_pc(ErrorType::InvalidSymbol(n + 1));
+            let (name, address) = if let Some(label) = line.strip_prefix("al ") {
+                let (address, name) = label.trim().split_once(' ').ok_or_else(invalid)?;
+                let address = u32::from_str_radix(address, 16).map_err(|_| invalid())?;
+                let name = name.trim().trim_start_matches('.');
+                (name, Address::try_from(address).map_err(|_| invalid())?)
+            } else {
+                let (name, value) = line.split_once('=').ok_or_else(invalid)?;
+                let name = name.trim().trim_start_matches('.');
+                (name, parse_value(value).ok_or_else(invalid)?)
+            };
+
+            if !valid_name(name) {
+                return Err(invalid());
+            }
+            symbols.insert(address, name);
+        }
+        Ok(symbols)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn insert(&mut self, address: Address, name: &str) {
+        self.names.insert(address, name.to_string());
+    }
+
+    // Adds every symbol from other, replacing any names already given
+    pub fn extend(&mut self, other: &Symbols) {
+        self.names.extend(other.names.clone());
+    }
+
+    pub fn name(&self, address: Address) -> Option<&str> {
+        self.names.get(&address).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Address, &str)> {
+        self.names
+            .iter()
+            .map(|(&address, name)| (address, name.as_str()))
+    }
+
+    // Names the targets of branches, JSRs and JMPs that land on one of the
+    // lines, as L followed by the address, unless they already have a name
+    pub fn add_labels(&mut self, lines: &[Line]) {
+        for line in lines {
+            let Statement::Instruction { opcode, mode, .. } = line.statement else {
+                continue;
+            };
+            let jump = matches!(mode, AddressingMode::Relative)
+                || matches!(
+                    (opcode, mode),
+                    (Opcode::JSR | Opcode::JMP, AddressingMode::Absolute)
+                );
+            let Some(target) = line.target().filter(|_| jump) else {
+                continue;
+            };
+            if self.name(target).is_none() && lines.iter().any(|l| l.address == target) {
+                self.insert(target, &format!("L{:04X}", target));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instruction_decode::InstructionDecoder;
+    use crate::disassembler::{listing, Disassembler};
+
+    #[test]
+    fn label_files() -> Result<()> {
+        let symbols = Symbols::parse(
+            "; game labels\n\
+             start = &1900\n\
+             .table=$1A00\n\
+             \n\
+             lives = 112 # zero page\n\
+             al 001B00 .irq_handler\n",
+        )?;
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.name(0x1900), Some("start"));
+        assert_eq!(symbols.name(0x1a00), Some("table"));
+        assert_eq!(symbols.name(0x0070), Some("lives"));
+        assert_eq!(symbols.name(0x1b00), Some("irq_handler"));
+
+        assert_eq!(
+            Symbols::parse("start = &1900\n2nd = &1902\n"),
+            Err(Error::without_pc(ErrorType::InvalidSymbol(2)))
+        );
+        assert_eq!(
+            Symbols::parse("al 1900\n"),
+            Err(Error::without_pc(ErrorType::InvalidSymbol(1)))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn names_in_disassembly() -> Result<()> {
+        let decoder = InstructionDecoder::new();
+        let bytes = [
+            0xa9, 0x41, // LDA #&41
+            0x20, 0xee, 0xff, // JSR OSWRCH
+            0xc6, 0x70, // DEC lives
+            0xd0, 0xf7, // BNE L1900
+            0x6c, 0x0e, 0x02, // JMP (WRCHV)
+        ];
+        let lines = Disassembler::new(&decoder).disassemble(&bytes, 0x1900)?;
+
+        let mut symbols = Symbols::mos();
+        symbols.extend(&Symbols::parse("lives = &70")?);
+        symbols.add_labels(&lines);
+        assert_eq!(symbols.name(0x1900), Some("L1900"));
+
+        let source: Vec<String> = lines.iter().map(|l| l.source_with(&symbols)).collect();
+        assert_eq!(
+            source,
+            [
+                "LDA #&41",
+                "JSR OSWRCH",
+                "DEC lives",
+                "BNE L1900",
+                "JMP (WRCHV)"
+            ]
+        );
+        assert!(listing(&lines, &symbols).starts_with(".L1900\n    LDA #&41\n    JSR OSWRCH\n"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,905 @@
+use std::fmt;
+
+use crate::cpu::{Byte, Error, ErrorType, Result, Word};
+use crate::disc::image::DiscImage;
+
+// Acorn ADFS with the old (S/M/L) free space map:
+//
+//   sector 0: free space start sectors (3 bytes each), total sectors at &FC,
+//             checksum at &FF
+//   sector 1: free space lengths (3 bytes each), disc ID at &FB, boot option
+//             at &FD, length of the free space lists at &FE, checksum at &FF
+//   sectors 2-6: root directory
+//
+// Directories are five sectors in "Hugo" format: a sequence number and
+// "Hugo", up to 47 26-byte entries, then a tail holding the directory's name,
+// parent, title and the sequence number and "Hugo" again.
+
+pub const ROOT_SECTOR: u32 = 2;
+pub const DIRECTORY_SECTORS: u32 = 5;
+pub const MAX_ENTRIES: usize = 47;
+pub const MAX_NAME: usize = 10;
+pub const MAX_TITLE: usize = 19;
+pub const MAX_FREE_SPACE: usize = 82;
+
+const SECTOR_SIZE: usize = 256;
+const DIRECTORY_SIZE: usize = DIRECTORY_SECTORS as usize * SECTOR_SIZE;
+const HUGO: &[u8; 4] = b"Hugo";
+const ENTRY_SIZE: usize = 26;
+const TAIL: usize = 0x4cb;
+
+fn checksum(sector: &[Byte]) -> Byte {
+    let mut sum: u32 = 255;
+    for &b in sector[..255].iter().rev() {
+        if sum > 255 {
+            sum = (sum + 1) & 0xff;
+        }
+        sum += b as u32;
+    }
+    sum as Byte
+}
+
+fn read24(bytes: &[Byte]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0])
+}
+
+fn write24(bytes: &mut [Byte], value: u32) {
+    bytes.copy_from_slice(&value.to_le_bytes()[..3]);
+}
+
+fn read32(bytes: &[Byte]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn bcd_increment(value: Byte) -> Byte {
+    let value = (value >> 4) * 10 + (value & 0x0f);
+    let value = (value + 1) % 100;
+    ((value / 10) << 4) | (value % 10)
+}
+
+// Names are CR terminated when shorter than their field, with attributes
+// kept in the top bits
+fn read_name(bytes: &[Byte]) -> String {
+    bytes
+        .iter()
+        .map(|&b| b & 0x7f)
+        .take_while(|&b| b >= 0x20)
+        .map(|b| b as char)
+        .collect()
+}
+
+fn write_name(bytes: &mut [Byte], name: &str) {
+    bytes.fill(0x0d);
+    for (n, b) in name.bytes().take(bytes.len()).enumerate() {
+        bytes[n] = b;
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME
+        && name
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !".:*#$&@^%\\\"".contains(c))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Attributes {
+    pub read: bool,
+    pub write: bool,
+    pub locked: bool,
+    pub directory: bool,
+    pub execute_only: bool,
+}
+
+impl Attributes {
+    pub fn file() -> Self {
+        Attributes {
+            read: true,
+            write: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn directory() -> Self {
+        Attributes {
+            read: true,
+            locked: true,
+            directory: true,
+            ..Default::default()
+        }
+    }
+}
+
+// As *INFO shows them
+impl fmt::Display for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (set, c) in [
+            (self.directory, 'D'),
+            (self.execute_only, 'E'),
+            (self.locked, 'L'),
+            (self.read, 'R'),
+            (self.write, 'W'),
+        ] {
+            if set {
+                f.write_fmt(format_args!("{}", c))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub load_address: u32,
+    pub exec_address: u32,
+    pub length: u32,
+    pub start_sector: u32,
+    pub sequence: Byte,
+    pub attributes: Attributes,
+}
+
+impl Entry {
+    pub fn is_directory(&self) -> bool {
+        self.attributes.directory
+    }
+
+    pub fn sectors(&self) -> u32 {
+        self.length.div_ceil(SECTOR_SIZE as u32)
+    }
+
+    fn from_bytes(bytes: &[Byte]) -> Self {
+        let bit = |n: usize| bytes[n] & 0x80 == 0x80;
+        Entry {
+            name: read_name(&bytes[..MAX_NAME]),
+            load_address: read32(&bytes[10..14]),
+            exec_address: read32(&bytes[14..18]),
+            length: read32(&bytes[18..22]),
+            start_sector: read24(&bytes[22..25]),
+            sequence: bytes[25],
+            attributes: Attributes {
+                read: bit(0),
+                write: bit(1),
+                locked: bit(2),
+                directory: bit(3),
+                execute_only: bit(4),
+            },
+        }
+    }
+
+    fn to_bytes(&self, bytes: &mut [Byte]) {
+        write_name(&mut bytes[..MAX_NAME], &self.name);
+        let attributes = [
+            self.attributes.read,
+            self.attributes.write,
+            self.attributes.locked,
+            self.attributes.directory,
+            self.attributes.execute_only,
+        ];
+        for (n, &set) in attributes.iter().enumerate() {
+            if set {
+                bytes[n] |= 0x80;
+            }
+        }
+
+        bytes[10..14].copy_from_slice(&self.load_address.to_le_bytes());
+        bytes[14..18].copy_from_slice(&self.exec_address.to_le_bytes());
+        bytes[18..22].copy_from_slice(&self.length.to_le_bytes());
+        write24(&mut bytes[22..25], self.start_sector);
+        bytes[25] = self.sequence;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directory {
+    pub name: String,
+    pub title: String,
+    pub parent: u32,
+    pub sequence: Byte,
+    pub entries: Vec<Entry>,
+}
+
+impl Directory {
+    pub fn new(name: &str, parent: u32) -> Self {
+        Directory {
+            name: name.to_string(),
+            title: name.to_string(),
+            parent,
+            sequence: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[Byte]) -> Result<Self> {
+        let invalid = || Error::without_pc(ErrorType::InvalidDiscImage);
+
+        if bytes.len() < DIRECTORY_SIZE
+            || &bytes[1..5] != HUGO
+            || &bytes[0x4fb..0x4ff] != HUGO
+            || bytes[0] != bytes[0x4fa]
+        {
+            return Err(invalid());
+        }
+
+        let mut directory = Directory {
+            name: read_name(&bytes[0x4cc..0x4d6]),
+            title: read_name(&bytes[0x4d9..0x4ec]),
+            parent: read24(&bytes[0x4d6..0x4d9]),
+            sequence: bytes[0],
+            entries: Vec::new(),
+        };
+
+        for n in 0..MAX_ENTRIES {
+            let offset = 5 + n * ENTRY_SIZE;
+            if bytes[offset] == 0x00 {
+                break;
+            }
+            directory
+                .entries
+                .push(Entry::from_bytes(&bytes[offset..offset + ENTRY_SIZE]));
+        }
+
+        Ok(directory)
+    }
+
+    pub fn to_bytes(&self) -> Vec<Byte> {
+        let mut bytes = vec![0; DIRECTORY_SIZE];
+        bytes[0] = self.sequence;
+        bytes[1..5].copy_from_slice(HUGO);
+
+        for (n, entry) in self.entries.iter().take(MAX_ENTRIES).enumerate() {
+            let offset = 5 + n * ENTRY_SIZE;
+            entry.to_bytes(&mut bytes[offset..offset + ENTRY_SIZE]);
+        }
+
+        bytes[TAIL] = 0x00;
+        write_name(&mut bytes[0x4cc..0x4d6], &self.name);
+        write24(&mut bytes[0x4d6..0x4d9], self.parent);
+        write_name(&mut bytes[0x4d9..0x4ec], &self.title);
+        bytes[0x4fa] = self.sequence;
+        bytes[0x4fb..0x4ff].copy_from_slice(HUGO);
+        bytes
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|e| e.name.eq_ignore_ascii_case(name))
+    }
+
+    // ADFS keeps directories in case-insensitive alphabetical order
+    fn insert(&mut self, entry: Entry) {
+        self.entries
+            .retain(|e| !e.name.eq_ignore_ascii_case(&entry.name));
+        self.entries.push(entry);
+        self.entries.sort_by_key(|e| e.name.to_ascii_uppercase());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FreeSpaceMap {
+    pub sectors: u32,
+    pub disc_id: Word,
+    pub boot_option: Byte,
+    // Start sector and length of each free area, in ascending order
+    pub free: Vec<(u32, u32)>,
+}
+
+impl FreeSpaceMap {
+    pub fn new(sectors: u32) -> Self {
+        let first = ROOT_SECTOR + DIRECTORY_SECTORS;
+        FreeSpaceMap {
+            sectors,
+            disc_id: 0,
+            boot_option: 0,
+            free: vec![(first, sectors - first)],
+        }
+    }
+
+    pub fn from_sectors(sector0: &[Byte], sector1: &[Byte]) -> Result<Self> {
+        let invalid = || Error::without_pc(ErrorType::InvalidDiscImage);
+
+        let count = sector1[0xfe] as usize;
+        if checksum(sector0) != sector0[0xff]
+            || checksum(sector1) != sector1[0xff]
+            || !count.is_multiple_of(3)
+            || count / 3 > MAX_FREE_SPACE
+        {
+            return Err(invalid());
+        }
+
+        let free = (0..count / 3)
+            .map(|n| (read24(&sector0[n * 3..]), read24(&sector1[n * 3..])))
+            .collect();
+
+        Ok(FreeSpaceMap {
+            sectors: read24(&sector0[0xfc..]),
+            disc_id: Word::from_le_bytes([sector1[0xfb], sector1[0xfc]]),
+            boot_option: sector1[0xfd],
+            free,
+        })
+    }
+
+    pub fn to_sectors(&self) -> (Vec<Byte>, Vec<Byte>) {
+        let mut sector0 = vec![0; SECTOR_SIZE];
+        let mut sector1 = vec![0; SECTOR_SIZE];
+
+        for (n, &(start, length)) in self.free.iter().enumerate() {
+            write24(&mut sector0[n * 3..n * 3 + 3], start);
+            write24(&mut sector1[n * 3..n * 3 + 3], length);
+        }
+
+        write24(&mut sector0[0xfc..0xff], self.sectors);
+        sector1[0xfb..0xfd].copy_from_slice(&self.disc_id.to_le_bytes());
+        sector1[0xfd] = self.boot_option;
+        sector1[0xfe] = (self.free.len() * 3) as Byte;
+
+        sector0[0xff] = checksum(&sector0);
+        sector1[0xff] = checksum(&sector1);
+        (sector0, sector1)
+    }
+
+    pub fn free_sectors(&self) -> u32 {
+        self.free.iter().map(|&(_, length)| length).sum()
+    }
+
+    // First fit, as ADFS does
+    pub fn allocate(&mut self, sectors: u32) -> Option<u32> {
+        if sectors == 0 {
+            return Some(0);
+        }
+
+        let n = self
+            .free
+            .iter()
+            .position(|&(_, length)| length >= sectors)?;
+        let (start, length) = self.free[n];
+        if length == sectors {
+            self.free.remove(n);
+        } else {
+            self.free[n] = (start + sectors, length - sectors);
+        }
+        Some(start)
+    }
+
+    // Returns an area to the map, merging it with its neighbours
+    pub fn release(&mut self, start: u32, sectors: u32) -> Result<()> {
+        if sectors == 0 {
+            return Ok(());
+        }
+
+        let n = self.free.partition_point(|&(s, _)| s < start);
+        self.free.insert(n, (start, sectors));
+
+        if n + 1 < self.free.len() && start + sectors == self.free[n + 1].0 {
+            self.free[n].1 += self.free[n + 1].1;
+            self.free.remove(n + 1);
+        }
+        if n > 0 && self.free[n - 1].0 + self.free[n - 1].1 == start {
+            self.free[n - 1].1 += self.free[n].1;
+            self.free.remove(n);
+        }
+
+        if self.free.len() > MAX_FREE_SPACE {
+            return Err(Error::without_pc(ErrorType::DiscFull));
+        }
+        Ok(())
+    }
+}
+
+// An ADFS filing system on an image. Paths are dot-separated from the root,
+// with or without a leading "$."
+pub struct Adfs<'a> {
+    image: &'a mut DiscImage,
+    map: FreeSpaceMap,
+}
+
+impl<'a> Adfs<'a> {
+    pub fn open(image: &'a mut DiscImage) -> Result<Self> {
+        if !image.format.double_density() {
+            return Err(Error::without_pc(ErrorType::InvalidDiscImage));
+        }
+
+        let map = FreeSpaceMap::from_sectors(
+            image.file_sector(0).unwrap(),
+            image.file_sector(1).unwrap(),
+        )?;
+        let adfs = Adfs { image, map };
+        adfs.read_directory_at(ROOT_SECTOR)?;
+        Ok(adfs)
+    }
+
+    // Writes an empty map and root directory, as *FORM does
+    pub fn format(image: &'a mut DiscImage) -> Result<Self> {
+        if !image.format.double_density() {
+            return Err(Error::without_pc(ErrorType::InvalidDiscImage));
+        }
+
+        let geometry = image.geometry();
+        let sectors = geometry.sides * geometry.tracks * geometry.sectors_per_track;
+        let mut adfs = Adfs {
+            image,
+            map: FreeSpaceMap::new(sectors as u32),
+        };
+        adfs.write_map();
+        adfs.write_directory_at(ROOT_SECTOR, &Directory::new("$", ROOT_SECTOR));
+        Ok(adfs)
+    }
+
+    pub fn map(&self) -> &FreeSpaceMap {
+        &self.map
+    }
+
+    pub fn set_boot_option(&mut self, boot_option: Byte) {
+        self.map.boot_option = boot_option & 0x03;
+        self.write_map();
+    }
+
+    fn write_map(&mut self) {
+        let (sector0, sector1) = self.map.to_sectors();
+        self.image
+            .file_sector_mut(0)
+            .unwrap()
+            .copy_from_slice(&sector0);
+        self.image
+            .file_sector_mut(1)
+            .unwrap()
+            .copy_from_slice(&sector1);
+    }
+
+    fn read_sectors(&self, start: u32, length: usize) -> Result<Vec<Byte>> {
+        let mut data = Vec::with_capacity(length);
+        let mut sector = start as usize;
+        while data.len() < length {
+            let bytes = self
+                .image
+                .file_sector(sector)
+                .ok_or_else(|| Error::without_pc(ErrorType::InvalidDiscImage))?;
+            let count = (length - data.len()).min(SECTOR_SIZE);
+            data.extend_from_slice(&bytes[..count]);
+            sector += 1;
+        }
+        Ok(data)
+    }
+
+    fn write_sectors(&mut self, start: u32, data: &[Byte]) {
+        for (n, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+            let sector = self.image.file_sector_mut(start as usize + n).unwrap();
+            sector[..chunk.len()].copy_from_slice(chunk);
+        }
+    }
+
+    fn read_directory_at(&self, sector: u32) -> Result<Directory> {
+        Directory::from_bytes(&self.read_sectors(sector, DIRECTORY_SIZE)?)
+    }
+
+    fn write_directory_at(&mut self, sector: u32, directory: &Directory) {
+        self.write_sectors(sector, &directory.to_bytes());
+    }
+
+    fn split_path(path: &str) -> Result<Vec<String>> {
+        if path.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let components: Vec<&str> = path.split('.').collect();
+        let components = match components.first() {
+            Some(&"$") => &components[1..],
+            _ => &components[..],
+        };
+
+        if components.iter().all(|c| valid_name(c)) {
+            Ok(components.iter().map(|c| c.to_string()).collect())
+        } else {
+            Err(Error::without_pc(ErrorType::InvalidFilename(
+                path.to_string(),
+            )))
+        }
+    }
+
+    // Walks down the path, returning the sector of the final directory
+    fn directory_sector(&self, path: &str, components: &[String]) -> Result<u32> {
+        let mut sector = ROOT_SECTOR;
+        for name in components {
+            let directory = self.read_directory_at(sector)?;
+            sector = match directory.find(name) {
+                Some(entry) if entry.is_directory() => entry.start_sector,
+                _ => return Err(Error::without_pc(ErrorType::FileNotFound(path.to_string()))),
+            };
+        }
+        Ok(sector)
+    }
+
+    // The directory holding the last path component, its sector and the
+    // leaf name
+    fn parent(&self, path: &str) -> Result<(u32, Directory, String)> {
+        let mut components = Adfs::split_path(path)?;
+        let leaf = components
+            .pop()
+            .ok_or_else(|| Error::without_pc(ErrorType::InvalidFilename(path.to_string())))?;
+        let sector = self.directory_sector(path, &components)?;
+        Ok((sector, self.read_directory_at(sector)?, leaf))
+    }
+
+    fn update_directory(&mut self, sector: u32, directory: &mut Directory) {
+        directory.sequence = bcd_increment(directory.sequence);
+        self.write_directory_at(sector, directory);
+    }
+
+    pub fn read_directory(&self, path: &str) -> Result<Directory> {
+        let components = Adfs::split_path(path)?;
+        let sector = self.directory_sector(path, &components)?;
+        self.read_directory_at(sector)
+    }
+
+    pub fn entry(&self, path: &str) -> Result<Entry> {
+        let (_, directory, leaf) = self.parent(path)?;
+        directory
+            .find(&leaf)
+            .cloned()
+            .ok_or_else(|| Error::without_pc(ErrorType::FileNotFound(path.to_string())))
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<Vec<Byte>> {
+        let entry = self.entry(path)?;
+        if entry.is_directory() {
+            return Err(Error::without_pc(ErrorType::FileNotFound(path.to_string())));
+        }
+        self.read_sectors(entry.start_sector, entry.length as usize)
+    }
+
+    // Saves a file, replacing any unlocked file of the same name
+    pub fn write_file(
+        &mut self,
+        path: &str,
+        load_address: u32,
+        exec_address: u32,
+        data: &[Byte],
+    ) -> Result<()> {
+        self.add_entry(
+            path,
+            Entry {
+                name: String::new(),
+                load_address,
+                exec_address,
+                length: data.len() as u32,
+                start_sector: 0,
+                sequence: 0,
+                attributes: Attributes::file(),
+            },
+            data,
+        )
+    }
+
+    pub fn create_directory(&mut self, path: &str) -> Result<()> {
+        let (parent, _, leaf) = self.parent(path)?;
+        let directory = Directory::new(&leaf, parent);
+
+        self.add_entry(
+            path,
+            Entry {
+                name: String::new(),
+                load_address: 0,
+                exec_address: 0,
+                length: DIRECTORY_SIZE as u32,
+                start_sector: 0,
+                sequence: 0,
+                attributes: Attributes::directory(),
+            },
+            &directory.to_bytes(),
+        )
+    }
+
+    fn add_entry(&mut self, path: &str, mut entry: Entry, data: &[Byte]) -> Result<()> {
+        let (sector, mut directory, leaf) = self.parent(path)?;
+        let map = self.map.clone();
+
+        if let Some(existing) = directory.find(&leaf) {
+            if existing.attributes.locked || existing.is_directory() {
+                return Err(Error::without_pc(ErrorType::FileLocked(path.to_string())));
+            }
+            self.map
+                .release(existing.start_sector, existing.sectors())?;
+        } else if directory.entries.len() == MAX_ENTRIES {
+            return Err(Error::without_pc(ErrorType::CatalogueFull));
+        }
+
+        let start = match self.map.allocate(entry.sectors()) {
+            Some(start) => start,
+            None => {
+                self.map = map;
+                return Err(Error::without_pc(ErrorType::DiscFull));
+            }
+        };
+
+        self.write_sectors(start, data);
+        entry.name = leaf;
+        entry.start_sector = start;
+        entry.sequence = bcd_increment(directory.sequence);
+        directory.insert(entry);
+
+        self.update_directory(sector, &mut directory);
+        self.write_map();
+        Ok(())
+    }
+
+    // Removes an unlocked file or empty directory. Directories are created
+    // locked, as ADFS does, so need unlocking first
+    pub fn delete(&mut self, path: &str) -> Result<()> {
+        let (sector, mut directory, leaf) = self.parent(path)?;
+        let entry = directory
+            .find(&leaf)
+            .cloned()
+            .ok_or_else(|| Error::without_pc(ErrorType::FileNotFound(path.to_string())))?;
+
+        if entry.is_directory()
+            && !self
+                .read_directory_at(entry.start_sector)?
+                .entries
+                .is_empty()
+        {
+            return Err(Error::without_pc(ErrorType::DirectoryNotEmpty(
+                path.to_string(),
+            )));
+        }
+        if entry.attributes.locked {
+            return Err(Error::without_pc(ErrorType::FileLocked(path.to_string())));
+        }
+
+        self.map.release(entry.start_sector, entry.sectors())?;
+        directory.entries.retain(|e| e != &entry);
+        self.update_directory(sector, &mut directory);
+        self.write_map();
+        Ok(())
+    }
+
+    pub fn set_attributes(&mut self, path: &str, attributes: Attributes) -> Result<()> {
+        let (sector, mut directory, leaf) = self.parent(path)?;
+        let entry = directory
+            .entries
+            .iter_mut()
+            .find(|e| e.name.eq_ignore_ascii_case(&leaf))
+            .ok_or_else(|| Error::without_pc(ErrorType::FileNotFound(path.to_string())))?;
+
+        // Whether an entry is a directory isn't an attribute that can change
+        entry.attributes = Attributes {
+            directory: entry.attributes.directory,
+            ..attributes
+        };
+        self.update_directory(sector, &mut directory);
+        Ok(())
+    }
+
+    pub fn set_title(&mut self, path: &str, title: &str) -> Result<()> {
+        let components = Adfs::split_path(path)?;
+        let sector = self.directory_sector(path, &components)?;
+        let mut directory = self.read_directory_at(sector)?;
+        directory.title = title.chars().take(MAX_TITLE).collect();
+        self.update_directory(sector, &mut directory);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disc::image::Format;
+
+    fn blank(format: Format, tracks: usize) -> DiscImage {
+        let mut image = DiscImage::new(format, tracks);
+        Adfs::format(&mut image).unwrap();
+        image
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(checksum(&[0; 256]), 0xff);
+
+        let mut sector = [0; 256];
+        sector[0] = 0x01;
+        sector[1] = 0xff;
+        // 255 + &FF carries, then + 1
+        assert_eq!(checksum(&sector), 0x00);
+    }
+
+    #[test]
+    fn format_sizes() -> Result<()> {
+        for (format, tracks, sectors) in [
+            (Format::Adf, 40, 640),
+            (Format::Adf, 80, 1280),
+            (Format::Adl, 80, 2560),
+        ] {
+            let mut image = blank(format, tracks);
+            let adfs = Adfs::open(&mut image)?;
+            assert_eq!(adfs.map().sectors, sectors);
+            assert_eq!(adfs.map().free, vec![(7, sectors - 7)]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn root_directory_layout() {
+        let image = blank(Format::Adf, 80);
+        let root = image.file_sector(2).unwrap();
+        assert_eq!(&root[..5], b"\0Hugo");
+        assert_eq!(root[5], 0x00);
+
+        let tail = image.file_sector(6).unwrap();
+        assert_eq!(&tail[0xcc..0xce], b"$\r");
+        assert_eq!(&tail[0xd6..0xd9], &[0x02, 0x00, 0x00]);
+        assert_eq!(&tail[0xfb..0xff], b"Hugo");
+    }
+
+    #[test]
+    fn corrupt_map_fails() {
+        let mut image = blank(Format::Adf, 80);
+        image.file_sector_mut(0).unwrap()[0] ^= 0xff;
+        assert!(Adfs::open(&mut image).is_err());
+    }
+
+    #[test]
+    fn dfs_image_is_not_adfs() {
+        let mut image = DiscImage::new(Format::Ssd, 80);
+        assert!(Adfs::format(&mut image).is_err());
+    }
+
+    #[test]
+    fn entry_layout() {
+        let entry = Entry {
+            name: "PROG".to_string(),
+            load_address: 0xffff1900,
+            exec_address: 0xffff8023,
+            length: 0x300,
+            start_sector: 0x123,
+            sequence: 0x05,
+            attributes: Attributes {
+                locked: true,
+                ..Attributes::file()
+            },
+        };
+
+        let mut bytes = [0; ENTRY_SIZE];
+        entry.to_bytes(&mut bytes);
+        assert_eq!(&bytes[..10], b"\xd0\xd2\xcfG\r\r\r\r\r\r");
+        assert_eq!(&bytes[10..14], &[0x00, 0x19, 0xff, 0xff]);
+        assert_eq!(&bytes[22..26], &[0x23, 0x01, 0x00, 0x05]);
+        assert_eq!(Entry::from_bytes(&bytes), entry);
+        assert_eq!(entry.attributes.to_string(), "LRW");
+    }
+
+    #[test]
+    fn write_and_read_files() -> Result<()> {
+        let mut image = blank(Format::Adf, 80);
+        let mut adfs = Adfs::open(&mut image)?;
+
+        let data: Vec<Byte> = (0..1000).map(|n| n as Byte).collect();
+        adfs.write_file("$.PROGRAM", 0xffff1900, 0xffff8023, &data)?;
+        adfs.write_file("!Boot", 0, 0, b"*RUN PROGRAM\r")?;
+
+        assert_eq!(adfs.read_file("program")?, data);
+        assert_eq!(adfs.read_file("$.!BOOT")?, b"*RUN PROGRAM\r");
+
+        let root = adfs.read_directory("$")?;
+        assert_eq!(root.entries[0].name, "!Boot");
+        assert_eq!(root.entries[1].start_sector, 7);
+        assert_eq!(root.entries[1].exec_address, 0xffff8023);
+        assert_eq!(root.sequence, 0x02);
+        assert_eq!(adfs.map().free, vec![(12, 1280 - 12)]);
+
+        let reopened = Adfs::open(&mut image)?;
+        assert_eq!(reopened.read_directory("")?.entries.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn nested_directories() -> Result<()> {
+        let mut image = blank(Format::Adl, 80);
+        let mut adfs = Adfs::open(&mut image)?;
+
+        adfs.create_directory("GAMES")?;
+        adfs.create_directory("$.GAMES.ARCADE")?;
+        adfs.write_file("GAMES.ARCADE.ELITE", 0x1900, 0x1900, &[0xea; 300])?;
+        adfs.set_title("GAMES", "Things to play")?;
+
+        let games = adfs.read_directory("$.GAMES")?;
+        assert_eq!(games.parent, ROOT_SECTOR);
+        assert_eq!(games.title, "Things to play");
+        assert!(games.entries[0].is_directory());
+
+        let arcade = adfs.read_directory("GAMES.ARCADE")?;
+        assert_eq!(arcade.name, "ARCADE");
+        assert_eq!(arcade.parent, 7);
+        assert_eq!(adfs.read_file("GAMES.ARCADE.ELITE")?, vec![0xea; 300]);
+
+        assert!(adfs.read_file("GAMES.MISSING.ELITE").is_err());
+        assert!(adfs.read_file("GAMES.ARCADE").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_merges_free_space() -> Result<()> {
+        let mut image = blank(Format::Adf, 80);
+        let mut adfs = Adfs::open(&mut image)?;
+
+        adfs.write_file("A", 0, 0, &[0; 512])?;
+        adfs.write_file("B", 0, 0, &[0; 512])?;
+        adfs.write_file("C", 0, 0, &[0; 512])?;
+        adfs.delete("A")?;
+        adfs.delete("C")?;
+        assert_eq!(adfs.map().free, vec![(7, 2), (11, 1280 - 11)]);
+
+        adfs.delete("B")?;
+        assert_eq!(adfs.map().free, vec![(7, 1280 - 7)]);
+        assert!(adfs.read_directory("$")?.entries.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn locked_files_and_full_directories() -> Result<()> {
+        let mut image = blank(Format::Adf, 80);
+        let mut adfs = Adfs::open(&mut image)?;
+
+        adfs.create_directory("D")?;
+        adfs.write_file("D.F", 0, 0, &[1])?;
+        assert_eq!(
+            adfs.delete("D"),
+            Err(Error::without_pc(ErrorType::DirectoryNotEmpty(
+                "D".to_string()
+            )))
+        );
+
+        adfs.set_attributes(
+            "D.F",
+            Attributes {
+                locked: true,
+                ..Attributes::file()
+            },
+        )?;
+        assert!(adfs.write_file("D.F", 0, 0, &[2]).is_err());
+        assert!(adfs.delete("D.F").is_err());
+
+        adfs.create_directory("E")?;
+        assert!(adfs.delete("E").is_err());
+        adfs.set_attributes("E", Attributes::default())?;
+        adfs.delete("E")?;
+
+        for n in 1..MAX_ENTRIES {
+            adfs.write_file(&format!("D.F{}", n), 0, 0, &[])?;
+        }
+        assert_eq!(
+            adfs.write_file("D.ONEMORE", 0, 0, &[]),
+            Err(Error::without_pc(ErrorType::CatalogueFull))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn disc_full() -> Result<()> {
+        let mut image = blank(Format::Adf, 40);
+        let mut adfs = Adfs::open(&mut image)?;
+
+        adfs.write_file("BIG", 0, 0, &vec![0; 600 * 256])?;
+        assert_eq!(
+            adfs.write_file("BIGGER", 0, 0, &vec![0; 100 * 256]),
+            Err(Error::without_pc(ErrorType::DiscFull))
+        );
+        assert_eq!(adfs.map().free_sectors(), 640 - 7 - 600);
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_names() {
+        let mut image = blank(Format::Adf, 80);
+        let mut adfs = Adfs::open(&mut image).unwrap();
+        assert!(adfs.write_file("ELEVENCHARS", 0, 0, &[]).is_err());
+        assert!(adfs.write_file("A B", 0, 0, &[]).is_err());
+        assert!(adfs.write_file("$.", 0, 0, &[]).is_err());
+    }
+}
@@ -0,0 +1,691 @@
+use crate::cpu::{Byte, Error, ErrorType, Result};
+use crate::disc::image::DiscImage;
+
+// Acorn DFS catalogue, held in the first two sectors of each side:
+//
+//   sector 0: title (first 8 chars), then per file 7 char name + directory
+//             (bit 7 set if locked)
+//   sector 1: title (last 4 chars), cycle number (BCD), file count * 8,
+//             boot option and sector count high bits, sector count, then per
+//             file load (2), exec (2), length (2), high bits (1), start sector
+//
+// Files are listed in descending order of start sector.
+
+pub const CATALOGUE_SECTORS: usize = 2;
+pub const MAX_FILES: usize = 31;
+pub const MAX_NAME: usize = 7;
+pub const MAX_TITLE: usize = 12;
+
+const SECTOR_SIZE: usize = 256;
+const ADDRESS_MASK: u32 = 0x3ffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootOption {
+    Off,
+    Load,
+    Run,
+    Exec,
+}
+
+impl BootOption {
+    pub fn from_bits(bits: Byte) -> Self {
+        match bits & 0x03 {
+            0 => BootOption::Off,
+            1 => BootOption::Load,
+            2 => BootOption::Run,
+            _ => BootOption::Exec,
+        }
+    }
+
+    pub fn bits(&self) -> Byte {
+        *self as Byte
+    }
+
+    // Accepts the *OPT 4 number or its name
+    pub fn parse(option: &str) -> Option<Self> {
+        match option.to_ascii_lowercase().as_str() {
+            "0" | "off" => Some(BootOption::Off),
+            "1" | "load" => Some(BootOption::Load),
+            "2" | "run" => Some(BootOption::Run),
+            "3" | "exec" => Some(BootOption::Exec),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BootOption::Off => "off",
+            BootOption::Load => "load",
+            BootOption::Run => "run",
+            BootOption::Exec => "exec",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub directory: char,
+    pub name: String,
+    // Addresses and length are 18 bits; &3xxxx means the I/O processor
+    pub load_address: u32,
+    pub exec_address: u32,
+    pub length: u32,
+    pub start_sector: usize,
+    pub locked: bool,
+}
+
+impl Entry {
+    pub fn full_name(&self) -> String {
+        format!("{}.{}", self.directory, self.name)
+    }
+
+    pub fn sectors(&self) -> usize {
+        (self.length as usize).div_ceil(SECTOR_SIZE)
+    }
+
+    fn matches(&self, directory: char, name: &str) -> bool {
+        self.directory.eq_ignore_ascii_case(&directory) && self.name.eq_ignore_ascii_case(name)
+    }
+}
+
+fn valid_char(c: char) -> bool {
+    c.is_ascii_graphic() && !".:*#\"".contains(c)
+}
+
+// Splits "D.NAME" into directory and name, defaulting the directory to $
+pub fn parse_filename(filename: &str) -> Result<(char, String)> {
+    let invalid = || Error::without_pc(ErrorType::InvalidFilename(filename.to_string()));
+
+    let chars: Vec<char> = filename.chars().collect();
+    let (directory, name) = if chars.len() > 2 && chars[1] == '.' {
+        (chars[0], &chars[2..])
+    } else {
+        ('$', &chars[..])
+    };
+
+    if !valid_char(directory) && directory != '$'
+        || name.is_empty()
+        || name.len() > MAX_NAME
+        || !name.iter().all(|&c| valid_char(c))
+    {
+        return Err(invalid());
+    }
+
+    Ok((directory, name.iter().collect()))
+}
+
+fn bcd_increment(value: Byte) -> Byte {
+    let value = (value >> 4) * 10 + (value & 0x0f);
+    let value = (value + 1) % 100;
+    ((value / 10) << 4) | (value % 10)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Catalogue {
+    pub title: String,
+    pub cycle: Byte,
+    pub boot_option: BootOption,
+    pub sectors: usize,
+    pub entries: Vec<Entry>,
+}
+
+impl Catalogue {
+    pub fn new(title: &str, sectors: usize) -> Self {
+        Catalogue {
+            title: title.chars().take(MAX_TITLE).collect(),
+            cycle: 0,
+            boot_option: BootOption::Off,
+            sectors,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn from_sectors(sector0: &[Byte], sector1: &[Byte]) -> Result<Self> {
+        let invalid = || Error::without_pc(ErrorType::InvalidDiscImage);
+
+        let count = sector1[5] as usize;
+        if !count.is_multiple_of(8) || count / 8 > MAX_FILES {
+            return Err(invalid());
+        }
+
+        let title: String = sector0[..8]
+            .iter()
+            .chain(&sector1[..4])
+            .take_while(|&&b| b != 0x00)
+            .map(|&b| (b & 0x7f) as char)
+            .collect();
+
+        let mut catalogue = Catalogue {
+            title: title.trim_end().to_string(),
+            cycle: sector1[4],
+            boot_option: BootOption::from_bits(sector1[6] >> 4),
+            sectors: ((sector1[6] as usize & 0x03) << 8) | sector1[7] as usize,
+            entries: Vec::new(),
+        };
+
+        for n in 0..count / 8 {
+            let name = &sector0[8 + n * 8..16 + n * 8];
+            let info = &sector1[8 + n * 8..16 + n * 8];
+            let high = info[6] as u32;
+
+            let mut load_address = u32::from_le_bytes([info[0], info[1], 0, 0]);
+            let mut exec_address = u32::from_le_bytes([info[2], info[3], 0, 0]);
+            let mut length = u32::from_le_bytes([info[4], info[5], 0, 0]);
+            load_address |= ((high >> 2) & 0x03) << 16;
+            exec_address |= ((high >> 6) & 0x03) << 16;
+            length |= ((high >> 4) & 0x03) << 16;
+
+            let entry = Entry {
+                directory: (name[7] & 0x7f) as char,
+                name: name[..7]
+                    .iter()
+                    .map(|&b| (b & 0x7f) as char)
+                    .collect::<String>()
+                    .trim_end()
+                    .to_string(),
+                load_address,
+                exec_address,
+                length,
+                start_sector: ((high as usize & 0x03) << 8) | info[7] as usize,
+                locked: name[7] & 0x80 == 0x80,
+            };
+
+            if entry.start_sector + entry.sectors() > catalogue.sectors {
+                return Err(invalid());
+            }
+            catalogue.entries.push(entry);
+        }
+
+        Ok(catalogue)
+    }
+
+    pub fn to_sectors(&self) -> (Vec<Byte>, Vec<Byte>) {
+        let mut sector0 = vec![0; SECTOR_SIZE];
+        let mut sector1 = vec![0; SECTOR_SIZE];
+
+        for (n, b) in self.title.bytes().take(MAX_TITLE).enumerate() {
+            if n < 8 {
+                sector0[n] = b;
+            } else {
+                sector1[n - 8] = b;
+            }
+        }
+
+        sector1[4] = self.cycle;
+        sector1[5] = (self.entries.len() * 8) as Byte;
+        sector1[6] = (self.boot_option.bits() << 4) | ((self.sectors >> 8) as Byte & 0x03);
+        sector1[7] = self.sectors as Byte;
+
+        for (n, entry) in self.entries.iter().enumerate() {
+            let name = &mut sector0[8 + n * 8..16 + n * 8];
+            name[..7].fill(b' ');
+            for (i, b) in entry.name.bytes().take(MAX_NAME).enumerate() {
+                name[i] = b;
+            }
+            name[7] = entry.directory as Byte | if entry.locked { 0x80 } else { 0x00 };
+
+            let info = &mut sector1[8 + n * 8..16 + n * 8];
+            info[0..2].copy_from_slice(&(entry.load_address as u16).to_le_bytes());
+            info[2..4].copy_from_slice(&(entry.exec_address as u16).to_le_bytes());
+            info[4..6].copy_from_slice(&(entry.length as u16).to_le_bytes());
+            info[6] = ((entry.exec_address >> 16) as Byte & 0x03) << 6
+                | ((entry.length >> 16) as Byte & 0x03) << 4
+                | ((entry.load_address >> 16) as Byte & 0x03) << 2
+                | ((entry.start_sector >> 8) as Byte & 0x03);
+            info[7] = entry.start_sector as Byte;
+        }
+
+        (sector0, sector1)
+    }
+
+    pub fn find(&self, filename: &str) -> Result<&Entry> {
+        let (directory, name) = parse_filename(filename)?;
+        self.entries
+            .iter()
+            .find(|e| e.matches(directory, &name))
+            .ok_or_else(|| Error::without_pc(ErrorType::FileNotFound(filename.to_string())))
+    }
+
+    pub fn used_sectors(&self) -> usize {
+        CATALOGUE_SECTORS + self.entries.iter().map(|e| e.sectors()).sum::<usize>()
+    }
+
+    pub fn free_sectors(&self) -> usize {
+        self.sectors.saturating_sub(self.used_sectors())
+    }
+
+    // First gap big enough, searching up from the catalogue
+    fn allocate(&self, sectors: usize) -> Option<usize> {
+        let mut entries: Vec<&Entry> = self.entries.iter().collect();
+        entries.sort_by_key(|e| e.start_sector);
+
+        let mut start = CATALOGUE_SECTORS;
+        for entry in entries {
+            if entry.start_sector >= start + sectors {
+                return Some(start);
+            }
+            start = start.max(entry.start_sector + entry.sectors());
+        }
+
+        if start + sectors <= self.sectors {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    fn sort(&mut self) {
+        self.entries
+            .sort_by_key(|e| std::cmp::Reverse(e.start_sector));
+    }
+}
+
+// A DFS catalogue on one side of an image
+pub struct Dfs<'a> {
+    image: &'a mut DiscImage,
+    side: usize,
+    catalogue: Catalogue,
+}
+
+impl<'a> Dfs<'a> {
+    pub fn open(image: &'a mut DiscImage, side: usize) -> Result<Self> {
+        if image.format.double_density() || side >= image.geometry().sides {
+            return Err(Error::without_pc(ErrorType::InvalidDiscImage));
+        }
+
+        let catalogue = {
+            let sector0 = image.logical_sector(side, 0).unwrap();
+            let sector1 = image.logical_sector(side, 1).unwrap();
+            Catalogue::from_sectors(sector0, sector1)?
+        };
+
+        Ok(Dfs {
+            image,
+            side,
+            catalogue,
+        })
+    }
+
+    // Writes an empty catalogue, as *FORM does
+    pub fn format(image: &'a mut DiscImage, side: usize, title: &str) -> Result<Self> {
+        if image.format.double_density() || side >= image.geometry().sides {
+            return Err(Error::without_pc(ErrorType::InvalidDiscImage));
+        }
+
+        let geometry = image.geometry();
+        let mut dfs = Dfs {
+            image,
+            side,
+            catalogue: Catalogue::new(title, geometry.tracks * geometry.sectors_per_track),
+        };
+        dfs.write_catalogue();
+        Ok(dfs)
+    }
+
+    pub fn catalogue(&self) -> &Catalogue {
+        &self.catalogue
+    }
+
+    fn write_catalogue(&mut self) {
+        let (sector0, sector1) = self.catalogue.to_sectors();
+        self.image
+            .logical_sector_mut(self.side, 0)
+            .unwrap()
+            .copy_from_slice(&sector0);
+        self.image
+            .logical_sector_mut(self.side, 1)
+            .unwrap()
+            .copy_from_slice(&sector1);
+    }
+
+    // Every change to the catalogue bumps the cycle number
+    fn update(&mut self) {
+        self.catalogue.cycle = bcd_increment(self.catalogue.cycle);
+        self.catalogue.sort();
+        self.write_catalogue();
+    }
+
+    fn read_sectors(&self, start: usize, length: usize) -> Vec<Byte> {
+        let mut data = Vec::with_capacity(length);
+        let mut sector = start;
+        while data.len() < length {
+            let bytes = self.image.logical_sector(self.side, sector).unwrap();
+            let count = (length - data.len()).min(SECTOR_SIZE);
+            data.extend_from_slice(&bytes[..count]);
+            sector += 1;
+        }
+        data
+    }
+
+    fn write_sectors(&mut self, start: usize, data: &[Byte]) {
+        for (n, chunk) in data.chunks(SECTOR_SIZE).enumerate() {
+            let sector = self.image.logical_sector_mut(self.side, start + n).unwrap();
+            sector[..chunk.len()].copy_from_slice(chunk);
+        }
+    }
+
+    pub fn read_file(&self, filename: &str) -> Result<Vec<Byte>> {
+        let entry = self.catalogue.find(filename)?;
+        Ok(self.read_sectors(entry.start_sector, entry.length as usize))
+    }
+
+    // Saves a file, replacing any unlocked file of the same name
+    pub fn write_file(
+        &mut self,
+        filename: &str,
+        load_address: u32,
+        exec_address: u32,
+        data: &[Byte],
+    ) -> Result<()> {
+        let (directory, name) = parse_filename(filename)?;
+        if data.len() > ADDRESS_MASK as usize {
+            return Err(Error::without_pc(ErrorType::DiscFull));
+        }
+
+        if let Ok(existing) = self.catalogue.find(filename) {
+            if existing.locked {
+                return Err(Error::without_pc(ErrorType::FileLocked(
+                    filename.to_string(),
+                )));
+            }
+            self.catalogue
+                .entries
+                .retain(|e| !e.matches(directory, &name));
+        } else if self.catalogue.entries.len() == MAX_FILES {
+            return Err(Error::without_pc(ErrorType::CatalogueFull));
+        }
+
+        let sectors = data.len().div_ceil(SECTOR_SIZE);
+        let start_sector = match self.catalogue.allocate(sectors) {
+            Some(start) => start,
+            None => {
+                // Leave the catalogue as it was on disc
+                self.catalogue = Catalogue::from_sectors(
+                    self.image.logical_sector(self.side, 0).unwrap(),
+                    self.image.logical_sector(self.side, 1).unwrap(),
+                )?;
+                return Err(Error::without_pc(ErrorType::DiscFull));
+            }
+        };
+
+        self.write_sectors(start_sector, data);
+        self.catalogue.entries.push(Entry {
+            directory,
+            name,
+            load_address: load_address & ADDRESS_MASK,
+            exec_address: exec_address & ADDRESS_MASK,
+            length: data.len() as u32,
+            start_sector,
+            locked: false,
+        });
+        self.update();
+        Ok(())
+    }
+
+    pub fn delete_file(&mut self, filename: &str) -> Result<()> {
+        let entry = self.catalogue.find(filename)?;
+        if entry.locked {
+            return Err(Error::without_pc(ErrorType::FileLocked(
+                filename.to_string(),
+            )));
+        }
+
+        let (directory, name) = parse_filename(filename)?;
+        self.catalogue
+            .entries
+            .retain(|e| !e.matches(directory, &name));
+        self.update();
+        Ok(())
+    }
+
+    pub fn set_locked(&mut self, filename: &str, locked: bool) -> Result<()> {
+        let (directory, name) = parse_filename(filename)?;
+        let entry = self
+            .catalogue
+            .entries
+            .iter_mut()
+            .find(|e| e.matches(directory, &name))
+            .ok_or_else(|| Error::without_pc(ErrorType::FileNotFound(filename.to_string())))?;
+        entry.locked = locked;
+        self.update();
+        Ok(())
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.catalogue.title = title.chars().take(MAX_TITLE).collect();
+        self.update();
+    }
+
+    pub fn set_boot_option(&mut self, boot_option: BootOption) {
+        self.catalogue.boot_option = boot_option;
+        self.update();
+    }
+
+    // Moves every file down to close the gaps between them, as *COMPACT does
+    pub fn compact(&mut self) {
+        let mut entries = std::mem::take(&mut self.catalogue.entries);
+        entries.sort_by_key(|e| e.start_sector);
+
+        let mut next = CATALOGUE_SECTORS;
+        for entry in &mut entries {
+            if entry.start_sector != next {
+                let data = self.read_sectors(entry.start_sector, entry.sectors() * SECTOR_SIZE);
+                self.write_sectors(next, &data);
+                entry.start_sector = next;
+            }
+            next += entry.sectors();
+        }
+
+        self.catalogue.entries = entries;
+        self.update();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disc::image::Format;
+
+    fn blank(format: Format) -> DiscImage {
+        let mut image = DiscImage::new(format, 80);
+        Dfs::format(&mut image, 0, "TEST").unwrap();
+        image
+    }
+
+    #[test]
+    fn filenames() -> Result<()> {
+        assert_eq!(parse_filename("!BOOT")?, ('$', "!BOOT".to_string()));
+        assert_eq!(parse_filename("W.GAME")?, ('W', "GAME".to_string()));
+        assert_eq!(parse_filename("$.ELITE")?, ('$', "ELITE".to_string()));
+        assert!(parse_filename("TOOLONGX").is_err());
+        assert!(parse_filename("A B").is_err());
+        assert!(parse_filename("").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn bcd_cycle() {
+        assert_eq!(bcd_increment(0x00), 0x01);
+        assert_eq!(bcd_increment(0x09), 0x10);
+        assert_eq!(bcd_increment(0x99), 0x00);
+    }
+
+    #[test]
+    fn format_writes_empty_catalogue() -> Result<()> {
+        let mut image = blank(Format::Ssd);
+        let sector1 = image.logical_sector(0, 1).unwrap();
+        assert_eq!(&sector1[4..8], &[0x00, 0x00, 0x03, 0x20]);
+
+        let dfs = Dfs::open(&mut image, 0)?;
+        assert_eq!(dfs.catalogue().title, "TEST");
+        assert_eq!(dfs.catalogue().sectors, 800);
+        assert_eq!(dfs.catalogue().free_sectors(), 798);
+
+        Ok(())
+    }
+
+    #[test]
+    fn catalogue_layout() {
+        let mut catalogue = Catalogue::new("LONGER TITLE", 800);
+        catalogue.boot_option = BootOption::Exec;
+        catalogue.entries.push(Entry {
+            directory: 'W',
+            name: "GAME".to_string(),
+            load_address: 0x31900,
+            exec_address: 0x38023,
+            length: 0x10001,
+            start_sector: 0x102,
+            locked: true,
+        });
+
+        let (sector0, sector1) = catalogue.to_sectors();
+        assert_eq!(&sector0[..8], b"LONGER T");
+        assert_eq!(&sector1[..4], b"ITLE");
+        assert_eq!(&sector0[8..16], b"GAME   \xd7");
+        assert_eq!(sector1[5], 8);
+        assert_eq!(sector1[6], 0x33);
+        assert_eq!(sector1[7], 0x20);
+        assert_eq!(
+            &sector1[8..16],
+            &[0x00, 0x19, 0x23, 0x80, 0x01, 0x00, 0xdd, 0x02]
+        );
+
+        let parsed = Catalogue::from_sectors(&sector0, &sector1);
+        assert_eq!(parsed, Ok(catalogue));
+    }
+
+    #[test]
+    fn write_and_read_files() -> Result<()> {
+        let mut image = blank(Format::Ssd);
+        let mut dfs = Dfs::open(&mut image, 0)?;
+
+        let program: Vec<Byte> = (0..600).map(|n| n as Byte).collect();
+        dfs.write_file("PROGRAM", 0xffff1900, 0xffff8023, &program)?;
+        dfs.write_file("W.DATA", 0x3000, 0x3000, &[1, 2, 3])?;
+
+        assert_eq!(dfs.read_file("program")?, program);
+        assert_eq!(dfs.read_file("W.DATA")?, vec![1, 2, 3]);
+
+        let catalogue = dfs.catalogue();
+        assert_eq!(catalogue.entries[0].full_name(), "W.DATA");
+        assert_eq!(catalogue.entries[0].start_sector, 5);
+        assert_eq!(catalogue.entries[1].load_address, 0x31900);
+        assert_eq!(catalogue.cycle, 0x02);
+
+        let reopened = Dfs::open(&mut image, 0)?;
+        assert_eq!(reopened.catalogue().entries.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn replacing_a_file() -> Result<()> {
+        let mut image = blank(Format::Ssd);
+        let mut dfs = Dfs::open(&mut image, 0)?;
+
+        dfs.write_file("A", 0, 0, &[1; 512])?;
+        dfs.write_file("A", 0, 0, &[2; 10])?;
+        assert_eq!(dfs.catalogue().entries.len(), 1);
+        assert_eq!(dfs.read_file("A")?, vec![2; 10]);
+
+        dfs.set_locked("A", true)?;
+        assert_eq!(
+            dfs.write_file("A", 0, 0, &[]),
+            Err(Error::without_pc(ErrorType::FileLocked("A".to_string())))
+        );
+        assert!(dfs.delete_file("A").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_file() -> Result<()> {
+        let mut image = blank(Format::Ssd);
+        let dfs = Dfs::open(&mut image, 0)?;
+        assert_eq!(
+            dfs.read_file("NOPE"),
+            Err(Error::without_pc(ErrorType::FileNotFound(
+                "NOPE".to_string()
+            )))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn catalogue_full() -> Result<()> {
+        let mut image = blank(Format::Ssd);
+        let mut dfs = Dfs::open(&mut image, 0)?;
+
+        for n in 0..MAX_FILES {
+            dfs.write_file(&format!("F{}", n), 0, 0, &[n as Byte])?;
+        }
+        assert_eq!(
+            dfs.write_file("ONEMORE", 0, 0, &[]),
+            Err(Error::without_pc(ErrorType::CatalogueFull))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn disc_full_until_compacted() -> Result<()> {
+        let mut image = DiscImage::new(Format::Ssd, 40);
+        let mut dfs = Dfs::format(&mut image, 0, "")?;
+
+        dfs.write_file("A", 0, 0, &vec![0xaa; 100 * 256])?;
+        dfs.write_file("B", 0, 0, &vec![0xbb; 100 * 256])?;
+        dfs.write_file("C", 0, 0, &vec![0xcc; 100 * 256])?;
+        dfs.delete_file("A")?;
+        dfs.delete_file("C")?;
+
+        assert_eq!(
+            dfs.write_file("D", 0, 0, &vec![0xdd; 250 * 256]),
+            Err(Error::without_pc(ErrorType::DiscFull))
+        );
+        assert_eq!(dfs.catalogue().entries.len(), 1);
+
+        dfs.compact();
+        assert_eq!(dfs.catalogue().entries[0].start_sector, 2);
+        assert_eq!(dfs.read_file("B")?, vec![0xbb; 100 * 256]);
+
+        dfs.write_file("D", 0, 0, &vec![0xdd; 250 * 256])?;
+        assert_eq!(dfs.catalogue().free_sectors(), 398 - 350);
+
+        Ok(())
+    }
+
+    #[test]
+    fn title_and_boot_option() -> Result<()> {
+        let mut image = blank(Format::Ssd);
+        {
+            let mut dfs = Dfs::open(&mut image, 0)?;
+            dfs.set_title("A VERY LONG TITLE");
+            dfs.set_boot_option(BootOption::Exec);
+        }
+
+        let dfs = Dfs::open(&mut image, 0)?;
+        assert_eq!(dfs.catalogue().title, "A VERY LONG");
+        assert_eq!(dfs.catalogue().boot_option, BootOption::Exec);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dsd_sides_are_separate() -> Result<()> {
+        let mut image = blank(Format::Dsd);
+        Dfs::format(&mut image, 1, "SIDE2")?.write_file("X", 0, 0, &[9])?;
+
+        assert!(Dfs::open(&mut image, 0)?.catalogue().entries.is_empty());
+        assert_eq!(Dfs::open(&mut image, 1)?.read_file("X")?, vec![9]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn adfs_image_is_not_dfs() {
+        let mut image = DiscImage::new(Format::Adf, 80);
+        assert!(Dfs::open(&mut image, 0).is_err());
+    }
+}
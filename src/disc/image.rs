@@ -0,0 +1,341 @@
+use std::fs;
+use std::path::Path;
+
+use crate::cpu::{Byte, Error, ErrorType, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // Single-sided DFS
+    Ssd,
+    // Double-sided DFS, tracks interleaved side 0, side 1, side 0...
+    Dsd,
+    // Single-sided ADFS S (40 track) or M (80 track)
+    Adf,
+    // Double-sided ADFS L, tracks interleaved like DSD
+    Adl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub sides: usize,
+    pub tracks: usize,
+    pub sectors_per_track: usize,
+    pub sector_size: usize,
+}
+
+impl Geometry {
+    pub fn track_size(&self) -> usize {
+        self.sectors_per_track * self.sector_size
+    }
+
+    pub fn side_size(&self) -> usize {
+        self.tracks * self.track_size()
+    }
+
+    pub fn size(&self) -> usize {
+        self.sides * self.side_size()
+    }
+}
+
+impl Format {
+    pub fn from_extension(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "ssd" => Some(Format::Ssd),
+            "dsd" => Some(Format::Dsd),
+            "adf" => Some(Format::Adf),
+            "adl" => Some(Format::Adl),
+            _ => None,
+        }
+    }
+
+    pub fn sides(&self) -> usize {
+        match self {
+            Format::Ssd | Format::Adf => 1,
+            Format::Dsd | Format::Adl => 2,
+        }
+    }
+
+    pub fn interleaved(&self) -> bool {
+        *self == Format::Dsd || *self == Format::Adl
+    }
+
+    // DFS discs are FM, ADFS discs MFM
+    pub fn double_density(&self) -> bool {
+        *self == Format::Adf || *self == Format::Adl
+    }
+
+    // DFS discs are 10 sectors of 256 bytes per track and ADFS discs 16, both
+    // 40 or 80 tracks
+    pub fn geometry(&self, length: usize) -> Geometry {
+        let sides = self.sides();
+        let sectors_per_track = if self.double_density() { 16 } else { 10 };
+        let track_size = sectors_per_track * 256;
+        let tracks = if length <= 40 * track_size * sides && *self != Format::Adl {
+            40
+        } else {
+            80
+        };
+
+        Geometry {
+            sides,
+            tracks,
+            sectors_per_track,
+            sector_size: 256,
+        }
+    }
+}
+
+pub struct DiscImage {
+    pub format: Format,
+    pub write_protected: bool,
+    geometry: Geometry,
+
+    // Stored side by side, each side track by track
+    data: Vec<Byte>,
+}
+
+impl DiscImage {
+    pub fn new(format: Format, tracks: usize) -> Self {
+        let mut geometry = format.geometry(0);
+        geometry.tracks = tracks;
+
+        DiscImage {
+            format,
+            write_protected: false,
+            geometry,
+            data: vec![0xe5; geometry.size()],
+        }
+    }
+
+    pub fn from_bytes(format: Format, bytes: &[Byte]) -> Result<Self> {
+        let geometry = format.geometry(bytes.len());
+        if bytes.len() > geometry.size() {
+            return Err(Error::without_pc(ErrorType::InvalidDiscImage));
+        }
+
+        let mut image = DiscImage {
+            format,
+            write_protected: false,
+            geometry,
+            data: vec![0xe5; geometry.size()],
+        };
+
+        // Images are commonly truncated after the last used sector
+        let track_size = geometry.track_size();
+        for (n, track) in bytes.chunks(track_size).enumerate() {
+            let offset = image.file_track_offset(n);
+            image.data[offset..offset + track.len()].copy_from_slice(track);
+        }
+
+        Ok(image)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let format = Format::from_extension(path.as_ref())
+            .ok_or_else(|| Error::without_pc(ErrorType::InvalidDiscImage))?;
+        DiscImage::from_bytes(format, &fs::read(path)?)
+    }
+
+    pub fn to_bytes(&self) -> Vec<Byte> {
+        let track_size = self.geometry.track_size();
+        let tracks = self.geometry.sides * self.geometry.tracks;
+
+        let mut bytes = Vec::with_capacity(self.data.len());
+        for n in 0..tracks {
+            let offset = self.file_track_offset(n);
+            bytes.extend_from_slice(&self.data[offset..offset + track_size]);
+        }
+        bytes
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    // Maps the nth track in the file onto our side-by-side layout
+    fn file_track_offset(&self, n: usize) -> usize {
+        let (side, track) = if self.format.interleaved() {
+            (n % self.geometry.sides, n / self.geometry.sides)
+        } else {
+            (n / self.geometry.tracks, n % self.geometry.tracks)
+        };
+        side * self.geometry.side_size() + track * self.geometry.track_size()
+    }
+
+    fn sector_offset(&self, side: usize, track: usize, sector: usize) -> Option<usize> {
+        if side >= self.geometry.sides
+            || track >= self.geometry.tracks
+            || sector >= self.geometry.sectors_per_track
+        {
+            return None;
+        }
+
+        Some(
+            side * self.geometry.side_size()
+                + track * self.geometry.track_size()
+                + sector * self.geometry.sector_size,
+        )
+    }
+
+    pub fn sector(&self, side: usize, track: usize, sector: usize) -> Option<&[Byte]> {
+        let offset = self.sector_offset(side, track, sector)?;
+        Some(&self.data[offset..offset + self.geometry.sector_size])
+    }
+
+    pub fn sector_mut(&mut self, side: usize, track: usize, sector: usize) -> Option<&mut [Byte]> {
+        let offset = self.sector_offset(side, track, sector)?;
+        Some(&mut self.data[offset..offset + self.geometry.sector_size])
+    }
+
+    // Logical sectors count through one side from track 0, as DFS addresses them
+    pub fn logical_sector(&self, side: usize, sector: usize) -> Option<&[Byte]> {
+        let spt = self.geometry.sectors_per_track;
+        self.sector(side, sector / spt, sector % spt)
+    }
+
+    pub fn logical_sector_mut(&mut self, side: usize, sector: usize) -> Option<&mut [Byte]> {
+        let spt = self.geometry.sectors_per_track;
+        self.sector_mut(side, sector / spt, sector % spt)
+    }
+
+    fn file_sector_offset(&self, sector: usize) -> Option<usize> {
+        let spt = self.geometry.sectors_per_track;
+        if sector >= self.geometry.sides * self.geometry.tracks * spt {
+            return None;
+        }
+        Some(self.file_track_offset(sector / spt) + (sector % spt) * self.geometry.sector_size)
+    }
+
+    // Sectors in the order they appear in the image file, which is how ADFS
+    // numbers them across both sides
+    pub fn file_sector(&self, sector: usize) -> Option<&[Byte]> {
+        let offset = self.file_sector_offset(sector)?;
+        Some(&self.data[offset..offset + self.geometry.sector_size])
+    }
+
+    pub fn file_sector_mut(&mut self, sector: usize) -> Option<&mut [Byte]> {
+        let offset = self.file_sector_offset(sector)?;
+        Some(&mut self.data[offset..offset + self.geometry.sector_size])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn construct() {
+        let image = DiscImage::new(Format::Ssd, 80);
+        assert_eq!(image.to_bytes().len(), 80 * 10 * 256);
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(
+            Format::from_extension(Path::new("GAME.SSD")),
+            Some(Format::Ssd)
+        );
+        assert_eq!(
+            Format::from_extension(Path::new("a/b.dsd")),
+            Some(Format::Dsd)
+        );
+        assert_eq!(
+            Format::from_extension(Path::new("hd.ADL")),
+            Some(Format::Adl)
+        );
+        assert_eq!(Format::from_extension(Path::new("disc.img")), None);
+    }
+
+    #[test]
+    fn truncated_ssd_is_padded() -> Result<()> {
+        let image = DiscImage::from_bytes(Format::Ssd, &[0x41; 600])?;
+
+        assert_eq!(image.geometry().tracks, 40);
+        assert_eq!(image.sector(0, 0, 2).unwrap()[87], 0x41);
+        assert_eq!(image.sector(0, 0, 2).unwrap()[88], 0xe5);
+        assert_eq!(image.to_bytes().len(), 40 * 2560);
+
+        Ok(())
+    }
+
+    #[test]
+    fn large_ssd_is_80_tracks() -> Result<()> {
+        let image = DiscImage::from_bytes(Format::Ssd, &vec![0; 41 * 2560])?;
+        assert_eq!(image.geometry().tracks, 80);
+
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_image_fails() {
+        let result = DiscImage::from_bytes(Format::Ssd, &vec![0; 81 * 2560]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn dsd_tracks_are_interleaved() -> Result<()> {
+        let mut bytes = vec![0; 4 * 2560];
+        bytes[0] = 0x00;
+        bytes[2560] = 0x10;
+        bytes[2 * 2560] = 0x01;
+        bytes[3 * 2560] = 0x11;
+
+        let image = DiscImage::from_bytes(Format::Dsd, &bytes)?;
+        assert_eq!(image.sector(0, 0, 0).unwrap()[0], 0x00);
+        assert_eq!(image.sector(1, 0, 0).unwrap()[0], 0x10);
+        assert_eq!(image.sector(0, 1, 0).unwrap()[0], 0x01);
+        assert_eq!(image.sector(1, 1, 0).unwrap()[0], 0x11);
+
+        assert_eq!(&image.to_bytes()[..4 * 2560], &bytes[..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn adfs_geometries() -> Result<()> {
+        let s = DiscImage::from_bytes(Format::Adf, &vec![0; 160 * 1024])?;
+        assert_eq!(s.geometry().tracks, 40);
+        assert_eq!(s.geometry().sectors_per_track, 16);
+
+        let m = DiscImage::from_bytes(Format::Adf, &vec![0; 320 * 1024])?;
+        assert_eq!(m.geometry().tracks, 80);
+
+        let l = DiscImage::from_bytes(Format::Adl, &vec![0; 4096])?;
+        assert_eq!(l.geometry().tracks, 80);
+        assert_eq!(l.geometry().sides, 2);
+        assert_eq!(l.to_bytes().len(), 640 * 1024);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sector_out_of_range() {
+        let image = DiscImage::new(Format::Ssd, 40);
+        assert!(image.sector(1, 0, 0).is_none());
+        assert!(image.sector(0, 40, 0).is_none());
+        assert!(image.sector(0, 0, 10).is_none());
+    }
+
+    #[test]
+    fn file_sectors_follow_interleave() {
+        let mut image = DiscImage::new(Format::Adl, 80);
+        image.file_sector_mut(16).unwrap()[0] = 0xaa;
+        image.file_sector_mut(33).unwrap()[0] = 0xbb;
+        assert_eq!(image.sector(1, 0, 0).unwrap()[0], 0xaa);
+        assert_eq!(image.sector(0, 1, 1).unwrap()[0], 0xbb);
+        assert!(image.file_sector(2560).is_none());
+    }
+
+    #[test]
+    fn logical_sectors() {
+        let mut image = DiscImage::new(Format::Ssd, 40);
+        image.logical_sector_mut(0, 23).unwrap()[0] = 0xaa;
+        assert_eq!(image.sector(0, 2, 3).unwrap()[0], 0xaa);
+    }
+}
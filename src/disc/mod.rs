@@ -0,0 +1,3 @@
+pub mod adfs;
+pub mod dfs;
+pub mod image;
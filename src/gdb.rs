@@ -0,0 +1,525 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::cpu::debug::{Access, Break, Breakpoint, Watchpoint};
+use crate::cpu::dispatch::DispatchResult;
+use crate::cpu::{Address, Byte};
+use crate::machine::Machine;
+
+// A GDB remote serial protocol stub, so debuggers that speak it can drive
+// the machine over TCP. GDB has no 6502 target of its own, so registers use
+// the layout other 6502 stubs have settled on: A, X, Y, P and SP as a byte
+// each, then the PC as a little-endian word. P is laid out as the 6502 pushes
+// it, NV1BDIZC.
+//
+// Breakpoints are the dispatcher's rather than BRKs patched into memory,
+// which wouldn't work in ROM, but are offered as software breakpoints (Z0)
+// since that's what debuggers ask for first.
+
+const REGISTER_SIZES: [usize; 6] = [1, 1, 1, 1, 1, 2];
+
+// The largest packet either side sends, so the most memory one 'm' can read
+// is half this, at two hex digits a byte
+const PACKET_SIZE: usize = 0x4000;
+
+const SIGINT: Byte = 2;
+const SIGILL: Byte = 4;
+const SIGTRAP: Byte = 5;
+
+// How many instructions to run between checks for an interrupt from the
+// debugger while continuing
+const POLL_INTERVAL: usize = 10_000;
+
+const INTERRUPT: u8 = 0x03;
+
+// Waits for one debugger to connect to a local port, then serves it until it
+// detaches or kills the session
+pub fn serve<A: ToSocketAddrs>(machine: &mut Machine, address: A) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    Session::new(machine, stream).run()
+}
+
+fn hex(bytes: &[Byte]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<Byte>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|n| Byte::from_str_radix(text.get(n..n + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+// "addr,length" as used by memory and breakpoint packets
+fn parse_range(text: &str) -> Option<(Address, usize)> {
+    let (address, length) = text.split_once(',')?;
+    let address = Address::try_from(parse_hex(address)?).ok()?;
+    Some((address, parse_hex(length)?))
+}
+
+pub struct Session<'a> {
+    machine: &'a mut Machine,
+    stream: TcpStream,
+    acks: bool,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(machine: &'a mut Machine, stream: TcpStream) -> Self {
+        Session {
+            machine,
+            stream,
+            acks: true,
+        }
+    }
+
+    // Handles packets until the debugger detaches, kills the session or
+    // disconnects
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            match self.handle(&packet)? {
+                Some(reply) => self.send(&reply)?,
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // The next packet's contents, or None at the end of the connection.
+    // Acks and stray interrupts between packets are skipped.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            let mut sum: u8 = 0;
+            loop {
+                let Some(byte) = self.read_byte()? else {
+                    return Ok(None);
+                };
+                if byte == b'#' {
+                    break;
+                }
+                sum = sum.wrapping_add(byte);
+                data.push(byte);
+            }
+
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let checksum = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok());
+
+            if self.acks {
+                if checksum != Some(sum) {
+                    self.stream.write_all(b"-")?;
+                    continue;
+                }
+                self.stream.write_all(b"+")?;
+            }
+
+            // Binary data escapes '#', '$', '}' and '*' with '}'
+            let mut packet = Vec::with_capacity(data.len());
+            let mut bytes = data.into_iter();
+            while let Some(byte) = bytes.next() {
+                match byte {
+                    b'}' => packet.push(bytes.next().unwrap_or(0) ^ 0x20),
+                    _ => packet.push(byte),
+                }
+            }
+            return Ok(Some(String::from_utf8_lossy(&packet).into_owned()));
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let sum = reply.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        loop {
+            write!(self.stream, "${}#{:02x}", reply, sum)?;
+            self.stream.flush()?;
+            if !self.acks {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // The reply to a packet, or None to end the session
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => hex(&self.registers()),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "s" => self.step(),
+            "c" => self.resume()?,
+            "Z" => self.breakpoint(args, true),
+            "z" => self.breakpoint(args, false),
+            "H" => "OK".to_string(),
+            "D" => {
+                self.send("OK")?;
+                return Ok(None);
+            }
+            "k" => return Ok(None),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            format!("PacketSize={:x};swbreak+;QStartNoAckMode+", PACKET_SIZE)
+        } else if packet == "QStartNoAckMode" {
+            // Takes effect once this packet has been acknowledged
+            self.acks = false;
+            "OK".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn registers(&self) -> Vec<Byte> {
+        let r = self.machine.cpu().registers();
+        let p = r.status();
+        vec![r.a, r.x, r.y, p, r.sp, r.pc as Byte, (r.pc >> 8) as Byte]
+    }
+
+    fn set_registers(&mut self, bytes: &[Byte]) {
+        let r = self.machine.cpu_mut().registers_mut();
+        r.a = bytes[0];
+        r.x = bytes[1];
+        r.y = bytes[2];
+        r.set_status(bytes[3]);
+        r.sp = bytes[4];
+        r.pc = bytes[5] as Address | (bytes[6] as Address) << 8;
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        match unhex(args) {
+            Some(bytes) if bytes.len() == REGISTER_SIZES.iter().sum() => {
+                self.set_registers(&bytes);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    // Where register n starts in the 'g' packet, and its size
+    fn register(args: &str) -> Option<(usize, usize)> {
+        let n = parse_hex(args)?;
+        let size = *REGISTER_SIZES.get(n)?;
+        Some((REGISTER_SIZES[..n].iter().sum(), size))
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        match Self::register(args) {
+            Some((offset, size)) => hex(&self.registers()[offset..offset + size]),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let Some((n, value)) = args.split_once('=') else {
+            return "E01".to_string();
+        };
+        match (Self::register(n), unhex(value)) {
+            (Some((offset, size)), Some(value)) if value.len() == size => {
+                let mut bytes = self.registers();
+                bytes[offset..offset + size].copy_from_slice(&value);
+                self.set_registers(&bytes);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((address, length)) = parse_range(args) else {
+            return "E01".to_string();
+        };
+        if length > PACKET_SIZE / 2 {
+            return "E01".to_string();
+        }
+        let bus = self.machine.bus();
+        let bytes: Option<Vec<Byte>> = (0..length)
+            .map(|n| bus.peek(address.wrapping_add(n as Address)).ok())
+            .collect();
+        match bytes {
+            Some(bytes) => hex(&bytes),
+            None => "E02".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        let (Some((address, length)), Some(bytes)) = (parse_range(range), unhex(data)) else {
+            return "E01".to_string();
+        };
+        if bytes.len() != length {
+            return "E01".to_string();
+        }
+        let bus = self.machine.bus_mut();
+        for (n, byte) in bytes.into_iter().enumerate() {
+            if bus
+                .write_byte(address.wrapping_add(n as Address), byte)
+                .is_err()
+            {
+                return "E02".to_string();
+            }
+        }
+        "OK".to_string()
+    }
+
+    // Z0 is a software breakpoint, Z1 a hardware one (treated the same), and
+    // Z2 to Z4 are write, read and access watchpoints
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut fields = args.splitn(2, ',');
+        let (Some(kind), Some((address, length))) =
+            (fields.next(), fields.next().and_then(parse_range))
+        else {
+            return "E01".to_string();
+        };
+        let length = length.max(1) as Address;
+        let range = address..=address.wrapping_add(length - 1);
+
+        let cpu = self.machine.cpu_mut();
+        match (kind, insert) {
+            ("0" | "1", true) => cpu.add_breakpoint(Breakpoint::at(address)),
+            ("0" | "1", false) => {
+                let at = Breakpoint::at(address);
+                if let Some(n) = cpu.breakpoints().iter().position(|b| *b == at) {
+                    cpu.remove_breakpoint(n);
+                }
+            }
+            ("2" | "3" | "4", _) => {
+                let watchpoint = match kind {
+                    "2" => Watchpoint::write(range),
+                    "3" => Watchpoint::read(range),
+                    _ => Watchpoint::access(range),
+                };
+                let watched = cpu.watched_mut();
+                if insert {
+                    watched.add_watchpoint(watchpoint);
+                } else if let Some(n) = watched.watchpoints().iter().position(|w| *w == watchpoint)
+                {
+                    watched.remove_watchpoint(n);
+                }
+            }
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+
+    fn stop_reply(reason: Option<Break>) -> String {
+        match reason {
+            Some(Break::Watchpoint(access)) => {
+                let kind = match access.access {
+                    Access::Write => "watch",
+                    Access::Read => "rwatch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, kind, access.address)
+            }
+            Some(Break::Breakpoint(_)) => format!("T{:02x}swbreak:;", SIGTRAP),
+            None => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    // Always runs an instruction, even when sitting on a breakpoint
+    fn step(&mut self) -> String {
+        let mut result = self.machine.step();
+        if let Ok(DispatchResult::Break(Break::Breakpoint(_), _)) = result {
+            result = self.machine.step();
+        }
+        match result {
+            Ok(DispatchResult::Break(reason @ Break::Watchpoint(_), _)) => {
+                Self::stop_reply(Some(reason))
+            }
+            Ok(_) => Self::stop_reply(None),
+            Err(_) => format!("S{:02x}", SIGILL),
+        }
+    }
+
+    // Runs until a breakpoint or watchpoint, an error, or an interrupt from
+    // the debugger
+    fn resume(&mut self) -> io::Result<String> {
+        loop {
+            for _ in 0..POLL_INTERVAL {
+                match self.machine.step() {
+                    Ok(DispatchResult::Break(reason, _)) => {
+                        return Ok(Self::stop_reply(Some(reason)))
+                    }
+                    Ok(_) => {}
+                    Err(_) => return Ok(format!("S{:02x}", SIGILL)),
+                }
+            }
+
+            self.stream.set_nonblocking(true)?;
+            let mut byte = [0];
+            let read = self.stream.read(&mut byte);
+            self.stream.set_nonblocking(false)?;
+            match read {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(_) if byte[0] == INTERRUPT => return Ok(format!("S{:02x}", SIGINT)),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::acia::StatusBits as AciaStatus;
+    use crate::machine::replay::Input;
+    use crate::machine::tests::test_mos;
+    use crate::machine::{Model, Roms};
+    use std::thread;
+
+    fn checksum(data: &str) -> u8 {
+        data.bytes().fold(0, |sum, b| sum.wrapping_add(b))
+    }
+
+    // Sends each packet in turn and collects the replies, acking them as
+    // GDB would
+    fn client(address: std::net::SocketAddr, packets: &[&str]) -> thread::JoinHandle<Vec<String>> {
+        let packets: Vec<String> = packets.iter().map(|p| p.to_string()).collect();
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut replies = Vec::new();
+            for packet in packets {
+                write!(stream, "${}#{:02x}", packet, checksum(&packet)).unwrap();
+                let mut ack = [0];
+                stream.read_exact(&mut ack).unwrap();
+                assert_eq!(ack[0], b'+');
+                if packet == "k" {
+                    break;
+                }
+
+                let mut reply = Vec::new();
+                let mut byte = [0];
+                loop {
+                    stream.read_exact(&mut byte).unwrap();
+                    reply.push(byte[0]);
+                    if byte[0] == b'#' {
+                        break;
+                    }
+                }
+                let mut sum = [0; 2];
+                stream.read_exact(&mut sum).unwrap();
+                stream.write_all(b"+").unwrap();
+
+                let reply = String::from_utf8(reply).unwrap();
+                let reply = &reply[1..reply.len() - 1];
+                assert_eq!(
+                    u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap(),
+                    checksum(reply)
+                );
+                replies.push(reply.to_string());
+            }
+            replies
+        })
+    }
+
+    fn session(packets: &[&str]) -> (Machine, Vec<String>) {
+        session_on(
+            Machine::new(Model::B, Roms::new(test_mos())).unwrap(),
+            packets,
+        )
+    }
+
+    fn session_on(mut machine: Machine, packets: &[&str]) -> (Machine, Vec<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = client(listener.local_addr().unwrap(), packets);
+        let (stream, _) = listener.accept().unwrap();
+        Session::new(&mut machine, stream).run().unwrap();
+        (machine, client.join().unwrap())
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let (machine, replies) = session(&[
+            "?",
+            "g",
+            "P0=5a",
+            "p0",
+            "M1900,3:a9ff60",
+            "m1900,3",
+            "m1900,zz",
+            "m1900,2001",
+            "P3=c3",
+            "p3",
+            "qfoo",
+            "k",
+        ]);
+        assert_eq!(replies[0], "S05");
+        // Reset leaves I set
+        assert_eq!(replies[1], "00000024fd00c0");
+        assert_eq!(
+            replies[2..],
+            ["OK", "5a", "OK", "a9ff60", "E01", "E01", "OK", "e3", ""]
+        );
+        let registers = machine.cpu().registers();
+        assert_eq!(registers.a, 0x5a);
+        assert!(registers.negative() && registers.overflow() && registers.carry());
+        assert!(!registers.int());
+    }
+
+    #[test]
+    fn memory_reads_leave_devices_alone() {
+        let mut machine = Machine::new(Model::B, Roms::new(test_mos())).unwrap();
+        machine.input(Input::Serial(0x41)).unwrap();
+        let (mut machine, replies) = session_on(machine, &["mfe08,2", "D"]);
+        assert_eq!(replies, ["ffff", "OK"]);
+
+        let serial = machine.bus_mut().serial_mut().unwrap();
+        assert_eq!(serial.acia.status() & AciaStatus::ReceiveFull as u8, 1);
+    }
+
+    #[test]
+    fn step_and_breakpoints() {
+        let (machine, replies) = session(&["s", "pa", "Z0,c002,1", "c", "p5", "z0,c002,1", "D"]);
+        assert_eq!(
+            replies,
+            ["S05", "E01", "OK", "T05swbreak:;", "02c0", "OK", "OK"]
+        );
+        assert!(machine.cpu().breakpoints().is_empty());
+    }
+}
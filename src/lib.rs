@@ -0,0 +1,13 @@
+pub mod assembler;
+pub mod cpu;
+pub mod devices;
+pub mod disassembler;
+pub mod disc;
+pub mod gdb;
+pub mod machine;
+pub mod monitor;
+pub mod roms;
+pub mod snapshot;
+pub mod sound;
+pub mod tape;
+pub mod video;
@@ -0,0 +1,651 @@
+use std::any::Any;
+use std::path::Path;
+
+use crate::cpu::registers::StatusBits;
+use crate::cpu::{Address, Byte, Error, ErrorType, Result, Word};
+use crate::devices::fdc8271::{
+    self, SPECIAL_MODE, SPECIAL_OUTPUT_PORT, SPECIAL_SURFACE0_BAD_TRACK1,
+    SPECIAL_SURFACE0_BAD_TRACK2, SPECIAL_SURFACE0_TRACK, SPECIAL_SURFACE1_BAD_TRACK1,
+    SPECIAL_SURFACE1_BAD_TRACK2, SPECIAL_SURFACE1_TRACK, WRITE_SPECIAL_REGISTER,
+};
+use crate::devices::system_via::SystemVia;
+use crate::devices::via::{
+    Via, REGISTER_ACR, REGISTER_DDRA, REGISTER_DDRB, REGISTER_IER, REGISTER_ORA, REGISTER_ORB,
+    REGISTER_PCR, REGISTER_T1LH, REGISTER_T1LL,
+};
+use crate::devices::wd1770;
+use crate::machine::master128::{
+    Master128, ACCCON_X, ACCCON_Y, ANDY_SIZE, HAZEL_SIZE, ROMSEL_ANDY, SIDEWAYS_RAM_BANKS,
+};
+use crate::machine::model_b::ModelB;
+use crate::machine::model_b_plus::{
+    ModelBPlus, ACCCON_SHADOW, PRIVATE_SIZE, ROMSEL_PRIVATE, SIDEWAYS_RAM_BANKS_128,
+};
+use crate::machine::{Bus, Machine, Model, ROM_SIZE};
+use crate::tape::uef::{Chunk, Uef};
+
+// BeebEm saves its state as a UEF file with its own chunks, one per part of
+// the machine. The layouts are those written by BeebEm 4.x; see UEFState.cpp
+// and the Save*UEF functions in its source.
+
+pub const CPU_STATE: Word = 0x0460;
+pub const MEMORY_CONTROL: Word = 0x0461;
+pub const MAIN_RAM: Word = 0x0462;
+pub const SHADOW_RAM: Word = 0x0463;
+pub const PRIVATE_RAM: Word = 0x0464;
+pub const FILING_SYSTEM_RAM: Word = 0x0465;
+pub const SIDEWAYS_RAM: Word = 0x0466;
+pub const VIA_STATE: Word = 0x0467;
+pub const VIDEO_STATE: Word = 0x0468;
+pub const EMULATOR_STATE: Word = 0x046a;
+pub const SOUND_STATE: Word = 0x046b;
+pub const EMULATOR_NAME: Word = 0x046c;
+pub const FDC8271_STATE: Word = 0x046e;
+pub const WD1770_STATE: Word = 0x046f;
+
+const EMULATOR: &[u8] = b"BeebEm";
+
+// BeebEm's machine types
+const MACHINE_B: Byte = 0;
+const MACHINE_B_PLUS: Byte = 2;
+const MACHINE_MASTER128: Byte = 3;
+
+const CPU_STATE_LENGTH: usize = 16;
+const VIA_STATE_LENGTH: usize = 22;
+const VIDEO_STATE_LENGTH: usize = 42;
+const MAIN_RAM_LENGTH: usize = 0x8000;
+
+// The shadow RAM is saved as a whole 32K bank, after two unused bytes
+const SHADOW_RAM_OFFSET: usize = 2;
+const SHADOW_START: Address = 0x3000;
+const SHADOW_END: Address = 0x8000;
+
+// 8271 state follows the two 256 byte disc image filenames
+const FDC8271_STATUS: usize = 513;
+const FDC8271_MODE: usize = 520;
+const FDC8271_TRACKS: usize = 521;
+const FDC8271_OUTPUT_PORT: usize = 523;
+const FDC8271_BAD_TRACKS: usize = 525;
+const FDC8271_STATE_LENGTH: usize = 529;
+
+// 1770 state follows the disc types, sector counts and filenames
+const WD1770_STATUS: usize = 516;
+const WD1770_DATA: usize = 517;
+const WD1770_TRACK: usize = 518;
+const WD1770_SECTOR: usize = 520;
+const WD1770_STATE_LENGTH: usize = 521;
+
+const SYSTEM_VIA: Address = 0xfe40;
+const USER_VIA: Address = 0xfe60;
+const CRTC: Address = 0xfe00;
+const VIDEO_ULA: Address = 0xfe20;
+const ROMSEL: Address = 0xfe30;
+const ACCCON: Address = 0xfe34;
+
+// Both controllers report busy in bit 7 of the 8271's status and bit 0 of
+// the 1770's; a command in progress can't be carried over
+const FDC8271_BUSY: Byte = 0x80;
+const WD1770_BUSY: Byte = 0x01;
+
+// 6502 flags as BeebEm (and the 6502) lay them out
+const PSR_OVERFLOW: Byte = 0x40;
+const PSR_NEGATIVE: Byte = 0x80;
+
+pub struct BeebEmState {
+    pub model: Model,
+    pub chunks: Vec<Chunk>,
+}
+
+impl BeebEmState {
+    pub fn from_bytes(bytes: &[Byte]) -> Result<Self> {
+        let uef = Uef::from_bytes(bytes)?;
+
+        let name = find(&uef.chunks, EMULATOR_NAME)?;
+        if !name.data.starts_with(EMULATOR) {
+            return Err(Error::without_pc(ErrorType::InvalidSnapshot));
+        }
+
+        let model = match find(&uef.chunks, EMULATOR_STATE)?.data.first() {
+            Some(&MACHINE_B) => Model::B,
+            Some(&MACHINE_B_PLUS) if uef.chunks.iter().any(|c| c.id == SIDEWAYS_RAM) => {
+                Model::BPlus128
+            }
+            Some(&MACHINE_B_PLUS) => Model::BPlus,
+            Some(&MACHINE_MASTER128) => Model::Master128,
+            Some(_) => {
+                return Err(Error::without_pc(ErrorType::UnsupportedChunk(
+                    EMULATOR_STATE,
+                )))
+            }
+            None => return Err(Error::without_pc(ErrorType::InvalidSnapshot)),
+        };
+
+        Ok(BeebEmState {
+            model,
+            chunks: uef.chunks,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        BeebEmState::from_bytes(&std::fs::read(path)?)
+    }
+
+    // The chunks that can't be carried over to this emulator, in file order.
+    // Callers that can live without them can remove them before restoring.
+    pub fn unsupported(&self) -> Vec<Word> {
+        self.chunks
+            .iter()
+            .filter(|chunk| !self.supported(chunk))
+            .map(|chunk| chunk.id)
+            .collect()
+    }
+
+    fn supported(&self, chunk: &Chunk) -> bool {
+        let master = self.model == Model::Master128;
+        let b_plus = self.model == Model::BPlus || self.model == Model::BPlus128;
+        match chunk.id {
+            EMULATOR_NAME | EMULATOR_STATE | CPU_STATE | MEMORY_CONTROL | MAIN_RAM => true,
+            VIA_STATE | VIDEO_STATE => true,
+            // BeebEm's SN76489 state is its own mixer's, which doesn't map
+            // onto the registers and counters here, so it's skipped and the
+            // chip starts silent until the MOS next writes to it
+            SOUND_STATE => true,
+            SHADOW_RAM | PRIVATE_RAM => master || b_plus,
+            FILING_SYSTEM_RAM => master,
+            SIDEWAYS_RAM => chunk
+                .data
+                .first()
+                .is_some_and(|&bank| sideways_ram_banks(self.model).contains(&(bank as usize))),
+            FDC8271_STATE => {
+                self.model == Model::B
+                    && chunk
+                        .data
+                        .get(FDC8271_STATUS)
+                        .is_some_and(|&status| status & FDC8271_BUSY == 0)
+            }
+            WD1770_STATE => {
+                (master || b_plus)
+                    && chunk
+                        .data
+                        .get(WD1770_STATUS)
+                        .is_some_and(|&status| status & WD1770_BUSY == 0)
+            }
+            _ => false,
+        }
+    }
+
+    // Powers the machine on and loads the state into it. Disc images named
+    // in the state aren't loaded, as with our own snapshots.
+    pub fn restore(&self, machine: &mut Machine) -> Result<()> {
+        if machine.model() != self.model {
+            return Err(Error::without_pc(ErrorType::InvalidSnapshot));
+        }
+        if let Some(id) = self.unsupported().first() {
+            return Err(Error::without_pc(ErrorType::UnsupportedChunk(*id)));
+        }
+
+        machine.power_on()?;
+        for chunk in &self.chunks {
+            match chunk.id {
+                MAIN_RAM => restore_main_ram(machine, chunk)?,
+                SHADOW_RAM => restore_shadow_ram(machine, chunk)?,
+                PRIVATE_RAM => restore_private_ram(machine, chunk)?,
+                FILING_SYSTEM_RAM => restore_filing_system_ram(machine, chunk)?,
+                SIDEWAYS_RAM => restore_sideways_ram(machine, chunk)?,
+                VIA_STATE => restore_via(machine, chunk)?,
+                VIDEO_STATE => restore_video(machine, chunk)?,
+                FDC8271_STATE => restore_8271(machine, chunk)?,
+                WD1770_STATE => restore_1770(machine, chunk)?,
+                _ => {}
+            }
+        }
+
+        // Paging was used to reach the RAM, so it's set last, as is the CPU
+        // whose PC decides what the VDU drivers see
+        if let Ok(chunk) = find(&self.chunks, MEMORY_CONTROL) {
+            restore_memory_control(machine, chunk)?;
+        }
+        restore_cpu(machine, find(&self.chunks, CPU_STATE)?)
+    }
+}
+
+fn find(chunks: &[Chunk], id: Word) -> Result<&Chunk> {
+    chunks
+        .iter()
+        .find(|chunk| chunk.id == id)
+        .ok_or(Error::without_pc(ErrorType::InvalidSnapshot))
+}
+
+fn data(chunk: &Chunk, length: usize) -> Result<&[Byte]> {
+    if chunk.data.len() < length {
+        return Err(Error::without_pc(ErrorType::InvalidSnapshot));
+    }
+    Ok(&chunk.data)
+}
+
+// RAM chunks can be short, but not longer than the RAM they fill
+fn data_within(chunk: &Chunk, length: usize) -> Result<&[Byte]> {
+    if chunk.data.len() > length {
+        return Err(Error::without_pc(ErrorType::InvalidSnapshot));
+    }
+    Ok(&chunk.data)
+}
+
+fn word(data: &[Byte], offset: usize) -> Word {
+    Word::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn sideways_ram_banks(model: Model) -> &'static [usize] {
+    match model {
+        Model::BPlus128 => &SIDEWAYS_RAM_BANKS_128,
+        Model::Master128 => &SIDEWAYS_RAM_BANKS,
+        _ => &[],
+    }
+}
+
+// Writes RAM through the bus with the paging set up to reach it, from code
+// at the given address
+fn write_paged(
+    machine: &mut Machine,
+    romsel: Byte,
+    acccon: Option<Byte>,
+    pc: Address,
+    start: Address,
+    bytes: &[Byte],
+) -> Result<()> {
+    let bus = machine.bus_mut();
+    bus.write_byte(ROMSEL, romsel)?;
+    if let Some(acccon) = acccon {
+        bus.write_byte(ACCCON, acccon)?;
+    }
+    bus.instruction_fetch(pc);
+
+    for (offset, &byte) in bytes.iter().enumerate() {
+        bus.write_byte(start + offset as Address, byte)?;
+    }
+    Ok(())
+}
+
+// The Model B has no ACCCON; its ROMSEL appears at &FE34 too
+fn acccon(machine: &Machine, acccon: Byte) -> Option<Byte> {
+    (machine.model() != Model::B).then_some(acccon)
+}
+
+fn restore_main_ram(machine: &mut Machine, chunk: &Chunk) -> Result<()> {
+    let ram = &data(chunk, MAIN_RAM_LENGTH)?[..MAIN_RAM_LENGTH];
+    let acccon = acccon(machine, 0);
+    write_paged(machine, 0, acccon, 0, 0, ram)
+}
+
+fn restore_shadow_ram(machine: &mut Machine, chunk: &Chunk) -> Result<()> {
+    let start = SHADOW_RAM_OFFSET + SHADOW_START as usize;
+    let end = SHADOW_RAM_OFFSET + SHADOW_END as usize;
+    let ram = &data(chunk, end)?[start..end];
+
+    // The B+ only shows it to the VDU drivers; the Master can show it to all
+    let (acccon, pc) = match machine.model() {
+        Model::Master128 => (ACCCON_X, 0),
+        _ => (ACCCON_SHADOW, 0xc000),
+    };
+    write_paged(machine, 0, Some(acccon), pc, SHADOW_START, ram)
+}
+
+fn restore_private_ram(machine: &mut Machine, chunk: &Chunk) -> Result<()> {
+    let (romsel, size) = match machine.model() {
+        Model::Master128 => (ROMSEL_ANDY, ANDY_SIZE),
+        _ => (ROMSEL_PRIVATE, PRIVATE_SIZE),
+    };
+    let ram = data_within(chunk, size)?;
+    write_paged(machine, romsel, Some(0), 0, 0x8000, ram)
+}
+
+fn restore_filing_system_ram(machine: &mut Machine, chunk: &Chunk) -> Result<()> {
+    let ram = data_within(chunk, HAZEL_SIZE)?;
+    write_paged(machine, 0, Some(ACCCON_Y), 0, 0xc000, ram)
+}
+
+fn restore_sideways_ram(machine: &mut Machine, chunk: &Chunk) -> Result<()> {
+    let data = data(chunk, 1 + ROM_SIZE)?;
+    let acccon = acccon(machine, 0);
+    write_paged(machine, data[0], acccon, 0, 0x8000, &data[1..1 + ROM_SIZE])
+}
+
+fn restore_memory_control(machine: &mut Machine, chunk: &Chunk) -> Result<()> {
+    let data = data(chunk, 2)?;
+    machine.bus_mut().write_byte(ROMSEL, data[0])?;
+    if let Some(acccon) = acccon(machine, data[1]) {
+        machine.bus_mut().write_byte(ACCCON, acccon)?;
+    }
+    Ok(())
+}
+
+fn restore_cpu(machine: &mut Machine, chunk: &Chunk) -> Result<()> {
+    let data = data(chunk, CPU_STATE_LENGTH)?;
+
+    let registers = machine.cpu.registers_mut();
+    registers.pc = word(data, 0);
+    registers.pc_next = registers.pc;
+    registers.a = data[2];
+    registers.x = data[3];
+    registers.y = data[4];
+    registers.sp = data[5];
+
+    // Our overflow and negative flags sit a bit lower than the 6502's
+    let psr = data[6];
+    registers.ps = psr & 0x1f;
+    registers.write_flag(StatusBits::Ovf, psr & PSR_OVERFLOW == PSR_OVERFLOW);
+    registers.write_flag(StatusBits::Neg, psr & PSR_NEGATIVE == PSR_NEGATIVE);
+
+    machine.cycles = u32::from_le_bytes([data[7], data[8], data[9], data[10]]) as u64;
+
+    // An NMI that has been raised but not yet taken is still pending. IRQs
+    // follow from the devices' own state.
+    let (nmi_status, nmi_lock) = (data[12], data[13]);
+    machine.cpu.set_nmi(false);
+    machine.cpu.set_nmi(nmi_status != 0 && nmi_lock == 0);
+
+    let pc = machine.cpu.registers().pc;
+    machine.bus_mut().instruction_fetch(pc);
+    Ok(())
+}
+
+// The VIAs' timers and flags can only be reached on the devices themselves
+fn vias(bus: &mut dyn Bus) -> Option<(&mut SystemVia, &mut Via)> {
+    let bus: &mut dyn Any = bus;
+    if bus.is::<ModelB>() {
+        let bus = bus.downcast_mut::<ModelB>()?;
+        Some((&mut bus.system_via, &mut bus.user_via))
+    } else if bus.is::<ModelBPlus>() {
+        let bus = bus.downcast_mut::<ModelBPlus>()?;
+        Some((&mut bus.system_via, &mut bus.user_via))
+    } else {
+        let bus = bus.downcast_mut::<Master128>()?;
+        Some((&mut bus.system_via, &mut bus.user_via))
+    }
+}
+
+fn restore_via(machine: &mut Machine, chunk: &Chunk) -> Result<()> {
+    let data = data(chunk, VIA_STATE_LENGTH)?;
+    let system = data[0] == 0;
+    let base = if system { SYSTEM_VIA } else { USER_VIA };
+    let bus = machine.bus_mut();
+
+    // The System VIA's addressable latch is set a bit at a time through
+    // port B, as the MOS does
+    if system {
+        let latch = data[21];
+        bus.write_byte(base + REGISTER_DDRB, 0xff)?;
+        for bit in 0..8 {
+            let level = if latch & (1 << bit) != 0 { 0x08 } else { 0x00 };
+            bus.write_byte(base + REGISTER_ORB, bit | level)?;
+        }
+    }
+
+    bus.write_byte(base + REGISTER_DDRB, data[5])?;
+    bus.write_byte(base + REGISTER_DDRA, data[6])?;
+    bus.write_byte(base + REGISTER_ORB, data[1])?;
+    bus.write_byte(base + REGISTER_ORA, data[3])?;
+    bus.write_byte(base + REGISTER_ACR, data[15])?;
+    bus.write_byte(base + REGISTER_PCR, data[16])?;
+    bus.write_byte(base + REGISTER_IER, 0x7f)?;
+    bus.write_byte(base + REGISTER_IER, 0x80 | data[18])?;
+
+    let timer1_latch = word(data, 9);
+    bus.write_byte(base + REGISTER_T1LL, timer1_latch as Byte)?;
+    bus.write_byte(base + REGISTER_T1LH, (timer1_latch >> 8) as Byte)?;
+
+    let (system_via, user_via) =
+        vias(bus).ok_or(Error::without_pc(ErrorType::UnsupportedChunk(VIA_STATE)))?;
+    let via = if system {
+        &mut system_via.via
+    } else {
+        user_via.port_a_input = data[4];
+        user_via.port_b_input = data[2];
+        user_via
+    };
+
+    // Timers are saved counting at 1MHz, as ours do. One that has shot
+    // won't interrupt again until it's reloaded.
+    via.load_timer1(word(data, 7), timer1_latch, data[19] == 0);
+    via.load_timer2(word(data, 11), word(data, 13) as Byte, data[20] == 0);
+    via.load_interrupt_flags(data[17]);
+    Ok(())
+}
+
+fn restore_video(machine: &mut Machine, chunk: &Chunk) -> Result<()> {
+    let data = data(chunk, VIDEO_STATE_LENGTH)?;
+    let bus = machine.bus_mut();
+
+    for (register, &value) in data[..18].iter().enumerate() {
+        bus.write_byte(CRTC, register as Byte)?;
+        bus.write_byte(CRTC + 1, value)?;
+    }
+    bus.write_byte(CRTC, data[41])?;
+
+    // The palette is saved as written, one entry per logical colour
+    bus.write_byte(VIDEO_ULA, data[18])?;
+    for (colour, &physical) in data[19..35].iter().enumerate() {
+        bus.write_byte(VIDEO_ULA + 1, ((colour as Byte) << 4) | (physical & 0x0f))?;
+    }
+    Ok(())
+}
+
+fn write_special_register(machine: &mut Machine, register: Byte, value: Byte) -> Result<()> {
+    let bus = machine.bus_mut();
+    bus.write_byte(fdc8271::BASE, WRITE_SPECIAL_REGISTER)?;
+    bus.write_byte(fdc8271::BASE + 1, register)?;
+    bus.write_byte(fdc8271::BASE + 1, value)
+}
+
+fn restore_8271(machine: &mut Machine, chunk: &Chunk) -> Result<()> {
+    let data = data(chunk, FDC8271_STATE_LENGTH)?;
+
+    for drive in 0..2 {
+        let track = data[FDC8271_TRACKS + drive];
+        if let Some(drive) = machine.bus_mut().drive_mut(drive) {
+            drive.seek(track as usize);
+        }
+    }
+
+    let bad = &data[FDC8271_BAD_TRACKS..FDC8271_BAD_TRACKS + 4];
+    for (register, value) in [
+        (SPECIAL_MODE, data[FDC8271_MODE]),
+        (SPECIAL_OUTPUT_PORT, data[FDC8271_OUTPUT_PORT]),
+        (SPECIAL_SURFACE0_TRACK, data[FDC8271_TRACKS]),
+        (SPECIAL_SURFACE1_TRACK, data[FDC8271_TRACKS + 1]),
+        (SPECIAL_SURFACE0_BAD_TRACK1, bad[0]),
+        (SPECIAL_SURFACE0_BAD_TRACK2, bad[1]),
+        (SPECIAL_SURFACE1_BAD_TRACK1, bad[2]),
+        (SPECIAL_SURFACE1_BAD_TRACK2, bad[3]),
+    ] {
+        write_special_register(machine, register, value)?;
+    }
+    Ok(())
+}
+
+// BeebEm keeps its own idea of where each head is; between commands it
+// matches the track register, which is all we have room for
+fn restore_1770(machine: &mut Machine, chunk: &Chunk) -> Result<()> {
+    let data = data(chunk, WD1770_STATE_LENGTH)?;
+    let base = match machine.model() {
+        Model::Master128 => wd1770::MASTER.fdc_base(),
+        _ => wd1770::ACORN.fdc_base(),
+    };
+
+    let track = data[WD1770_TRACK];
+    let bus = machine.bus_mut();
+    for drive in 0..2 {
+        if let Some(drive) = bus.drive_mut(drive) {
+            drive.seek(track as usize);
+        }
+    }
+    bus.write_byte(base + 1, track)?;
+    bus.write_byte(base + 2, data[WD1770_SECTOR])?;
+    bus.write_byte(base + 3, data[WD1770_DATA])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::via::{REGISTER_IFR, REGISTER_T1CH, REGISTER_T1CL};
+    use crate::machine::tests::test_mos;
+    use crate::machine::Roms;
+
+    fn state(machine_type: Byte, chunks: Vec<Chunk>) -> Vec<Byte> {
+        let mut uef = Uef::new();
+        let mut name = EMULATOR.to_vec();
+        name.resize(16, 0);
+        uef.push(Chunk::new(EMULATOR_NAME, name));
+        uef.push(Chunk::new(EMULATOR_STATE, vec![machine_type, 0, 0, 0]));
+        for chunk in chunks {
+            uef.push(chunk);
+        }
+        uef.to_bytes()
+    }
+
+    fn cpu(pc: Word, psr: Byte, cycles: u32) -> Chunk {
+        let mut data = pc.to_le_bytes().to_vec();
+        data.extend_from_slice(&[0x11, 0x22, 0x33, 0xf0, psr]);
+        data.extend_from_slice(&cycles.to_le_bytes());
+        data.extend_from_slice(&[0; 5]);
+        Chunk::new(CPU_STATE, data)
+    }
+
+    #[test]
+    fn cpu_and_ram() -> Result<()> {
+        let mut ram = vec![0; MAIN_RAM_LENGTH];
+        ram[0x1900] = 0xa9;
+        let bytes = state(
+            MACHINE_B,
+            vec![
+                cpu(0x1900, 0xc1, 123456),
+                Chunk::new(MEMORY_CONTROL, vec![0x0f, 0x00]),
+                Chunk::new(MAIN_RAM, ram),
+            ],
+        );
+
+        let state = BeebEmState::from_bytes(&bytes)?;
+        assert_eq!(state.model, Model::B);
+        let mut machine = Machine::new(Model::B, Roms::new(test_mos()))?;
+        state.restore(&mut machine)?;
+
+        let registers = machine.cpu().registers();
+        assert_eq!(registers.pc, 0x1900);
+        assert_eq!((registers.a, registers.x, registers.y), (0x11, 0x22, 0x33));
+        assert_eq!(registers.sp, 0xf0);
+        assert!(registers.carry() && registers.overflow() && registers.negative());
+        assert_eq!(machine.cycles(), 123456);
+        assert_eq!(machine.bus().read_byte(0x1900)?, 0xa9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn via_timers() -> Result<()> {
+        let mut via = vec![0; VIA_STATE_LENGTH];
+        via[7..9].copy_from_slice(&0x1234u16.to_le_bytes());
+        via[9..11].copy_from_slice(&0x4e1eu16.to_le_bytes());
+        via[17] = 0x40;
+        via[18] = 0x40;
+        via[21] = 0x0f;
+        let bytes = state(
+            MACHINE_B,
+            vec![cpu(0xc000, 0x04, 0), Chunk::new(VIA_STATE, via)],
+        );
+
+        let mut machine = Machine::new(Model::B, Roms::new(test_mos()))?;
+        BeebEmState::from_bytes(&bytes)?.restore(&mut machine)?;
+
+        let bus = machine.bus();
+        assert_eq!(bus.read_byte(SYSTEM_VIA + REGISTER_T1CH)?, 0x12);
+        assert_eq!(bus.read_byte(SYSTEM_VIA + REGISTER_T1LL)?, 0x1e);
+        assert_eq!(bus.read_byte(SYSTEM_VIA + REGISTER_IFR)?, 0xc0);
+        assert_eq!(bus.read_byte(SYSTEM_VIA + REGISTER_T1CL)?, 0x34);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sound_is_skipped() -> Result<()> {
+        let mut ram = vec![0; MAIN_RAM_LENGTH];
+        ram[0x1900] = 0xea;
+        let bytes = state(
+            MACHINE_B,
+            vec![
+                cpu(0x1900, 0, 0),
+                Chunk::new(SOUND_STATE, vec![0x55; 20]),
+                Chunk::new(MAIN_RAM, ram),
+            ],
+        );
+        let state = BeebEmState::from_bytes(&bytes)?;
+        assert!(state.unsupported().is_empty());
+
+        let mut machine = Machine::new(Model::B, Roms::new(test_mos()))?;
+        state.restore(&mut machine)?;
+        assert_eq!(machine.cpu().registers().pc, 0x1900);
+        assert_eq!(machine.bus().read_byte(0x1900)?, 0xea);
+        assert_eq!(machine.bus().sound_level(), 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn master_paged_ram() -> Result<()> {
+        let mut shadow = vec![0; SHADOW_RAM_OFFSET + 0x8000];
+        shadow[SHADOW_RAM_OFFSET + 0x3000] = 0x5a;
+        let mut sideways = vec![4];
+        sideways.extend(vec![0xc3; ROM_SIZE]);
+        let bytes = state(
+            MACHINE_MASTER128,
+            vec![
+                cpu(0xc000, 0x04, 0),
+                Chunk::new(MEMORY_CONTROL, vec![0x04, ACCCON_X]),
+                Chunk::new(SHADOW_RAM, shadow),
+                Chunk::new(FILING_SYSTEM_RAM, vec![0xa5; 0x2000]),
+                Chunk::new(SIDEWAYS_RAM, sideways),
+            ],
+        );
+
+        let mut machine = Machine::new(Model::Master128, Roms::new(test_mos()))?;
+        BeebEmState::from_bytes(&bytes)?.restore(&mut machine)?;
+
+        let bus = machine.bus_mut();
+        assert_eq!(bus.read_byte(0x3000)?, 0x5a);
+        assert_eq!(bus.read_byte(0x8000)?, 0xc3);
+        bus.write_byte(ACCCON, ACCCON_Y)?;
+        assert_eq!(bus.read_byte(0xc000)?, 0xa5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn paged_ram_must_fit() -> Result<()> {
+        for (machine_type, id, size) in [
+            (MACHINE_MASTER128, PRIVATE_RAM, ANDY_SIZE),
+            (MACHINE_MASTER128, FILING_SYSTEM_RAM, HAZEL_SIZE),
+            (MACHINE_B_PLUS, PRIVATE_RAM, PRIVATE_SIZE),
+        ] {
+            let chunks = |length| vec![cpu(0xc000, 0x04, 0), Chunk::new(id, vec![0; length])];
+            let fits = BeebEmState::from_bytes(&state(machine_type, chunks(size)))?;
+            let mut machine = Machine::new(fits.model, Roms::new(test_mos()))?;
+            fits.restore(&mut machine)?;
+
+            let oversize = BeebEmState::from_bytes(&state(machine_type, chunks(0x10000)))?;
+            assert_eq!(
+                oversize.restore(&mut machine),
+                Err(Error::without_pc(ErrorType::InvalidSnapshot))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn not_a_beebem_state() {
+        let result = BeebEmState::from_bytes(&Uef::new().to_bytes());
+        assert_eq!(
+            result.err(),
+            Some(Error::without_pc(ErrorType::InvalidSnapshot))
+        );
+    }
+}
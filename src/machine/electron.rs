@@ -0,0 +1,281 @@
+use crate::cpu::ram::Ram;
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::electron_ula::ElectronUla;
+use crate::devices::Device;
+use crate::machine::{Bus, Roms, ROM_SIZE};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use crate::video::{Screen, FONT_SIZE};
+
+// The Acorn Electron memory map:
+//
+//   &0000-&7FFF  32K RAM
+//   &8000-&BFFF  paged ROM: the keyboard in pages 8 and 9, BASIC in 10
+//                and 11, cartridges and expansion ROMs in the rest
+//   &C000-&FBFF  MOS
+//   &FC00-&FDFF  1MHz bus (nothing fitted)
+//   &FE00-&FEFF  the ULA, repeated every 16 bytes
+//   &FF00-&FFFF  MOS
+
+pub const RAM_SIZE: usize = 0x8000;
+
+// The keyboard is 14 columns of 4 keys
+pub const KEYBOARD_COLUMNS: usize = 14;
+pub const KEYBOARD_ROWS: usize = 4;
+
+const PAGED_ROM_START: Address = 0x8000;
+const MOS_START: Address = 0xc000;
+const BASIC_PAGES: [usize; 2] = [10, 11];
+
+// Each mode as the BBC's video ULA and CRTC would show it: the control byte
+// its MOS uses, characters across and down, scanlines per row and the size
+// of the screen, which is what addresses past &7FFF wrap back by
+const MODES: [(Byte, usize, usize, usize, usize); 7] = [
+    (0x9c, 80, 32, 8, 0x5000),
+    (0xd8, 80, 32, 8, 0x5000),
+    (0xf4, 80, 32, 8, 0x5000),
+    (0x9c, 80, 25, 10, 0x4000),
+    (0x88, 40, 32, 8, 0x2800),
+    (0xc4, 40, 32, 8, 0x2800),
+    (0x88, 40, 25, 10, 0x2000),
+];
+
+pub struct Electron {
+    ram: Ram,
+    mos: Vec<Byte>,
+    pages: Vec<Option<Vec<Byte>>>,
+
+    // Bits 0-3 of each column are its rows; a set bit is a pressed key
+    keyboard: [Byte; KEYBOARD_COLUMNS],
+
+    pub ula: ElectronUla,
+}
+
+impl Electron {
+    // BASIC appears in both pages 10 and 11, so it only needs inserting once
+    pub fn new(roms: &Roms) -> Self {
+        let mut pages = roms.sideways_banks();
+        if pages[BASIC_PAGES[1]].is_none() {
+            pages[BASIC_PAGES[1]] = pages[BASIC_PAGES[0]].clone();
+        }
+
+        Electron {
+            ram: Ram::new(RAM_SIZE),
+            mos: roms.mos.clone(),
+            pages,
+            keyboard: [0; KEYBOARD_COLUMNS],
+            ula: ElectronUla::new(),
+        }
+    }
+
+    // Each address line A0-A13 held low selects a column onto D0-D3
+    fn read_keyboard(&self, address: Address) -> Byte {
+        (0..KEYBOARD_COLUMNS)
+            .filter(|&column| address & (1 << column) == 0)
+            .fold(0, |rows, column| rows | self.keyboard[column])
+    }
+}
+
+impl Memory for Electron {
+    fn length(&self) -> usize {
+        0x10000
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        match address {
+            0x0000..=0x7fff => self.ram.read_byte(address),
+            0x8000..=0xbfff if self.ula.rom_page() & 0x0e == 0x08 => {
+                Ok(self.read_keyboard(address))
+            }
+            0x8000..=0xbfff => match &self.pages[self.ula.rom_page() as usize] {
+                Some(rom) => Ok(rom[(address - PAGED_ROM_START) as usize]),
+                None => Ok(0xff),
+            },
+            0xfc00..=0xfdff => Ok(0xff),
+            0xfe00..=0xfeff => self.ula.read_byte(address & 0x0f),
+            _ => Ok(self.mos[(address - MOS_START) as usize % ROM_SIZE]),
+        }
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let lsb = self.read_byte(address)? as Word;
+        let msb = self.read_byte(address.wrapping_add(1))? as Word;
+        Ok(lsb + (msb << 8))
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        match address {
+            0x0000..=0x7fff => self.ram.write_byte(address, data),
+            0xfe00..=0xfeff => self.ula.write_byte(address & 0x0f, data),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for Electron {
+    fn tick(&mut self, cycles: usize) {
+        self.ula.tick(cycles);
+    }
+
+    fn irq(&self) -> bool {
+        self.ula.irq()
+    }
+}
+
+impl Snapshot for Electron {
+    fn save(&self, state: &mut StateWriter) {
+        self.ram.save(state);
+        state.array(&self.keyboard);
+        self.ula.save(state);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.ram.restore(state)?;
+        state.array(&mut self.keyboard)?;
+        self.ula.restore(state)
+    }
+}
+
+impl Bus for Electron {
+    fn power_on(&mut self) {
+        self.ram = Ram::new(RAM_SIZE);
+        self.ula.power_on();
+    }
+
+    fn press_key(&mut self, row: usize, column: usize) {
+        if row < KEYBOARD_ROWS && column < KEYBOARD_COLUMNS {
+            self.keyboard[column] |= 1 << row;
+        }
+    }
+
+    fn release_key(&mut self, row: usize, column: usize) {
+        if row < KEYBOARD_ROWS && column < KEYBOARD_COLUMNS {
+            self.keyboard[column] &= !(1 << row);
+        }
+    }
+
+    fn insert_tape(&mut self, data: Vec<Byte>) {
+        self.ula.insert_tape(data);
+    }
+
+    fn sound_level(&self) -> f32 {
+        self.ula.sound_level()
+    }
+
+    // The bitmap is laid out as on the BBC, so only the palette differs:
+    // each of the BBC's 16 entries gets the colour of the pixel value it
+    // stands for in the two, four or 16 colour modes
+    fn screen(&self) -> Option<Screen> {
+        let mode = self.ula.screen_mode() as usize;
+        let (control, columns, rows, scanlines, wrap) = MODES[mode];
+        let colours = match mode {
+            2 => 0x0f,
+            1 | 5 => 0x0a,
+            _ => 0x08,
+        };
+        Some(Screen {
+            start: self.ula.screen_start() >> 3,
+            columns,
+            rows,
+            scanlines,
+            control,
+            palette: std::array::from_fn(|n| (self.ula.colour(n & colours) ^ 0x07) as Byte),
+            wrap,
+            memory: self.ram.bytes().to_vec(),
+            font: self.mos[..FONT_SIZE.min(self.mos.len())].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::tests::test_mos;
+    use crate::video::{Rgb, COLOURS};
+
+    fn electron() -> Electron {
+        let mut roms = Roms::new(test_mos());
+        roms.insert(10, vec![0xba; ROM_SIZE]);
+        Electron::new(&roms)
+    }
+
+    #[test]
+    fn basic_in_pages_10_and_11() -> Result<()> {
+        let mut bus = electron();
+        bus.write_byte(0xfe05, 0x0a)?;
+        assert_eq!(bus.read_byte(0x8000)?, 0xba);
+        bus.write_byte(0xfe05, 0x0b)?;
+        assert_eq!(bus.read_byte(0x8000)?, 0xba);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keyboard_in_paged_rom_area() -> Result<()> {
+        let mut bus = electron();
+        bus.write_byte(0xfe05, 0x08)?;
+        bus.press_key(2, 13);
+
+        // A13 low selects column 13
+        assert_eq!(bus.read_byte(0x9fff)?, 0x04);
+        assert_eq!(bus.read_byte(0xbfff)?, 0x00);
+
+        bus.release_key(2, 13);
+        assert_eq!(bus.read_byte(0x9fff)?, 0x00);
+
+        Ok(())
+    }
+
+    #[test]
+    fn screen_in_mode_1() -> Result<()> {
+        let mut bus = electron();
+        bus.write_byte(0xfe02, 0x00)?;
+        bus.write_byte(0xfe03, 0x18)?;
+        bus.write_byte(0xfe07, 0x08)?;
+        // Black, red, yellow and white
+        bus.write_byte(0xfe08, 0x73)?;
+        bus.write_byte(0xfe09, 0x31)?;
+        // Four pixels: colours 0, 1, 2 and 3
+        bus.write_byte(0x3000, 0b0011_0101)?;
+
+        let frame = bus.screen().unwrap().render();
+        assert_eq!((frame.width(), frame.height()), (640, 256));
+        let row: Vec<Rgb> = (0..8).step_by(2).map(|x| frame.pixel(x, 0)).collect();
+        assert_eq!(row, [COLOURS[0], COLOURS[1], COLOURS[3], COLOURS[7]]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn screen_in_mode_6() -> Result<()> {
+        let mut bus = electron();
+        bus.write_byte(0xfe03, 0x30)?;
+        bus.write_byte(0xfe07, 0x30)?;
+        // Black and white
+        bus.write_byte(0xfe08, 0x10)?;
+        bus.write_byte(0xfe09, 0x11)?;
+        bus.write_byte(0x6000, 0x80)?;
+
+        let frame = bus.screen().unwrap().render();
+        assert_eq!((frame.width(), frame.height()), (640, 250));
+        assert_eq!(frame.pixel(1, 0), COLOURS[7]);
+        assert_eq!(frame.pixel(2, 0), COLOURS[0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ula_is_mirrored() -> Result<()> {
+        let mut bus = electron();
+        assert_eq!(bus.read_byte(0xfe00)? & 0x02, 0x02);
+
+        bus.write_byte(0xfe17, 0x30)?;
+        assert_eq!(bus.ula.screen_mode(), 6);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,472 @@
+use crate::cpu::ram::Ram;
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::crtc::Crtc;
+use crate::devices::disc_drive::DiscDrive;
+use crate::devices::mc146818::Mc146818;
+use crate::devices::serial::Serial;
+use crate::devices::system_via::SystemVia;
+use crate::devices::upd7002::Upd7002;
+use crate::devices::via::Via;
+use crate::devices::video_ula::VideoUla;
+use crate::devices::wd1770::{self, Wd1770};
+use crate::devices::Device;
+use crate::machine::{restore_sideways_ram, save_sideways_ram, Bus, Roms, ROM_SIZE};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use crate::video::Screen;
+
+// The Master 128 memory map, with its extra RAM paged in by ROMSEL and
+// ACCCON:
+//
+//   &0000-&2FFF  main RAM
+//   &3000-&7FFF  main RAM, or LYNNE (shadow screen RAM)
+//   &8000-&8FFF  sideways bank, or ANDY when ROMSEL bit 7 is set
+//   &9000-&BFFF  sideways bank (4-7 are RAM)
+//   &C000-&DFFF  MOS, or HAZEL (filing system workspace)
+//   &E000-&FBFF  MOS
+//   &FC00-&FEFF  FRED, JIM and SHEILA, or the MOS when testing
+//   &FF00-&FFFF  MOS
+
+pub const RAM_SIZE: usize = 0x8000;
+pub const LYNNE_SIZE: usize = 0x5000;
+pub const ANDY_SIZE: usize = 0x1000;
+pub const HAZEL_SIZE: usize = 0x2000;
+pub const SIDEWAYS_RAM_BANKS: [usize; 4] = [4, 5, 6, 7];
+
+pub const ROMSEL_ANDY: Byte = 0x80;
+
+pub const ACCCON_IRR: Byte = 0x80;
+pub const ACCCON_TST: Byte = 0x40;
+pub const ACCCON_IFJ: Byte = 0x20;
+pub const ACCCON_ITU: Byte = 0x10;
+pub const ACCCON_Y: Byte = 0x08;
+pub const ACCCON_X: Byte = 0x04;
+pub const ACCCON_E: Byte = 0x02;
+pub const ACCCON_D: Byte = 0x01;
+
+const LYNNE_START: Address = 0x3000;
+const ANDY_START: Address = 0x8000;
+const SIDEWAYS_START: Address = 0x8000;
+const HAZEL_START: Address = 0xc000;
+const MOS_START: Address = 0xc000;
+const VDU_DRIVERS_END: Address = 0xdfff;
+
+pub struct Master128 {
+    ram: Ram,
+    lynne: Ram,
+    andy: Ram,
+    hazel: Ram,
+    mos: Vec<Byte>,
+    sideways: Vec<Option<Vec<Byte>>>,
+    romsel: Byte,
+    acccon: Byte,
+
+    // Whether the instruction being run is in the VDU drivers, which see
+    // LYNNE when ACCCON E is set
+    vdu_driver: bool,
+
+    pub crtc: Crtc,
+    pub serial: Serial,
+    pub video_ula: VideoUla,
+    pub system_via: SystemVia,
+    pub user_via: Via,
+    pub fdc: Wd1770,
+    pub adc: Upd7002,
+}
+
+impl Master128 {
+    pub fn new(roms: &Roms) -> Self {
+        let mut sideways = roms.sideways_banks();
+        for bank in SIDEWAYS_RAM_BANKS {
+            sideways[bank].get_or_insert_with(|| vec![0; ROM_SIZE]);
+        }
+
+        let mut system_via = SystemVia::new();
+        system_via.rtc = Some(Mc146818::new());
+
+        Master128 {
+            ram: Ram::new(RAM_SIZE),
+            lynne: Ram::new(LYNNE_SIZE),
+            andy: Ram::new(ANDY_SIZE),
+            hazel: Ram::new(HAZEL_SIZE),
+            mos: roms.mos.clone(),
+            sideways,
+            romsel: 0,
+            acccon: 0,
+            vdu_driver: false,
+            crtc: Crtc::new(),
+            serial: Serial::new(),
+            video_ula: VideoUla::new(),
+            system_via,
+            user_via: Via::new(),
+            fdc: Wd1770::new(wd1770::MASTER),
+            adc: Upd7002::new(),
+        }
+    }
+
+    pub fn romsel(&self) -> Byte {
+        self.romsel
+    }
+
+    pub fn acccon(&self) -> Byte {
+        self.acccon
+    }
+
+    // Whether the CRTC is showing LYNNE rather than main RAM
+    pub fn display_shadow(&self) -> bool {
+        self.acccon & ACCCON_D == ACCCON_D
+    }
+
+    fn bank(&self) -> usize {
+        (self.romsel & 0x0f) as usize
+    }
+
+    fn lynne_selected(&self) -> bool {
+        self.acccon & ACCCON_X == ACCCON_X
+            || (self.acccon & ACCCON_E == ACCCON_E && self.vdu_driver)
+    }
+
+    fn andy_selected(&self) -> bool {
+        self.romsel & ROMSEL_ANDY == ROMSEL_ANDY
+    }
+
+    fn hazel_selected(&self) -> bool {
+        self.acccon & ACCCON_Y == ACCCON_Y
+    }
+
+    fn read_mos(&self, address: Address) -> Byte {
+        self.mos[(address - MOS_START) as usize % ROM_SIZE]
+    }
+
+    fn read_sheila(&self, address: Address) -> Result<Byte> {
+        match address {
+            0xfe00..=0xfe07 => self.crtc.read_byte(address - 0xfe00),
+            0xfe08..=0xfe17 => self.serial.read_byte(address - 0xfe08),
+            0xfe18..=0xfe1f => self.adc.read_byte(address - 0xfe18),
+            0xfe20..=0xfe23 => self.video_ula.read_byte(address - 0xfe20),
+            _ if self.fdc.interface.contains(address) => {
+                self.fdc.read_byte(address - self.fdc.interface.base)
+            }
+            0xfe30..=0xfe33 => Ok(self.romsel),
+            0xfe34..=0xfe37 => Ok(self.acccon),
+            0xfe40..=0xfe5f => self.system_via.read_byte(address - 0xfe40),
+            0xfe60..=0xfe7f => self.user_via.read_byte(address - 0xfe60),
+            _ => Ok(0xff),
+        }
+    }
+
+    fn write_sheila(&mut self, address: Address, data: Byte) -> Result<()> {
+        match address {
+            0xfe00..=0xfe07 => self.crtc.write_byte(address - 0xfe00, data),
+            0xfe08..=0xfe17 => self.serial.write_byte(address - 0xfe08, data),
+            0xfe18..=0xfe1f => self.adc.write_byte(address - 0xfe18, data),
+            0xfe20..=0xfe23 => {
+                self.video_ula.write_byte(address - 0xfe20, data)?;
+                self.crtc.fast_clock = self.video_ula.fast_clock();
+                Ok(())
+            }
+            _ if self.fdc.interface.contains(address) => {
+                self.fdc.write_byte(address - self.fdc.interface.base, data)
+            }
+            0xfe30..=0xfe33 => {
+                self.romsel = data & (ROMSEL_ANDY | 0x0f);
+                Ok(())
+            }
+            0xfe34..=0xfe37 => {
+                self.acccon = data;
+                Ok(())
+            }
+            0xfe40..=0xfe5f => self.system_via.write_byte(address - 0xfe40, data),
+            0xfe60..=0xfe7f => self.user_via.write_byte(address - 0xfe60, data),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Memory for Master128 {
+    fn length(&self) -> usize {
+        0x10000
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        match address {
+            0x3000..=0x7fff if self.lynne_selected() => self.lynne.read_byte(address - LYNNE_START),
+            0x0000..=0x7fff => self.ram.read_byte(address),
+            0x8000..=0x8fff if self.andy_selected() => self.andy.read_byte(address - ANDY_START),
+            0x8000..=0xbfff => match &self.sideways[self.bank()] {
+                Some(bank) => Ok(bank[(address - SIDEWAYS_START) as usize]),
+                None => Ok(0xff),
+            },
+            0xc000..=0xdfff if self.hazel_selected() => self.hazel.read_byte(address - HAZEL_START),
+            0xfc00..=0xfeff if self.acccon & ACCCON_TST == ACCCON_TST => Ok(self.read_mos(address)),
+            0xfc00..=0xfdff => Ok(0xff),
+            0xfe00..=0xfeff => self.read_sheila(address),
+            _ => Ok(self.read_mos(address)),
+        }
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let lsb = self.read_byte(address)? as Word;
+        let msb = self.read_byte(address.wrapping_add(1))? as Word;
+        Ok(lsb + (msb << 8))
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        match address {
+            0x3000..=0x7fff if self.lynne_selected() => {
+                self.lynne.write_byte(address - LYNNE_START, data)
+            }
+            0x0000..=0x7fff => self.ram.write_byte(address, data),
+            0x8000..=0x8fff if self.andy_selected() => {
+                self.andy.write_byte(address - ANDY_START, data)
+            }
+            0x8000..=0xbfff => {
+                let bank = self.bank();
+                if SIDEWAYS_RAM_BANKS.contains(&bank) {
+                    if let Some(ram) = self.sideways[bank].as_mut() {
+                        ram[(address - SIDEWAYS_START) as usize] = data;
+                    }
+                }
+                Ok(())
+            }
+            0xc000..=0xdfff if self.hazel_selected() => {
+                self.hazel.write_byte(address - HAZEL_START, data)
+            }
+            0xfe00..=0xfeff => self.write_sheila(address, data),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for Master128 {
+    fn tick(&mut self, cycles: usize) {
+        self.crtc.tick(cycles);
+        self.system_via.via.set_ca1(self.crtc.vsync());
+        self.adc.tick(cycles);
+        self.system_via.via.set_cb1(!self.adc.end_of_conversion());
+        self.system_via.tick(cycles);
+        self.user_via.tick(cycles);
+        self.serial.tick(cycles);
+        self.fdc.tick(cycles);
+    }
+
+    fn irq(&self) -> bool {
+        self.system_via.irq() || self.user_via.irq() || self.serial.acia.irq()
+    }
+
+    fn nmi(&self) -> bool {
+        self.fdc.nmi()
+    }
+}
+
+impl Snapshot for Master128 {
+    fn save(&self, state: &mut StateWriter) {
+        self.ram.save(state);
+        self.lynne.save(state);
+        self.andy.save(state);
+        self.hazel.save(state);
+        save_sideways_ram(state, &self.sideways, &SIDEWAYS_RAM_BANKS);
+        state.byte(self.romsel);
+        state.byte(self.acccon);
+        state.bool(self.vdu_driver);
+        self.crtc.save(state);
+        self.serial.save(state);
+        self.video_ula.save(state);
+        self.system_via.save(state);
+        self.user_via.save(state);
+        self.fdc.save(state);
+        self.adc.save(state);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.ram.restore(state)?;
+        self.lynne.restore(state)?;
+        self.andy.restore(state)?;
+        self.hazel.restore(state)?;
+        restore_sideways_ram(state, &mut self.sideways, &SIDEWAYS_RAM_BANKS)?;
+        self.romsel = state.byte()?;
+        self.acccon = state.byte()?;
+        self.vdu_driver = state.bool()?;
+        self.crtc.restore(state)?;
+        self.serial.restore(state)?;
+        self.video_ula.restore(state)?;
+        self.system_via.restore(state)?;
+        self.user_via.restore(state)?;
+        self.fdc.restore(state)?;
+        self.adc.restore(state)
+    }
+}
+
+impl Bus for Master128 {
+    // The CMOS RAM and clock are battery backed, so they survive
+    fn power_on(&mut self) {
+        self.ram = Ram::new(RAM_SIZE);
+        self.lynne = Ram::new(LYNNE_SIZE);
+        self.andy = Ram::new(ANDY_SIZE);
+        self.hazel = Ram::new(HAZEL_SIZE);
+        self.romsel = 0;
+        self.acccon = 0;
+        self.crtc = Crtc::new();
+        self.video_ula = VideoUla::new();
+        self.system_via.reset();
+        self.user_via.reset();
+        self.fdc.reset();
+    }
+
+    fn instruction_fetch(&mut self, pc: Address) {
+        self.vdu_driver = (HAZEL_START..=VDU_DRIVERS_END).contains(&pc);
+    }
+
+    // With TST set the I/O area is the MOS, which is safe to read
+    fn peek(&self, address: Address) -> Result<Byte> {
+        match address {
+            0xfc00..=0xfeff if self.acccon & ACCCON_TST == 0 => Ok(0xff),
+            _ => self.read_byte(address),
+        }
+    }
+
+    fn press_key(&mut self, row: usize, column: usize) {
+        self.system_via.press_key(row, column);
+    }
+
+    fn release_key(&mut self, row: usize, column: usize) {
+        self.system_via.release_key(row, column);
+    }
+
+    fn drive_mut(&mut self, drive: usize) -> Option<&mut DiscDrive> {
+        self.fdc.drives.get_mut(drive)
+    }
+
+    fn serial_mut(&mut self) -> Option<&mut Serial> {
+        Some(&mut self.serial)
+    }
+
+    fn adc_mut(&mut self) -> Option<&mut Upd7002> {
+        Some(&mut self.adc)
+    }
+
+    fn insert_tape(&mut self, data: Vec<Byte>) {
+        self.serial.insert_tape(data);
+    }
+
+    fn sound_level(&self) -> f32 {
+        (self.system_via.sound.level() + self.serial.sound_level()).clamp(-1.0, 1.0)
+    }
+
+    fn screen(&self) -> Option<Screen> {
+        let mut memory = self.ram.bytes().to_vec();
+        if self.display_shadow() {
+            memory[LYNNE_START as usize..].copy_from_slice(self.lynne.bytes());
+        }
+        Some(Screen::new(
+            &self.crtc,
+            &self.video_ula,
+            self.system_via.latch(),
+            memory,
+            &self.mos,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::tests::test_mos;
+
+    fn master() -> Master128 {
+        let mut roms = Roms::new(test_mos());
+        roms.insert(15, vec![0x0f; ROM_SIZE]);
+        Master128::new(&roms)
+    }
+
+    #[test]
+    fn andy_pages_over_sideways() -> Result<()> {
+        let mut bus = master();
+        bus.write_byte(0xfe30, 0x0f)?;
+        assert_eq!(bus.read_byte(0x8000)?, 0x0f);
+
+        bus.write_byte(0xfe30, 0x8f)?;
+        bus.write_byte(0x8000, 0x42)?;
+        assert_eq!(bus.read_byte(0x8000)?, 0x42);
+        assert_eq!(bus.read_byte(0x9000)?, 0x0f);
+
+        bus.write_byte(0xfe30, 0x0f)?;
+        assert_eq!(bus.read_byte(0x8000)?, 0x0f);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sideways_ram() -> Result<()> {
+        let mut bus = master();
+        bus.write_byte(0xfe30, 0x04)?;
+        bus.write_byte(0xa000, 0x44)?;
+        bus.write_byte(0xfe30, 0x07)?;
+        bus.write_byte(0xa000, 0x77)?;
+
+        bus.write_byte(0xfe30, 0x04)?;
+        assert_eq!(bus.read_byte(0xa000)?, 0x44);
+
+        // ROMs stay read only
+        bus.write_byte(0xfe30, 0x0f)?;
+        bus.write_byte(0xa000, 0x00)?;
+        assert_eq!(bus.read_byte(0xa000)?, 0x0f);
+
+        Ok(())
+    }
+
+    #[test]
+    fn hazel_pages_over_mos() -> Result<()> {
+        let mut bus = master();
+        bus.write_byte(0xfe34, ACCCON_Y)?;
+        bus.write_byte(0xc000, 0x42)?;
+        assert_eq!(bus.read_byte(0xc000)?, 0x42);
+        assert_eq!(bus.read_byte(0xfe34)?, ACCCON_Y);
+
+        bus.write_byte(0xfe34, 0x00)?;
+        assert_eq!(bus.read_byte(0xc000)?, 0x58);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lynne_shadows_screen_memory() -> Result<()> {
+        let mut bus = master();
+        bus.write_byte(0x3000, 0x11)?;
+        bus.write_byte(0xfe34, ACCCON_X)?;
+        bus.write_byte(0x3000, 0x22)?;
+        assert_eq!(bus.read_byte(0x3000)?, 0x22);
+        assert_eq!(bus.read_byte(0x2fff)?, 0x00);
+
+        // With E, only the VDU drivers see it
+        bus.write_byte(0xfe34, ACCCON_E)?;
+        bus.instruction_fetch(0x8000);
+        assert_eq!(bus.read_byte(0x3000)?, 0x11);
+        bus.instruction_fetch(0xc123);
+        assert_eq!(bus.read_byte(0x3000)?, 0x22);
+
+        Ok(())
+    }
+
+    #[test]
+    fn wd1770_at_fe24() -> Result<()> {
+        let mut bus = master();
+        bus.write_byte(0xfe2a, 0x27)?;
+        assert_eq!(bus.read_byte(0xfe2a)?, 0x27);
+        assert_eq!(bus.read_byte(0xfe2c)?, 0xff);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cmos_survives_power_on() {
+        let mut bus = master();
+        bus.system_via.rtc.as_mut().unwrap().ram_mut()[0] = 0x5a;
+        bus.power_on();
+        assert_eq!(bus.system_via.rtc.as_ref().unwrap().ram()[0], 0x5a);
+    }
+}
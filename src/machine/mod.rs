@@ -0,0 +1,1013 @@
+use std::any::Any;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::cpu::address::AddressAndDataDispatch;
+use crate::cpu::debug::{Break, Watched};
+use crate::cpu::dispatch::{DispatchResult, Dispatcher};
+use crate::cpu::execution::ExecutionUnit;
+use crate::cpu::history::{History, Step};
+use crate::cpu::instruction_decode::InstructionDecoder;
+use crate::cpu::registers::Registers;
+use crate::cpu::trace::Trace;
+use crate::cpu::writeback::WritebackUnit;
+use crate::cpu::{Address, Byte, Error, ErrorType, Memory, Result, Word};
+use crate::devices::disc_drive::DiscDrive;
+use crate::devices::serial::Serial;
+use crate::devices::upd7002::Upd7002;
+use crate::devices::Device;
+use crate::disc::image::DiscImage;
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use crate::sound::SoundCapture;
+use crate::tape::uef::Uef;
+use crate::video::capture::VideoCapture;
+use crate::video::{Frame, Screen};
+
+pub mod beebem;
+pub mod electron;
+pub mod master128;
+pub mod model_b;
+pub mod model_b_plus;
+pub mod replay;
+
+use self::electron::Electron;
+use self::master128::Master128;
+use self::model_b::ModelB;
+use self::model_b_plus::{ModelBPlus, SIDEWAYS_RAM_BANKS_128};
+use self::replay::{Event, Input, Recording};
+
+pub const ROM_SIZE: usize = 0x4000;
+pub const SIDEWAYS_SLOTS: usize = 16;
+
+// FRED, JIM and SHEILA, on every model
+pub const IO: RangeInclusive<Address> = 0xfc00..=0xfeff;
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"BEEBSNAP";
+pub const SNAPSHOT_VERSION: Byte = 3;
+
+// Everything the CPU sees: memory, paging and the memory-mapped devices.
+// Each machine model provides one.
+pub trait Bus: Any + Device + Snapshot {
+    // What the power-on reset circuit does, as opposed to pressing BREAK
+    fn power_on(&mut self);
+
+    // Called with the address of each instruction before it runs, for
+    // paging that depends on where the code is
+    fn instruction_fetch(&mut self, _pc: Address) {}
+
+    // A read for debuggers. Reading a device register can acknowledge an
+    // interrupt or consume a byte, so the I/O area reads as &FF instead.
+    fn peek(&self, address: Address) -> Result<Byte> {
+        if IO.contains(&address) {
+            Ok(0xff)
+        } else {
+            self.read_byte(address)
+        }
+    }
+
+    fn press_key(&mut self, row: usize, column: usize);
+    fn release_key(&mut self, row: usize, column: usize);
+
+    fn drive_mut(&mut self, _drive: usize) -> Option<&mut DiscDrive> {
+        None
+    }
+
+    // The RS423 port, on the models that have one
+    fn serial_mut(&mut self) -> Option<&mut Serial> {
+        None
+    }
+
+    // The joystick port's converter, on the models that have one
+    fn adc_mut(&mut self) -> Option<&mut Upd7002> {
+        None
+    }
+
+    // Queues the bytes on a tape to be read once the cassette motor starts
+    fn insert_tape(&mut self, data: Vec<Byte>);
+
+    // What the display hardware is showing, on the models it's emulated for
+    fn screen(&self) -> Option<Screen> {
+        None
+    }
+
+    // What's on the speaker right now, from -1.0 to 1.0
+    fn sound_level(&self) -> f32 {
+        0.0
+    }
+}
+
+impl Memory for Box<dyn Bus> {
+    fn length(&self) -> usize {
+        (**self).length()
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        (**self).read_byte(address)
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        (**self).read_word(address)
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        (**self).write_byte(address, data)
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        (**self).write_word(address, data)
+    }
+}
+
+impl Device for Box<dyn Bus> {
+    fn tick(&mut self, cycles: usize) {
+        (**self).tick(cycles)
+    }
+
+    fn irq(&self) -> bool {
+        (**self).irq()
+    }
+
+    fn nmi(&self) -> bool {
+        (**self).nmi()
+    }
+}
+
+impl Snapshot for Box<dyn Bus> {
+    fn save(&self, state: &mut StateWriter) {
+        (**self).save(state)
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        (**self).restore(state)
+    }
+}
+
+// Sideways RAM banks are saved in the order given; ROMs are never saved
+pub(crate) fn save_sideways_ram(
+    state: &mut StateWriter,
+    sideways: &[Option<Vec<Byte>>],
+    banks: &[usize],
+) {
+    for &bank in banks {
+        state.bytes(sideways[bank].as_deref().unwrap_or_default());
+    }
+}
+
+pub(crate) fn restore_sideways_ram(
+    state: &mut StateReader,
+    sideways: &mut [Option<Vec<Byte>>],
+    banks: &[usize],
+) -> Result<()> {
+    for &bank in banks {
+        let ram = state.bytes()?;
+        if ram.len() != ROM_SIZE {
+            return Err(Error::without_pc(ErrorType::InvalidSnapshot));
+        }
+        sideways[bank] = Some(ram);
+    }
+    Ok(())
+}
+
+pub type Cpu = Dispatcher<
+    InstructionDecoder,
+    AddressAndDataDispatch<Watched<Box<dyn Bus>>>,
+    Box<dyn Bus>,
+    ExecutionUnit<Watched<Box<dyn Bus>>>,
+    WritebackUnit<Watched<Box<dyn Bus>>>,
+>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    B,
+    BPlus,
+    BPlus128,
+    Master128,
+    Electron,
+}
+
+// The MOS and whatever is plugged into the sideways ROM sockets. 8K images
+// are mirrored to fill their 16K slot.
+pub struct Roms {
+    pub mos: Vec<Byte>,
+    pub sideways: [Option<Vec<Byte>>; SIDEWAYS_SLOTS],
+}
+
+impl Roms {
+    pub fn new(mos: Vec<Byte>) -> Self {
+        Roms {
+            mos,
+            sideways: Default::default(),
+        }
+    }
+
+    pub fn insert(&mut self, slot: usize, image: Vec<Byte>) {
+        self.sideways[slot & 0x0f] = Some(image);
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.mos.len() != ROM_SIZE {
+            return Err(Error::without_pc(ErrorType::InvalidRom));
+        }
+        for image in self.sideways.iter().flatten() {
+            if image.len() != ROM_SIZE && image.len() != ROM_SIZE / 2 {
+                return Err(Error::without_pc(ErrorType::InvalidRom));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn sideways_banks(&self) -> Vec<Option<Vec<Byte>>> {
+        self.sideways
+            .iter()
+            .map(|image| {
+                image.as_ref().map(|image| {
+                    let mut bank = image.clone();
+                    while bank.len() < ROM_SIZE {
+                        bank.extend_from_slice(image);
+                    }
+                    bank
+                })
+            })
+            .collect()
+    }
+}
+
+pub struct Machine {
+    model: Model,
+    cpu: Cpu,
+    cycles: u64,
+    recording: Option<Recording>,
+    sound: Option<SoundCapture>,
+    video: Option<VideoCapture>,
+}
+
+impl Machine {
+    // Builds the machine and switches it on, so the MOS is about to cold start
+    pub fn new(model: Model, roms: Roms) -> Result<Self> {
+        roms.validate()?;
+
+        let (bus, instruction_decoder): (Box<dyn Bus>, _) = match model {
+            Model::B => (Box::new(ModelB::new(&roms)), InstructionDecoder::new()),
+            Model::BPlus => (
+                Box::new(ModelBPlus::new(&roms, &[])),
+                InstructionDecoder::new(),
+            ),
+            Model::BPlus128 => (
+                Box::new(ModelBPlus::new(&roms, &SIDEWAYS_RAM_BANKS_128)),
+                InstructionDecoder::new(),
+            ),
+            Model::Electron => (Box::new(Electron::new(&roms)), InstructionDecoder::new()),
+            Model::Master128 => (
+                Box::new(Master128::new(&roms)),
+                InstructionDecoder::new_65c02(),
+            ),
+        };
+
+        let mut machine = Machine {
+            model,
+            cpu: Dispatcher::new(
+                Registers::new(),
+                bus,
+                instruction_decoder,
+                AddressAndDataDispatch::new(),
+                ExecutionUnit::new(),
+                WritebackUnit::new(),
+            ),
+            cycles: 0,
+            recording: None,
+            sound: None,
+            video: None,
+        };
+        machine.power_on()?;
+        Ok(machine)
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn bus(&self) -> &dyn Bus {
+        self.cpu.memory().as_ref()
+    }
+
+    pub fn bus_mut(&mut self) -> &mut dyn Bus {
+        self.cpu.memory_mut().as_mut()
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // The display hardware's state, for looking at the screen. The Electron's
+    // display isn't drawn yet.
+    pub fn screen(&self) -> Option<Screen> {
+        self.bus().screen()
+    }
+
+    // The picture on the screen right now
+    pub fn frame(&self) -> Option<Frame> {
+        self.screen().map(|screen| screen.render())
+    }
+
+    // Starts sampling the sound output from here on, discarding anything
+    // captured already
+    pub fn start_sound_capture(&mut self, sample_rate: u32) {
+        self.sound = Some(SoundCapture::new(sample_rate));
+    }
+
+    pub fn stop_sound_capture(&mut self) -> Option<SoundCapture> {
+        self.sound.take()
+    }
+
+    pub fn sound_capture(&self) -> Option<&SoundCapture> {
+        self.sound.as_ref()
+    }
+
+    // Starts writing a frame of video every 50th of a second, with the first
+    // one now. Started along with sound capture, frame N goes with the
+    // samples from N/50 seconds in.
+    pub fn start_video_capture(&mut self, mut capture: VideoCapture) -> Result<()> {
+        capture.write_frame(self.frame().as_ref())?;
+        self.video = Some(capture);
+        Ok(())
+    }
+
+    pub fn stop_video_capture(&mut self) -> Result<Option<VideoCapture>> {
+        if let Some(video) = &mut self.video {
+            video.flush()?;
+        }
+        Ok(self.video.take())
+    }
+
+    pub fn video_capture(&self) -> Option<&VideoCapture> {
+        self.video.as_ref()
+    }
+
+    // Starts or stops tracing, with the trace's cycle count matching the
+    // machine's
+    pub fn set_trace(&mut self, trace: Option<Trace>) -> Option<Trace> {
+        let cycles = self.cycles;
+        self.cpu
+            .set_trace(trace.map(|trace| trace.with_cycles(cycles)))
+    }
+
+    // Starts or stops keeping a history of the last few instructions, for
+    // stepping back. Writes to I/O can't be undone.
+    pub fn set_history(&mut self, history: Option<History>) -> Option<History> {
+        self.cpu
+            .set_history(history.map(|history| history.without(IO)))
+    }
+
+    // Undoes the last instruction, if the history has it. The devices aren't
+    // wound back with it, so they run ahead of the CPU from then on.
+    pub fn step_back(&mut self) -> Result<Option<Step>> {
+        let step = self.cpu.step_back()?;
+        if let Some(step) = &step {
+            self.cycles -= step.cycles as u64;
+        }
+        Ok(step)
+    }
+
+    // A history from before the state changed wholesale can't undo anything
+    fn forget_history(&mut self) {
+        if let Some(history) = self.cpu.history_mut() {
+            history.clear();
+        }
+    }
+
+    // Clears memory and resets every device as well as the CPU. The System
+    // VIA comes up with IER reading &80, which is how the MOS tells a power
+    // on from BREAK.
+    pub fn power_on(&mut self) -> Result<()> {
+        self.cpu.memory_mut().power_on();
+        *self.cpu.registers_mut() = Registers::new();
+        self.cpu.registers_mut().sp = 0x00;
+        self.cycles = 0;
+        self.forget_history();
+        self.cpu.reset()
+    }
+
+    // BREAK only pulls the CPU's RESET line; devices and memory keep their state
+    pub fn reset(&mut self) -> Result<()> {
+        self.cpu.reset()
+    }
+
+    // Runs one instruction (or interrupt) and clocks the devices for as long
+    // as it took
+    pub fn step(&mut self) -> Result<DispatchResult> {
+        let irq = self.cpu.memory().irq();
+        let nmi = self.cpu.memory().nmi();
+        self.cpu.set_irq(irq);
+        self.cpu.set_nmi(nmi);
+
+        let pc = self.cpu.registers().pc;
+        self.cpu.memory_mut().instruction_fetch(pc);
+
+        let result = self.cpu.dispatch()?;
+        self.cpu.memory_mut().tick(result.cycles());
+        self.cycles += result.cycles() as u64;
+        if let Some(sound) = &mut self.sound {
+            sound.advance(result.cycles(), self.cpu.memory().sound_level());
+        }
+        let due = match &mut self.video {
+            Some(video) => video.advance(result.cycles()),
+            None => 0,
+        };
+        if due > 0 {
+            let frame = self.frame();
+            if let Some(video) = &mut self.video {
+                for _ in 0..due {
+                    video.write_frame(frame.as_ref())?;
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    // Runs for at least the given number of 2MHz cycles, or until a
+    // breakpoint or watchpoint stops it
+    pub fn run(&mut self, cycles: u64) -> Result<Option<Break>> {
+        let end = self.cycles + cycles;
+        while self.cycles < end {
+            if let DispatchResult::Break(reason, _) = self.step()? {
+                return Ok(Some(reason));
+            }
+        }
+        Ok(None)
+    }
+
+    // Everything needed to carry on from this exact cycle, apart from the
+    // ROMs and any disc or tape media, which the machine restoring it must
+    // already have
+    pub fn snapshot(&self) -> Vec<Byte> {
+        let mut state = StateWriter::new();
+        state.array(SNAPSHOT_MAGIC);
+        state.byte(SNAPSHOT_VERSION);
+        state.byte(self.model as Byte);
+        state.u64(self.cycles);
+        self.cpu.save(&mut state);
+        state.into_bytes()
+    }
+
+    // The snapshot must be from the same model, and is checked before
+    // anything is changed. A truncated snapshot leaves the machine in an
+    // undefined state.
+    pub fn restore(&mut self, snapshot: &[Byte]) -> Result<()> {
+        let mut state = StateReader::new(snapshot);
+        let mut magic = [0; SNAPSHOT_MAGIC.len()];
+        state.array(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC
+            || state.byte()? != SNAPSHOT_VERSION
+            || state.byte()? != self.model as Byte
+        {
+            return Err(Error::without_pc(ErrorType::InvalidSnapshot));
+        }
+
+        self.cycles = state.u64()?;
+        self.cpu.restore(&mut state)?;
+        self.forget_history();
+        if !state.at_end() {
+            return Err(Error::without_pc(ErrorType::InvalidSnapshot));
+        }
+        Ok(())
+    }
+
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.snapshot())?;
+        Ok(())
+    }
+
+    pub fn load_snapshot<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.restore(&fs::read(path)?)
+    }
+
+    pub fn press_key(&mut self, row: usize, column: usize) {
+        self.bus_mut().press_key(row, column);
+        self.record(Input::KeyDown { row, column });
+    }
+
+    pub fn release_key(&mut self, row: usize, column: usize) {
+        self.bus_mut().release_key(row, column);
+        self.record(Input::KeyUp { row, column });
+    }
+
+    fn drive(&mut self, drive: usize) -> Result<&mut DiscDrive> {
+        self.bus_mut()
+            .drive_mut(drive)
+            .ok_or(Error::without_pc(ErrorType::NoSuchDrive(drive)))
+    }
+
+    pub fn insert_disc(&mut self, drive: usize, image: DiscImage) -> Result<Option<DiscImage>> {
+        let input = Input::InsertDisc {
+            drive,
+            format: image.format,
+            write_protected: image.write_protected,
+            bytes: image.to_bytes(),
+        };
+        let old = self.drive(drive)?.insert(image);
+        self.record(input);
+        Ok(old)
+    }
+
+    pub fn eject_disc(&mut self, drive: usize) -> Result<Option<DiscImage>> {
+        let old = self.drive(drive)?.eject();
+        self.record(Input::EjectDisc { drive });
+        Ok(old)
+    }
+
+    pub fn insert_tape(&mut self, uef: &Uef) {
+        let data = uef.tape_data();
+        self.bus_mut().insert_tape(data.clone());
+        self.record(Input::InsertTape(data));
+    }
+
+    // Any input from outside the machine. Models without an RS423 or
+    // joystick port lose what's sent to it.
+    pub fn input(&mut self, input: Input) -> Result<()> {
+        match input {
+            Input::KeyDown { row, column } => self.press_key(row, column),
+            Input::KeyUp { row, column } => self.release_key(row, column),
+            Input::InsertDisc {
+                drive,
+                format,
+                write_protected,
+                ref bytes,
+            } => {
+                let mut image = DiscImage::from_bytes(format, bytes)?;
+                image.write_protected = write_protected;
+                self.insert_disc(drive, image)?;
+            }
+            Input::EjectDisc { drive } => {
+                self.eject_disc(drive)?;
+            }
+            Input::Serial(data) => {
+                if let Some(serial) = self.bus_mut().serial_mut() {
+                    serial.acia.receive(data);
+                }
+                self.record(input);
+            }
+            Input::Adc { channel, value } => {
+                if let Some(adc) = self.bus_mut().adc_mut() {
+                    adc.set_input(channel, value);
+                }
+                self.record(input);
+            }
+            Input::InsertTape(ref data) => {
+                self.bus_mut().insert_tape(data.clone());
+                self.record(input);
+            }
+        }
+        Ok(())
+    }
+
+    // Starts recording input from here on, along with a snapshot and what's
+    // in the drives, which snapshots leave out
+    pub fn start_recording(&mut self) {
+        let mut events = Vec::new();
+        let cycle = self.cycles;
+        for drive in 0.. {
+            let Some(disc_drive) = self.bus_mut().drive_mut(drive) else {
+                break;
+            };
+            let input = match &disc_drive.image {
+                Some(image) => Input::InsertDisc {
+                    drive,
+                    format: image.format,
+                    write_protected: image.write_protected,
+                    bytes: image.to_bytes(),
+                },
+                None => Input::EjectDisc { drive },
+            };
+            events.push(Event { cycle, input });
+        }
+
+        self.recording = Some(Recording {
+            snapshot: self.snapshot(),
+            events,
+        });
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Recording> {
+        self.recording.take()
+    }
+
+    pub fn recording(&self) -> Option<&Recording> {
+        self.recording.as_ref()
+    }
+
+    fn record(&mut self, input: Input) {
+        if let Some(recording) = &mut self.recording {
+            recording.events.push(Event {
+                cycle: self.cycles,
+                input,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::cpu::{IRQ_VECTOR, RESET_VECTOR};
+    use crate::devices::acia::StatusBits as AciaStatus;
+    use crate::devices::via::{
+        InterruptBits, REGISTER_DDRA, REGISTER_DDRB, REGISTER_IER, REGISTER_IFR, REGISTER_ORA,
+        REGISTER_ORB, REGISTER_T1CH, REGISTER_T1CL,
+    };
+    use crate::devices::CLOCK_HZ;
+    use crate::sound::DEFAULT_SAMPLE_RATE;
+    use crate::tape::uef::{Chunk, IMPLICIT_DATA};
+    use crate::video::capture::{VideoFormat, DEFAULT_HEIGHT, DEFAULT_WIDTH};
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    const SYSTEM_VIA: Address = 0xfe40;
+
+    // A MOS that clears the interrupt mask and spins at &C000, with an IRQ
+    // handler that spins at &C010
+    pub(crate) fn test_mos() -> Vec<Byte> {
+        let mut mos = vec![0xff; ROM_SIZE];
+        mos[0x0000..0x0004].copy_from_slice(&[0x58, 0x18, 0x90, 0xfd]); // CLI: CLC: BCC
+        mos[0x0010..0x0013].copy_from_slice(&[0x18, 0x90, 0xfd]); // CLC: BCC
+        mos[(RESET_VECTOR - 0xc000) as usize..][..2].copy_from_slice(&[0x00, 0xc0]);
+        mos[(IRQ_VECTOR - 0xc000) as usize..][..2].copy_from_slice(&[0x10, 0xc0]);
+        mos
+    }
+
+    fn model_b() -> Result<Machine> {
+        Machine::new(Model::B, Roms::new(test_mos()))
+    }
+
+    #[test]
+    fn boots_a_mos_with_interrupts() -> Result<()> {
+        let mut machine = Machine::new(Model::B, Roms::new(crate::roms::hello_mos()))?;
+        machine.run(10 * 40_000)?;
+
+        let screen = machine.screen().unwrap();
+        assert!(screen.teletext());
+        assert_eq!(screen.text()[0], " BBC Computer");
+        // A vertical sync each 50th of a second, each taken as an IRQ
+        let vsyncs = machine.bus().read_byte(0x72)?;
+        assert!((9..=11).contains(&vsyncs), "{} vsyncs", vsyncs);
+        assert_eq!(machine.cpu().registers().sp, 0xff);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stores_leave_device_registers_unread() -> Result<()> {
+        let mut machine = model_b()?;
+        machine.input(Input::Serial(0x41))?;
+
+        // STA &FE09, which would take the byte if it read the data register
+        machine.bus_mut().write_byte(0x2000, 0x8d)?;
+        machine.bus_mut().write_word(0x2001, 0xfe09)?;
+        machine.cpu_mut().registers_mut().pc = 0x2000;
+        machine.step()?;
+
+        let serial = machine.bus_mut().serial_mut().unwrap();
+        assert_eq!(serial.acia.status() & AciaStatus::ReceiveFull as u8, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn joystick_conversion_ends_on_cb1() -> Result<()> {
+        let mut machine = model_b()?;
+        machine.input(Input::Adc {
+            channel: 1,
+            value: 0x1234,
+        })?;
+
+        machine.bus_mut().write_byte(0xfec0, 0x09)?;
+        machine.run(CLOCK_HZ as u64 / 100)?;
+        assert_eq!(machine.bus().read_byte(0xfec1)?, 0x12);
+        assert_eq!(machine.bus().read_byte(0xfec2)?, 0x30);
+        let ifr = machine.bus().read_byte(SYSTEM_VIA + REGISTER_IFR)?;
+        assert_eq!(ifr & InterruptBits::Cb1 as Byte, InterruptBits::Cb1 as Byte);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tapes_are_read_through_the_acia() -> Result<()> {
+        let mut machine = model_b()?;
+        let mut uef = Uef::new();
+        uef.push(Chunk::new(IMPLICIT_DATA, vec![0x2a]));
+        machine.insert_tape(&uef);
+
+        // Motor on, cassette selected
+        machine.bus_mut().write_byte(0xfe10, 0x80)?;
+        machine.run(CLOCK_HZ as u64 * 11 / 10)?;
+        assert_eq!(machine.bus().read_byte(0xfe09)?, 0x2a);
+
+        Ok(())
+    }
+
+    #[test]
+    fn master_runs_65c02_code() -> Result<()> {
+        let mut machine = Machine::new(Model::Master128, Roms::new(test_mos()))?;
+        assert_eq!(machine.model(), Model::Master128);
+        assert_eq!(machine.cpu().registers().pc, 0xc000);
+
+        // BRA -2
+        machine.bus_mut().write_word(0x2000, 0xfe80)?;
+        machine.cpu_mut().registers_mut().pc = 0x2000;
+        assert_eq!(machine.step()?, DispatchResult::Cycles(3));
+        assert_eq!(machine.cpu().registers().pc, 0x2000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn master_bit_immediate_only_sets_zero() -> Result<()> {
+        let mut machine = Machine::new(Model::Master128, Roms::new(test_mos()))?;
+        let code = [
+            0xa9, 0x0f, // LDA #&0F
+            0x89, 0xc0, // BIT #&C0
+            0x89, 0x01, // BIT #&01
+        ];
+        for (n, &byte) in code.iter().enumerate() {
+            machine.bus_mut().write_byte(0x2000 + n as Address, byte)?;
+        }
+        machine.cpu_mut().registers_mut().pc = 0x2000;
+
+        machine.step()?;
+        assert_eq!(machine.step()?, DispatchResult::Cycles(2));
+        let registers = machine.cpu().registers();
+        assert!(registers.zero() && !registers.negative() && !registers.overflow());
+
+        machine.cpu_mut().registers_mut().set_status(0xc0);
+        machine.step()?;
+        let registers = machine.cpu().registers();
+        assert!(!registers.zero() && registers.negative() && registers.overflow());
+
+        Ok(())
+    }
+
+    #[test]
+    fn master_runs_65c02_accumulator_and_indexed_jumps() -> Result<()> {
+        let mut machine = Machine::new(Model::Master128, Roms::new(test_mos()))?;
+        let code = [
+            0xa9, 0x41, // LDA #&41
+            0x1a, // INC A
+            0x1a, // INC A
+            0x3a, // DEC A
+            0xa2, 0x02, // LDX #2
+            0x7c, 0x00, 0x21, // JMP (&2100,X)
+        ];
+        for (n, &byte) in code.iter().enumerate() {
+            machine.bus_mut().write_byte(0x2000 + n as Address, byte)?;
+        }
+        machine.bus_mut().write_word(0x2102, 0x3000)?;
+        machine.cpu_mut().registers_mut().pc = 0x2000;
+
+        for _ in 0..6 {
+            machine.step()?;
+        }
+        assert_eq!(machine.cpu().registers().a, 0x42);
+        assert_eq!(machine.cpu().registers().pc, 0x3000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn power_on_starts_at_reset_vector() -> Result<()> {
+        let machine = model_b()?;
+        assert_eq!(machine.cpu().registers().pc, 0xc000);
+        assert_eq!(machine.cpu().registers().sp, 0xfd);
+        assert!(machine.cpu().registers().int());
+
+        Ok(())
+    }
+
+    #[test]
+    fn mos_must_be_16k() {
+        let result = Machine::new(Model::B, Roms::new(vec![0; 0x2000]));
+        assert!(matches!(
+            result,
+            Err(Error {
+                error_type: ErrorType::InvalidRom,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn break_keeps_via_state() -> Result<()> {
+        let mut machine = model_b()?;
+        assert_eq!(machine.bus().read_byte(SYSTEM_VIA + REGISTER_IER)?, 0x80);
+
+        let timer1 = 0x80 | InterruptBits::Timer1 as Byte;
+        machine
+            .bus_mut()
+            .write_byte(SYSTEM_VIA + REGISTER_IER, timer1)?;
+        machine.run(100)?;
+
+        machine.reset()?;
+        assert_eq!(machine.cpu().registers().pc, 0xc000);
+        assert_eq!(machine.bus().read_byte(SYSTEM_VIA + REGISTER_IER)?, timer1);
+
+        machine.power_on()?;
+        assert_eq!(machine.bus().read_byte(SYSTEM_VIA + REGISTER_IER)?, 0x80);
+
+        Ok(())
+    }
+
+    #[test]
+    fn via_timer_interrupts_cpu() -> Result<()> {
+        let mut machine = model_b()?;
+        let bus = machine.bus_mut();
+        bus.write_byte(
+            SYSTEM_VIA + REGISTER_IER,
+            0x80 | InterruptBits::Timer1 as Byte,
+        )?;
+        bus.write_byte(SYSTEM_VIA + REGISTER_T1CL, 0x00)?;
+        bus.write_byte(SYSTEM_VIA + REGISTER_T1CH, 0x01)?;
+
+        machine.run(200)?;
+        assert_eq!(machine.cpu().registers().pc & 0xfff0, 0xc000);
+
+        machine.run(400)?;
+        assert_eq!(machine.cpu().registers().pc & 0xfff0, 0xc010);
+        assert_ne!(
+            machine.bus().read_byte(SYSTEM_VIA + REGISTER_IFR)? & InterruptBits::Timer1 as Byte,
+            0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_resumes_at_same_cycle() -> Result<()> {
+        let mut machine = model_b()?;
+        let bus = machine.bus_mut();
+        bus.write_byte(
+            SYSTEM_VIA + REGISTER_IER,
+            0x80 | InterruptBits::Timer1 as Byte,
+        )?;
+        bus.write_byte(SYSTEM_VIA + REGISTER_T1CL, 0x00)?;
+        bus.write_byte(SYSTEM_VIA + REGISTER_T1CH, 0x01)?;
+        machine.run(200)?;
+
+        let snapshot = machine.snapshot();
+        let cycles = machine.cycles();
+        machine.bus_mut().write_byte(0x1000, 0x55)?;
+        machine.run(400)?;
+        let pc = machine.cpu().registers().pc;
+        let ifr = machine.bus().read_byte(SYSTEM_VIA + REGISTER_IFR)?;
+
+        machine.restore(&snapshot)?;
+        assert_eq!(machine.cycles(), cycles);
+        assert_eq!(machine.bus().read_byte(0x1000)?, 0x00);
+
+        machine.bus_mut().write_byte(0x1000, 0x55)?;
+        machine.run(400)?;
+        assert_eq!(machine.cpu().registers().pc, pc);
+        assert_eq!(machine.bus().read_byte(SYSTEM_VIA + REGISTER_IFR)?, ifr);
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_round_trips_every_model() -> Result<()> {
+        for model in [
+            Model::B,
+            Model::BPlus,
+            Model::BPlus128,
+            Model::Master128,
+            Model::Electron,
+        ] {
+            let mut machine = Machine::new(model, Roms::new(test_mos()))?;
+            machine.run(1000)?;
+            let snapshot = machine.snapshot();
+
+            let mut restored = Machine::new(model, Roms::new(test_mos()))?;
+            restored.restore(&snapshot)?;
+            assert_eq!(restored.snapshot(), snapshot);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_must_match_model() -> Result<()> {
+        let snapshot = Machine::new(Model::Master128, Roms::new(test_mos()))?.snapshot();
+        let mut machine = model_b()?;
+        assert_eq!(
+            machine.restore(&snapshot),
+            Err(Error::without_pc(ErrorType::InvalidSnapshot))
+        );
+        assert_eq!(
+            machine.restore(&snapshot[..4]),
+            Err(Error::without_pc(ErrorType::InvalidSnapshot))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sound_capture() -> Result<()> {
+        let mut machine = model_b()?;
+        machine.start_sound_capture(DEFAULT_SAMPLE_RATE);
+        machine.run(20_000)?;
+        let samples = machine.sound_capture().unwrap().samples().len();
+        assert!(samples >= 441);
+        assert!(machine
+            .sound_capture()
+            .unwrap()
+            .samples()
+            .iter()
+            .all(|&s| s == 0));
+
+        // Channel 0 at 1kHz and full volume, a byte at a time through the
+        // slow data bus as latch bit 0 goes low
+        let bus = machine.bus_mut();
+        bus.write_byte(SYSTEM_VIA + REGISTER_DDRA, 0xff)?;
+        bus.write_byte(SYSTEM_VIA + REGISTER_DDRB, 0x0f)?;
+        for data in [0x8d, 0x07, 0x90] {
+            bus.write_byte(SYSTEM_VIA + REGISTER_ORA, data)?;
+            bus.write_byte(SYSTEM_VIA + REGISTER_ORB, 0x08)?;
+            bus.write_byte(SYSTEM_VIA + REGISTER_ORB, 0x00)?;
+        }
+        machine.run(40_000)?;
+
+        let capture = machine.stop_sound_capture().unwrap();
+        assert!(machine.sound_capture().is_none());
+        let tone = &capture.samples()[samples + 1..];
+        assert!(tone.len() >= 881);
+        // A quarter of full scale, changing sign twice a millisecond
+        assert_eq!(tone.iter().max(), Some(&8192));
+        assert_eq!(tone.iter().min(), Some(&-8192));
+        let loud: Vec<bool> = tone
+            .iter()
+            .filter(|s| s.abs() > 4000)
+            .map(|&s| s > 0)
+            .collect();
+        let changes = loud.windows(2).filter(|pair| pair[0] != pair[1]).count();
+        let milliseconds = tone.len() * 1000 / DEFAULT_SAMPLE_RATE as usize;
+        assert!(changes.abs_diff(milliseconds * 2) <= 2);
+
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn video_capture_keeps_up_with_sound() -> Result<()> {
+        let mut machine = model_b()?;
+        let shared = Shared(Rc::new(RefCell::new(Vec::new())));
+        machine.start_sound_capture(DEFAULT_SAMPLE_RATE);
+        machine.start_video_capture(VideoCapture::to_writer(shared.clone(), VideoFormat::Rgb))?;
+        let size = DEFAULT_WIDTH * DEFAULT_HEIGHT * 3;
+        // The display is off to start with
+        assert_eq!(*shared.0.borrow(), vec![0; size]);
+
+        // MODE 7 with its first character showing in the MOS's solid font
+        let bus = machine.bus_mut();
+        for (register, data) in [(1, 40), (6, 25), (8, 0x93), (9, 18), (12, 0x28)] {
+            bus.write_byte(0xfe00, register)?;
+            bus.write_byte(0xfe01, data)?;
+        }
+        bus.write_byte(0xfe20, 0x4b)?;
+        bus.write_byte(0x7c00, b'A')?;
+        machine.run(200_000)?;
+
+        // A tenth of a second of each
+        let video = machine.stop_video_capture()?.unwrap();
+        assert!(machine.video_capture().is_none());
+        assert_eq!(video.frames(), 6);
+        let samples = machine.stop_sound_capture().unwrap().samples().len();
+        assert!(samples.abs_diff(4410) <= 1);
+
+        let bytes = shared.0.borrow();
+        assert_eq!(bytes.len(), 6 * size);
+        assert!(bytes[size..]
+            .chunks(size)
+            .all(|frame| frame.contains(&0xff)));
+
+        Ok(())
+    }
+}
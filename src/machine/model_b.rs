@@ -0,0 +1,320 @@
+use crate::cpu::ram::Ram;
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::crtc::Crtc;
+use crate::devices::disc_drive::DiscDrive;
+use crate::devices::fdc8271::{self, Fdc8271};
+use crate::devices::serial::Serial;
+use crate::devices::system_via::SystemVia;
+use crate::devices::upd7002::Upd7002;
+use crate::devices::via::Via;
+use crate::devices::video_ula::VideoUla;
+use crate::devices::Device;
+use crate::machine::{Bus, Roms, ROM_SIZE};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use crate::video::Screen;
+
+// The BBC Model B memory map:
+//
+//   &0000-&7FFF  32K RAM
+//   &8000-&BFFF  sideways ROM selected by ROMSEL
+//   &C000-&FBFF  MOS
+//   &FC00-&FDFF  FRED and JIM (1MHz bus, nothing fitted)
+//   &FE00-&FEFF  SHEILA
+//   &FF00-&FFFF  MOS
+
+pub const RAM_SIZE: usize = 0x8000;
+
+const SIDEWAYS_START: Address = 0x8000;
+const MOS_START: Address = 0xc000;
+const SHEILA_START: Address = 0xfe00;
+const SHEILA_END: Address = 0xfeff;
+const FRED_START: Address = 0xfc00;
+
+pub struct ModelB {
+    ram: Ram,
+    mos: Vec<Byte>,
+    sideways: Vec<Option<Vec<Byte>>>,
+    romsel: Byte,
+
+    pub crtc: Crtc,
+    pub serial: Serial,
+    pub video_ula: VideoUla,
+    pub system_via: SystemVia,
+    pub user_via: Via,
+    pub fdc: Fdc8271,
+    pub adc: Upd7002,
+}
+
+impl ModelB {
+    pub fn new(roms: &Roms) -> Self {
+        ModelB {
+            ram: Ram::new(RAM_SIZE),
+            mos: roms.mos.clone(),
+            sideways: roms.sideways_banks(),
+            romsel: 0,
+            crtc: Crtc::new(),
+            serial: Serial::new(),
+            video_ula: VideoUla::new(),
+            system_via: SystemVia::new(),
+            user_via: Via::new(),
+            fdc: Fdc8271::new(),
+            adc: Upd7002::new(),
+        }
+    }
+
+    pub fn romsel(&self) -> Byte {
+        self.romsel
+    }
+
+    fn read_sheila(&self, address: Address) -> Result<Byte> {
+        match address {
+            0xfe00..=0xfe07 => self.crtc.read_byte(address - 0xfe00),
+            0xfe08..=0xfe17 => self.serial.read_byte(address - 0xfe08),
+            0xfe20..=0xfe2f => self.video_ula.read_byte(address - 0xfe20),
+            0xfe40..=0xfe5f => self.system_via.read_byte(address - 0xfe40),
+            0xfe60..=0xfe7f => self.user_via.read_byte(address - 0xfe60),
+            fdc8271::BASE..=fdc8271::END => self.fdc.read_byte(address - fdc8271::BASE),
+            0xfec0..=0xfedf => self.adc.read_byte(address - 0xfec0),
+            _ => Ok(0xff),
+        }
+    }
+
+    fn write_sheila(&mut self, address: Address, data: Byte) -> Result<()> {
+        match address {
+            0xfe00..=0xfe07 => self.crtc.write_byte(address - 0xfe00, data),
+            0xfe08..=0xfe17 => self.serial.write_byte(address - 0xfe08, data),
+            0xfe20..=0xfe2f => {
+                self.video_ula.write_byte(address - 0xfe20, data)?;
+                self.crtc.fast_clock = self.video_ula.fast_clock();
+                Ok(())
+            }
+            0xfe30..=0xfe3f => {
+                self.romsel = data & 0x0f;
+                Ok(())
+            }
+            0xfe40..=0xfe5f => self.system_via.write_byte(address - 0xfe40, data),
+            0xfe60..=0xfe7f => self.user_via.write_byte(address - 0xfe60, data),
+            fdc8271::BASE..=fdc8271::END => self.fdc.write_byte(address - fdc8271::BASE, data),
+            0xfec0..=0xfedf => self.adc.write_byte(address - 0xfec0, data),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Memory for ModelB {
+    fn length(&self) -> usize {
+        0x10000
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        match address {
+            0x0000..=0x7fff => self.ram.read_byte(address),
+            0x8000..=0xbfff => match &self.sideways[self.romsel as usize] {
+                Some(bank) => Ok(bank[(address - SIDEWAYS_START) as usize]),
+                None => Ok(0xff),
+            },
+            FRED_START..SHEILA_START => Ok(0xff),
+            SHEILA_START..=SHEILA_END => self.read_sheila(address),
+            _ => Ok(self.mos[(address - MOS_START) as usize % ROM_SIZE]),
+        }
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let lsb = self.read_byte(address)? as Word;
+        let msb = self.read_byte(address.wrapping_add(1))? as Word;
+        Ok(lsb + (msb << 8))
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        match address {
+            0x0000..=0x7fff => self.ram.write_byte(address, data),
+            SHEILA_START..=SHEILA_END => self.write_sheila(address, data),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for ModelB {
+    fn tick(&mut self, cycles: usize) {
+        self.crtc.tick(cycles);
+        self.system_via.via.set_ca1(self.crtc.vsync());
+        self.adc.tick(cycles);
+        self.system_via.via.set_cb1(!self.adc.end_of_conversion());
+        self.system_via.tick(cycles);
+        self.user_via.tick(cycles);
+        self.serial.tick(cycles);
+        self.fdc.tick(cycles);
+    }
+
+    fn irq(&self) -> bool {
+        self.system_via.irq() || self.user_via.irq() || self.serial.acia.irq()
+    }
+
+    fn nmi(&self) -> bool {
+        self.fdc.nmi()
+    }
+}
+
+// The sideways sockets are all ROM, so only their selection is saved
+impl Snapshot for ModelB {
+    fn save(&self, state: &mut StateWriter) {
+        self.ram.save(state);
+        state.byte(self.romsel);
+        self.crtc.save(state);
+        self.serial.save(state);
+        self.video_ula.save(state);
+        self.system_via.save(state);
+        self.user_via.save(state);
+        self.fdc.save(state);
+        self.adc.save(state);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.ram.restore(state)?;
+        self.romsel = state.byte()?;
+        self.crtc.restore(state)?;
+        self.serial.restore(state)?;
+        self.video_ula.restore(state)?;
+        self.system_via.restore(state)?;
+        self.user_via.restore(state)?;
+        self.fdc.restore(state)?;
+        self.adc.restore(state)
+    }
+}
+
+impl Bus for ModelB {
+    fn power_on(&mut self) {
+        self.ram = Ram::new(RAM_SIZE);
+        self.romsel = 0;
+        self.crtc = Crtc::new();
+        self.video_ula = VideoUla::new();
+        self.system_via.reset();
+        self.user_via.reset();
+        self.fdc.reset();
+    }
+
+    fn press_key(&mut self, row: usize, column: usize) {
+        self.system_via.press_key(row, column);
+    }
+
+    fn release_key(&mut self, row: usize, column: usize) {
+        self.system_via.release_key(row, column);
+    }
+
+    fn drive_mut(&mut self, drive: usize) -> Option<&mut DiscDrive> {
+        self.fdc.drives.get_mut(drive)
+    }
+
+    fn serial_mut(&mut self) -> Option<&mut Serial> {
+        Some(&mut self.serial)
+    }
+
+    fn adc_mut(&mut self) -> Option<&mut Upd7002> {
+        Some(&mut self.adc)
+    }
+
+    fn insert_tape(&mut self, data: Vec<Byte>) {
+        self.serial.insert_tape(data);
+    }
+
+    fn sound_level(&self) -> f32 {
+        (self.system_via.sound.level() + self.serial.sound_level()).clamp(-1.0, 1.0)
+    }
+
+    fn screen(&self) -> Option<Screen> {
+        Some(Screen::new(
+            &self.crtc,
+            &self.video_ula,
+            self.system_via.latch(),
+            self.ram.bytes().to_vec(),
+            &self.mos,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::via::{InterruptBits, REGISTER_IER, REGISTER_IFR, REGISTER_PCR};
+    use crate::machine::tests::test_mos;
+
+    fn model_b() -> ModelB {
+        let mut roms = Roms::new(test_mos());
+        roms.insert(15, vec![0x0f; ROM_SIZE]);
+        roms.insert(12, vec![0x0c; ROM_SIZE / 2]);
+        ModelB::new(&roms)
+    }
+
+    #[test]
+    fn memory_map() -> Result<()> {
+        let mut bus = model_b();
+        bus.write_byte(0x1900, 0x42)?;
+        assert_eq!(bus.read_byte(0x1900)?, 0x42);
+
+        // The MOS is write protected and shows through above SHEILA
+        bus.write_byte(0xc000, 0x00)?;
+        assert_eq!(bus.read_byte(0xc000)?, 0x58);
+        assert_eq!(bus.read_word(0xfffc)?, 0xc000);
+
+        assert_eq!(bus.read_byte(0xfc00)?, 0xff);
+
+        Ok(())
+    }
+
+    #[test]
+    fn romsel_pages_sideways_roms() -> Result<()> {
+        let mut bus = model_b();
+        assert_eq!(bus.read_byte(0x8000)?, 0xff);
+
+        bus.write_byte(0xfe30, 0x0f)?;
+        assert_eq!(bus.read_byte(0x8000)?, 0x0f);
+        assert_eq!(bus.romsel(), 0x0f);
+
+        // 8K ROMs appear twice
+        bus.write_byte(0xfe30, 0x0c)?;
+        assert_eq!(bus.read_byte(0xa000)?, 0x0c);
+
+        Ok(())
+    }
+
+    #[test]
+    fn vsync_reaches_system_via() -> Result<()> {
+        let mut bus = model_b();
+        for (register, value) in [(0, 0x3f), (4, 0x26), (7, 0x20), (9, 0x07), (3, 0x28)] {
+            bus.write_byte(0xfe00, register)?;
+            bus.write_byte(0xfe01, value)?;
+        }
+        bus.write_byte(0xfe40 + REGISTER_PCR, 0x05)?;
+        bus.write_byte(0xfe40 + REGISTER_IER, 0x80 | InterruptBits::Ca1 as Byte)?;
+
+        for _ in 0..40_000 {
+            bus.tick(1);
+        }
+        assert!(bus.irq());
+        assert_ne!(
+            bus.read_byte(0xfe40 + REGISTER_IFR)? & InterruptBits::Ca1 as Byte,
+            0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn keyboard_through_system_via() -> Result<()> {
+        let mut bus = model_b();
+        bus.write_byte(0xfe42, 0x0f)?;
+        bus.write_byte(0xfe43, 0x7f)?;
+        bus.write_byte(0xfe40, 0x03)?;
+
+        bus.press_key(4, 1);
+        bus.write_byte(0xfe4f, 0x41)?;
+        assert_eq!(bus.read_byte(0xfe4f)? & 0x80, 0x80);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,427 @@
+use crate::cpu::ram::Ram;
+use crate::cpu::{Address, Byte, Memory, Result, Word};
+use crate::devices::crtc::Crtc;
+use crate::devices::disc_drive::DiscDrive;
+use crate::devices::serial::Serial;
+use crate::devices::system_via::SystemVia;
+use crate::devices::upd7002::Upd7002;
+use crate::devices::via::Via;
+use crate::devices::video_ula::VideoUla;
+use crate::devices::wd1770::{self, Wd1770};
+use crate::devices::Device;
+use crate::machine::{restore_sideways_ram, save_sideways_ram, Bus, Roms, ROM_SIZE};
+use crate::snapshot::{Snapshot, StateReader, StateWriter};
+use crate::video::Screen;
+
+// The Model B+ memory map. It's a Model B with a 1770 and two extra blocks
+// of RAM:
+//
+//   &0000-&2FFF  main RAM
+//   &3000-&7FFF  main RAM, or shadow screen RAM for the VDU drivers
+//   &8000-&AFFF  sideways bank, or private RAM when ROMSEL bit 7 is set
+//   &B000-&BFFF  sideways bank
+//   &C000-&FBFF  MOS
+//   &FC00-&FDFF  FRED and JIM
+//   &FE00-&FEFF  SHEILA
+//   &FF00-&FFFF  MOS
+//
+// The 128K version adds sideways RAM in banks 0, 1, 12 and 13.
+
+pub const RAM_SIZE: usize = 0x8000;
+pub const SHADOW_SIZE: usize = 0x5000;
+pub const PRIVATE_SIZE: usize = 0x3000;
+pub const SIDEWAYS_RAM_BANKS_128: [usize; 4] = [0, 1, 12, 13];
+
+pub const ROMSEL_PRIVATE: Byte = 0x80;
+pub const ACCCON_SHADOW: Byte = 0x80;
+
+const SHADOW_START: Address = 0x3000;
+const PRIVATE_START: Address = 0x8000;
+const SIDEWAYS_START: Address = 0x8000;
+const MOS_START: Address = 0xc000;
+const VDU_DRIVERS_START: Address = 0xc000;
+const VDU_DRIVERS_END: Address = 0xdfff;
+
+pub struct ModelBPlus {
+    ram: Ram,
+    shadow: Ram,
+    private: Ram,
+    mos: Vec<Byte>,
+    sideways: Vec<Option<Vec<Byte>>>,
+    sideways_ram: Vec<usize>,
+    romsel: Byte,
+    acccon: Byte,
+
+    // The shadow RAM is only seen by code running from &C000-&DFFF
+    vdu_driver: bool,
+
+    pub crtc: Crtc,
+    pub serial: Serial,
+    pub video_ula: VideoUla,
+    pub system_via: SystemVia,
+    pub user_via: Via,
+    pub fdc: Wd1770,
+    pub adc: Upd7002,
+}
+
+impl ModelBPlus {
+    // Banks listed in sideways_ram are RAM rather than ROM sockets
+    pub fn new(roms: &Roms, sideways_ram: &[usize]) -> Self {
+        let mut sideways = roms.sideways_banks();
+        for &bank in sideways_ram {
+            sideways[bank].get_or_insert_with(|| vec![0; ROM_SIZE]);
+        }
+
+        ModelBPlus {
+            ram: Ram::new(RAM_SIZE),
+            shadow: Ram::new(SHADOW_SIZE),
+            private: Ram::new(PRIVATE_SIZE),
+            mos: roms.mos.clone(),
+            sideways,
+            sideways_ram: sideways_ram.to_vec(),
+            romsel: 0,
+            acccon: 0,
+            vdu_driver: false,
+            crtc: Crtc::new(),
+            serial: Serial::new(),
+            video_ula: VideoUla::new(),
+            system_via: SystemVia::new(),
+            user_via: Via::new(),
+            fdc: Wd1770::new(wd1770::ACORN),
+            adc: Upd7002::new(),
+        }
+    }
+
+    pub fn romsel(&self) -> Byte {
+        self.romsel
+    }
+
+    pub fn acccon(&self) -> Byte {
+        self.acccon
+    }
+
+    // Whether the CRTC is showing the shadow RAM rather than main RAM
+    pub fn display_shadow(&self) -> bool {
+        self.acccon & ACCCON_SHADOW == ACCCON_SHADOW
+    }
+
+    fn bank(&self) -> usize {
+        (self.romsel & 0x0f) as usize
+    }
+
+    fn shadow_selected(&self) -> bool {
+        self.display_shadow() && self.vdu_driver
+    }
+
+    fn private_selected(&self) -> bool {
+        self.romsel & ROMSEL_PRIVATE == ROMSEL_PRIVATE
+    }
+
+    fn read_sheila(&self, address: Address) -> Result<Byte> {
+        match address {
+            0xfe00..=0xfe07 => self.crtc.read_byte(address - 0xfe00),
+            0xfe08..=0xfe17 => self.serial.read_byte(address - 0xfe08),
+            0xfe20..=0xfe2f => self.video_ula.read_byte(address - 0xfe20),
+            0xfe30..=0xfe33 => Ok(self.romsel),
+            0xfe34..=0xfe37 => Ok(self.acccon),
+            0xfe40..=0xfe5f => self.system_via.read_byte(address - 0xfe40),
+            0xfe60..=0xfe7f => self.user_via.read_byte(address - 0xfe60),
+            _ if self.fdc.interface.contains(address) => {
+                self.fdc.read_byte(address - self.fdc.interface.base)
+            }
+            0xfec0..=0xfedf => self.adc.read_byte(address - 0xfec0),
+            _ => Ok(0xff),
+        }
+    }
+
+    fn write_sheila(&mut self, address: Address, data: Byte) -> Result<()> {
+        match address {
+            0xfe00..=0xfe07 => self.crtc.write_byte(address - 0xfe00, data),
+            0xfe08..=0xfe17 => self.serial.write_byte(address - 0xfe08, data),
+            0xfe20..=0xfe2f => {
+                self.video_ula.write_byte(address - 0xfe20, data)?;
+                self.crtc.fast_clock = self.video_ula.fast_clock();
+                Ok(())
+            }
+            0xfe30..=0xfe33 => {
+                self.romsel = data & (ROMSEL_PRIVATE | 0x0f);
+                Ok(())
+            }
+            0xfe34..=0xfe37 => {
+                self.acccon = data & ACCCON_SHADOW;
+                Ok(())
+            }
+            0xfe40..=0xfe5f => self.system_via.write_byte(address - 0xfe40, data),
+            0xfe60..=0xfe7f => self.user_via.write_byte(address - 0xfe60, data),
+            _ if self.fdc.interface.contains(address) => {
+                self.fdc.write_byte(address - self.fdc.interface.base, data)
+            }
+            0xfec0..=0xfedf => self.adc.write_byte(address - 0xfec0, data),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Memory for ModelBPlus {
+    fn length(&self) -> usize {
+        0x10000
+    }
+
+    fn read_byte(&self, address: Address) -> Result<Byte> {
+        match address {
+            0x3000..=0x7fff if self.shadow_selected() => {
+                self.shadow.read_byte(address - SHADOW_START)
+            }
+            0x0000..=0x7fff => self.ram.read_byte(address),
+            0x8000..=0xafff if self.private_selected() => {
+                self.private.read_byte(address - PRIVATE_START)
+            }
+            0x8000..=0xbfff => match &self.sideways[self.bank()] {
+                Some(bank) => Ok(bank[(address - SIDEWAYS_START) as usize]),
+                None => Ok(0xff),
+            },
+            0xfc00..=0xfdff => Ok(0xff),
+            0xfe00..=0xfeff => self.read_sheila(address),
+            _ => Ok(self.mos[(address - MOS_START) as usize % ROM_SIZE]),
+        }
+    }
+
+    fn read_word(&self, address: Address) -> Result<Word> {
+        let lsb = self.read_byte(address)? as Word;
+        let msb = self.read_byte(address.wrapping_add(1))? as Word;
+        Ok(lsb + (msb << 8))
+    }
+
+    fn write_byte(&mut self, address: Address, data: Byte) -> Result<()> {
+        match address {
+            0x3000..=0x7fff if self.shadow_selected() => {
+                self.shadow.write_byte(address - SHADOW_START, data)
+            }
+            0x0000..=0x7fff => self.ram.write_byte(address, data),
+            0x8000..=0xafff if self.private_selected() => {
+                self.private.write_byte(address - PRIVATE_START, data)
+            }
+            0x8000..=0xbfff => {
+                let bank = self.bank();
+                if self.sideways_ram.contains(&bank) {
+                    if let Some(ram) = self.sideways[bank].as_mut() {
+                        ram[(address - SIDEWAYS_START) as usize] = data;
+                    }
+                }
+                Ok(())
+            }
+            0xfe00..=0xfeff => self.write_sheila(address, data),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_word(&mut self, address: Address, data: Word) -> Result<()> {
+        self.write_byte(address, (data & 0xff) as Byte)?;
+        self.write_byte(address.wrapping_add(1), (data >> 8) as Byte)
+    }
+}
+
+impl Device for ModelBPlus {
+    fn tick(&mut self, cycles: usize) {
+        self.crtc.tick(cycles);
+        self.system_via.via.set_ca1(self.crtc.vsync());
+        self.adc.tick(cycles);
+        self.system_via.via.set_cb1(!self.adc.end_of_conversion());
+        self.system_via.tick(cycles);
+        self.user_via.tick(cycles);
+        self.serial.tick(cycles);
+        self.fdc.tick(cycles);
+    }
+
+    fn irq(&self) -> bool {
+        self.system_via.irq() || self.user_via.irq() || self.serial.acia.irq()
+    }
+
+    fn nmi(&self) -> bool {
+        self.fdc.nmi()
+    }
+}
+
+impl Snapshot for ModelBPlus {
+    fn save(&self, state: &mut StateWriter) {
+        self.ram.save(state);
+        self.shadow.save(state);
+        self.private.save(state);
+        save_sideways_ram(state, &self.sideways, &self.sideways_ram);
+        state.byte(self.romsel);
+        state.byte(self.acccon);
+        state.bool(self.vdu_driver);
+        self.crtc.save(state);
+        self.serial.save(state);
+        self.video_ula.save(state);
+        self.system_via.save(state);
+        self.user_via.save(state);
+        self.fdc.save(state);
+        self.adc.save(state);
+    }
+
+    fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.ram.restore(state)?;
+        self.shadow.restore(state)?;
+        self.private.restore(state)?;
+        restore_sideways_ram(state, &mut self.sideways, &self.sideways_ram)?;
+        self.romsel = state.byte()?;
+        self.acccon = state.byte()?;
+        self.vdu_driver = state.bool()?;
+        self.crtc.restore(state)?;
+        self.serial.restore(state)?;
+        self.video_ula.restore(state)?;
+        self.system_via.restore(state)?;
+        self.user_via.restore(state)?;
+        self.fdc.restore(state)?;
+        self.adc.restore(state)
+    }
+}
+
+impl Bus for ModelBPlus {
+    fn power_on(&mut self) {
+        self.ram = Ram::new(RAM_SIZE);
+        self.shadow = Ram::new(SHADOW_SIZE);
+        self.private = Ram::new(PRIVATE_SIZE);
+        self.romsel = 0;
+        self.acccon = 0;
+        self.crtc = Crtc::new();
+        self.video_ula = VideoUla::new();
+        self.system_via.reset();
+        self.user_via.reset();
+        self.fdc.reset();
+    }
+
+    fn instruction_fetch(&mut self, pc: Address) {
+        self.vdu_driver = (VDU_DRIVERS_START..=VDU_DRIVERS_END).contains(&pc);
+    }
+
+    fn press_key(&mut self, row: usize, column: usize) {
+        self.system_via.press_key(row, column);
+    }
+
+    fn release_key(&mut self, row: usize, column: usize) {
+        self.system_via.release_key(row, column);
+    }
+
+    fn drive_mut(&mut self, drive: usize) -> Option<&mut DiscDrive> {
+        self.fdc.drives.get_mut(drive)
+    }
+
+    fn serial_mut(&mut self) -> Option<&mut Serial> {
+        Some(&mut self.serial)
+    }
+
+    fn adc_mut(&mut self) -> Option<&mut Upd7002> {
+        Some(&mut self.adc)
+    }
+
+    fn insert_tape(&mut self, data: Vec<Byte>) {
+        self.serial.insert_tape(data);
+    }
+
+    fn sound_level(&self) -> f32 {
+        (self.system_via.sound.level() + self.serial.sound_level()).clamp(-1.0, 1.0)
+    }
+
+    fn screen(&self) -> Option<Screen> {
+        let mut memory = self.ram.bytes().to_vec();
+        if self.display_shadow() {
+            memory[SHADOW_START as usize..].copy_from_slice(self.shadow.bytes());
+        }
+        Some(Screen::new(
+            &self.crtc,
+            &self.video_ula,
+            self.system_via.latch(),
+            memory,
+            &self.mos,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::tests::test_mos;
+
+    fn b_plus(sideways_ram: &[usize]) -> ModelBPlus {
+        let mut roms = Roms::new(test_mos());
+        roms.insert(15, vec![0x0f; ROM_SIZE]);
+        ModelBPlus::new(&roms, sideways_ram)
+    }
+
+    #[test]
+    fn private_ram_via_romsel() -> Result<()> {
+        let mut bus = b_plus(&[]);
+        bus.write_byte(0xfe30, 0x8f)?;
+        bus.write_byte(0x8000, 0x12)?;
+        bus.write_byte(0xafff, 0x34)?;
+        assert_eq!(bus.read_byte(0x8000)?, 0x12);
+        assert_eq!(bus.read_byte(0xafff)?, 0x34);
+        assert_eq!(bus.read_byte(0xb000)?, 0x0f);
+
+        bus.write_byte(0xfe30, 0x0f)?;
+        assert_eq!(bus.read_byte(0x8000)?, 0x0f);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shadow_ram_for_vdu_drivers() -> Result<()> {
+        let mut bus = b_plus(&[]);
+        bus.write_byte(0x3000, 0x11)?;
+        bus.write_byte(0xfe34, ACCCON_SHADOW)?;
+        assert!(bus.display_shadow());
+
+        bus.instruction_fetch(0xc400);
+        bus.write_byte(0x3000, 0x22)?;
+        assert_eq!(bus.read_byte(0x3000)?, 0x22);
+
+        // Everything else still sees main RAM
+        bus.instruction_fetch(0x1900);
+        assert_eq!(bus.read_byte(0x3000)?, 0x11);
+
+        Ok(())
+    }
+
+    #[test]
+    fn screen_shows_shadow_ram() -> Result<()> {
+        let mut bus = b_plus(&[]);
+        bus.write_byte(0x3000, 0x11)?;
+        assert_eq!(bus.screen().unwrap().byte(0x3000), 0x11);
+
+        bus.write_byte(0xfe34, ACCCON_SHADOW)?;
+        bus.instruction_fetch(0xc400);
+        bus.write_byte(0x3000, 0x22)?;
+        let screen = bus.screen().unwrap();
+        assert_eq!(screen.byte(0x3000), 0x22);
+        assert_eq!(screen.memory.len(), RAM_SIZE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sideways_ram_on_128k() -> Result<()> {
+        let mut bus = b_plus(&SIDEWAYS_RAM_BANKS_128);
+        bus.write_byte(0xfe30, 0x0d)?;
+        bus.write_byte(0x8000, 0x5a)?;
+        assert_eq!(bus.read_byte(0x8000)?, 0x5a);
+
+        let mut bus = b_plus(&[]);
+        bus.write_byte(0xfe30, 0x0d)?;
+        bus.write_byte(0x8000, 0x5a)?;
+        assert_eq!(bus.read_byte(0x8000)?, 0xff);
+
+        Ok(())
+    }
+
+    #[test]
+    fn wd1770_at_fe80() -> Result<()> {
+        let mut bus = b_plus(&[]);
+        bus.write_byte(0xfe85, 0x27)?;
+        assert_eq!(bus.read_byte(0xfe85)?, 0x27);
+        assert_eq!(bus.read_byte(0xfe8d)?, 0xff);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+use std::fs;
+use std::path::Path;
+
+use crate::cpu::debug::Break;
+use crate::cpu::dispatch::DispatchResult;
+use crate::cpu::{Byte, Error, ErrorType, Result, Word};
+use crate::disc::image::Format;
+use crate::machine::Machine;
+use crate::snapshot::{StateReader, StateWriter};
+
+// A session as a snapshot of where it started and every input after that,
+// each stamped with the cycle it arrived on. The machine only takes input
+// between instructions, so feeding the same inputs in at the same cycles
+// runs exactly the same way again.
+
+pub const RECORDING_MAGIC: &[u8; 8] = b"BEEBPLAY";
+pub const RECORDING_VERSION: Byte = 2;
+
+const KEY_DOWN: Byte = 0;
+const KEY_UP: Byte = 1;
+const INSERT_DISC: Byte = 2;
+const EJECT_DISC: Byte = 3;
+const SERIAL: Byte = 4;
+const ADC: Byte = 5;
+const INSERT_TAPE: Byte = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    KeyDown {
+        row: usize,
+        column: usize,
+    },
+    KeyUp {
+        row: usize,
+        column: usize,
+    },
+    // The whole image, as it was when it went in
+    InsertDisc {
+        drive: usize,
+        format: Format,
+        write_protected: bool,
+        bytes: Vec<Byte>,
+    },
+    EjectDisc {
+        drive: usize,
+    },
+    // A byte arriving at the RS423 port
+    Serial(Byte),
+    // A joystick or other analogue input moving
+    Adc {
+        channel: usize,
+        value: Word,
+    },
+    // The bytes on the tape, without its tones and gaps
+    InsertTape(Vec<Byte>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub cycle: u64,
+    pub input: Input,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recording {
+    pub snapshot: Vec<Byte>,
+    pub events: Vec<Event>,
+}
+
+fn format_byte(format: Format) -> Byte {
+    match format {
+        Format::Ssd => 0,
+        Format::Dsd => 1,
+        Format::Adf => 2,
+        Format::Adl => 3,
+    }
+}
+
+fn byte_format(byte: Byte) -> Result<Format> {
+    match byte {
+        0 => Ok(Format::Ssd),
+        1 => Ok(Format::Dsd),
+        2 => Ok(Format::Adf),
+        3 => Ok(Format::Adl),
+        _ => Err(Error::without_pc(ErrorType::InvalidRecording)),
+    }
+}
+
+impl Input {
+    fn save(&self, state: &mut StateWriter) {
+        match self {
+            Input::KeyDown { row, column } | Input::KeyUp { row, column } => {
+                let kind = match self {
+                    Input::KeyDown { .. } => KEY_DOWN,
+                    _ => KEY_UP,
+                };
+                state.byte(kind);
+                state.usize(*row);
+                state.usize(*column);
+            }
+            Input::InsertDisc {
+                drive,
+                format,
+                write_protected,
+                bytes,
+            } => {
+                state.byte(INSERT_DISC);
+                state.usize(*drive);
+                state.byte(format_byte(*format));
+                state.bool(*write_protected);
+                state.bytes(bytes);
+            }
+            Input::EjectDisc { drive } => {
+                state.byte(EJECT_DISC);
+                state.usize(*drive);
+            }
+            Input::Serial(data) => {
+                state.byte(SERIAL);
+                state.byte(*data);
+            }
+            Input::Adc { channel, value } => {
+                state.byte(ADC);
+                state.usize(*channel);
+                state.word(*value);
+            }
+            Input::InsertTape(bytes) => {
+                state.byte(INSERT_TAPE);
+                state.bytes(bytes);
+            }
+        }
+    }
+
+    fn restore(state: &mut StateReader) -> Result<Self> {
+        Ok(match state.byte()? {
+            KEY_DOWN => Input::KeyDown {
+                row: state.usize()?,
+                column: state.usize()?,
+            },
+            KEY_UP => Input::KeyUp {
+                row: state.usize()?,
+                column: state.usize()?,
+            },
+            INSERT_DISC => Input::InsertDisc {
+                drive: state.usize()?,
+                format: byte_format(state.byte()?)?,
+                write_protected: state.bool()?,
+                bytes: state.bytes()?,
+            },
+            EJECT_DISC => Input::EjectDisc {
+                drive: state.usize()?,
+            },
+            SERIAL => Input::Serial(state.byte()?),
+            ADC => Input::Adc {
+                channel: state.usize()?,
+                value: state.word()?,
+            },
+            INSERT_TAPE => Input::InsertTape(state.bytes()?),
+            _ => return Err(Error::without_pc(ErrorType::InvalidRecording)),
+        })
+    }
+}
+
+impl Recording {
+    pub fn to_bytes(&self) -> Vec<Byte> {
+        let mut state = StateWriter::new();
+        state.array(RECORDING_MAGIC);
+        state.byte(RECORDING_VERSION);
+        state.bytes(&self.snapshot);
+        state.usize(self.events.len());
+        for event in &self.events {
+            state.u64(event.cycle);
+            event.input.save(&mut state);
+        }
+        state.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[Byte]) -> Result<Self> {
+        let invalid = |_| Error::without_pc(ErrorType::InvalidRecording);
+        let mut state = StateReader::new(bytes);
+        let mut magic = [0; RECORDING_MAGIC.len()];
+        state.array(&mut magic).map_err(invalid)?;
+        if &magic != RECORDING_MAGIC || state.byte().map_err(invalid)? != RECORDING_VERSION {
+            return Err(Error::without_pc(ErrorType::InvalidRecording));
+        }
+
+        let snapshot = state.bytes().map_err(invalid)?;
+        let count = state.usize().map_err(invalid)?;
+        let mut events = Vec::new();
+        for _ in 0..count {
+            let cycle = state.u64().map_err(invalid)?;
+            let input = Input::restore(&mut state).map_err(invalid)?;
+            if events.last().is_some_and(|last: &Event| last.cycle > cycle) {
+                return Err(Error::without_pc(ErrorType::InvalidRecording));
+            }
+            events.push(Event { cycle, input });
+        }
+        if !state.at_end() {
+            return Err(Error::without_pc(ErrorType::InvalidRecording));
+        }
+
+        Ok(Recording { snapshot, events })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+// Plays a recording back into a machine of the same model, which is put back
+// to where the recording started
+pub struct Replay {
+    events: Vec<Event>,
+    next: usize,
+}
+
+impl Replay {
+    pub fn start(recording: &Recording, machine: &mut Machine) -> Result<Self> {
+        machine.restore(&recording.snapshot)?;
+        Ok(Replay {
+            events: recording.events.clone(),
+            next: 0,
+        })
+    }
+
+    // Whether every input has gone in
+    pub fn finished(&self) -> bool {
+        self.next == self.events.len()
+    }
+
+    fn feed(&mut self, machine: &mut Machine) -> Result<()> {
+        while let Some(event) = self.events.get(self.next) {
+            if event.cycle > machine.cycles() {
+                break;
+            }
+            machine.input(event.input.clone())?;
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    pub fn step(&mut self, machine: &mut Machine) -> Result<DispatchResult> {
+        self.feed(machine)?;
+        machine.step()
+    }
+
+    // Like Machine::run, but with the inputs going in as their cycles come up
+    pub fn run(&mut self, machine: &mut Machine, cycles: u64) -> Result<Option<Break>> {
+        let end = machine.cycles() + cycles;
+        while machine.cycles() < end {
+            if let DispatchResult::Break(reason, _) = self.step(machine)? {
+                return Ok(Some(reason));
+            }
+        }
+        self.feed(machine)?;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disc::image::DiscImage;
+    use crate::machine::tests::test_mos;
+    use crate::machine::{Model, Roms};
+    use crate::tape::uef::{Chunk, Uef, IMPLICIT_DATA};
+
+    fn model_b() -> Result<Machine> {
+        Machine::new(Model::B, Roms::new(test_mos()))
+    }
+
+    #[test]
+    fn replay_matches_the_session() -> Result<()> {
+        let mut machine = model_b()?;
+        machine.run(1000)?;
+        machine.start_recording();
+
+        machine.run(500)?;
+        machine.press_key(0, 2);
+        machine.run(300)?;
+        machine.input(Input::Serial(0x41))?;
+        machine.release_key(0, 2);
+        machine.insert_disc(1, DiscImage::new(Format::Ssd, 40))?;
+        machine.run(700)?;
+
+        let recording = machine.stop_recording().unwrap();
+        assert!(machine.stop_recording().is_none());
+        // Drives 0 and 1 as they were at the start, then the session
+        assert_eq!(recording.events.len(), 6);
+        assert_eq!(
+            recording.events[2].input,
+            Input::KeyDown { row: 0, column: 2 }
+        );
+
+        let recording = Recording::from_bytes(&recording.to_bytes())?;
+        let mut replayed = model_b()?;
+        replayed.insert_disc(0, DiscImage::new(Format::Dsd, 80))?;
+        let mut replay = Replay::start(&recording, &mut replayed)?;
+        let cycles = machine.cycles() - replayed.cycles();
+        replay.run(&mut replayed, cycles)?;
+
+        assert!(replay.finished());
+        assert_eq!(replayed.cycles(), machine.cycles());
+        assert_eq!(replayed.snapshot(), machine.snapshot());
+        assert!(replayed.bus_mut().drive_mut(0).unwrap().image.is_none());
+        assert!(replayed.bus_mut().drive_mut(1).unwrap().image.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn joystick_and_tape_replay() -> Result<()> {
+        let mut machine = model_b()?;
+        machine.start_recording();
+        machine.run(200)?;
+        machine.input(Input::Adc {
+            channel: 3,
+            value: 0xabcd,
+        })?;
+        let mut uef = Uef::new();
+        uef.push(Chunk::new(IMPLICIT_DATA, vec![0x2a, 0x2b]));
+        machine.insert_tape(&uef);
+        machine.run(300)?;
+
+        let recording = Recording::from_bytes(&machine.stop_recording().unwrap().to_bytes())?;
+        let inputs: Vec<_> = recording.events.iter().map(|e| &e.input).collect();
+        assert!(inputs.contains(&&Input::InsertTape(vec![0x2a, 0x2b])));
+
+        let mut replayed = model_b()?;
+        let mut replay = Replay::start(&recording, &mut replayed)?;
+        let cycles = machine.cycles() - replayed.cycles();
+        replay.run(&mut replayed, cycles)?;
+
+        assert!(replay.finished());
+        assert_eq!(replayed.bus_mut().adc_mut().unwrap().input(3), 0xabcd);
+        assert_eq!(replayed.snapshot(), machine.snapshot());
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_recordings() -> Result<()> {
+        let mut machine = model_b()?;
+        machine.start_recording();
+        machine.press_key(1, 1);
+        let bytes = machine.stop_recording().unwrap().to_bytes();
+
+        let invalid = Err(Error::without_pc(ErrorType::InvalidRecording));
+        assert_eq!(Recording::from_bytes(&bytes[..bytes.len() - 1]), invalid);
+        assert_eq!(Recording::from_bytes(b"BEEBSNAP"), invalid);
+        let mut unknown = bytes.clone();
+        let kind = unknown.len() - 17;
+        unknown[kind] = 0x7f;
+        assert_eq!(Recording::from_bytes(&unknown), invalid);
+
+        assert_eq!(
+            machine.input(Input::EjectDisc { drive: 7 }),
+            Err(Error::without_pc(ErrorType::NoSuchDrive(7)))
+        );
+
+        Ok(())
+    }
+}
@@ -1,29 +1,53 @@
-mod cpu;
-mod disassembler;
-mod roms;
+use beeb_rs::cpu::address::AddressAndDataDispatch;
+use beeb_rs::cpu::dispatch::Dispatcher;
+use beeb_rs::cpu::instruction_decode::InstructionDecoder;
+use beeb_rs::cpu::writeback::WritebackUnit;
+use beeb_rs::disassembler::execution::ExecutionUnit;
+use beeb_rs::disassembler::symbols::Symbols;
 
-use cpu::address::AddressAndDataDispatch;
-use cpu::dispatch::Dispatcher;
-use cpu::instruction_decode::InstructionDecoder;
-use cpu::writeback::WritebackUnit;
-use disassembler::execution::ExecutionUnit;
+use beeb_rs::cpu::memory::OverlayMemory;
+use beeb_rs::cpu::ram::Ram;
+use beeb_rs::cpu::rom::Rom;
 
-use cpu::memory::OverlayMemory;
-use cpu::ram::Ram;
-use cpu::rom::Rom;
+mod commands;
 
-fn main() -> cpu::Result<()> {
-    let mut registers = cpu::registers::Registers::new();
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let result = match args.first().map(String::as_str) {
+        None => run().map_err(|e| e.to_string()),
+        Some("assemble") => commands::assemble::run(&args[1..]),
+        Some("capture") => commands::capture::run(&args[1..]),
+        Some("disassemble") => commands::disassemble::run(&args[1..]),
+        Some("disc") => commands::disc::run(&args[1..]),
+        Some("gdb") => commands::gdb::run(&args[1..]),
+        Some("monitor") => commands::monitor::run(&args[1..]),
+        Some("screenshot") => commands::screenshot::run(&args[1..]),
+        Some(command) => Err(format!(
+            "unknown command '{}'\n\
+             usage: beeb-rs [assemble|capture|disassemble|disc|gdb|monitor|screenshot ...]",
+            command
+        )),
+    };
+
+    if let Err(message) = result {
+        eprintln!("beeb-rs: {}", message);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> beeb_rs::cpu::Result<()> {
+    let mut registers = beeb_rs::cpu::registers::Registers::new();
 
     let address_data_dispatch = AddressAndDataDispatch::new();
 
     let ram = Ram::new(64 * 1024);
-    let rom = Rom::new(roms::test_rom1());
+    let rom = Rom::new(beeb_rs::roms::test_rom1());
     let overlay_memory = OverlayMemory::new(ram, rom, 0xff00);
 
     let instruction_decoder = InstructionDecoder::new();
 
-    let execution_unit = ExecutionUnit::new();
+    let execution_unit = ExecutionUnit::with_symbols(Symbols::mos());
     let writeback_unit = WritebackUnit::new();
 
     registers.pc = 0xff00;
@@ -0,0 +1,612 @@
+use std::io::{self, BufRead, Write};
+
+use crate::cpu::debug::{Break, Breakpoint, Comparison, Condition, Register, Watchpoint};
+use crate::cpu::dispatch::DispatchResult;
+use crate::cpu::history::History;
+use crate::cpu::registers::StatusBits;
+use crate::cpu::trace::Trace;
+use crate::cpu::{Address, Byte, Error, Word};
+use crate::disassembler::disassemble;
+use crate::machine::Machine;
+
+// A machine code monitor in the style of the old ROM-based ones: one command
+// per line, numbers in hex (&1900, 0x1900 or just 1900).
+
+const HELP: &str = "commands:
+  step [N]             s  run N instructions (default 1)
+  run [CYCLES]         g  run until a breakpoint, or for up to CYCLES
+  break [ADDR] [COND]  b  set a breakpoint, or list them
+  clear ADDR           c  remove the breakpoints at ADDR
+  watch [r|w] START [END] [COND]
+                       w  stop on reads or writes in a range, or list them
+  unwatch N            u  remove watchpoint N
+  regs                 r  show the registers
+  mem ADDR [LENGTH]    m  dump memory
+  edit ADDR BYTE...    e  write bytes to memory
+  dis [ADDR [COUNT]]   d  disassemble, from the PC by default
+  trace FILE|ring N|off
+                       t  log instructions to FILE, or keep the last N, or
+                          stop; on its own, list what's been kept
+  history [N|off]      y  keep the last N instructions so they can be undone,
+                          or stop; on its own, list them
+  back [N]             k  undo the last N instructions (default 1)
+  help                 h  show this list
+  quit                 q  leave the monitor
+
+conditions compare a register (A X Y SP PS PC) or flag (C Z I D B V N) with a
+value using =, !=, < or >, such as X=1F or C=1; all of them must hold";
+
+const DUMP_LENGTH: usize = 0x80;
+const DUMP_ROW: usize = 16;
+const DISASSEMBLY_LINES: usize = 16;
+const TRAIL_LENGTH: usize = 8;
+
+pub struct Monitor {
+    pub machine: Machine,
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    let digits = text
+        .strip_prefix('&')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    u32::from_str_radix(digits, 16).ok()
+}
+
+fn parse_address(text: &str) -> Option<Address> {
+    parse_number(text).and_then(|n| Address::try_from(n).ok())
+}
+
+fn parse_byte(text: &str) -> Option<Byte> {
+    parse_number(text).and_then(|n| Byte::try_from(n).ok())
+}
+
+fn parse_condition(text: &str) -> Option<Condition> {
+    let (name, comparison, value) = [
+        ("!=", Comparison::NotEqual),
+        ("=", Comparison::Equal),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+    ]
+    .into_iter()
+    .find_map(|(operator, comparison)| {
+        text.split_once(operator)
+            .map(|(name, value)| (name, comparison, value))
+    })?;
+    let value = parse_number(value).and_then(|n| Word::try_from(n).ok())?;
+
+    let register = match name.to_ascii_uppercase().as_str() {
+        "A" => Some(Register::A),
+        "X" => Some(Register::X),
+        "Y" => Some(Register::Y),
+        "SP" => Some(Register::SP),
+        "PS" => Some(Register::PS),
+        "PC" => Some(Register::PC),
+        _ => None,
+    };
+    if let Some(register) = register {
+        return Some(Condition::Register(register, comparison, value));
+    }
+
+    let flag = match name.to_ascii_uppercase().as_str() {
+        "C" => StatusBits::Carry,
+        "Z" => StatusBits::Zero,
+        "I" => StatusBits::Int,
+        "D" => StatusBits::Dec,
+        "B" => StatusBits::Brk,
+        "V" => StatusBits::Ovf,
+        "N" => StatusBits::Neg,
+        _ => return None,
+    };
+    match (comparison, value) {
+        (Comparison::Equal, 0) | (Comparison::NotEqual, 1) => Some(Condition::Flag(flag, false)),
+        (Comparison::Equal, 1) | (Comparison::NotEqual, 0) => Some(Condition::Flag(flag, true)),
+        _ => None,
+    }
+}
+
+// Any number of conditions, which must all hold
+fn parse_conditions(args: &[&str]) -> Result<Option<Condition>, String> {
+    let mut conditions = args
+        .iter()
+        .map(|text| parse_condition(text).ok_or(format!("invalid condition '{}'", text)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(match conditions.len() {
+        0 => None,
+        1 => conditions.pop(),
+        _ => Some(Condition::All(conditions)),
+    })
+}
+
+fn describe(reason: &Break) -> String {
+    match reason {
+        Break::Breakpoint(pc) => format!("breakpoint at &{:04X}", pc),
+        Break::Watchpoint(access) => format!(
+            "{:?} of &{:02X} at &{:04X}",
+            access.access, access.data, access.address
+        )
+        .to_lowercase(),
+    }
+}
+
+impl Monitor {
+    pub fn new(machine: Machine) -> Self {
+        Monitor { machine }
+    }
+
+    // Reads commands until quit or the end of the input
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+        write!(output, "> ")?;
+        output.flush()?;
+        for line in input.lines() {
+            if !self.command(&line?, &mut output)? {
+                break;
+            }
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    // Runs one command, returning false once it's time to quit. Mistakes in
+    // the command and errors from the machine are reported to the output.
+    pub fn command<W: Write>(&mut self, line: &str, output: &mut W) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            return Ok(true);
+        };
+
+        let result = match command {
+            "step" | "s" => self.step(args, output),
+            "run" | "g" => self.run_to_breakpoint(args, output),
+            "break" | "b" => self.set_breakpoint(args, output),
+            "clear" | "c" => self.clear_breakpoint(args),
+            "watch" | "w" => self.set_watchpoint(args, output),
+            "unwatch" | "u" => self.clear_watchpoint(args),
+            "regs" | "r" => self.show_registers(output),
+            "mem" | "m" => self.dump(args, output),
+            "edit" | "e" => self.edit(args),
+            "dis" | "d" => self.disassemble(args, output),
+            "trace" | "t" => self.trace(args, output),
+            "history" | "y" => self.history(args, output),
+            "back" | "k" => self.step_back(args, output),
+            "help" | "h" | "?" => writeln!(output, "{}", HELP).map_err(|e| e.to_string()),
+            "quit" | "q" => return Ok(false),
+            _ => Err(format!("unknown command '{}', try help", command)),
+        };
+
+        if let Err(message) = result {
+            writeln!(output, "{}", message)?;
+        }
+        Ok(true)
+    }
+
+    // Stops early if a breakpoint or watchpoint is hit
+    fn step<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), String> {
+        let count = match args.first() {
+            Some(text) => parse_number(text).ok_or(format!("invalid count '{}'", text))?,
+            None => 1,
+        };
+        for _ in 0..count {
+            if let DispatchResult::Break(reason, _) =
+                self.machine.step().map_err(|e| self.failure(e))?
+            {
+                writeln!(output, "{}", describe(&reason)).map_err(|e| e.to_string())?;
+                break;
+            }
+        }
+        self.show_registers(output)
+    }
+
+    // Carries on from a breakpoint rather than stopping at it again
+    fn run_to_breakpoint<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), String> {
+        let limit = match args.first() {
+            Some(text) => {
+                parse_number(text).ok_or(format!("invalid cycle count '{}'", text))? as u64
+            }
+            None => u64::MAX - self.machine.cycles(),
+        };
+
+        if let Some(reason) = self.machine.run(limit).map_err(|e| self.failure(e))? {
+            writeln!(output, "{}", describe(&reason)).map_err(|e| e.to_string())?;
+        }
+        self.show_registers(output)
+    }
+
+    fn set_breakpoint<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), String> {
+        let cpu = self.machine.cpu_mut();
+        let Some(text) = args.first() else {
+            for (n, breakpoint) in cpu.breakpoints().iter().enumerate() {
+                let address = match breakpoint.address {
+                    Some(address) => format!("&{:04X}", address),
+                    None => "any".to_string(),
+                };
+                writeln!(output, "{}: {} {:?}", n, address, breakpoint.condition)
+                    .map_err(|e| e.to_string())?;
+            }
+            return Ok(());
+        };
+
+        let breakpoint = match parse_address(text) {
+            Some(address) => match parse_conditions(&args[1..])? {
+                Some(condition) => Breakpoint::at(address).with_condition(condition),
+                None => Breakpoint::at(address),
+            },
+            None => match parse_conditions(args) {
+                Ok(Some(condition)) => Breakpoint::when(condition),
+                _ => return Err(format!("invalid address '{}'", text)),
+            },
+        };
+        if !cpu.breakpoints().contains(&breakpoint) {
+            cpu.add_breakpoint(breakpoint);
+        }
+        Ok(())
+    }
+
+    fn clear_breakpoint(&mut self, args: &[&str]) -> Result<(), String> {
+        let text = args.first().ok_or("clear needs an address")?;
+        let address = parse_address(text).ok_or(format!("invalid address '{}'", text))?;
+        let cpu = self.machine.cpu_mut();
+        while let Some(n) = cpu
+            .breakpoints()
+            .iter()
+            .position(|b| b.address == Some(address))
+        {
+            cpu.remove_breakpoint(n);
+        }
+        Ok(())
+    }
+
+    fn set_watchpoint<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), String> {
+        let watched = self.machine.cpu_mut().watched_mut();
+        if args.is_empty() {
+            for (n, watchpoint) in watched.watchpoints().iter().enumerate() {
+                let access = match (watchpoint.read, watchpoint.write) {
+                    (true, false) => "r",
+                    (false, true) => "w",
+                    _ => "rw",
+                };
+                writeln!(
+                    output,
+                    "{}: {} &{:04X}-&{:04X} {:?}",
+                    n,
+                    access,
+                    watchpoint.range.start(),
+                    watchpoint.range.end(),
+                    watchpoint.condition
+                )
+                .map_err(|e| e.to_string())?;
+            }
+            return Ok(());
+        }
+
+        let (make, args): (fn(_) -> Watchpoint, _) = match args[0] {
+            "r" => (Watchpoint::read, &args[1..]),
+            "w" => (Watchpoint::write, &args[1..]),
+            "rw" => (Watchpoint::access, &args[1..]),
+            _ => (Watchpoint::access, args),
+        };
+        let text = args.first().ok_or("watch needs an address")?;
+        let start = parse_address(text).ok_or(format!("invalid address '{}'", text))?;
+        let (end, conditions) = match args.get(1).and_then(|text| parse_address(text)) {
+            Some(end) if end >= start => (end, &args[2..]),
+            Some(_) => return Err(format!("invalid range '{}'", args[1])),
+            None => (start, &args[1..]),
+        };
+
+        let mut watchpoint = make(start..=end);
+        watchpoint.condition = parse_conditions(conditions)?;
+        watched.add_watchpoint(watchpoint);
+        Ok(())
+    }
+
+    fn clear_watchpoint(&mut self, args: &[&str]) -> Result<(), String> {
+        let text = args.first().ok_or("unwatch needs a number")?;
+        let n = text
+            .parse()
+            .map_err(|_| format!("invalid watchpoint '{}'", text))?;
+        self.machine
+            .cpu_mut()
+            .watched_mut()
+            .remove_watchpoint(n)
+            .ok_or(format!("no watchpoint {}", n))?;
+        Ok(())
+    }
+
+    // The error, and how the CPU got there if there's a history
+    fn failure(&self, error: Error) -> String {
+        let trail = self
+            .machine
+            .cpu()
+            .history()
+            .map(|h| h.trail())
+            .unwrap_or_default();
+        if trail.is_empty() {
+            return error.to_string();
+        }
+        let recent: Vec<String> = trail[trail.len().saturating_sub(TRAIL_LENGTH)..]
+            .iter()
+            .map(|pc| format!("&{:04X}", pc))
+            .collect();
+        format!("{}\nafter {}", error, recent.join(" "))
+    }
+
+    fn history<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), String> {
+        let history = match args {
+            [] => {
+                let cpu = self.machine.cpu();
+                let Some(history) = cpu.history() else {
+                    return writeln!(output, "no history").map_err(|e| e.to_string());
+                };
+                for pc in history.trail() {
+                    let (line, _) = disassemble(cpu.memory(), cpu.instruction_decoder(), pc)
+                        .map_err(|e| e.to_string())?;
+                    writeln!(output, "{}", line).map_err(|e| e.to_string())?;
+                }
+                return Ok(());
+            }
+            ["off"] => None,
+            [text] => {
+                let capacity = parse_number(text).ok_or(format!("invalid count '{}'", text))?;
+                Some(History::new(capacity as usize))
+            }
+            _ => return Err("history needs a count or off".to_string()),
+        };
+        self.machine.set_history(history);
+        Ok(())
+    }
+
+    fn step_back<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), String> {
+        let count = match args.first() {
+            Some(text) => parse_number(text).ok_or(format!("invalid count '{}'", text))?,
+            None => 1,
+        };
+        if self.machine.cpu().history().is_none() {
+            return Err("no history, try history N".to_string());
+        }
+        for _ in 0..count {
+            match self.machine.step_back().map_err(|e| e.to_string())? {
+                Some(step) if !step.undoable() => {
+                    writeln!(output, "I/O writes at &{:04X} not undone", step.pc())
+                        .map_err(|e| e.to_string())?;
+                }
+                Some(_) => {}
+                None => {
+                    writeln!(output, "start of history").map_err(|e| e.to_string())?;
+                    break;
+                }
+            }
+        }
+        self.show_registers(output)
+    }
+
+    fn show_registers<W: Write>(&self, output: &mut W) -> Result<(), String> {
+        let cpu = self.machine.cpu();
+        let pc = cpu.registers().pc;
+        let (next, _) =
+            disassemble(cpu.memory(), cpu.instruction_decoder(), pc).map_err(|e| e.to_string())?;
+        writeln!(output, "{}\n{}", cpu.registers(), next).map_err(|e| e.to_string())
+    }
+
+    fn dump<W: Write>(&self, args: &[&str], output: &mut W) -> Result<(), String> {
+        let text = args.first().ok_or("mem needs an address")?;
+        let start = parse_address(text).ok_or(format!("invalid address '{}'", text))?;
+        let length = match args.get(1) {
+            Some(text) => parse_number(text).ok_or(format!("invalid length '{}'", text))? as usize,
+            None => DUMP_LENGTH,
+        };
+
+        let bus = self.machine.bus();
+        for row in (0..length).step_by(DUMP_ROW) {
+            let address = start.wrapping_add(row as Address);
+            let mut hex = String::new();
+            let mut ascii = String::new();
+            for n in 0..DUMP_ROW.min(length - row) {
+                let byte = bus
+                    .peek(address.wrapping_add(n as Address))
+                    .map_err(|e| e.to_string())?;
+                hex += &format!(" {:02X}", byte);
+                ascii.push(if (0x20..0x7f).contains(&byte) {
+                    byte as char
+                } else {
+                    '.'
+                });
+            }
+            writeln!(output, "{:04X} {:<48}  {}", address, hex, ascii)
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn edit(&mut self, args: &[&str]) -> Result<(), String> {
+        let Some((text, bytes)) = args.split_first() else {
+            return Err("edit needs an address and bytes".to_string());
+        };
+        let start = parse_address(text).ok_or(format!("invalid address '{}'", text))?;
+
+        let bytes = bytes
+            .iter()
+            .map(|text| parse_byte(text).ok_or(format!("invalid byte '{}'", text)))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (n, byte) in bytes.into_iter().enumerate() {
+            self.machine
+                .bus_mut()
+                .write_byte(start.wrapping_add(n as Address), byte)
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn disassemble<W: Write>(&self, args: &[&str], output: &mut W) -> Result<(), String> {
+        let cpu = self.machine.cpu();
+        let mut address = match args.first() {
+            Some(text) => parse_address(text).ok_or(format!("invalid address '{}'", text))?,
+            None => cpu.registers().pc,
+        };
+        let count = match args.get(1) {
+            Some(text) => parse_number(text).ok_or(format!("invalid count '{}'", text))? as usize,
+            None => DISASSEMBLY_LINES,
+        };
+
+        for _ in 0..count {
+            let (line, next) = disassemble(cpu.memory(), cpu.instruction_decoder(), address)
+                .map_err(|e| e.to_string())?;
+            writeln!(output, "{}", line).map_err(|e| e.to_string())?;
+            address = next;
+        }
+        Ok(())
+    }
+
+    fn trace<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), String> {
+        let trace = match args {
+            [] => {
+                let Some(trace) = self.machine.cpu().trace() else {
+                    return writeln!(output, "not tracing").map_err(|e| e.to_string());
+                };
+                for entry in trace.entries() {
+                    writeln!(output, "{}", entry).map_err(|e| e.to_string())?;
+                }
+                return Ok(());
+            }
+            ["off"] => None,
+            ["ring", text] => {
+                let capacity = parse_number(text).ok_or(format!("invalid count '{}'", text))?;
+                Some(Trace::ring(capacity as usize))
+            }
+            [path] => Some(Trace::to_file(path).map_err(|e| format!("{}: {}", path, e))?),
+            _ => return Err("trace needs a file, ring N or off".to_string()),
+        };
+
+        if let Some(mut old) = self.machine.set_trace(trace) {
+            old.flush().map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::acia::StatusBits as AciaStatus;
+    use crate::machine::replay::Input;
+    use crate::machine::tests::test_mos;
+    use crate::machine::{Model, Roms};
+
+    fn monitor() -> Monitor {
+        Monitor::new(Machine::new(Model::B, Roms::new(test_mos())).unwrap())
+    }
+
+    fn run(monitor: &mut Monitor, commands: &str) -> String {
+        let mut output = Vec::new();
+        monitor.run(commands.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn edit_and_dump() {
+        let mut monitor = monitor();
+        let output = run(&mut monitor, "e &1900 41 42 43\nm 1900 4\n");
+        assert!(output.contains("1900  41 42 43 00"));
+        assert!(output.contains("ABC."));
+    }
+
+    #[test]
+    fn dump_leaves_devices_alone() {
+        let mut monitor = monitor();
+        monitor.machine.input(Input::Serial(0x41)).unwrap();
+        let output = run(&mut monitor, "m fe08 2\n");
+        assert!(output.contains("FE08  FF FF"));
+
+        let serial = monitor.machine.bus_mut().serial_mut().unwrap();
+        assert_eq!(serial.acia.status() & AciaStatus::ReceiveFull as u8, 1);
+    }
+
+    #[test]
+    fn step_shows_registers() {
+        let mut monitor = monitor();
+        let output = run(&mut monitor, "s 2\n");
+        assert!(output.contains("PC: c002"));
+        assert!(output.contains("C002  90 FD     BCC &C001"));
+    }
+
+    #[test]
+    fn run_stops_at_breakpoint() {
+        let mut monitor = monitor();
+        let output = run(&mut monitor, "b c002\nb\ng\n");
+        assert!(output.contains("0: &C002 None\n"));
+        assert!(output.contains("breakpoint at &C002"));
+        assert_eq!(monitor.machine.cpu().registers().pc, 0xc002);
+
+        run(&mut monitor, "c c002\ng 100\n");
+        assert!(monitor.machine.cpu().breakpoints().is_empty());
+        assert!(monitor.machine.cycles() >= 100);
+    }
+
+    #[test]
+    fn conditions_and_watchpoints() {
+        let mut monitor = monitor();
+        let output = run(&mut monitor, "b c001 c=1\ng 40\n");
+        assert!(!output.contains("breakpoint"));
+        assert!(monitor.machine.cycles() >= 40);
+
+        let output = run(&mut monitor, "b pc=c000 i=1\nb c001 q=1\n");
+        assert!(output.contains("invalid condition 'q=1'"));
+        assert_eq!(monitor.machine.cpu().breakpoints().len(), 2);
+
+        let output = run(&mut monitor, "w w 70 7f\nw\nu 0\nu 0\n");
+        assert!(output.contains("0: w &0070-&007F None"));
+        assert!(output.contains("no watchpoint 0"));
+    }
+
+    #[test]
+    fn disassembles_range() {
+        let mut monitor = monitor();
+        let output = run(&mut monitor, "d c000 3\n");
+        assert!(
+            output.contains("C000  58        CLI\nC001  18        CLC\nC002  90 FD     BCC &C001")
+        );
+    }
+
+    #[test]
+    fn trace_keeps_recent_instructions() {
+        let mut monitor = monitor();
+        let output = run(&mut monitor, "t\nt ring 2\ns 3\nt\n");
+        assert!(output.contains("not tracing"));
+        assert!(!output.contains("C000  58"));
+        assert!(output.contains(
+            "C001  18        CLC                             A:00 X:00 Y:00 P:20 SP:FD CYC:2\n\
+             C002  90 FD     BCC &C001                       A:00 X:00 Y:00 P:20 SP:FD CYC:4\n"
+        ));
+
+        run(&mut monitor, "t off\n");
+        assert!(monitor.machine.cpu().trace().is_none());
+    }
+
+    #[test]
+    fn step_back_through_history() {
+        let mut monitor = monitor();
+        let output = run(&mut monitor, "k\ny 10\ns 4\ny\n");
+        assert!(output.contains("no history"));
+        assert!(output.contains("C000  58        CLI\nC001  18        CLC\nC002  90 FD"));
+        let cycles = monitor.machine.cycles();
+
+        let output = run(&mut monitor, "k 2\nk 5\n");
+        assert!(output.contains("PC: c002"));
+        assert!(output.contains("start of history"));
+        assert_eq!(monitor.machine.cpu().registers().pc, 0xc000);
+        assert!(monitor.machine.cpu().registers().int());
+        assert_eq!(monitor.machine.cycles(), 0);
+
+        run(&mut monitor, "s 4\n");
+        assert_eq!(monitor.machine.cycles(), cycles);
+    }
+
+    #[test]
+    fn mistakes_are_reported() {
+        let mut monitor = monitor();
+        let output = run(&mut monitor, "frob\nm zz\nq\ns\n");
+        assert!(output.contains("unknown command 'frob'"));
+        assert!(output.contains("invalid address 'zz'"));
+        assert!(!output.contains("PC:"));
+    }
+}
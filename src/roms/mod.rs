@@ -1,46 +1,56 @@
+use crate::assembler::Assembler;
+use crate::cpu::instruction_decode::InstructionDecoder;
+
+const TEST_ROM1: &str = r"
+    ORG &FF00
+.start
+    LDA start           \ a = &AD
+    JMP (vector)
+.vector
+    EQUW main
+.invert
+    EOR #255
+    RTS
+    EQUB &F2            \ NXX (NOP)
+    LDA #7 : PHA
+    LDA #2 : PHA
+    TSX : INX : TXS
+    PLA : TAY
+    STY &200
+    RTI
+.main
+    AND #&0F            \ a = &0D
+    ASL A : ASL A       \ a = &34
+    ASL A : ASL A       \ a = &D0
+    ASL A               \ a = &A0, c = 1
+    BCS store
+    NOP : NOP
+.store
+    STA &FF
+    CLC
+    DEX : DEY
+    LDA #1
+    JSR invert
+    DEC &00,X
+    LDA &FF
+    BIT start + 1       \ z = 1
+    BEQ back
+.break
+    BRK
+    SEC
+    BCS hcf
+.back
+    BEQ break
+.hcf
+    EQUB &FF            \ HCF
+";
+
 #[allow(dead_code)]
 pub fn test_rom1() -> Vec<u8> {
-    vec![
-        0xad, 0x00, 0xff, // LDA &FF00    (a=0xad)
-        0x6c, 0x06, 0xff, // JMP (&FF06)
-        0x1b, 0xff, // (&FF1B)
-        0x49, 0xff, // EOR #255
-        0x60, // RTS
-        0xf2, // NXX (NOP)
-        0xa9, 7,    // LDA #7
-        0x48, // PHA
-        0xa9, 2,    // LDA #2
-        0x48, // PHA
-        0xba, // TSX
-        0xe8, // INX
-        0x9a, // TXS
-        0x68, // PLA
-        0xa8, // TAY
-        0x8c, 0x00, 0x02, // STY &200
-        0x40, // RTI
-        0x29, 0x0f, // AND #&0F    (a=0x0d)
-        0x0a, // ASL A    (a=0x1a)
-        0x0a, // ASL A    (a=0x34)
-        0x0a, // ASL A    (a=0x68)
-        0x0a, // ASL A    (a=0xd0)
-        0x0a, // ASL A    (a=0xa0  c=1)
-        0xb0, 2, // BCS +2    (to the STA)
-        0xea, 0xea, // NOP:NOP
-        0x85, 0xff, // STA &FF
-        0x18, // CLC
-        0xca, 0x88, // DEX : DEY
-        0xa9, 1, // LDA #1
-        0x20, 0x08, 0xff, // JSR &FF08
-        0xd6, 0, // DEC &00, X
-        0xa5, 0xff, // LDA &FF
-        0x2c, 0x01, 0xff, // BIT &FF01    (z = 1)
-        0xf0, 4,    // BEQ +4
-        0x0,  // BRK
-        0x38, // SEC
-        0xb0, 2, // BCS +2 (HCF)
-        0xf0, 0xfa, // BEQ -6 (to the BRK)
-        0xff, // HCF
-    ]
+    Assembler::new(&InstructionDecoder::new())
+        .assemble(TEST_ROM1)
+        .expect("test ROM 1 should assemble")
+        .bytes
 }
 
 #[allow(dead_code)]
@@ -54,3 +64,125 @@ pub fn test_rom2() -> Vec<u8> {
         0x79, 0x34, 0x00, // ADC &0034, Y
     ]
 }
+
+// A stand-in for the MOS, for running a machine end to end: it puts the
+// display in MODE 7, prints a line and counts vertical syncs in &72 from
+// the System VIA's CA1 interrupt. Like the MOS it starts with the shapes of
+// characters &20-&7F, though only the ones it prints are filled in.
+const HELLO_MOS: &str = r#"
+screen = &7C00
+pointer = &70
+vsyncs = &72
+font = &C000
+
+    ORG font
+    EQUB 0, 0, 0, 0, 0, 0, 0, 0                \ space
+    ORG font + 8 * (&42 - &20)
+    EQUB &7C, &66, &66, &7C, &66, &66, &7C, 0  \ B
+    EQUB &3C, &66, &60, &60, &60, &66, &3C, 0  \ C
+    ORG font + 8 * (&65 - &20)
+    EQUB 0, 0, &3C, &66, &7E, &60, &3C, 0      \ e
+    ORG font + 8 * (&6D - &20)
+    EQUB 0, 0, &36, &7F, &6B, &6B, &63, 0      \ m
+    ORG font + 8 * (&6F - &20)
+    EQUB 0, 0, &3C, &66, &66, &66, &3C, 0      \ o
+    EQUB 0, 0, &7C, &66, &66, &7C, &60, &60    \ p
+    ORG font + 8 * (&72 - &20)
+    EQUB 0, 0, &6C, &76, &60, &60, &60, 0      \ r
+    ORG font + 8 * (&74 - &20)
+    EQUB &30, &30, &7C, &30, &30, &30, &1C, 0  \ t
+    EQUB 0, 0, &66, &66, &66, &66, &3E, 0      \ u
+
+    ORG &C300
+.reset
+    SEI : CLD
+    LDX #&FF : TXS
+    LDX #0
+.crtc
+    STX &FE00
+    LDA mode7,X
+    STA &FE01
+    INX
+    CPX #14
+    BNE crtc
+    LDA #&4B : STA &FE20
+
+    LDA #0 : STA pointer : STA vsyncs
+    LDA #HI(screen) : STA pointer + 1
+    LDA #32
+    LDY #0
+.clear
+    STA (pointer),Y
+    INY
+    BNE clear
+    INC pointer + 1
+    LDX pointer + 1
+    CPX #&80
+    BNE clear
+
+    JSR print
+    LDA #&7F : STA &FE4E  \ interrupts off, then CA1 on
+    LDA #&82 : STA &FE4E
+    CLI
+.idle
+    JMP idle
+
+.print
+    LDX #0
+.next
+    LDA message,X
+    BEQ printed
+    STA screen,X
+    INX
+    BNE next
+.printed
+    RTS
+
+.irq
+    PHA
+    LDA &FE4D
+    AND #&02
+    BEQ other
+    STA &FE4D
+    INC vsyncs
+.other
+    PLA
+    RTI
+
+.message
+    EQUS 129, "BBC Computer", 0
+.mode7
+    EQUB 63, 40, 51, &24, 30, 2, 25, 27, &93, 18, &72, &13, &28, 0
+
+    ORG &FFFA
+    EQUW reset, reset, irq
+"#;
+
+// 16K from &C000
+pub fn hello_mos() -> Vec<u8> {
+    Assembler::new(&InstructionDecoder::new())
+        .assemble(HELLO_MOS)
+        .expect("the hello MOS should assemble")
+        .bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test ROM 1 as it was before it was assembled from source, which the
+    // CPU tests stepping through it were written against
+    #[test]
+    fn test_rom1_is_unchanged() {
+        #[rustfmt::skip]
+        let original = [
+            0xad, 0x00, 0xff, 0x6c, 0x06, 0xff, 0x1b, 0xff, 0x49, 0xff, 0x60, 0xf2,
+            0xa9, 0x07, 0x48, 0xa9, 0x02, 0x48, 0xba, 0xe8, 0x9a, 0x68, 0xa8, 0x8c,
+            0x00, 0x02, 0x40, 0x29, 0x0f, 0x0a, 0x0a, 0x0a, 0x0a, 0x0a, 0xb0, 0x02,
+            0xea, 0xea, 0x85, 0xff, 0x18, 0xca, 0x88, 0xa9, 0x01, 0x20, 0x08, 0xff,
+            0xd6, 0x00, 0xa5, 0xff, 0x2c, 0x01, 0xff, 0xf0, 0x04, 0x00, 0x38, 0xb0,
+            0x02, 0xf0, 0xfa, 0xff,
+        ];
+        assert_eq!(test_rom1(), original);
+    }
+}
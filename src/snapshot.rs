@@ -0,0 +1,183 @@
+use crate::cpu::{Byte, Error, ErrorType, Result, Word};
+
+// Machine state is saved as a flat stream of little-endian values, written
+// and read back in the same order by each component. There are no field
+// names or lengths, other than for variable length data, so the stream is
+// only meaningful to the version that wrote it.
+
+pub trait Snapshot {
+    fn save(&self, state: &mut StateWriter);
+    fn restore(&mut self, state: &mut StateReader) -> Result<()>;
+}
+
+pub struct StateWriter {
+    bytes: Vec<Byte>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { bytes: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<Byte> {
+        self.bytes
+    }
+
+    pub fn byte(&mut self, value: Byte) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.byte(value as Byte);
+    }
+
+    pub fn word(&mut self, value: Word) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn option_byte(&mut self, value: Option<Byte>) {
+        self.bool(value.is_some());
+        self.byte(value.unwrap_or(0));
+    }
+
+    // Fixed size data, such as a register file
+    pub fn array(&mut self, values: &[Byte]) {
+        self.bytes.extend_from_slice(values);
+    }
+
+    // Variable length data, preceded by its length
+    pub fn bytes(&mut self, values: &[Byte]) {
+        self.usize(values.len());
+        self.array(values);
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [Byte],
+    offset: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [Byte]) -> Self {
+        StateReader { bytes, offset: 0 }
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.offset == self.bytes.len()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [Byte]> {
+        if self.bytes.len() - self.offset < length {
+            return Err(Error::without_pc(ErrorType::InvalidSnapshot));
+        }
+        let bytes = &self.bytes[self.offset..self.offset + length];
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    pub fn byte(&mut self) -> Result<Byte> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::without_pc(ErrorType::InvalidSnapshot)),
+        }
+    }
+
+    pub fn word(&mut self) -> Result<Word> {
+        let bytes = self.take(2)?;
+        Ok(Word::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    pub fn usize(&mut self) -> Result<usize> {
+        usize::try_from(self.u64()?).map_err(|_| Error::without_pc(ErrorType::InvalidSnapshot))
+    }
+
+    pub fn i32(&mut self) -> Result<i32> {
+        let mut value = [0; 4];
+        value.copy_from_slice(self.take(4)?);
+        Ok(i32::from_le_bytes(value))
+    }
+
+    pub fn option_byte(&mut self) -> Result<Option<Byte>> {
+        let present = self.bool()?;
+        let value = self.byte()?;
+        Ok(present.then_some(value))
+    }
+
+    pub fn array(&mut self, values: &mut [Byte]) -> Result<()> {
+        values.copy_from_slice(self.take(values.len())?);
+        Ok(())
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<Byte>> {
+        let length = self.usize()?;
+        Ok(self.take(length)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip() -> Result<()> {
+        let mut state = StateWriter::new();
+        state.byte(0x12);
+        state.bool(true);
+        state.word(0x3456);
+        state.usize(1_000_000);
+        state.i32(-1);
+        state.option_byte(None);
+        state.bytes(&[1, 2, 3]);
+        let bytes = state.into_bytes();
+
+        let mut state = StateReader::new(&bytes);
+        assert_eq!(state.byte()?, 0x12);
+        assert!(state.bool()?);
+        assert_eq!(state.word()?, 0x3456);
+        assert_eq!(state.usize()?, 1_000_000);
+        assert_eq!(state.i32()?, -1);
+        assert_eq!(state.option_byte()?, None);
+        assert_eq!(state.bytes()?, vec![1, 2, 3]);
+        assert!(state.at_end());
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_state_is_invalid() {
+        let mut state = StateReader::new(&[0x01]);
+        assert_eq!(
+            state.word(),
+            Err(Error::without_pc(ErrorType::InvalidSnapshot))
+        );
+    }
+}
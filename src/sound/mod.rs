@@ -0,0 +1,109 @@
+use std::fs;
+use std::path::Path;
+
+use crate::cpu::{Byte, Result};
+use crate::devices::CLOCK_HZ;
+
+pub mod wav;
+
+// Everything that comes out of the speaker, sampled for a WAV file: the
+// SN76489 on the BBCs and the Electron's 1-bit sound, along with the tones
+// each is writing to cassette.
+//
+// The level is taken after each instruction, so each sample is the average
+// of the levels over the cycles it spans.
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+const CLOCK: u64 = CLOCK_HZ as u64;
+
+pub struct SoundCapture {
+    sample_rate: u32,
+    samples: Vec<i16>,
+
+    // Progress through the current sample in units of 1 / (clock * rate)
+    // seconds, and the level summed over its cycles so far
+    phase: u64,
+    total: f64,
+    cycles: u64,
+}
+
+impl SoundCapture {
+    // The sample rate can be anything up to the 2MHz clock
+    pub fn new(sample_rate: u32) -> Self {
+        SoundCapture {
+            sample_rate: sample_rate.clamp(1, CLOCK_HZ as u32),
+            samples: Vec::new(),
+            phase: 0,
+            total: 0.0,
+            cycles: 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    // The level, from -1.0 to 1.0, held for the given number of cycles
+    pub(crate) fn advance(&mut self, cycles: usize, level: f32) {
+        let rate = self.sample_rate as u64;
+        let mut cycles = cycles as u64;
+        while cycles > 0 {
+            let until_sample = (CLOCK - self.phase).div_ceil(rate);
+            let taken = cycles.min(until_sample);
+            self.total += level as f64 * taken as f64;
+            self.cycles += taken;
+            self.phase += taken * rate;
+            cycles -= taken;
+
+            if self.phase >= CLOCK {
+                self.phase -= CLOCK;
+                let level = (self.total / self.cycles as f64).clamp(-1.0, 1.0);
+                self.samples.push((level * i16::MAX as f64).round() as i16);
+                self.total = 0.0;
+                self.cycles = 0;
+            }
+        }
+    }
+
+    pub fn to_wav(&self) -> Vec<Byte> {
+        wav::encode(self.sample_rate, &self.samples)
+    }
+
+    pub fn save_wav<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_wav())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_average_the_level() {
+        // 2MHz down to 500kHz is four cycles a sample
+        let mut capture = SoundCapture::new(500_000);
+        capture.advance(3, 1.0);
+        capture.advance(2, -1.0);
+        capture.advance(7, 0.5);
+        assert_eq!(capture.samples(), [16384, 4096, 16384]);
+    }
+
+    #[test]
+    fn samples_at_an_uneven_rate() {
+        let mut capture = SoundCapture::new(DEFAULT_SAMPLE_RATE);
+        for _ in 0..CLOCK_HZ / 4 {
+            capture.advance(4, 0.0);
+        }
+        assert_eq!(capture.samples().len(), DEFAULT_SAMPLE_RATE as usize);
+        assert_eq!(
+            capture.to_wav().len(),
+            44 + 2 * DEFAULT_SAMPLE_RATE as usize
+        );
+    }
+}
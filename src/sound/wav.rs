@@ -0,0 +1,51 @@
+use crate::cpu::Byte;
+
+// 16-bit mono PCM in a RIFF WAVE file, as everything can play it
+
+const FORMAT_PCM: u16 = 1;
+const CHANNELS: u16 = 1;
+const BITS: u16 = 16;
+
+pub fn encode(sample_rate: u32, samples: &[i16]) -> Vec<Byte> {
+    let block = CHANNELS * BITS / 8;
+    let data = samples.len() as u32 * block as u32;
+
+    let mut wav = Vec::with_capacity(44 + data as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16_u32.to_le_bytes());
+    wav.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+    wav.extend_from_slice(&CHANNELS.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * block as u32).to_le_bytes());
+    wav.extend_from_slice(&block.to_le_bytes());
+    wav.extend_from_slice(&BITS.to_le_bytes());
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_and_samples() {
+        let wav = encode(44100, &[0, -1, 0x1234]);
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(wav[4..8], 42_u32.to_le_bytes());
+        assert_eq!(
+            wav[8..44],
+            *b"WAVEfmt \x10\0\0\0\x01\0\x01\0\x44\xac\0\0\x88\x58\x01\0\x02\0\x10\0data\x06\0\0\0"
+        );
+        assert_eq!(wav[44..], [0x00, 0x00, 0xff, 0xff, 0x34, 0x12]);
+    }
+}
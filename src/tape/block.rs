@@ -0,0 +1,290 @@
+use crate::cpu::{Byte, Error, ErrorType, Result, Word};
+
+// Acorn cassette filing system block, as written by the MOS:
+//
+//   &2A sync, filename (1-10 chars) + &00, load address (4), exec address (4),
+//   block number (2), data length (2), flag (1), next file address (4),
+//   header CRC (2, big-endian), data, data CRC (2, big-endian, if any data)
+
+pub const SYNC: Byte = 0x2a;
+pub const MAX_FILENAME: usize = 10;
+pub const MAX_DATA: usize = 256;
+
+pub const FLAG_LAST_BLOCK: Byte = 0x80;
+pub const FLAG_EMPTY_BLOCK: Byte = 0x40;
+pub const FLAG_LOCKED: Byte = 0x01;
+
+// CRC-16 with polynomial &1021, as calculated by the MOS
+pub fn crc16(bytes: &[Byte]) -> Word {
+    let mut crc: Word = 0;
+    for &byte in bytes {
+        crc ^= (byte as Word) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 == 0x8000 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub filename: String,
+    pub load_address: u32,
+    pub exec_address: u32,
+    pub block_number: Word,
+    pub flag: Byte,
+    pub next_address: u32,
+    pub data: Vec<Byte>,
+}
+
+impl Block {
+    pub fn is_last(&self) -> bool {
+        self.flag & FLAG_LAST_BLOCK == FLAG_LAST_BLOCK
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.flag & FLAG_LOCKED == FLAG_LOCKED
+    }
+
+    fn header_bytes(&self) -> Vec<Byte> {
+        let mut header = Vec::new();
+        header.extend(self.filename.bytes().take(MAX_FILENAME));
+        header.push(0x00);
+        header.extend_from_slice(&self.load_address.to_le_bytes());
+        header.extend_from_slice(&self.exec_address.to_le_bytes());
+        header.extend_from_slice(&self.block_number.to_le_bytes());
+        header.extend_from_slice(&(self.data.len() as Word).to_le_bytes());
+        header.push(self.flag);
+        header.extend_from_slice(&self.next_address.to_le_bytes());
+        header
+    }
+
+    pub fn to_bytes(&self) -> Vec<Byte> {
+        let header = self.header_bytes();
+
+        let mut bytes = vec![SYNC];
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(&crc16(&header).to_be_bytes());
+
+        if !self.data.is_empty() {
+            bytes.extend_from_slice(&self.data);
+            bytes.extend_from_slice(&crc16(&self.data).to_be_bytes());
+        }
+
+        bytes
+    }
+
+    // Parses a block starting at the sync byte, returning it along with the
+    // number of bytes consumed
+    pub fn parse(bytes: &[Byte]) -> Result<(Block, usize)> {
+        let truncated = || Error::without_pc(ErrorType::InvalidTapeBlock);
+
+        if bytes.first() != Some(&SYNC) {
+            return Err(truncated());
+        }
+
+        let name_end = bytes[1..]
+            .iter()
+            .take(MAX_FILENAME + 1)
+            .position(|&b| b == 0x00)
+            .ok_or_else(truncated)?
+            + 1;
+
+        let header_end = name_end + 1 + 17;
+        if bytes.len() < header_end + 2 {
+            return Err(truncated());
+        }
+
+        let header = &bytes[1..header_end];
+        let crc = Word::from_be_bytes([bytes[header_end], bytes[header_end + 1]]);
+        if crc16(header) != crc {
+            return Err(Error::without_pc(ErrorType::CrcMismatch));
+        }
+
+        let fields = &bytes[name_end + 1..header_end];
+        let le32 =
+            |o: usize| u32::from_le_bytes([fields[o], fields[o + 1], fields[o + 2], fields[o + 3]]);
+        let le16 = |o: usize| Word::from_le_bytes([fields[o], fields[o + 1]]);
+
+        let length = le16(10) as usize;
+        let mut block = Block {
+            filename: String::from_utf8_lossy(&bytes[1..name_end]).into_owned(),
+            load_address: le32(0),
+            exec_address: le32(4),
+            block_number: le16(8),
+            flag: fields[12],
+            next_address: le32(13),
+            data: Vec::new(),
+        };
+
+        let mut consumed = header_end + 2;
+        if length > 0 {
+            if bytes.len() < consumed + length + 2 {
+                return Err(truncated());
+            }
+
+            let data = &bytes[consumed..consumed + length];
+            let crc = Word::from_be_bytes([bytes[consumed + length], bytes[consumed + length + 1]]);
+            if crc16(data) != crc {
+                return Err(Error::without_pc(ErrorType::CrcMismatch));
+            }
+
+            block.data = data.to_vec();
+            consumed += length + 2;
+        }
+
+        Ok((block, consumed))
+    }
+}
+
+// Finds and parses every block in a recorded byte stream
+pub fn parse_blocks(stream: &[Byte]) -> Result<Vec<Block>> {
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+
+    while let Some(start) = stream[offset..].iter().position(|&b| b == SYNC) {
+        let (block, consumed) = Block::parse(&stream[offset + start..])?;
+        blocks.push(block);
+        offset += start + consumed;
+    }
+
+    Ok(blocks)
+}
+
+// Splits a file into blocks the way *SAVE does
+pub fn file_blocks(
+    filename: &str,
+    load_address: u32,
+    exec_address: u32,
+    data: &[Byte],
+) -> Vec<Block> {
+    let chunks: Vec<&[Byte]> = if data.is_empty() {
+        vec![&[]]
+    } else {
+        data.chunks(MAX_DATA).collect()
+    };
+
+    let count = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(n, chunk)| {
+            let mut flag = 0;
+            if n == count - 1 {
+                flag |= FLAG_LAST_BLOCK;
+            }
+            if chunk.is_empty() {
+                flag |= FLAG_EMPTY_BLOCK;
+            }
+            Block {
+                filename: filename.to_string(),
+                load_address,
+                exec_address,
+                block_number: n as Word,
+                flag,
+                next_address: 0,
+                data: chunk.to_vec(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc16(&[]), 0x0000);
+    }
+
+    #[test]
+    fn block_layout() {
+        let block = Block {
+            filename: "A".to_string(),
+            load_address: 0xffff1900,
+            exec_address: 0xffff8023,
+            block_number: 0,
+            flag: FLAG_LAST_BLOCK,
+            next_address: 0,
+            data: vec![0x0d, 0xff],
+        };
+
+        let bytes = block.to_bytes();
+        assert_eq!(bytes[0], SYNC);
+        assert_eq!(&bytes[1..3], b"A\0");
+        assert_eq!(&bytes[3..7], &[0x00, 0x19, 0xff, 0xff]);
+        assert_eq!(&bytes[7..11], &[0x23, 0x80, 0xff, 0xff]);
+        assert_eq!(&bytes[11..13], &[0x00, 0x00]);
+        assert_eq!(&bytes[13..15], &[0x02, 0x00]);
+        assert_eq!(bytes[15], FLAG_LAST_BLOCK);
+
+        let header_crc = crc16(&bytes[1..20]);
+        assert_eq!(&bytes[20..22], &header_crc.to_be_bytes());
+        assert_eq!(&bytes[22..24], &[0x0d, 0xff]);
+        assert_eq!(&bytes[24..26], &crc16(&[0x0d, 0xff]).to_be_bytes());
+        assert_eq!(bytes.len(), 26);
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let block = Block {
+            filename: "PROGRAM".to_string(),
+            load_address: 0x1900,
+            exec_address: 0x8023,
+            block_number: 3,
+            flag: FLAG_LOCKED,
+            next_address: 0,
+            data: (0..=255).collect(),
+        };
+
+        let bytes = block.to_bytes();
+        let (parsed, consumed) = Block::parse(&bytes)?;
+        assert_eq!(parsed, block);
+        assert_eq!(consumed, bytes.len());
+        assert!(parsed.is_locked());
+        assert!(!parsed.is_last());
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_data_fails_crc() {
+        let block = file_blocks("X", 0, 0, &[1, 2, 3]).remove(0);
+        let mut bytes = block.to_bytes();
+        let last = bytes.len() - 3;
+        bytes[last] ^= 0xff;
+
+        let result = Block::parse(&bytes);
+        assert_eq!(result, Err(Error::without_pc(ErrorType::CrcMismatch)));
+    }
+
+    #[test]
+    fn file_split_into_blocks() -> Result<()> {
+        let data: Vec<Byte> = (0..600).map(|n| n as Byte).collect();
+        let blocks = file_blocks("DATA", 0x3000, 0x3000, &data);
+
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[2].data.len(), 88);
+        assert!(blocks[2].is_last());
+        assert!(!blocks[0].is_last());
+
+        let stream: Vec<Byte> = blocks.iter().flat_map(|b| b.to_bytes()).collect();
+        let parsed = parse_blocks(&stream)?;
+        assert_eq!(parsed, blocks);
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty_file_is_one_empty_block() {
+        let blocks = file_blocks("EMPTY", 0, 0, &[]);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].flag, FLAG_LAST_BLOCK | FLAG_EMPTY_BLOCK);
+    }
+}
@@ -0,0 +1,259 @@
+use std::path::Path;
+
+use crate::cpu::{Byte, Result, Word};
+use crate::devices::acia::{Parity, WordFormat};
+use crate::devices::CLOCK_HZ;
+use crate::tape::uef::{
+    Chunk, Uef, BAUD_RATE, CARRIER_TONE, DEFINED_DATA, IMPLICIT_DATA, INTEGER_GAP,
+};
+
+// Tones and gaps are measured in cycles of twice the 1200 baud base frequency
+const CARRIER_HZ: usize = 2400;
+const DEFAULT_BAUD: usize = 1200;
+
+const IMPLICIT_FORMAT: WordFormat = WordFormat {
+    data_bits: 8,
+    parity: Parity::None,
+    stop_bits: 1,
+};
+
+// Turns the bytes the ACIA shifts out, and the carrier between them, into UEF
+// chunks while the cassette motor is running
+pub struct TapeRecorder {
+    uef: Uef,
+    motor: bool,
+    idle_cycles: usize,
+    data: Vec<Byte>,
+    format: WordFormat,
+    baud: usize,
+}
+
+impl TapeRecorder {
+    pub fn new() -> Self {
+        TapeRecorder {
+            uef: Uef::new(),
+            motor: false,
+            idle_cycles: 0,
+            data: Vec::new(),
+            format: IMPLICIT_FORMAT,
+            baud: DEFAULT_BAUD,
+        }
+    }
+
+    pub fn motor_on(&self) -> bool {
+        self.motor
+    }
+
+    pub fn motor(&mut self, on: bool) {
+        if on == self.motor {
+            return;
+        }
+
+        if on {
+            if !self.uef.chunks.is_empty() {
+                self.push_duration(INTEGER_GAP, self.idle_cycles);
+            }
+        } else {
+            self.flush_data();
+            self.push_duration(CARRIER_TONE, self.idle_cycles);
+        }
+
+        self.idle_cycles = 0;
+        self.motor = on;
+    }
+
+    // Time passing without a byte being shifted out: carrier while the motor
+    // runs, silence while it doesn't
+    pub fn idle(&mut self, cycles: usize) {
+        self.idle_cycles += cycles;
+    }
+
+    pub fn byte(&mut self, data: Byte, format: WordFormat, baud: usize) {
+        if !self.motor {
+            return;
+        }
+
+        // Anything shorter than a bit is just the MOS refilling the ACIA
+        if self.idle_cycles >= CLOCK_HZ / baud.max(1) {
+            self.flush_data();
+            self.push_duration(CARRIER_TONE, self.idle_cycles);
+        }
+        self.idle_cycles = 0;
+
+        if format != self.format || baud != self.baud {
+            self.flush_data();
+            if baud != self.baud {
+                self.uef.push(Chunk::with_word(BAUD_RATE, baud as Word));
+            }
+            self.format = format;
+            self.baud = baud;
+        }
+
+        self.data.push(data);
+    }
+
+    // Returns everything recorded so far, leaving the recorder empty
+    pub fn take(&mut self) -> Uef {
+        self.flush_data();
+        if self.motor {
+            self.push_duration(CARRIER_TONE, self.idle_cycles);
+            self.idle_cycles = 0;
+        }
+        self.format = IMPLICIT_FORMAT;
+        self.baud = DEFAULT_BAUD;
+        std::mem::take(&mut self.uef)
+    }
+
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.take().save(path)
+    }
+
+    fn flush_data(&mut self) {
+        if self.data.is_empty() {
+            return;
+        }
+
+        let data = std::mem::take(&mut self.data);
+        if self.format == IMPLICIT_FORMAT {
+            self.uef.push(Chunk::new(IMPLICIT_DATA, data));
+        } else {
+            let parity = match self.format.parity {
+                Parity::None => b'N',
+                Parity::Even => b'E',
+                Parity::Odd => b'O',
+            };
+            let mut chunk = vec![
+                self.format.data_bits as Byte,
+                parity,
+                self.format.stop_bits as Byte,
+            ];
+            chunk.extend(data);
+            self.uef.push(Chunk::new(DEFINED_DATA, chunk));
+        }
+    }
+
+    fn push_duration(&mut self, id: Word, cycles: usize) {
+        let mut remaining = (cycles * CARRIER_HZ + CLOCK_HZ / 2) / CLOCK_HZ;
+        while remaining > 0 {
+            let length = remaining.min(Word::MAX as usize);
+            self.uef.push(Chunk::with_word(id, length as Word));
+            remaining -= length;
+        }
+    }
+}
+
+impl Default for TapeRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARRIER_CYCLE: usize = CLOCK_HZ / CARRIER_HZ;
+
+    #[test]
+    fn construct() {
+        let _ = TapeRecorder::new();
+    }
+
+    #[test]
+    fn nothing_recorded_with_motor_off() {
+        let mut recorder = TapeRecorder::new();
+        recorder.idle(CLOCK_HZ);
+        recorder.byte(0x2a, IMPLICIT_FORMAT, 1200);
+
+        assert!(recorder.take().chunks.is_empty());
+    }
+
+    #[test]
+    fn leader_then_data_then_trailer() {
+        let mut recorder = TapeRecorder::new();
+        recorder.motor(true);
+        recorder.idle(CARRIER_CYCLE * 100);
+        recorder.byte(0x2a, IMPLICIT_FORMAT, 1200);
+        recorder.byte(0x41, IMPLICIT_FORMAT, 1200);
+        recorder.idle(CARRIER_CYCLE * 20);
+        recorder.motor(false);
+
+        let uef = recorder.take();
+        assert_eq!(
+            uef.chunks,
+            vec![
+                Chunk::with_word(CARRIER_TONE, 100),
+                Chunk::new(IMPLICIT_DATA, vec![0x2a, 0x41]),
+                Chunk::with_word(CARRIER_TONE, 20),
+            ]
+        );
+    }
+
+    #[test]
+    fn short_idle_does_not_split_data() {
+        let mut recorder = TapeRecorder::new();
+        recorder.motor(true);
+        recorder.byte(0x2a, IMPLICIT_FORMAT, 1200);
+        recorder.idle(100);
+        recorder.byte(0x41, IMPLICIT_FORMAT, 1200);
+
+        let uef = recorder.take();
+        assert_eq!(
+            uef.chunks,
+            vec![Chunk::new(IMPLICIT_DATA, vec![0x2a, 0x41])]
+        );
+    }
+
+    #[test]
+    fn motor_off_between_files_is_a_gap() {
+        let mut recorder = TapeRecorder::new();
+        recorder.motor(true);
+        recorder.byte(0x2a, IMPLICIT_FORMAT, 1200);
+        recorder.motor(false);
+        recorder.idle(CLOCK_HZ);
+        recorder.motor(true);
+        recorder.byte(0x2a, IMPLICIT_FORMAT, 1200);
+
+        let uef = recorder.take();
+        assert_eq!(uef.chunks[1], Chunk::with_word(INTEGER_GAP, 2400));
+    }
+
+    #[test]
+    fn long_tones_are_split() {
+        let mut recorder = TapeRecorder::new();
+        recorder.motor(true);
+        recorder.idle(CLOCK_HZ * 30);
+        recorder.motor(false);
+
+        let uef = recorder.take();
+        assert_eq!(
+            uef.chunks,
+            vec![
+                Chunk::with_word(CARRIER_TONE, 65535),
+                Chunk::with_word(CARRIER_TONE, 6465),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_formats_use_defined_data() {
+        let format = WordFormat {
+            data_bits: 7,
+            parity: Parity::Even,
+            stop_bits: 2,
+        };
+
+        let mut recorder = TapeRecorder::new();
+        recorder.motor(true);
+        recorder.byte(0x41, format, 300);
+
+        let uef = recorder.take();
+        assert_eq!(
+            uef.chunks,
+            vec![
+                Chunk::with_word(BAUD_RATE, 300),
+                Chunk::new(DEFINED_DATA, vec![7, b'E', 2, 0x41]),
+            ]
+        );
+    }
+}
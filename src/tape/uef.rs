@@ -0,0 +1,208 @@
+use std::fs;
+use std::path::Path;
+
+use crate::cpu::{Byte, Error, ErrorType, Result, Word};
+
+// See http://electrem.emuunlim.com/UEFSpecs.html
+
+pub const MAGIC: &[u8; 10] = b"UEF File!\0";
+
+pub const ORIGIN: Word = 0x0000;
+pub const IMPLICIT_DATA: Word = 0x0100;
+pub const DEFINED_DATA: Word = 0x0104;
+pub const CARRIER_TONE: Word = 0x0110;
+pub const INTEGER_GAP: Word = 0x0112;
+pub const BAUD_RATE: Word = 0x0117;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub id: Word,
+    pub data: Vec<Byte>,
+}
+
+impl Chunk {
+    pub fn new(id: Word, data: Vec<Byte>) -> Self {
+        Chunk { id, data }
+    }
+
+    pub fn with_word(id: Word, value: Word) -> Self {
+        Chunk::new(id, value.to_le_bytes().to_vec())
+    }
+
+    pub fn word(&self) -> Option<Word> {
+        if self.data.len() < 2 {
+            return None;
+        }
+        Some(Word::from_le_bytes([self.data[0], self.data[1]]))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uef {
+    pub minor_version: Byte,
+    pub major_version: Byte,
+    pub chunks: Vec<Chunk>,
+}
+
+impl Uef {
+    pub fn new() -> Self {
+        Uef {
+            minor_version: 10,
+            major_version: 0,
+            chunks: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, chunk: Chunk) {
+        self.chunks.push(chunk);
+    }
+
+    pub fn to_bytes(&self) -> Vec<Byte> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(self.minor_version);
+        bytes.push(self.major_version);
+
+        for chunk in &self.chunks {
+            bytes.extend_from_slice(&chunk.id.to_le_bytes());
+            bytes.extend_from_slice(&(chunk.data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&chunk.data);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[Byte]) -> Result<Self> {
+        if bytes.len() < MAGIC.len() + 2 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(Error::without_pc(ErrorType::InvalidUef));
+        }
+
+        let mut uef = Uef {
+            minor_version: bytes[MAGIC.len()],
+            major_version: bytes[MAGIC.len() + 1],
+            chunks: Vec::new(),
+        };
+
+        let mut offset = MAGIC.len() + 2;
+        while offset < bytes.len() {
+            if offset + 6 > bytes.len() {
+                return Err(Error::without_pc(ErrorType::InvalidUef));
+            }
+
+            let id = Word::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+            let length = u32::from_le_bytes([
+                bytes[offset + 2],
+                bytes[offset + 3],
+                bytes[offset + 4],
+                bytes[offset + 5],
+            ]) as usize;
+            offset += 6;
+
+            if offset + length > bytes.len() {
+                return Err(Error::without_pc(ErrorType::InvalidUef));
+            }
+
+            uef.push(Chunk::new(id, bytes[offset..offset + length].to_vec()));
+            offset += length;
+        }
+
+        Ok(uef)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Uef::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    // The raw byte stream recorded on the tape, ignoring tones and gaps
+    pub fn tape_data(&self) -> Vec<Byte> {
+        let mut data = Vec::new();
+        for chunk in &self.chunks {
+            match chunk.id {
+                IMPLICIT_DATA => data.extend_from_slice(&chunk.data),
+                DEFINED_DATA if chunk.data.len() >= 3 => data.extend_from_slice(&chunk.data[3..]),
+                _ => {}
+            }
+        }
+        data
+    }
+}
+
+impl Default for Uef {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn construct() {
+        let uef = Uef::new();
+        assert_eq!(uef.minor_version, 10);
+        assert_eq!(uef.major_version, 0);
+    }
+
+    #[test]
+    fn empty_file_is_header_only() {
+        let bytes = Uef::new().to_bytes();
+        assert_eq!(bytes, b"UEF File!\0\x0a\x00".to_vec());
+    }
+
+    #[test]
+    fn chunk_layout() {
+        let mut uef = Uef::new();
+        uef.push(Chunk::with_word(CARRIER_TONE, 0x1234));
+
+        let bytes = uef.to_bytes();
+        assert_eq!(
+            &bytes[12..],
+            &[0x10, 0x01, 0x02, 0x00, 0x00, 0x00, 0x34, 0x12]
+        );
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let mut uef = Uef::new();
+        uef.push(Chunk::with_word(CARRIER_TONE, 12000));
+        uef.push(Chunk::new(IMPLICIT_DATA, vec![0x2a, 0x41, 0x00]));
+        uef.push(Chunk::with_word(INTEGER_GAP, 100));
+
+        let read = Uef::from_bytes(&uef.to_bytes())?;
+        assert_eq!(read, uef);
+        assert_eq!(read.chunks[0].word(), Some(12000));
+
+        Ok(())
+    }
+
+    #[test]
+    fn bad_magic_fails() {
+        let result = Uef::from_bytes(b"UEF Flie!\0\x0a\x00");
+        assert_eq!(result, Err(Error::without_pc(ErrorType::InvalidUef)));
+    }
+
+    #[test]
+    fn truncated_chunk_fails() {
+        let mut bytes = Uef::new().to_bytes();
+        bytes.extend_from_slice(&[0x00, 0x01, 0x10, 0x00, 0x00, 0x00, 0x2a]);
+
+        let result = Uef::from_bytes(&bytes);
+        assert_eq!(result, Err(Error::without_pc(ErrorType::InvalidUef)));
+    }
+
+    #[test]
+    fn tape_data_skips_tones() {
+        let mut uef = Uef::new();
+        uef.push(Chunk::with_word(CARRIER_TONE, 12000));
+        uef.push(Chunk::new(IMPLICIT_DATA, vec![0x2a, 0x41]));
+        uef.push(Chunk::with_word(CARRIER_TONE, 10));
+        uef.push(Chunk::new(DEFINED_DATA, vec![8, b'N', 1, 0x00]));
+
+        assert_eq!(uef.tape_data(), vec![0x2a, 0x41, 0x00]);
+    }
+}
@@ -0,0 +1,207 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::cpu::{Byte, Result};
+use crate::video::{Frame, Rgb, COLOURS};
+
+// The display as a stream of frames, one every 40,000 cycles from when
+// capture starts: 50 a second by the emulated clock, whatever the CRTC is
+// doing, so they line up with sound captured over the same run. Each frame
+// is drawn as the display is at that moment, so changes part way down the
+// screen show up as whichever side of them the frame fell.
+//
+// Every frame is the same size, with the picture in the top left corner,
+// cut off or filled out with black. YUV4MPEG2 is 4:4:4 with BT.601 colour;
+// raw frames are 24-bit RGB, top line first, with nothing between them.
+
+pub const FRAME_CYCLES: u64 = 40_000;
+pub const FRAMES_PER_SECOND: u64 = 50;
+
+// What the MOS's 32 line modes fill
+pub const DEFAULT_WIDTH: usize = 640;
+pub const DEFAULT_HEIGHT: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoFormat {
+    Y4m,
+    Rgb,
+}
+
+pub struct VideoCapture {
+    output: Box<dyn Write>,
+    format: VideoFormat,
+    width: usize,
+    height: usize,
+    frames: u64,
+    cycles: u64,
+}
+
+// BT.601 studio range, with the colour difference planes at full size
+fn yuv([r, g, b]: Rgb) -> [Byte; 3] {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    [y as Byte, u as Byte, v as Byte]
+}
+
+impl VideoCapture {
+    pub fn to_writer<W: Write + 'static>(writer: W, format: VideoFormat) -> Self {
+        VideoCapture {
+            output: Box::new(writer),
+            format,
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            frames: 0,
+            cycles: 0,
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(path: P, format: VideoFormat) -> Result<Self> {
+        Ok(Self::to_writer(BufWriter::new(File::create(path)?), format))
+    }
+
+    pub fn with_size(mut self, width: usize, height: usize) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    pub fn format(&self) -> VideoFormat {
+        self.format
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.output.flush()?;
+        Ok(())
+    }
+
+    // How many frames are due after the given number of cycles more
+    pub(crate) fn advance(&mut self, cycles: usize) -> u64 {
+        self.cycles += cycles as u64;
+        let due = self.cycles / FRAME_CYCLES;
+        self.cycles %= FRAME_CYCLES;
+        due
+    }
+
+    // A machine with no display to capture gives black frames
+    pub(crate) fn write_frame(&mut self, frame: Option<&Frame>) -> Result<()> {
+        if self.frames == 0 && self.format == VideoFormat::Y4m {
+            writeln!(
+                self.output,
+                "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444",
+                self.width, self.height, FRAMES_PER_SECOND
+            )?;
+        }
+
+        let pixel = |x: usize, y: usize| match frame {
+            Some(frame) if x < frame.width() && y < frame.height() => frame.pixel(x, y),
+            _ => COLOURS[0],
+        };
+        let mut bytes = Vec::with_capacity(self.width * self.height * 3);
+        match self.format {
+            VideoFormat::Rgb => {
+                for y in 0..self.height {
+                    for x in 0..self.width {
+                        bytes.extend_from_slice(&pixel(x, y));
+                    }
+                }
+            }
+            VideoFormat::Y4m => {
+                bytes.extend_from_slice(b"FRAME\n");
+                for plane in 0..3 {
+                    for y in 0..self.height {
+                        for x in 0..self.width {
+                            bytes.push(yuv(pixel(x, y))[plane]);
+                        }
+                    }
+                }
+            }
+        }
+        self.output.write_all(&bytes)?;
+        self.frames += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame() -> Frame {
+        let mut frame = Frame::new(3, 1);
+        frame.set_pixel(0, 0, COLOURS[7]);
+        frame.set_pixel(1, 0, COLOURS[1]);
+        frame
+    }
+
+    #[test]
+    fn y4m_frames() -> Result<()> {
+        let shared = Shared(Rc::new(RefCell::new(Vec::new())));
+        let mut capture = VideoCapture::to_writer(shared.clone(), VideoFormat::Y4m).with_size(2, 2);
+        capture.write_frame(Some(&frame()))?;
+        capture.write_frame(None)?;
+
+        let mut expected = b"YUV4MPEG2 W2 H2 F50:1 Ip A1:1 C444\nFRAME\n".to_vec();
+        // White and red, then black below and cut off to the right
+        expected.extend_from_slice(&[235, 82, 16, 16, 128, 90, 128, 128, 128, 240, 128, 128]);
+        expected.extend_from_slice(b"FRAME\n");
+        expected.extend_from_slice(&[16, 16, 16, 16, 128, 128, 128, 128, 128, 128, 128, 128]);
+        assert_eq!(*shared.0.borrow(), expected);
+        assert_eq!(capture.frames(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn raw_rgb_frames() -> Result<()> {
+        let shared = Shared(Rc::new(RefCell::new(Vec::new())));
+        let mut capture = VideoCapture::to_writer(shared.clone(), VideoFormat::Rgb).with_size(4, 1);
+        capture.write_frame(Some(&frame()))?;
+        assert_eq!(
+            *shared.0.borrow(),
+            [255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn fifty_frames_a_second() {
+        let shared = Shared(Rc::new(RefCell::new(Vec::new())));
+        let mut capture = VideoCapture::to_writer(shared, VideoFormat::Rgb);
+        assert_eq!(capture.advance(39_999), 0);
+        assert_eq!(capture.advance(7), 1);
+        let due: u64 = (0..2_000_000 / 4).map(|_| capture.advance(4)).sum();
+        assert_eq!(due, FRAMES_PER_SECOND);
+    }
+}
@@ -0,0 +1,493 @@
+use std::fs;
+use std::path::Path;
+
+use crate::cpu::{Byte, Result, Word};
+use crate::devices::crtc::{Crtc, R1_HORIZONTAL_DISPLAYED, R6_VERTICAL_DISPLAYED};
+use crate::devices::video_ula::{
+    VideoUla, CONTROL_CHARACTERS_PER_LINE, CONTROL_FAST_CLOCK, CONTROL_FLASH, CONTROL_TELETEXT,
+};
+
+pub mod capture;
+pub mod png;
+pub mod text;
+
+// A picture of what the CRTC and video ULA are set up to show, drawn from
+// display memory in one go rather than as the beam goes. A pixel is one tick
+// of the 16MHz pixel clock across and one scanline down, so MODEs 0-6 all
+// come out 640 wide: 640x256 for the MOS's 32 line modes, 640x250 for its
+// 25 line ones. There's no cursor, and anything that changes the registers
+// part way down the screen is drawn as the registers are at the end.
+//
+// MODE 7 is drawn from the MOS's character set rather than the SAA5050's,
+// in cells 16 pixels wide and as many high as the CRTC gives each row. Only
+// the colour, background and mosaic codes are followed; double height,
+// flashing, concealed and held graphics are drawn as plain text.
+
+pub type Rgb = [Byte; 3];
+
+// The physical colours, by number: bit 0 red, bit 1 green, bit 2 blue
+pub const COLOURS: [Rgb; 8] = [
+    [0x00, 0x00, 0x00],
+    [0xff, 0x00, 0x00],
+    [0x00, 0xff, 0x00],
+    [0xff, 0xff, 0x00],
+    [0x00, 0x00, 0xff],
+    [0xff, 0x00, 0xff],
+    [0x00, 0xff, 0xff],
+    [0xff, 0xff, 0xff],
+];
+
+// The CRTC only shows 32K
+pub const DISPLAY_MEMORY: usize = 0x8000;
+
+// Where the MOS keeps the shapes of characters &20-&7F, 8 bytes each
+pub const FONT_SIZE: usize = 0x300;
+
+// MODE 7's 1K sits at &7C00, or at &3C00 when MA11 is clear
+const TELETEXT_SCREEN: usize = 0x7c00;
+const TELETEXT_PAGE: usize = 0x3c00;
+
+const CHARACTER_WIDTH: usize = 16;
+const GLYPH_HEIGHT: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgb>,
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize) -> Self {
+        Frame {
+            width,
+            height,
+            pixels: vec![COLOURS[0]; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Rgb {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, colour: Rgb) {
+        self.pixels[y * self.width + x] = colour;
+    }
+
+    // Rows of pixels, top first
+    pub fn pixels(&self) -> &[Rgb] {
+        &self.pixels
+    }
+
+    pub fn to_png(&self) -> Vec<Byte> {
+        png::encode(self.width, self.height, &self.pixels)
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_png())?;
+        Ok(())
+    }
+}
+
+// Everything the display hardware reads to make a picture
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screen {
+    // In CRTC character addresses (MA0-13)
+    pub start: Word,
+    pub columns: usize,
+    pub rows: usize,
+    pub scanlines: usize,
+
+    // The video ULA's control register and palette, as written
+    pub control: Byte,
+    pub palette: [Byte; 16],
+
+    // What's taken off a bitmap address that runs past &7FFF, from
+    // addressable latch bits 4 and 5
+    pub wrap: usize,
+
+    pub memory: Vec<Byte>,
+    pub font: Vec<Byte>,
+}
+
+impl Screen {
+    // The memory is the 32K the CRTC reads, which on the shadow RAM models
+    // isn't always what the CPU sees. The font is the start of the MOS.
+    pub fn new(crtc: &Crtc, ula: &VideoUla, latch: Byte, memory: Vec<Byte>, font: &[Byte]) -> Self {
+        let wrap = match (latch >> 4) & 0x03 {
+            0 => 0x4000,
+            1 => 0x2000,
+            2 => 0x5000,
+            _ => 0x2800,
+        };
+        Screen {
+            start: crtc.start_address(),
+            columns: crtc.register(R1_HORIZONTAL_DISPLAYED) as usize,
+            rows: crtc.register(R6_VERTICAL_DISPLAYED) as usize,
+            scanlines: crtc.scanlines_per_row(),
+            control: ula.control(),
+            palette: std::array::from_fn(|n| ula.palette(n)),
+            wrap,
+            memory,
+            font: font[..FONT_SIZE.min(font.len())].to_vec(),
+        }
+    }
+
+    pub fn teletext(&self) -> bool {
+        self.control & CONTROL_TELETEXT != 0
+    }
+
+    fn fast_clock(&self) -> bool {
+        self.control & CONTROL_FAST_CLOCK != 0
+    }
+
+    fn flash(&self) -> bool {
+        self.control & CONTROL_FLASH != 0
+    }
+
+    // The byte at an address in display memory, with anything past the end
+    // reading as zero
+    pub fn byte(&self, address: usize) -> Byte {
+        self.memory.get(address).copied().unwrap_or(0)
+    }
+
+    // Where in memory the character at a row and column comes from: the
+    // byte itself in MODE 7, otherwise its top scanline
+    pub fn address(&self, column: usize, row: usize) -> usize {
+        let ma = (self.start as usize + row * self.columns + column) & 0x3fff;
+        if self.teletext() {
+            let page = if ma & 0x800 != 0 {
+                TELETEXT_SCREEN
+            } else {
+                TELETEXT_PAGE
+            };
+            return page | (ma & 0x3ff);
+        }
+
+        let address = ma << 3;
+        if address >= DISPLAY_MEMORY {
+            (address - self.wrap) & (DISPLAY_MEMORY - 1)
+        } else {
+            address
+        }
+    }
+
+    // Pixels the ULA shifts out of each byte, one every 16 / rate ticks
+    fn pixels_per_byte(&self) -> usize {
+        let rate = 2 << ((self.control & CONTROL_CHARACTERS_PER_LINE) >> 2);
+        let bytes = if self.fast_clock() { 2 } else { 1 };
+        rate / bytes
+    }
+
+    fn character_width(&self) -> usize {
+        if self.teletext() || !self.fast_clock() {
+            CHARACTER_WIDTH
+        } else {
+            CHARACTER_WIDTH / 2
+        }
+    }
+
+    // The physical colour for a logical one, with flashing colours showing
+    // whichever way round the flash bit has them
+    pub fn colour(&self, logical: usize) -> usize {
+        let entry = self.palette[logical & 0x0f];
+        let colour = (entry & 0x07) ^ 0x07;
+        if entry & 0x08 != 0 && self.flash() {
+            (colour ^ 0x07) as usize
+        } else {
+            colour as usize
+        }
+    }
+
+    // The physical colour of each pixel the ULA shifts out of a byte. The
+    // logical colour is in bits 7, 5, 3 and 1, and ones shift in at the
+    // bottom.
+    fn byte_colours(&self, mut byte: Byte) -> Vec<usize> {
+        let mut colours = Vec::new();
+        for _ in 0..self.pixels_per_byte().max(1) {
+            let logical =
+                (byte >> 4 & 0x08) | (byte >> 3 & 0x04) | (byte >> 2 & 0x02) | (byte >> 1 & 0x01);
+            colours.push(self.colour(logical as usize));
+            byte = byte << 1 | 0x01;
+        }
+        colours
+    }
+
+    pub fn render(&self) -> Frame {
+        let mut frame = Frame::new(
+            self.columns * self.character_width(),
+            self.rows * self.scanlines,
+        );
+        for row in 0..self.rows {
+            if self.teletext() {
+                self.teletext_row(&mut frame, row);
+            } else {
+                self.bitmap_row(&mut frame, row);
+            }
+        }
+        frame
+    }
+
+    fn bitmap_row(&self, frame: &mut Frame, row: usize) {
+        let per_byte = self.pixels_per_byte().max(1);
+        let width = self.character_width() / per_byte.min(self.character_width());
+        // Scanlines past the eighth are blank
+        for scanline in 0..self.scanlines.min(8) {
+            let y = row * self.scanlines + scanline;
+            for column in 0..self.columns {
+                let byte = self.byte(self.address(column, row) + scanline);
+                for (pixel, colour) in self.byte_colours(byte).into_iter().enumerate() {
+                    let x = column * self.character_width() + pixel * width;
+                    for x in x..(x + width).min(frame.width) {
+                        frame.set_pixel(x, y, COLOURS[colour]);
+                    }
+                }
+            }
+        }
+    }
+
+    fn teletext_row(&self, frame: &mut Frame, row: usize) {
+        let mut foreground = 7;
+        let mut background = 0;
+        let mut graphics = false;
+        let mut separated = false;
+
+        for column in 0..self.columns {
+            let code = self.byte(self.address(column, row)) & 0x7f;
+            // Background changes take effect on the control code itself,
+            // colour changes on the character after
+            match code {
+                0x1c => background = 0,
+                0x1d => background = foreground,
+                _ => (),
+            }
+            let cell = |x: usize, line: usize| -> bool {
+                if code < 0x20 {
+                    false
+                } else if graphics && code & 0x20 != 0 {
+                    mosaic(code, separated, x, line, self.scanlines)
+                } else {
+                    let line = line.wrapping_sub((self.scanlines - GLYPH_HEIGHT) / 2);
+                    line < GLYPH_HEIGHT
+                        && self
+                            .font
+                            .get((code as usize - 0x20) * 8 + line)
+                            .copied()
+                            .unwrap_or(0)
+                            & (0x80 >> (x / 2))
+                            != 0
+                }
+            };
+            for line in 0..self.scanlines {
+                for x in 0..CHARACTER_WIDTH {
+                    let colour = if cell(x, line) {
+                        foreground
+                    } else {
+                        background
+                    };
+                    frame.set_pixel(
+                        column * CHARACTER_WIDTH + x,
+                        row * self.scanlines + line,
+                        COLOURS[colour],
+                    );
+                }
+            }
+            match code {
+                0x01..=0x07 => {
+                    foreground = code as usize;
+                    graphics = false;
+                }
+                0x11..=0x17 => {
+                    foreground = code as usize & 0x07;
+                    graphics = true;
+                }
+                0x19 => separated = false,
+                0x1a => separated = true,
+                _ => (),
+            }
+        }
+    }
+}
+
+// Six blocks in a 2x3 grid, from bits 0-4 and 6 of the character. Separated
+// graphics leave a gap down the left and along the bottom of each block.
+fn mosaic(code: Byte, separated: bool, x: usize, line: usize, height: usize) -> bool {
+    let third = height / 3;
+    let bands = [third, height - third, height];
+    let band = bands.iter().position(|&end| line < end).unwrap_or(2);
+    let bit = [0x01, 0x02, 0x04, 0x08, 0x10, 0x40][band * 2 + x / (CHARACTER_WIDTH / 2)];
+    if separated && (x % (CHARACTER_WIDTH / 2) < 2 || line + 1 == bands[band]) {
+        return false;
+    }
+    code & bit != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Memory;
+    use crate::devices::crtc::{R12_START_HIGH, R13_START_LOW, R8_INTERLACE, R9_SCANLINES};
+
+    fn crtc(registers: &[(usize, Byte)]) -> Crtc {
+        let mut crtc = Crtc::new();
+        for &(register, data) in registers {
+            crtc.set_register(register, data);
+        }
+        crtc
+    }
+
+    fn ula(control: Byte, palette: &[Byte]) -> Result<VideoUla> {
+        let mut ula = VideoUla::new();
+        ula.write_byte(0, control)?;
+        for &entry in palette {
+            ula.write_byte(1, entry)?;
+        }
+        Ok(ula)
+    }
+
+    // MODE 1 as the MOS sets it up: 80 columns of 2MHz characters from
+    // &3000, with black, red, yellow and white
+    fn mode1() -> Result<(Crtc, VideoUla)> {
+        let crtc = crtc(&[
+            (R1_HORIZONTAL_DISPLAYED, 80),
+            (R6_VERTICAL_DISPLAYED, 32),
+            (R9_SCANLINES, 7),
+            (R12_START_HIGH, 0x06),
+            (R13_START_LOW, 0x00),
+        ]);
+        let mut palette = Vec::new();
+        for logical in 0..16 {
+            let colour = match logical & 0x0a {
+                0x00 => 0,
+                0x02 => 1,
+                0x08 => 3,
+                _ => 7,
+            };
+            palette.push(logical << 4 | (colour ^ 0x07));
+        }
+        Ok((crtc, ula(0xd8, &palette)?))
+    }
+
+    #[test]
+    fn mode1_pixels() -> Result<()> {
+        let (crtc, ula) = mode1()?;
+        let mut memory = vec![0; DISPLAY_MEMORY];
+        // Four pixels: colours 0, 1, 2 and 3
+        memory[0x3000] = 0b0011_0101;
+        // The second scanline of the second character
+        memory[0x3009] = 0xff;
+        let screen = Screen::new(&crtc, &ula, 0x20, memory, &[]);
+        let frame = screen.render();
+
+        assert_eq!((frame.width(), frame.height()), (640, 256));
+        let row: Vec<Rgb> = (0..8).step_by(2).map(|x| frame.pixel(x, 0)).collect();
+        assert_eq!(row, [COLOURS[0], COLOURS[1], COLOURS[3], COLOURS[7]]);
+        assert_eq!(frame.pixel(1, 0), COLOURS[0]);
+        assert_eq!(frame.pixel(3, 0), COLOURS[1]);
+        assert_eq!(frame.pixel(8, 0), COLOURS[0]);
+        assert_eq!(frame.pixel(8, 1), COLOURS[7]);
+        assert_eq!(frame.pixel(15, 1), COLOURS[7]);
+        assert_eq!(frame.pixel(16, 1), COLOURS[0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn scrolled_screen_wraps() -> Result<()> {
+        let (mut crtc, ula) = mode1()?;
+        // Scrolled so the last character of the top row is at &7FF8
+        crtc.set_register(R12_START_HIGH, 0x0f);
+        crtc.set_register(R13_START_LOW, 0xb0);
+        let mut memory = vec![0; DISPLAY_MEMORY];
+        memory[0x7ff8] = 0xff;
+        memory[0x3000] = 0xf0;
+        let screen = Screen::new(&crtc, &ula, 0x20, memory, &[]);
+
+        assert_eq!(screen.address(79, 0), 0x7ff8);
+        assert_eq!(screen.address(0, 1), 0x3000);
+        let frame = screen.render();
+        assert_eq!(frame.pixel(639, 0), COLOURS[7]);
+        assert_eq!(frame.pixel(0, 8), COLOURS[3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn flashing_colours() -> Result<()> {
+        let ula_off = ula(0x88, &[0x07, 0x8e])?;
+        let ula_on = ula(0x89, &[0x07, 0x8e])?;
+        let screen = Screen::new(&Crtc::new(), &ula_off, 0, Vec::new(), &[]);
+        assert_eq!(screen.colour(0), 0);
+        assert_eq!(screen.colour(8), 1);
+        let screen = Screen::new(&Crtc::new(), &ula_on, 0, Vec::new(), &[]);
+        assert_eq!(screen.colour(8), 6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn mode7_text_and_graphics() -> Result<()> {
+        let crtc = crtc(&[
+            (R1_HORIZONTAL_DISPLAYED, 40),
+            (R6_VERTICAL_DISPLAYED, 25),
+            (R8_INTERLACE, 0x93),
+            (R9_SCANLINES, 18),
+            (R12_START_HIGH, 0x28),
+            (R13_START_LOW, 0x00),
+        ]);
+        let ula = ula(0x4b, &[])?;
+        let mut font = vec![0; FONT_SIZE];
+        // 'A' as a solid block on its top line
+        font[(b'A' - 0x20) as usize * 8] = 0xff;
+        let mut memory = vec![0x20; DISPLAY_MEMORY];
+        // Red text, then green graphics with a solid block
+        memory[0x7c00..0x7c05].copy_from_slice(&[0x81, b'A', 0x92, 0x7f, 0x9d]);
+        let screen = Screen::new(&crtc, &ula, 0, memory, &font);
+        let frame = screen.render();
+
+        assert_eq!((frame.width(), frame.height()), (640, 250));
+        assert_eq!(screen.address(1, 0), 0x7c01);
+        // Control codes show as spaces
+        assert_eq!(frame.pixel(0, 1), COLOURS[0]);
+        assert_eq!(frame.pixel(16, 0), COLOURS[0]);
+        assert_eq!(frame.pixel(16, 1), COLOURS[1]);
+        assert_eq!(frame.pixel(31, 1), COLOURS[1]);
+        assert_eq!(frame.pixel(16, 2), COLOURS[0]);
+        // &7F is every block; &9D is new background with the colour so far
+        assert_eq!(frame.pixel(48, 0), COLOURS[2]);
+        assert_eq!(frame.pixel(63, 9), COLOURS[2]);
+        assert_eq!(frame.pixel(64, 5), COLOURS[2]);
+        assert_eq!(frame.pixel(639, 5), COLOURS[2]);
+        assert_eq!(frame.pixel(0, 15), COLOURS[0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn separated_mosaics() {
+        // Bottom left block only
+        assert!(mosaic(0x30, false, 0, 9, 10));
+        assert!(!mosaic(0x30, false, 8, 9, 10));
+        assert!(!mosaic(0x30, false, 0, 6, 10));
+        assert!(!mosaic(0x30, true, 0, 8, 10));
+        assert!(!mosaic(0x30, true, 3, 9, 10));
+        assert!(mosaic(0x30, true, 3, 8, 10));
+    }
+
+    #[test]
+    fn png_of_a_frame() {
+        let mut frame = Frame::new(4, 2);
+        frame.set_pixel(3, 1, COLOURS[4]);
+        let png = frame.to_png();
+        assert_eq!(png[16..24], [0, 0, 0, 4, 0, 0, 0, 2]);
+        assert_eq!(png, png::encode(4, 2, frame.pixels()));
+    }
+}
@@ -0,0 +1,254 @@
+use crate::cpu::Byte;
+use crate::video::Rgb;
+
+// A PNG encoder with nothing to tune, so the same pixels always give the same
+// file. Images with up to 256 colours are written with a palette, in the
+// order the colours first appear, otherwise as 8-bit RGB. Every scanline uses
+// filter 0 and the deflate stream is one block of fixed Huffman codes, with
+// matches only against the previous pixel and the scanline above: that's
+// most of what there is to find on a screen of runs and repeated lines.
+
+const SIGNATURE: [Byte; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+const COLOUR_TYPE_RGB: Byte = 2;
+const COLOUR_TYPE_PALETTE: Byte = 3;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+const LENGTH_BASE: [usize; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [usize; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const MAX_DISTANCE: usize = 32768;
+
+fn crc32(bytes: &[Byte]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[Byte]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn chunk(png: &mut Vec<Byte>, kind: &[Byte; 4], data: &[Byte]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Deflate packs bits from the least significant end, but Huffman codes go in
+// most significant bit first
+struct Bits {
+    bytes: Vec<Byte>,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits {
+    fn write(&mut self, value: u32, count: u32) {
+        self.buffer |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as Byte);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn literal(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn length(&mut self, length: usize) {
+        let n = LENGTH_BASE
+            .iter()
+            .rposition(|&base| base <= length)
+            .unwrap();
+        self.literal(257 + n as u32);
+        self.write((length - LENGTH_BASE[n]) as u32, LENGTH_EXTRA[n]);
+    }
+
+    fn distance(&mut self, distance: usize) {
+        let n = DISTANCE_BASE
+            .iter()
+            .rposition(|&base| base <= distance)
+            .unwrap();
+        self.code(n as u32, 5);
+        self.write((distance - DISTANCE_BASE[n]) as u32, DISTANCE_EXTRA[n]);
+    }
+
+    fn finish(mut self) -> Vec<Byte> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as Byte);
+        }
+        self.bytes
+    }
+}
+
+fn deflate(data: &[Byte], stride: usize) -> Vec<Byte> {
+    let mut bits = Bits {
+        bytes: Vec::new(),
+        buffer: 0,
+        count: 0,
+    };
+    // Final block, fixed Huffman codes
+    bits.write(0b011, 3);
+
+    let matching = |position: usize, distance: usize| {
+        if distance == 0 || distance > position || distance > MAX_DISTANCE {
+            return 0;
+        }
+        let most = MAX_MATCH.min(data.len() - position);
+        (0..most)
+            .take_while(|&n| data[position + n] == data[position + n - distance])
+            .count()
+    };
+
+    let mut position = 0;
+    while position < data.len() {
+        let (length, distance) = [1, stride]
+            .iter()
+            .map(|&distance| (matching(position, distance), distance))
+            .max_by_key(|&(length, distance)| (length, usize::MAX - distance))
+            .unwrap();
+        if length >= MIN_MATCH {
+            bits.length(length);
+            bits.distance(distance);
+            position += length;
+        } else {
+            bits.literal(data[position] as u32);
+            position += 1;
+        }
+    }
+    bits.literal(256);
+
+    let mut zlib = vec![0x78, 0x01];
+    zlib.extend(bits.finish());
+    zlib.extend_from_slice(&adler32(data).to_be_bytes());
+    zlib
+}
+
+pub fn encode(width: usize, height: usize, pixels: &[Rgb]) -> Vec<Byte> {
+    let mut palette: Vec<Rgb> = Vec::new();
+    for pixel in pixels {
+        if !palette.contains(pixel) {
+            palette.push(*pixel);
+            if palette.len() > 256 {
+                break;
+            }
+        }
+    }
+    let indexed = palette.len() <= 256;
+
+    let stride = if indexed { width + 1 } else { width * 3 + 1 };
+    let mut data = Vec::with_capacity(stride * height);
+    for line in pixels.chunks(width.max(1)).take(height) {
+        data.push(0);
+        for pixel in line {
+            if indexed {
+                data.push(palette.iter().position(|colour| colour == pixel).unwrap() as Byte);
+            } else {
+                data.extend_from_slice(pixel);
+            }
+        }
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    let colour_type = if indexed {
+        COLOUR_TYPE_PALETTE
+    } else {
+        COLOUR_TYPE_RGB
+    };
+    // 8 bits, deflate, no filter choice, not interlaced
+    header.extend_from_slice(&[8, colour_type, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    if indexed {
+        chunk(&mut png, b"PLTE", &palette.concat());
+    }
+    chunk(&mut png, b"IDAT", &deflate(&data, stride));
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn small_indexed_image() {
+        let red = [0xff, 0x00, 0x00];
+        let blue = [0x00, 0x00, 0xff];
+        let png = encode(2, 2, &[red, blue, red, blue]);
+
+        let mut expected = SIGNATURE.to_vec();
+        // IHDR: 2x2, 8 bits, palette
+        expected.extend_from_slice(b"\0\0\0\x0dIHDR\0\0\0\x02\0\0\0\x02\x08\x03\0\0\0");
+        expected.extend_from_slice(b"\x45\x68\xfd\x16");
+        // PLTE: red then blue
+        expected.extend_from_slice(b"\0\0\0\x06PLTE\xff\0\0\0\0\xff\x6c\xa1\xfd\x8e");
+        // IDAT: the first scanline's filter byte and indexes as literals,
+        // then the second as a match with the first
+        expected.extend_from_slice(b"\0\0\0\x0cIDAT\x78\x01\x63\x60\x60\x04\x22\0");
+        expected.extend_from_slice(b"\0\x0b\0\x03\x34\x5b\x6e\xdc");
+        expected.extend_from_slice(b"\0\0\0\0IEND\xae\x42\x60\x82");
+        assert_eq!(png, expected);
+    }
+
+    #[test]
+    fn many_colours_are_rgb() {
+        let pixels: Vec<Rgb> = (0..300).map(|n| [n as Byte, (n >> 8) as Byte, 0]).collect();
+        let png = encode(300, 1, &pixels);
+        assert_eq!(png[24..26], [8, COLOUR_TYPE_RGB]);
+        assert_eq!(&png[37..41], b"IDAT");
+    }
+}
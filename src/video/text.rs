@@ -0,0 +1,256 @@
+use crate::cpu::Byte;
+use crate::video::{Screen, FONT_SIZE, GLYPH_HEIGHT};
+
+// Reading the screen back as text, for checking what a program printed
+// without comparing pixels. MODE 7 is read straight from the character
+// codes. In the bitmap modes each character cell is matched against the
+// MOS's own character set, in whichever two colours the cell uses, so only
+// text the MOS drew on a character boundary comes back: anything else reads
+// as UNKNOWN, and a cell in one colour reads as a space.
+
+pub const UNKNOWN: char = '\u{fffd}';
+
+// The MOS swaps these around in MODE 7 so they show as they're printed
+fn teletext_character(code: Byte) -> char {
+    match code {
+        0x23 => '£',
+        0x5f => '#',
+        0x60 => '_',
+        0x7f => ' ',
+        _ => code as char,
+    }
+}
+
+fn font_character(code: usize) -> char {
+    match code {
+        0x60 => '£',
+        _ => code as u8 as char,
+    }
+}
+
+impl Screen {
+    // Bytes across each character in a bitmap mode: one in the two colour
+    // modes, two in the four colour ones and four in MODE 2
+    fn bytes_per_character(&self) -> usize {
+        (8 / self.pixels_per_byte().max(1)).max(1)
+    }
+
+    // A line per character row, without trailing spaces
+    pub fn text(&self) -> Vec<String> {
+        (0..self.rows)
+            .map(|row| {
+                let line = if self.teletext() {
+                    self.teletext_line(row)
+                } else {
+                    self.bitmap_line(row)
+                };
+                line.trim_end().to_string()
+            })
+            .collect()
+    }
+
+    // Whether the text is anywhere on the screen, within a line
+    pub fn contains(&self, text: &str) -> bool {
+        self.text().iter().any(|line| line.contains(text))
+    }
+
+    fn teletext_line(&self, row: usize) -> String {
+        let mut graphics = false;
+        (0..self.columns)
+            .map(|column| {
+                let code = self.byte(self.address(column, row)) & 0x7f;
+                match code {
+                    0x01..=0x07 => graphics = false,
+                    0x11..=0x17 => graphics = true,
+                    _ => (),
+                }
+                if code < 0x20 || (graphics && code & 0x20 != 0) {
+                    ' '
+                } else {
+                    teletext_character(code)
+                }
+            })
+            .collect()
+    }
+
+    fn bitmap_line(&self, row: usize) -> String {
+        let width = self.bytes_per_character();
+        (0..self.columns / width)
+            .map(|column| self.cell_character(column * width, row))
+            .collect()
+    }
+
+    fn cell_character(&self, first: usize, row: usize) -> char {
+        let width = self.bytes_per_character();
+        let mut cell = Vec::new();
+        for line in 0..GLYPH_HEIGHT.min(self.scanlines) {
+            let mut pixels = Vec::new();
+            for column in first..first + width {
+                let byte = self.byte(self.address(column, row) + line);
+                pixels.extend(self.byte_colours(byte));
+            }
+            pixels.truncate(8);
+            cell.push(pixels);
+        }
+
+        let mut colours: Vec<usize> = Vec::new();
+        for &colour in cell.iter().flatten() {
+            if !colours.contains(&colour) {
+                colours.push(colour);
+            }
+        }
+        match colours.len() {
+            0 | 1 => return ' ',
+            2 => (),
+            _ => return UNKNOWN,
+        }
+        // The usual way round first, on logical colour 0
+        if colours[1] == self.colour(0) {
+            colours.swap(0, 1);
+        }
+
+        for background in colours {
+            let glyph: Vec<Byte> = cell
+                .iter()
+                .map(|pixels| {
+                    pixels.iter().fold(0, |bits, &colour| {
+                        bits << 1 | (colour != background) as Byte
+                    })
+                })
+                .collect();
+            let found = self
+                .font
+                .chunks(8)
+                .take(FONT_SIZE / 8)
+                .position(|shape| shape[..glyph.len()] == glyph[..]);
+            if let Some(n) = found {
+                return font_character(0x20 + n);
+            }
+        }
+        UNKNOWN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{Memory, Result};
+    use crate::devices::crtc::{
+        Crtc, R12_START_HIGH, R1_HORIZONTAL_DISPLAYED, R6_VERTICAL_DISPLAYED, R8_INTERLACE,
+        R9_SCANLINES,
+    };
+    use crate::devices::video_ula::VideoUla;
+    use crate::video::DISPLAY_MEMORY;
+
+    // Every character's shape is its code on the top line, apart from the
+    // space
+    fn font() -> Vec<Byte> {
+        let mut font = vec![0; FONT_SIZE];
+        for code in 0x21..0x80 {
+            font[(code - 0x20) * 8] = code as Byte;
+            font[(code - 0x20) * 8 + 7] = 0x81;
+        }
+        font
+    }
+
+    fn screen(
+        registers: &[(usize, Byte)],
+        control: Byte,
+        palette: &[Byte],
+        memory: Vec<Byte>,
+    ) -> Result<Screen> {
+        let mut crtc = Crtc::new();
+        for &(register, data) in registers {
+            crtc.set_register(register, data);
+        }
+        let mut ula = VideoUla::new();
+        ula.write_byte(0, control)?;
+        for &entry in palette {
+            ula.write_byte(1, entry)?;
+        }
+        Ok(Screen::new(&crtc, &ula, 0x20, memory, &font()))
+    }
+
+    #[test]
+    fn mode7_text() -> Result<()> {
+        let mut memory = vec![0x20; DISPLAY_MEMORY];
+        memory[0x7c00..0x7c0e].copy_from_slice(b"\x81BBC Computer\x86");
+        // Graphics aren't text, but capitals in graphics mode still are
+        memory[0x7c28..0x7c31].copy_from_slice(b"\x97\x7f\x35AB\x82\x5f\x60\x23");
+        let screen = screen(
+            &[
+                (R1_HORIZONTAL_DISPLAYED, 40),
+                (R6_VERTICAL_DISPLAYED, 25),
+                (R8_INTERLACE, 0x93),
+                (R9_SCANLINES, 18),
+                (R12_START_HIGH, 0x28),
+            ],
+            0x4b,
+            &[],
+            memory,
+        )?;
+
+        let text = screen.text();
+        assert_eq!(text.len(), 25);
+        assert_eq!(text[0], " BBC Computer");
+        assert_eq!(text[1], "   AB #_£");
+        assert_eq!(text[2], "");
+        assert!(screen.contains("BBC Computer"));
+        assert!(!screen.contains("BBC Computer 32K"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn mode1_glyphs() -> Result<()> {
+        // Red on black, black on yellow, a shape that isn't a character,
+        // a blank cell in yellow and red on yellow
+        let mut palette = Vec::new();
+        for logical in 0..16 {
+            let colour = match logical & 0x0a {
+                0x00 => 0,
+                0x02 => 1,
+                0x08 => 3,
+                _ => 7,
+            };
+            palette.push(logical << 4 | (colour ^ 0x07));
+        }
+        let mut memory = vec![0; DISPLAY_MEMORY];
+        let mut draw = |column: usize, shape: [Byte; 8], foreground: Byte, background: Byte| {
+            for (line, &bits) in shape.iter().enumerate() {
+                for half in 0..2 {
+                    let mut byte = 0;
+                    for pixel in 0..4 {
+                        let set = bits & (0x80 >> (half * 4 + pixel)) != 0;
+                        let colour = if set { foreground } else { background };
+                        byte |= (colour >> 1 & 1) << (7 - pixel) | (colour & 1) << (3 - pixel);
+                    }
+                    memory[0x3000 + (column * 2 + half) * 8 + line] = byte;
+                }
+            }
+        };
+        draw(0, [b'O', 0, 0, 0, 0, 0, 0, 0x81], 1, 0);
+        draw(1, [b'K', 0, 0, 0, 0, 0, 0, 0x81], 0, 2);
+        draw(3, [0xaa, 0, 0, 0, 0, 0, 0, 0], 1, 0);
+        draw(4, [0, 0, 0, 0, 0, 0, 0, 0], 2, 2);
+        draw(5, [b'!', 0, 0, 0, 0, 0, 0, 0x81], 1, 3);
+        let screen = screen(
+            &[
+                (R1_HORIZONTAL_DISPLAYED, 80),
+                (R6_VERTICAL_DISPLAYED, 32),
+                (R9_SCANLINES, 7),
+                (R12_START_HIGH, 0x06),
+            ],
+            0xd8,
+            &palette,
+            memory,
+        )?;
+
+        let text = screen.text();
+        assert_eq!(text.len(), 32);
+        assert_eq!(text[0], format!("OK {} !", UNKNOWN));
+        assert!(text[1..].iter().all(String::is_empty));
+
+        Ok(())
+    }
+}